@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use iot_pal::link_format::parse_core_links;
+
+// Parsing must not panic, and successfully parsed links must round-trip
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = std::str::from_utf8(data) {
+        if let Ok(links) = parse_core_links(s) {
+            let encoded: Vec<_> = links.iter().map(|l| l.to_string()).collect();
+            let decoded = parse_core_links(&encoded.join(",")).expect("failed to parse encoded links");
+
+            assert_eq!(links, decoded);
+        }
+    }
+});
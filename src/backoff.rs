@@ -0,0 +1,205 @@
+//! Reconnect backoff with configurable jitter
+//!
+//! Jitter spreads reconnect attempts so a fleet of devices does not reconnect in lockstep
+//! when a broker restarts, see <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Error;
+use rand::Rng;
+
+
+/// Jitter strategy applied to exponential backoff delays
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Jitter {
+    /// No jitter, `min(max, min_delay * 2^attempt)`
+    None,
+    /// Random delay between zero and the exponential delay
+    Full,
+    /// Half the exponential delay plus a random delay up to the other half
+    Equal,
+    /// Random delay between the minimum and three times the previous delay,
+    /// recommended for large numbers of clients
+    Decorrelated,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        Jitter::Decorrelated
+    }
+}
+
+impl FromStr for Jitter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Jitter::None),
+            "full" => Ok(Jitter::Full),
+            "equal" => Ok(Jitter::Equal),
+            "decorrelated" => Ok(Jitter::Decorrelated),
+            _ => Err(Error::msg(format!("Unsupported jitter strategy: {:?} (expected none, full, equal or decorrelated)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Jitter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Jitter::None => write!(f, "none"),
+            Jitter::Full => write!(f, "full"),
+            Jitter::Equal => write!(f, "equal"),
+            Jitter::Decorrelated => write!(f, "decorrelated"),
+        }
+    }
+}
+
+/// Reconnect backoff options
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackoffOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "1s", parse(try_from_str = humantime::parse_duration)))]
+    /// Minimum (initial) reconnect delay
+    pub backoff_min: Duration,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "2m", parse(try_from_str = humantime::parse_duration)))]
+    /// Maximum reconnect delay
+    pub backoff_max: Duration,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "decorrelated"))]
+    /// Jitter strategy (none, full, equal, decorrelated)
+    pub backoff_jitter: Jitter,
+}
+
+impl Default for BackoffOptions {
+    fn default() -> Self {
+        Self {
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(120),
+            backoff_jitter: Jitter::default(),
+        }
+    }
+}
+
+/// Backoff state, producing the delay before each reconnect attempt
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    opts: BackoffOptions,
+    attempt: u32,
+    last: Duration,
+}
+
+impl Backoff {
+    /// Create a new backoff with the provided options
+    pub fn new(opts: BackoffOptions) -> Self {
+        let last = opts.backoff_min;
+        Self { opts, attempt: 0, last }
+    }
+
+    /// Fetch the number of attempts since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Reset the backoff following a successful connection
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.last = self.opts.backoff_min;
+    }
+
+    /// Compute the delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let min = self.opts.backoff_min.as_millis() as u64;
+        let max = (self.opts.backoff_max.as_millis() as u64).max(min);
+
+        // Exponential delay, saturating at the maximum
+        let exp = min.saturating_mul(1u64.checked_shl(self.attempt).unwrap_or(u64::MAX)).min(max);
+
+        let mut rng = rand::thread_rng();
+        let delay = match self.opts.backoff_jitter {
+            Jitter::None => exp,
+            Jitter::Full => rng.gen_range(0, exp + 1),
+            Jitter::Equal => exp / 2 + rng.gen_range(0, exp / 2 + 1),
+            Jitter::Decorrelated => {
+                let last = self.last.as_millis() as u64;
+                rng.gen_range(min, last.saturating_mul(3).max(min) + 1).min(max)
+            },
+        };
+
+        self.attempt = self.attempt.saturating_add(1);
+        self.last = Duration::from_millis(delay);
+
+        self.last
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        Some(self.next_delay())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(jitter: Jitter) -> BackoffOptions {
+        BackoffOptions {
+            backoff_min: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(10),
+            backoff_jitter: jitter,
+        }
+    }
+
+    /// Exponential delay for the provided attempt, saturating at the maximum
+    fn exp(o: &BackoffOptions, attempt: u32) -> Duration {
+        (o.backoff_min * 2u32.saturating_pow(attempt.min(16))).min(o.backoff_max)
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        for jitter in &[Jitter::None, Jitter::Full, Jitter::Equal, Jitter::Decorrelated] {
+            let o = opts(*jitter);
+            let mut b = Backoff::new(o.clone());
+            let mut last = o.backoff_min;
+
+            for attempt in 0..64 {
+                let d = b.next_delay();
+                let e = exp(&o, attempt);
+
+                let (lower, upper) = match jitter {
+                    Jitter::None => (e, e),
+                    Jitter::Full => (Duration::from_millis(0), e),
+                    Jitter::Equal => (e / 2, e),
+                    Jitter::Decorrelated => (o.backoff_min, (last * 3).min(o.backoff_max)),
+                };
+
+                assert!(d >= lower && d <= upper, "{} attempt {}: {:?} not in [{:?}, {:?}]", jitter, attempt, d, lower, upper);
+                assert!(d <= o.backoff_max);
+
+                last = d;
+            }
+
+            assert_eq!(b.attempts(), 64);
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let o = opts(Jitter::None);
+        let mut b = Backoff::new(o.clone());
+
+        for _ in 0..5 {
+            b.next_delay();
+        }
+
+        b.reset();
+        assert_eq!(b.attempts(), 0);
+        assert_eq!(b.next_delay(), o.backoff_min);
+    }
+}
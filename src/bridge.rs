@@ -0,0 +1,195 @@
+//! Helpers for bridging messages between clients
+//!
+//! Received messages carry only the topic and payload, so protocol specific attributes
+//! are not preserved when forwarding. Following an MQTT to CoAP hop only the topic
+//! (as the resource path) and payload survive, QoS, retain and any MQTT v5 properties
+//! are dropped and the message is sent as a confirmable PUT. Following a CoAP to MQTT
+//! hop the message is published at QoS 0 without retain.
+
+use log::{trace};
+use futures::stream::{Stream, StreamExt};
+use anyhow::Error;
+
+use crate::clients::ClientPub;
+
+#[cfg(all(feature = "serde", feature = "tokio"))]
+use {
+    log::debug,
+    serde::de::DeserializeOwned,
+    crate::codec::Codec,
+    crate::stores::Store,
+};
+
+
+/// Forward a received message to another client
+pub async fn forward<P: ClientPub + Send>(msg: &(String, Vec<u8>), dest: &mut P) -> Result<(), Error> {
+    trace!("Forwarding message on {} ({} bytes)", msg.0, msg.1.len());
+
+    dest.publish(&msg.0, &msg.1).await
+}
+
+/// Forward all messages from a subscription to another client until the source stream ends,
+/// returning the number of forwarded messages.
+///
+/// `map` is called to map each source topic to a destination topic, messages where this
+/// returns `None` are skipped.
+pub async fn forward_all<S, P, F>(src: &mut S, dest: &mut P, mut map: F) -> Result<usize, Error>
+where
+    S: Stream<Item = (String, Vec<u8>)> + Unpin,
+    P: ClientPub + Send,
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut count = 0;
+
+    while let Some((topic, data)) = src.next().await {
+        let topic = match map(&topic) {
+            Some(t) => t,
+            None => continue,
+        };
+
+        forward(&(topic, data), dest).await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Store all messages from a subscription until the source stream ends, returning the
+/// number of stored messages.
+///
+/// Payloads are decoded to `R` using the provided codec and written via `Store::store`, with
+/// the destination (ie. the index) determined by the store for each record. The subscription
+/// is not polled while a record is being stored, so when used with a batching store (ie.
+/// `ElasticBatch`) a store that can't keep up slows consumption and the client's (bounded)
+/// inbox applies rather than buffering here. Pending records are also flushed once the
+/// store's `next_flush` elapses while waiting for messages.
+///
+/// Per-message decode or store errors are passed to `on_error` with the originating topic
+/// (`None` for timed flushes) and do not stop the bridge. Pending records are flushed when
+/// the stream ends, with any failure here returned.
+#[cfg(all(feature = "serde", feature = "tokio"))]
+pub async fn bridge_to_store<Sub, S, C, R, E>(sub: &mut Sub, store: &mut S, codec: &C, mut on_error: E) -> Result<usize, Error>
+where
+    Sub: Stream<Item = (String, Vec<u8>)> + Unpin,
+    S: Store<R>,
+    C: Codec,
+    R: DeserializeOwned + Send + 'static,
+    E: FnMut(Option<&str>, Error),
+{
+    let mut count = 0;
+
+    loop {
+        // Wait for the next message, or pending records to become due
+        let next = match store.next_flush() {
+            Some(t) => match tokio::time::timeout_at(t.into(), sub.next()).await {
+                Ok(m) => m,
+                Err(_) => {
+                    if let Err(e) = store.flush().await {
+                        on_error(None, e);
+                    }
+                    continue;
+                }
+            },
+            None => sub.next().await,
+        };
+
+        let (topic, data) = match next {
+            Some(m) => m,
+            None => break,
+        };
+
+        let record = match codec.decode::<R>(&data) {
+            Ok(r) => r,
+            Err(e) => {
+                on_error(Some(&topic), e);
+                continue;
+            }
+        };
+
+        match store.store(record).await {
+            Ok(_) => count += 1,
+            Err(e) => on_error(Some(&topic), e),
+        }
+    }
+
+    debug!("Bridge source ended, flushing pending records");
+
+    store.flush().await?;
+
+    Ok(count)
+}
+
+#[cfg(all(test, feature = "serde", feature = "tokio", feature = "serde_json"))]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+    use serde::Serialize;
+
+    struct TestCodec;
+
+    impl Codec for TestCodec {
+        fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+            Ok(serde_json::from_slice(data)?)
+        }
+
+        fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error> {
+            Ok(serde_json::to_vec(v)?)
+        }
+    }
+
+    /// In-memory store, records are pending until flushed
+    #[derive(Default)]
+    struct MemStore {
+        pending: Vec<u32>,
+        records: Vec<u32>,
+    }
+
+    #[async_trait]
+    impl Store<u32> for MemStore {
+        type Query = ();
+
+        async fn store(&mut self, record: u32) -> Result<(), Error> {
+            match record {
+                0 => Err(Error::msg("zero")),
+                r => {
+                    self.pending.push(r);
+                    Ok(())
+                }
+            }
+        }
+
+        async fn search(&mut self, _query: ()) -> Result<Vec<u32>, Error> {
+            Ok(self.records.clone())
+        }
+
+        async fn flush(&mut self) -> Result<(), Error> {
+            self.records.extend(self.pending.drain(..));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bridge_mem_store() {
+        let msgs = vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), b"nope".to_vec()),
+            ("c".to_string(), b"0".to_vec()),
+            ("d".to_string(), b"4".to_vec()),
+        ];
+        let mut sub = futures::stream::iter(msgs);
+        let mut store = MemStore::default();
+        let mut errors = vec![];
+
+        let n = futures::executor::block_on(bridge_to_store(&mut sub, &mut store, &TestCodec, |t: Option<&str>, _e| {
+            errors.push(t.map(|t| t.to_string()))
+        })).unwrap();
+
+        assert_eq!(n, 2);
+        assert_eq!(errors, vec![Some("b".to_string()), Some("c".to_string())]);
+
+        // Pending records are flushed when the source ends
+        assert!(store.pending.is_empty());
+        assert_eq!(store.records, vec![1, 4]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Memory budgets for bounding internal buffers
+//!
+//! A `MemoryBudget` is shared (by cloning) between the components it bounds, and tracks
+//! current usage across these for introspection via `MemoryBudget::usage`.
+//!
+//! Bounds apply to the MQTT receive inbox (`MqttClient::with_budget`), the reorder
+//! buffer (`Reorder::with_budget`), and the Elasticsearch batch buffer (`ElasticBatch::with_budget`).
+//! Unset bounds fall back to the existing per-component options.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Error;
+
+
+/// Policy applied when a buffer reaches its bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BudgetPolicy {
+    /// Wait for space to become available
+    ///
+    /// For the MQTT inbox this blocks the receive callback, applying backpressure to the
+    /// broker connection, for up to `INBOX_BLOCK_TIMEOUT` before dropping. For the reorder
+    /// buffer (which only drains as messages arrive) buffered messages are released with
+    /// gaps treated as lost. For batches the buffer is flushed before accepting the new document.
+    Block,
+    /// Drop the new message or document
+    Drop,
+    /// Drop the new message or document and raise `PalError::BudgetExceeded`
+    ///
+    /// Returned directly where possible, for the MQTT inbox this is recorded as the
+    /// client's `last_error` and for reorder buffers (which cannot return errors) this
+    /// behaves as `Drop`.
+    Error,
+}
+
+impl Default for BudgetPolicy {
+    fn default() -> Self {
+        BudgetPolicy::Drop
+    }
+}
+
+impl FromStr for BudgetPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(BudgetPolicy::Block),
+            "drop" => Ok(BudgetPolicy::Drop),
+            "error" => Ok(BudgetPolicy::Error),
+            _ => Err(Error::msg(format!("Unsupported budget policy: {:?} (expected block, drop, or error)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for BudgetPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetPolicy::Block => write!(f, "block"),
+            BudgetPolicy::Drop => write!(f, "drop"),
+            BudgetPolicy::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Current buffer usage for components sharing a `MemoryBudget`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemoryUsage {
+    /// Messages buffered in receive inboxes
+    pub stream_msgs: usize,
+    /// Messages held in reorder buffers
+    pub reorder_msgs: usize,
+    /// Encoded bytes held in batch buffers
+    pub batch_bytes: usize,
+    /// Messages or documents dropped due to the budget
+    pub dropped: usize,
+}
+
+/// Shared bounds and usage for internal buffers
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    stream_msgs: Option<usize>,
+    reorder_msgs: Option<usize>,
+    batch_bytes: Option<usize>,
+    policy: BudgetPolicy,
+    usage: Arc<Usage>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Usage {
+    pub(crate) stream_msgs: AtomicUsize,
+    pub(crate) reorder_msgs: AtomicUsize,
+    pub(crate) batch_bytes: AtomicUsize,
+    pub(crate) dropped: AtomicUsize,
+}
+
+impl MemoryBudget {
+    /// Create a new (unbounded) budget with the provided policy
+    pub fn new(policy: BudgetPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Bound the number of messages buffered in a receive inbox
+    pub fn stream_msgs(mut self, limit: usize) -> Self {
+        self.stream_msgs = Some(limit);
+        self
+    }
+
+    /// Bound the number of messages held across all topics in a reorder buffer
+    pub fn reorder_msgs(mut self, limit: usize) -> Self {
+        self.reorder_msgs = Some(limit);
+        self
+    }
+
+    /// Bound the encoded size of documents held in a batch buffer
+    pub fn batch_bytes(mut self, limit: usize) -> Self {
+        self.batch_bytes = Some(limit);
+        self
+    }
+
+    /// Fetch the policy applied when a bound is reached
+    pub fn policy(&self) -> BudgetPolicy {
+        self.policy
+    }
+
+    /// Fetch current usage across all components sharing this budget
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            stream_msgs: self.usage.stream_msgs.load(Ordering::Relaxed),
+            reorder_msgs: self.usage.reorder_msgs.load(Ordering::Relaxed),
+            batch_bytes: self.usage.batch_bytes.load(Ordering::Relaxed),
+            dropped: self.usage.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn stream_limit(&self) -> Option<usize> {
+        self.stream_msgs
+    }
+
+    pub(crate) fn reorder_limit(&self) -> Option<usize> {
+        self.reorder_msgs
+    }
+
+    pub(crate) fn batch_limit(&self) -> Option<usize> {
+        self.batch_bytes
+    }
+
+    pub(crate) fn tracker(&self) -> Arc<Usage> {
+        self.usage.clone()
+    }
+}
+
+impl Usage {
+    /// Update a usage counter by the difference between the previous and current values
+    pub(crate) fn update(counter: &AtomicUsize, prev: usize, next: usize) {
+        if next > prev {
+            counter.fetch_add(next - prev, Ordering::Relaxed);
+        } else {
+            counter.fetch_sub(prev - next, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,290 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::timeout;
+
+use lapin::{Connection, ConnectionProperties, Channel, Consumer, BasicProperties};
+use lapin::options::{BasicPublishOptions, BasicConsumeOptions, QueueDeclareOptions, QueueBindOptions, QueueDeleteOptions};
+use lapin::types::FieldTable;
+use lapin::uri::{AMQPUri, AMQPScheme};
+use lapin::tcp::OwnedTLSConfig;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// Default exchange for publishing and subscriptions, matches the RabbitMQ MQTT plugin
+pub const DEFAULT_EXCHANGE: &str = "amq.topic";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AmqpOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for AMQP broker (prefixed with amqp:// or amqps://)
+    pub amqp_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "amq.topic"))]
+    /// Topic exchange for publishing and subscriptions
+    pub amqp_exchange: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// AMQP connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub amqp_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for AmqpOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            amqp_url: url.to_string(),
+            amqp_exchange: DEFAULT_EXCHANGE.to_string(),
+            amqp_connect_timeout: None,
+            tls_opts: TlsOptions::default(),
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for AmqpOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Generic futures-based AMQP 0-9-1 client abstraction
+///
+/// Messages are published to a topic exchange, with MQTT-style topics mapped to
+/// routing keys (`/` separators to `.`, `+` wildcards to `*`). Each subscription
+/// binds an exclusive, auto-deleted queue to the exchange and consumes from this.
+pub struct AmqpClient {
+    conn: Connection,
+    channel: Channel,
+    exchange: String,
+    subs: Vec<Subscription>,
+    last_error: Option<(Instant, PalError)>,
+    /// Index of the next subscription to poll, rotated for fairness
+    next: usize,
+}
+
+struct Subscription {
+    topic: String,
+    queue: String,
+    consumer: Consumer,
+}
+
+/// Map an MQTT-style topic (or filter) to an AMQP routing key
+fn routing_key(topic: &str) -> String {
+    topic.split('/')
+        .map(|l| if l == "+" { "*" } else { l })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Map an AMQP routing key to an MQTT-style topic
+fn topic(routing_key: &str) -> String {
+    routing_key.replace('.', "/")
+}
+
+impl AmqpClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<AmqpOptions>>(opts: O) -> Result<AmqpClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.amqp_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        match timeout(connect_timeout, Self::connect(&o)).await {
+            Ok(r) => r.map_err(|e| o.tls_opts.with_diagnostics(&o.amqp_url, e)),
+            Err(_) => Err(PalError::Timeout{ operation: format!("AMQP connect to {}", o.amqp_url), timeout: connect_timeout }.into()),
+        }
+    }
+
+    async fn connect(o: &AmqpOptions) -> Result<AmqpClient, Error> {
+        let mut uri = AMQPUri::from_str(&o.amqp_url).map_err(Error::msg)?;
+
+        if let Some(u) = &o.user_opts.username {
+            uri.authority.userinfo.username = u.clone();
+        }
+        if let Some(p) = &o.user_opts.password {
+            uri.authority.userinfo.password = p.clone();
+        }
+        if uri.query.heartbeat.is_none() {
+            uri.query.heartbeat = Some(o.defaults.keepalive.as_secs() as u16);
+        }
+
+        let tls = match uri.scheme {
+            AMQPScheme::AMQPS => Self::tls_config(&o.tls_opts)?,
+            AMQPScheme::AMQP => OwnedTLSConfig::default(),
+        };
+
+        debug!("Connecting to AMQP broker {}:{}", uri.authority.host, uri.authority.port);
+
+        let conn = Connection::connect_uri_with_config(uri, ConnectionProperties::default(), tls).await?;
+        let channel = conn.create_channel().await?;
+
+        Ok(AmqpClient{
+            conn,
+            channel,
+            exchange: o.amqp_exchange.clone(),
+            subs: vec![],
+            last_error: None,
+            next: 0,
+        })
+    }
+
+    /// Build the lapin TLS configuration
+    ///
+    /// lapin only supports client identities as PKCS#12 archives, so PEM client
+    /// certificates and insecure mode are rejected rather than silently ignored.
+    fn tls_config(tls: &TlsOptions) -> Result<OwnedTLSConfig, Error> {
+        tls.validate()?;
+
+        if tls.load_cert()?.is_some() || tls.load_key()?.is_some() {
+            return Err(Error::msg("AMQP client does not support TLS client certificates"))
+        }
+        if tls.tls_insecure || tls.tls_min_version.is_some() {
+            return Err(Error::msg("AMQP client does not support tls_insecure or tls_min_version"))
+        }
+
+        let cert_chain = match tls.load_ca()? {
+            Some(d) => Some(String::from_utf8(d)?),
+            None => None,
+        };
+
+        Ok(OwnedTLSConfig{ identity: None, cert_chain })
+    }
+
+    /// Fetch the exchange used for publishing and subscriptions
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+}
+
+#[async_trait]
+impl ClientBase for AmqpClient {
+    /// Close the AMQP connection
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.subs.clear();
+        self.conn.close(200, "OK").await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.conn.status().connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for AmqpClient {
+    /// Publish data to a topic (mapped to a routing key on the configured exchange)
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.conn.status().connected() {
+            return Err(PalError::NotConnected.into())
+        }
+
+        self.channel.basic_publish(&self.exchange, &routing_key(topic),
+            BasicPublishOptions::default(), data.to_vec(), BasicProperties::default()).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for AmqpClient {
+    /// Subscribe to a topic, binding a new exclusive queue to the configured exchange
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let opts = QueueDeclareOptions{ exclusive: true, auto_delete: true, ..Default::default() };
+        let queue = self.channel.queue_declare("", opts, FieldTable::default()).await?;
+        let queue = queue.name().as_str().to_string();
+
+        self.channel.queue_bind(&queue, &self.exchange, &routing_key(topic),
+            QueueBindOptions::default(), FieldTable::default()).await?;
+
+        let opts = BasicConsumeOptions{ no_ack: true, ..Default::default() };
+        let consumer = self.channel.basic_consume(&queue, "", opts, FieldTable::default()).await?;
+
+        debug!("Subscribed to {} (queue: {})", topic, queue);
+
+        self.subs.push(Subscription{ topic: topic.to_string(), queue, consumer });
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic, deleting the associated queue
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let s = self.subs.remove(i);
+        if self.next > i {
+            self.next -= 1;
+        }
+
+        self.channel.queue_delete(&s.queue, QueueDeleteOptions::default()).await?;
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for AmqpClient, polling subscriptions in turn
+impl Stream for AmqpClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let n = this.subs.len();
+
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+
+            match this.subs[idx].consumer.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok((_channel, d)))) => {
+                    this.next = (idx + 1) % n;
+                    return Poll::Ready(Some((topic(d.routing_key.as_str()), d.data)))
+                },
+                Poll::Ready(Some(Err(e))) => {
+                    warn!("AMQP consumer error: {:?}", e);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    return Poll::Ready(None)
+                },
+                Poll::Ready(None) => {
+                    this.last_error = Some((Instant::now(), PalError::Subscription{
+                        topic: this.subs[idx].topic.clone(),
+                        error: "consumer cancelled".to_string(),
+                    }));
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => (),
+            }
+        }
+
+        Poll::Pending
+    }
+}
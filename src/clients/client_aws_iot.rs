@@ -0,0 +1,292 @@
+//! AWS IoT Core device client, built on the MQTT client
+//!
+//! This configures X.509 mutual TLS (optionally via ALPN on port 443 for networks
+//! restricting outbound ports) and provides helpers for device shadow topics
+//! (`$aws/things/{thing}/shadow/...`), including named shadows.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use super::client_mqtt::{MqttClient, MqttOptions};
+use crate::{TlsOptions, TransportDefaults, PalError};
+
+/// ALPN protocol for MQTT with X.509 client certificates on port 443
+pub const ALPN_PROTOCOL: &str = "x-amzn-mqtt-ca";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AwsIotOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Account data endpoint (ie. example-ats.iot.us-east-1.amazonaws.com)
+    pub aws_endpoint: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Thing name for shadow topics
+    pub aws_thing_name: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// MQTT client ID (defaults to the thing name)
+    pub aws_client_id: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Connect on port 443 using ALPN rather than 8883
+    pub aws_port_443: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// MQTT keepalive interval (defaults to `TransportDefaults::keepalive`)
+    pub aws_keepalive: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl AwsIotOptions {
+    /// Create options for a thing authenticating with the provided TLS options
+    /// (requiring a client certificate and key)
+    pub fn new(endpoint: &str, thing_name: &str, tls_opts: TlsOptions) -> Self {
+        Self {
+            aws_endpoint: endpoint.to_string(),
+            aws_thing_name: thing_name.to_string(),
+            aws_client_id: None,
+            aws_port_443: false,
+            aws_keepalive: None,
+            tls_opts,
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Build MQTT options for the endpoint
+    pub fn mqtt_options(&self) -> Result<MqttOptions, Error> {
+        let has_cert = self.tls_opts.tls_cert_file.is_some() || self.tls_opts.tls_cert_pem.is_some();
+        let has_key = self.tls_opts.tls_key_file.is_some() || self.tls_opts.tls_key_pem.is_some();
+        if !has_cert || !has_key {
+            return Err(Error::msg("AWS IoT requires a client certificate and key for mutual TLS"))
+        }
+
+        let port = match self.aws_port_443 {
+            true => 443,
+            false => 8883,
+        };
+
+        let mut o = MqttOptions::from((format!("ssl://{}:{}", self.aws_endpoint, port), self.tls_opts.clone()));
+
+        o.mqtt_id = Some(self.aws_client_id.clone().unwrap_or_else(|| self.aws_thing_name.clone()));
+        o.mqtt_keepalive = self.aws_keepalive;
+        if self.aws_port_443 {
+            o.mqtt_alpn = vec![ALPN_PROTOCOL.to_string()];
+        }
+        o.defaults = self.defaults.clone();
+
+        Ok(o)
+    }
+}
+
+/// Device shadow topics, for the classic (unnamed) or a named shadow
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shadow {
+    prefix: String,
+}
+
+impl Shadow {
+    /// Create shadow topics for a thing, with an optional shadow name
+    pub fn new(thing_name: &str, shadow_name: Option<&str>) -> Self {
+        let prefix = match shadow_name {
+            Some(n) => format!("$aws/things/{}/shadow/name/{}", thing_name, n),
+            None => format!("$aws/things/{}/shadow", thing_name),
+        };
+        Self{ prefix }
+    }
+
+    /// Topic for shadow get requests
+    pub fn get(&self) -> String {
+        format!("{}/get", self.prefix)
+    }
+
+    /// Topic for shadow update requests
+    pub fn update(&self) -> String {
+        format!("{}/update", self.prefix)
+    }
+
+    /// Topic for shadow delete requests
+    pub fn delete(&self) -> String {
+        format!("{}/delete", self.prefix)
+    }
+
+    /// Topic for delta updates (differences between desired and reported state)
+    pub fn delta(&self) -> String {
+        format!("{}/update/delta", self.prefix)
+    }
+
+    /// Topic for complete shadow documents following each update
+    pub fn documents(&self) -> String {
+        format!("{}/update/documents", self.prefix)
+    }
+}
+
+/// AWS IoT Core device client
+///
+/// Topics are passed through to the underlying MQTT client, see `Shadow` for shadow topics
+/// and `get_shadow` / `update_shadow` for shadow requests.
+pub struct AwsIotClient {
+    mqtt: MqttClient,
+    thing_name: String,
+    /// Messages received while waiting on shadow responses, emitted before `mqtt`
+    pending: VecDeque<(String, Vec<u8>)>,
+}
+
+impl AwsIotClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: AwsIotOptions) -> Result<AwsIotClient, Error> {
+        let mqtt = MqttClient::new(opts.mqtt_options()?).await?;
+
+        debug!("Connected to AWS IoT {} as {}", opts.aws_endpoint, opts.aws_thing_name);
+
+        Ok(AwsIotClient{
+            mqtt,
+            thing_name: opts.aws_thing_name,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Fetch the inner MQTT client
+    pub fn inner(&mut self) -> &mut MqttClient {
+        &mut self.mqtt
+    }
+
+    /// Shadow topics for this thing
+    pub fn shadow(&self, name: Option<&str>) -> Shadow {
+        Shadow::new(&self.thing_name, name)
+    }
+
+    /// Fetch the shadow document (JSON)
+    pub async fn get_shadow(&mut self, name: Option<&str>, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let topic = self.shadow(name).get();
+        self.shadow_request(&topic, &[], timeout).await
+    }
+
+    /// Update the shadow with a (partial) JSON document, returning the accepted document
+    pub async fn update_shadow(&mut self, name: Option<&str>, doc: &[u8], timeout: Duration) -> Result<Vec<u8>, Error> {
+        let topic = self.shadow(name).update();
+        self.shadow_request(&topic, doc, timeout).await
+    }
+
+    /// Delete the shadow
+    pub async fn delete_shadow(&mut self, name: Option<&str>, timeout: Duration) -> Result<(), Error> {
+        let topic = self.shadow(name).delete();
+        self.shadow_request(&topic, &[], timeout).await?;
+        Ok(())
+    }
+
+    /// Subscribe to shadow delta updates
+    pub async fn subscribe_delta(&mut self, name: Option<&str>) -> Result<(), Error> {
+        let topic = self.shadow(name).delta();
+        self.mqtt.subscribe_qos(&topic, 1).await
+    }
+
+    /// Subscribe to complete shadow documents following each update
+    pub async fn subscribe_documents(&mut self, name: Option<&str>) -> Result<(), Error> {
+        let topic = self.shadow(name).documents();
+        self.mqtt.subscribe_qos(&topic, 1).await
+    }
+
+    /// Issue a shadow request, awaiting the accepted (or rejected) response
+    ///
+    /// The response topics are subscribed for the duration of the request. Messages received
+    /// on other topics while waiting are buffered and emitted by the stream as usual.
+    async fn shadow_request(&mut self, topic: &str, data: &[u8], timeout: Duration) -> Result<Vec<u8>, Error> {
+        let (accepted, rejected) = (format!("{}/accepted", topic), format!("{}/rejected", topic));
+
+        self.mqtt.subscribe_qos(&accepted, 1).await?;
+        self.mqtt.subscribe_qos(&rejected, 1).await?;
+        self.mqtt.publish_qos(topic, data, 1).await?;
+
+        let (mqtt, pending) = (&mut self.mqtt, &mut self.pending);
+        let wait = async {
+            while let Some((t, d)) = mqtt.next().await {
+                if t == accepted {
+                    return Ok(d)
+                } else if t == rejected {
+                    return Err(Error::msg(format!("AWS IoT shadow request {} rejected: {}", topic, String::from_utf8_lossy(&d))))
+                } else {
+                    pending.push_back((t, d));
+                }
+            }
+            Err(Error::from(PalError::ConnectionLost))
+        };
+
+        let r = match tokio::time::timeout(timeout, wait).await {
+            Ok(r) => r,
+            Err(_) => Err(PalError::Timeout{ operation: format!("AWS IoT shadow request {}", topic), timeout }.into()),
+        };
+
+        self.mqtt.unsubscribe(&accepted).await?;
+        self.mqtt.unsubscribe(&rejected).await?;
+
+        r
+    }
+}
+
+#[async_trait]
+impl ClientBase for AwsIotClient {
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.mqtt.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.mqtt.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.mqtt.last_error()
+    }
+}
+
+#[async_trait]
+impl ClientPub for AwsIotClient {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.mqtt.publish(topic, data).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for AwsIotClient {
+    /// Subscribe to a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.mqtt.subscribe(topic).await
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.mqtt.unsubscribe(topic).await
+    }
+}
+
+/// Stream implementation for AwsIotClient, emitting messages buffered during shadow requests first
+impl Stream for AwsIotClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(m) = this.pending.pop_front() {
+            return Poll::Ready(Some(m))
+        }
+
+        this.mqtt.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,399 @@
+//! Azure IoT Hub device client, built on the MQTT client
+//!
+//! This handles the IoT Hub MQTT conventions: client ID and username formats, SAS token
+//! generation and renewal, telemetry and cloud-to-device (C2D) topics, and device twin
+//! requests. Devices authenticate with either a symmetric key (SAS tokens) or an X.509
+//! certificate (via `TlsOptions`).
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use super::client_mqtt::{MqttClient, MqttOptions};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// IoT Hub MQTT API version
+pub const API_VERSION: &str = "2018-06-30";
+
+/// Default SAS token lifetime
+pub const DEFAULT_SAS_TTL: Duration = Duration::from_secs(3600);
+
+/// Interval prior to SAS token expiry at which tokens are renewed
+const SAS_RENEW_MARGIN: Duration = Duration::from_secs(300);
+
+/// Twin response topic filter
+const TWIN_RESPONSE_TOPIC: &str = "$iothub/twin/res/#";
+
+/// Desired property update topic filter
+pub const TWIN_DESIRED_TOPIC: &str = "$iothub/twin/PATCH/properties/desired/#";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AzureIotOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// IoT Hub hostname (ie. example.azure-devices.net)
+    pub azure_hub: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Device ID registered with the hub
+    pub azure_device_id: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    /// Device symmetric key (base64) for SAS token authentication,
+    /// X.509 authentication is used via `tls_cert_file` / `tls_key_file` if not set
+    pub azure_device_key: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// SAS token lifetime, tokens are renewed (reconnecting) prior to expiry (defaults to 1h)
+    pub azure_sas_ttl: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// MQTT keepalive interval (defaults to `TransportDefaults::keepalive`)
+    pub azure_keepalive: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl AzureIotOptions {
+    /// Create options for a device authenticating with a symmetric key
+    pub fn new(hub: &str, device_id: &str, device_key: &str) -> Self {
+        Self {
+            azure_hub: hub.to_string(),
+            azure_device_id: device_id.to_string(),
+            azure_device_key: Some(device_key.to_string()),
+            azure_sas_ttl: None,
+            azure_keepalive: None,
+            tls_opts: TlsOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Create options from a device connection string
+    /// (ie. `HostName=example.azure-devices.net;DeviceId=device;SharedAccessKey=...`)
+    pub fn from_connection_string(s: &str) -> Result<Self, Error> {
+        let (mut hub, mut device_id, mut key) = (None, None, None);
+
+        for part in s.split(';').filter(|p| !p.is_empty()) {
+            let i = part.find('=')
+                .ok_or_else(|| Error::msg(format!("Invalid IoT Hub connection string field: {:?}", part)))?;
+
+            match &part[..i] {
+                "HostName" => hub = Some(&part[i+1..]),
+                "DeviceId" => device_id = Some(&part[i+1..]),
+                "SharedAccessKey" => key = Some(&part[i+1..]),
+                f => debug!("Ignoring IoT Hub connection string field: {}", f),
+            }
+        }
+
+        match (hub, device_id, key) {
+            (Some(h), Some(d), Some(k)) => Ok(Self::new(h, d, k)),
+            _ => Err(Error::msg("IoT Hub connection string requires HostName, DeviceId and SharedAccessKey")),
+        }
+    }
+
+    /// Build MQTT options for the hub, generating a SAS token where a device key is set
+    pub fn mqtt_options(&self) -> Result<MqttOptions, Error> {
+        let password = match &self.azure_device_key {
+            Some(k) => {
+                let ttl = self.azure_sas_ttl.unwrap_or(DEFAULT_SAS_TTL);
+                let resource = format!("{}/devices/{}", self.azure_hub, self.azure_device_id);
+                Some(sas_token(&resource, k, None, SystemTime::now() + ttl)?)
+            },
+            None if self.tls_opts.tls_cert_file.is_some() || self.tls_opts.tls_cert_pem.is_some() => None,
+            None => return Err(Error::msg("IoT Hub authentication requires a device key or client certificate")),
+        };
+
+        let mut o = MqttOptions::from((format!("ssl://{}:8883", self.azure_hub), self.tls_opts.clone()));
+
+        o.mqtt_id = Some(self.azure_device_id.clone());
+        o.mqtt_keepalive = self.azure_keepalive;
+        o.user_opts = UserOptions {
+            username: Some(format!("{}/{}/?api-version={}", self.azure_hub, self.azure_device_id, API_VERSION)),
+            password,
+        };
+        o.defaults = self.defaults.clone();
+
+        Ok(o)
+    }
+}
+
+/// Generate an IoT Hub SAS token for a resource URI, signed with the provided (base64) key
+///
+/// `policy` sets the shared access policy name, for tokens signed with hub-level keys.
+pub fn sas_token(resource: &str, key: &str, policy: Option<&str>, expiry: SystemTime) -> Result<String, Error> {
+    let key = base64::decode(key)
+        .map_err(|e| Error::msg(format!("Invalid IoT Hub key (expected base64): {:?}", e)))?;
+
+    let resource = url_encode(resource);
+    let expiry = expiry.duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut mac = Hmac::<Sha256>::new_varkey(&key)
+        .map_err(|_| Error::msg("Invalid IoT Hub key length"))?;
+    mac.update(format!("{}\n{}", resource, expiry).as_bytes());
+    let sig = base64::encode(mac.finalize().into_bytes());
+
+    let mut token = format!("SharedAccessSignature sr={}&sig={}&se={}", resource, url_encode(&sig), expiry);
+    if let Some(p) = policy {
+        token.push_str(&format!("&skn={}", p));
+    }
+
+    Ok(token)
+}
+
+/// Percent-encode all but unreserved characters
+fn url_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// Azure IoT Hub device client
+///
+/// Publishing to `devices/...` or `$iothub/...` topics is passed through, other topics are sent
+/// as telemetry with the topic attached as the `topic` message property.
+/// Subscriptions are passed through, see `subscribe_c2d` and `subscribe_desired` for hub topics.
+///
+/// SAS tokens are renewed prior to expiry on publish / subscribe / twin requests,
+/// reconnecting and restoring subscriptions, so idle clients should call `renew` periodically.
+pub struct AzureIotClient {
+    mqtt: MqttClient,
+    opts: AzureIotOptions,
+    /// Time at which the current SAS token should be renewed
+    renew_at: Option<Instant>,
+    /// Next twin request ID
+    rid: u32,
+    twin_subscribed: bool,
+    /// Messages received while waiting on twin responses, emitted before `mqtt`
+    pending: VecDeque<(String, Vec<u8>)>,
+}
+
+impl AzureIotClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: AzureIotOptions) -> Result<AzureIotClient, Error> {
+        let mqtt = MqttClient::new(opts.mqtt_options()?).await?;
+
+        debug!("Connected to IoT Hub {} as {}", opts.azure_hub, opts.azure_device_id);
+
+        Ok(AzureIotClient{
+            mqtt,
+            renew_at: Self::renew_at(&opts),
+            opts,
+            rid: 1,
+            twin_subscribed: false,
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn renew_at(o: &AzureIotOptions) -> Option<Instant> {
+        o.azure_device_key.as_ref().map(|_| {
+            let ttl = o.azure_sas_ttl.unwrap_or(DEFAULT_SAS_TTL);
+            Instant::now() + ttl.checked_sub(SAS_RENEW_MARGIN).unwrap_or(ttl / 2)
+        })
+    }
+
+    /// Renew the SAS token if this is near expiry, reconnecting with the new token
+    pub async fn renew(&mut self) -> Result<(), Error> {
+        match self.renew_at {
+            Some(t) if Instant::now() >= t => (),
+            _ => return Ok(()),
+        }
+
+        debug!("Renewing IoT Hub SAS token for {}", self.opts.azure_device_id);
+
+        self.mqtt.update_options(self.opts.mqtt_options()?).await?;
+        self.renew_at = Self::renew_at(&self.opts);
+
+        Ok(())
+    }
+
+    /// Fetch the inner MQTT client
+    pub fn inner(&mut self) -> &mut MqttClient {
+        &mut self.mqtt
+    }
+
+    /// Telemetry (device-to-cloud) topic, with optional url-encoded message properties
+    pub fn telemetry_topic(&self, properties: &[(&str, &str)]) -> String {
+        let props: Vec<_> = properties.iter()
+            .map(|(k, v)| format!("{}={}", url_encode(k), url_encode(v)))
+            .collect();
+
+        format!("devices/{}/messages/events/{}", self.opts.azure_device_id, props.join("&"))
+    }
+
+    /// Cloud-to-device message topic filter
+    pub fn c2d_topic(&self) -> String {
+        format!("devices/{}/messages/devicebound/#", self.opts.azure_device_id)
+    }
+
+    /// Send telemetry with optional message properties
+    pub async fn send_telemetry(&mut self, data: &[u8], properties: &[(&str, &str)]) -> Result<(), Error> {
+        self.renew().await?;
+
+        let topic = self.telemetry_topic(properties);
+        self.mqtt.publish_qos(&topic, data, 1).await
+    }
+
+    /// Subscribe to cloud-to-device messages
+    pub async fn subscribe_c2d(&mut self) -> Result<(), Error> {
+        self.renew().await?;
+
+        let topic = self.c2d_topic();
+        self.mqtt.subscribe_qos(&topic, 1).await
+    }
+
+    /// Subscribe to desired property updates (received on `TWIN_DESIRED_TOPIC`)
+    pub async fn subscribe_desired(&mut self) -> Result<(), Error> {
+        self.renew().await?;
+
+        self.mqtt.subscribe(TWIN_DESIRED_TOPIC).await
+    }
+
+    /// Fetch the device twin document (JSON)
+    pub async fn get_twin(&mut self, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let (status, body) = self.twin_request("$iothub/twin/GET/", &[], timeout).await?;
+
+        match status {
+            200 => Ok(body),
+            s => Err(Error::msg(format!("IoT Hub twin request failed with status {}", s))),
+        }
+    }
+
+    /// Update reported properties with a JSON patch
+    pub async fn update_reported(&mut self, patch: &[u8], timeout: Duration) -> Result<(), Error> {
+        let (status, _) = self.twin_request("$iothub/twin/PATCH/properties/reported/", patch, timeout).await?;
+
+        match status {
+            204 => Ok(()),
+            s => Err(Error::msg(format!("IoT Hub reported property update failed with status {}", s))),
+        }
+    }
+
+    /// Issue a twin request, awaiting the response status and body
+    ///
+    /// Messages received on other topics while waiting are buffered and emitted by the stream as usual.
+    async fn twin_request(&mut self, topic: &str, data: &[u8], timeout: Duration) -> Result<(u16, Vec<u8>), Error> {
+        self.renew().await?;
+
+        if !self.twin_subscribed {
+            self.mqtt.subscribe(TWIN_RESPONSE_TOPIC).await?;
+            self.twin_subscribed = true;
+        }
+
+        let rid = self.rid.to_string();
+        self.rid = self.rid.wrapping_add(1);
+
+        self.mqtt.publish(&format!("{}?$rid={}", topic, rid), data).await?;
+
+        let (mqtt, pending) = (&mut self.mqtt, &mut self.pending);
+        let wait = async {
+            while let Some((t, d)) = mqtt.next().await {
+                match parse_twin_response(&t) {
+                    Some((status, r)) if r == rid => return Ok((status, d)),
+                    Some(_) => debug!("Ignoring IoT Hub twin response {}", t),
+                    None => pending.push_back((t, d)),
+                }
+            }
+            Err(Error::from(PalError::ConnectionLost))
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(r) => r,
+            Err(_) => Err(PalError::Timeout{ operation: format!("IoT Hub twin request {}", topic), timeout }.into()),
+        }
+    }
+}
+
+/// Parse a twin response topic (`$iothub/twin/res/{status}/?$rid={rid}[&$version={version}]`)
+/// into status and request ID
+fn parse_twin_response(topic: &str) -> Option<(u16, &str)> {
+    let rest = topic.strip_prefix("$iothub/twin/res/")?;
+    let (status, query) = rest.split_at(rest.find('/')?);
+
+    let rid = query.trim_start_matches("/?").split('&')
+        .find_map(|p| p.strip_prefix("$rid="))?;
+
+    Some((status.parse().ok()?, rid))
+}
+
+#[async_trait]
+impl ClientBase for AzureIotClient {
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.mqtt.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.mqtt.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.mqtt.last_error()
+    }
+}
+
+#[async_trait]
+impl ClientPub for AzureIotClient {
+    /// Publish to hub topics, or send telemetry with the topic as a message property
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if topic.starts_with("devices/") || topic.starts_with("$iothub/") {
+            self.renew().await?;
+            return self.mqtt.publish(topic, data).await
+        }
+
+        self.send_telemetry(data, &[("topic", topic)]).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for AzureIotClient {
+    /// Subscribe to a hub topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.renew().await?;
+        self.mqtt.subscribe(topic).await
+    }
+
+    /// Unsubscribe from a hub topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.mqtt.unsubscribe(topic).await
+    }
+}
+
+/// Stream implementation for AzureIotClient, emitting messages buffered during twin requests first
+impl Stream for AzureIotClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(m) = this.pending.pop_front() {
+            return Poll::Ready(Some(m))
+        }
+
+        loop {
+            match this.mqtt.poll_next_unpin(cx) {
+                // Drop late twin responses
+                Poll::Ready(Some((t, _))) if parse_twin_response(&t).is_some() => {
+                    debug!("Ignoring late IoT Hub twin response {}", t);
+                },
+                r => return r,
+            }
+        }
+    }
+}
@@ -0,0 +1,1190 @@
+//! BACnet/IP client
+//!
+//! Property paths (`object-type/instance/property[/index]`, ie. `analog-input/1/present-value`,
+//! with names or numeric identifiers) are used as topics. Publishing writes a property,
+//! subscribing issues a SubscribeCOV request for the object and delivers change of value
+//! notifications for the subscribed property via `Stream`.
+//!
+//! Property values are exchanged as text (multiple values separated by `,`). Writes are encoded
+//! using the type of the current property value (read prior to writing), `null` relinquishes
+//! a commanded value at the configured priority.
+//!
+//! Segmented messages are not supported, limiting requests and responses to a single APDU.
+//! Notifications are only handled while the client is polled as a `Stream` or while
+//! awaiting the response to a request.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use futures::lock::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::time::{Delay, Instant, delay_until, timeout_at};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+/// Default BACnet/IP port (0xBAC0)
+pub const DEFAULT_PORT: u16 = 47808;
+
+/// Default COV subscription lifetime
+pub const DEFAULT_COV_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Interval before retransmitting confirmed requests (APDU timeout)
+const APDU_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Maximum BACnet/IP datagram size
+const MAX_PACKET_LEN: usize = 1500;
+
+/// Max segments (unspecified) and max APDU length (1476 bytes) for confirmed requests
+const MAX_APDU: u8 = 0x05;
+
+const PDU_CONFIRMED_REQUEST: u8 = 0;
+const PDU_UNCONFIRMED_REQUEST: u8 = 1;
+const PDU_SIMPLE_ACK: u8 = 2;
+const PDU_COMPLEX_ACK: u8 = 3;
+const PDU_ERROR: u8 = 5;
+const PDU_REJECT: u8 = 6;
+const PDU_ABORT: u8 = 7;
+
+const SERVICE_CONFIRMED_COV_NOTIFICATION: u8 = 1;
+const SERVICE_SUBSCRIBE_COV: u8 = 5;
+const SERVICE_READ_PROPERTY: u8 = 12;
+const SERVICE_WRITE_PROPERTY: u8 = 15;
+const SERVICE_UNCONFIRMED_COV_NOTIFICATION: u8 = 2;
+
+const REJECT_UNRECOGNIZED_SERVICE: u8 = 9;
+const ABORT_SEGMENTATION_NOT_SUPPORTED: u8 = 4;
+
+const TAG_NULL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_UNSIGNED: u8 = 2;
+const TAG_SIGNED: u8 = 3;
+const TAG_REAL: u8 = 4;
+const TAG_DOUBLE: u8 = 5;
+const TAG_OCTET_STRING: u8 = 6;
+const TAG_CHARACTER_STRING: u8 = 7;
+const TAG_BIT_STRING: u8 = 8;
+const TAG_ENUMERATED: u8 = 9;
+const TAG_DATE: u8 = 10;
+const TAG_TIME: u8 = 11;
+const TAG_OBJECT_ID: u8 = 12;
+
+/// Object type names
+const OBJECT_TYPES: &[(&str, u16)] = &[
+    ("analog-input", 0), ("analog-output", 1), ("analog-value", 2),
+    ("binary-input", 3), ("binary-output", 4), ("binary-value", 5),
+    ("calendar", 6), ("command", 7), ("device", 8), ("event-enrollment", 9),
+    ("file", 10), ("group", 11), ("loop", 12),
+    ("multi-state-input", 13), ("multi-state-output", 14), ("notification-class", 15),
+    ("program", 16), ("schedule", 17), ("averaging", 18), ("multi-state-value", 19),
+    ("trend-log", 20),
+];
+
+/// Property identifier names
+const PROPERTIES: &[(&str, u32)] = &[
+    ("active-text", 4), ("cov-increment", 22), ("description", 28), ("event-state", 36),
+    ("inactive-text", 46), ("max-pres-value", 65), ("min-pres-value", 69),
+    ("number-of-states", 74), ("object-identifier", 75), ("object-list", 76),
+    ("object-name", 77), ("object-type", 79), ("out-of-service", 81), ("present-value", 85),
+    ("priority-array", 87), ("reliability", 103), ("relinquish-default", 104),
+    ("state-text", 110), ("status-flags", 111), ("units", 117),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BacnetOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// BACnet/IP device address (optionally prefixed with bacnet://, port defaults to 47808)
+    pub bacnet_device: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Local address to bind (defaults to an ephemeral port, some devices require 47808)
+    pub bacnet_bind: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Priority for property writes (1 to 16, defaults to none for non-commandable properties)
+    pub bacnet_write_priority: Option<u8>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// COV subscription lifetime, subscriptions are renewed at half the lifetime
+    /// (defaults to 5m, 0 for indefinite subscriptions)
+    pub bacnet_cov_lifetime: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Request confirmed COV notifications
+    pub bacnet_cov_confirmed: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for confirmed requests, including retries (defaults to `TransportDefaults::request_timeout`)
+    pub bacnet_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for BacnetOptions {
+    fn from(device: &str) -> Self {
+        Self {
+            bacnet_device: device.to_string(),
+            bacnet_bind: None,
+            bacnet_write_priority: None,
+            bacnet_cov_lifetime: None,
+            bacnet_cov_confirmed: false,
+            bacnet_request_timeout: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// BACnet object identifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObjectId {
+    pub object_type: u16,
+    pub instance: u32,
+}
+
+impl ObjectId {
+    fn encode(&self) -> [u8; 4] {
+        (((self.object_type as u32) << 22) | (self.instance & 0x3F_FFFF)).to_be_bytes()
+    }
+
+    fn decode(d: &[u8]) -> Result<Self, Error> {
+        if d.len() != 4 {
+            return Err(Error::msg("Invalid BACnet object identifier length"))
+        }
+        let v = u32::from_be_bytes([d[0], d[1], d[2], d[3]]);
+        Ok(Self{ object_type: (v >> 22) as u16, instance: v & 0x3F_FFFF })
+    }
+}
+
+impl std::fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match OBJECT_TYPES.iter().find(|(_, t)| *t == self.object_type) {
+            Some((n, _)) => write!(f, "{}/{}", n, self.instance),
+            None => write!(f, "{}/{}", self.object_type, self.instance),
+        }
+    }
+}
+
+/// BACnet property path (`object-type/instance/property[/index]`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BacnetPath {
+    pub object: ObjectId,
+    pub property: u32,
+    /// Array index
+    pub index: Option<u32>,
+}
+
+impl FromStr for BacnetPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::msg(format!("Invalid BACnet property path: {:?} (expected object-type/instance/property[/index])", s));
+
+        let parts: Vec<_> = s.trim_matches('/').split('/').collect();
+        let (t, i, p, index) = match parts.as_slice() {
+            [t, i, p] => (t, i, p, None),
+            [t, i, p, x] => (t, i, p, Some(x.parse().map_err(|_| invalid())?)),
+            _ => return Err(invalid()),
+        };
+
+        let object_type = match OBJECT_TYPES.iter().find(|(n, _)| n == t) {
+            Some((_, v)) => *v,
+            None => t.parse().map_err(|_| Error::msg(format!("Unknown BACnet object type: {:?}", t)))?,
+        };
+        let property = match PROPERTIES.iter().find(|(n, _)| n == p) {
+            Some((_, v)) => *v,
+            None => p.parse().map_err(|_| Error::msg(format!("Unknown BACnet property: {:?}", p)))?,
+        };
+
+        let instance = i.parse().map_err(|_| invalid())?;
+        if instance > 0x3F_FFFF {
+            return Err(Error::msg(format!("Invalid BACnet object instance: {} (expected 0 to 4194303)", instance)))
+        }
+
+        Ok(Self{ object: ObjectId{ object_type, instance }, property, index })
+    }
+}
+
+impl std::fmt::Display for BacnetPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/", self.object)?;
+        match PROPERTIES.iter().find(|(_, p)| *p == self.property) {
+            Some((n, _)) => write!(f, "{}", n)?,
+            None => write!(f, "{}", self.property)?,
+        }
+        if let Some(i) = self.index {
+            write!(f, "/{}", i)?;
+        }
+        Ok(())
+    }
+}
+
+/// BACnet application-tagged (primitive) value
+#[derive(Debug, Clone, PartialEq)]
+pub enum BacnetValue {
+    Null,
+    Boolean(bool),
+    Unsigned(u64),
+    Signed(i64),
+    Real(f32),
+    Double(f64),
+    OctetString(Vec<u8>),
+    CharacterString(String),
+    BitString(Vec<bool>),
+    Enumerated(u32),
+    /// Year (since 1900), month, day, weekday
+    Date([u8; 4]),
+    /// Hour, minute, second, hundredths
+    Time([u8; 4]),
+    ObjectId(ObjectId),
+}
+
+impl BacnetValue {
+    /// Parse a value from text using the type of this value
+    pub fn parse_as(&self, s: &str) -> Result<BacnetValue, Error> {
+        let s = s.trim();
+        let invalid = || Error::msg(format!("Invalid BACnet value for {:?}: {:?}", self, s));
+
+        if s == "null" {
+            return Ok(BacnetValue::Null)
+        }
+
+        let v = match self {
+            BacnetValue::Boolean(_) => match s {
+                "true" | "1" | "active" => BacnetValue::Boolean(true),
+                "false" | "0" | "inactive" => BacnetValue::Boolean(false),
+                _ => return Err(invalid()),
+            },
+            BacnetValue::Unsigned(_) => BacnetValue::Unsigned(s.parse().map_err(|_| invalid())?),
+            BacnetValue::Signed(_) => BacnetValue::Signed(s.parse().map_err(|_| invalid())?),
+            BacnetValue::Real(_) => BacnetValue::Real(s.parse().map_err(|_| invalid())?),
+            BacnetValue::Double(_) => BacnetValue::Double(s.parse().map_err(|_| invalid())?),
+            BacnetValue::Enumerated(_) => match s {
+                // Binary present values
+                "active" => BacnetValue::Enumerated(1),
+                "inactive" => BacnetValue::Enumerated(0),
+                _ => BacnetValue::Enumerated(s.parse().map_err(|_| invalid())?),
+            },
+            BacnetValue::CharacterString(_) => BacnetValue::CharacterString(s.to_string()),
+            BacnetValue::OctetString(_) => {
+                if s.len() % 2 != 0 {
+                    return Err(invalid())
+                }
+                let d: Result<Vec<u8>, _> = (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16)).collect();
+                BacnetValue::OctetString(d.map_err(|_| invalid())?)
+            },
+            _ => return Err(Error::msg(format!("Writing BACnet {:?} values is not supported", self))),
+        };
+
+        Ok(v)
+    }
+
+    fn decode(tag: u8, d: &[u8], len: u32) -> Result<Self, Error> {
+        let fixed = |n: usize| match d.len() == n {
+            true => Ok(()),
+            false => Err(Error::msg(format!("Invalid BACnet value length for tag {}: {}", tag, d.len()))),
+        };
+
+        let v = match tag {
+            TAG_NULL => BacnetValue::Null,
+            TAG_BOOLEAN => BacnetValue::Boolean(len != 0),
+            TAG_UNSIGNED => BacnetValue::Unsigned(unsigned(d)),
+            TAG_SIGNED => BacnetValue::Signed(signed(d)),
+            TAG_REAL => {
+                fixed(4)?;
+                BacnetValue::Real(f32::from_be_bytes([d[0], d[1], d[2], d[3]]))
+            },
+            TAG_DOUBLE => {
+                fixed(8)?;
+                let mut b = [0u8; 8];
+                b.copy_from_slice(d);
+                BacnetValue::Double(f64::from_be_bytes(b))
+            },
+            TAG_OCTET_STRING => BacnetValue::OctetString(d.to_vec()),
+            TAG_CHARACTER_STRING => {
+                // Character set 0 is UTF-8 (ANSI X3.4 in earlier revisions), others are decoded lossily
+                let s = d.get(1..).unwrap_or_default();
+                BacnetValue::CharacterString(String::from_utf8_lossy(s).to_string())
+            },
+            TAG_BIT_STRING => {
+                let unused = *d.get(0).unwrap_or(&0) as usize;
+                let bits = d.get(1..).unwrap_or_default();
+                let n = (bits.len() * 8).saturating_sub(unused);
+                BacnetValue::BitString((0..n).map(|i| bits[i / 8] & (0x80 >> (i % 8)) != 0).collect())
+            },
+            TAG_ENUMERATED => BacnetValue::Enumerated(unsigned(d) as u32),
+            TAG_DATE => {
+                fixed(4)?;
+                BacnetValue::Date([d[0], d[1], d[2], d[3]])
+            },
+            TAG_TIME => {
+                fixed(4)?;
+                BacnetValue::Time([d[0], d[1], d[2], d[3]])
+            },
+            TAG_OBJECT_ID => BacnetValue::ObjectId(ObjectId::decode(d)?),
+            _ => return Err(Error::msg(format!("Unsupported BACnet application tag: {}", tag))),
+        };
+
+        Ok(v)
+    }
+
+    fn encode(&self, b: &mut Vec<u8>) {
+        match self {
+            BacnetValue::Null => b.push(TAG_NULL << 4),
+            BacnetValue::Boolean(v) => b.push((TAG_BOOLEAN << 4) | *v as u8),
+            BacnetValue::Unsigned(v) => app(b, TAG_UNSIGNED, &unsigned_bytes(*v)),
+            BacnetValue::Signed(v) => app(b, TAG_SIGNED, &signed_bytes(*v)),
+            BacnetValue::Real(v) => app(b, TAG_REAL, &v.to_be_bytes()),
+            BacnetValue::Double(v) => app(b, TAG_DOUBLE, &v.to_be_bytes()),
+            BacnetValue::OctetString(v) => app(b, TAG_OCTET_STRING, v),
+            BacnetValue::CharacterString(v) => {
+                let mut d = vec![0];
+                d.extend_from_slice(v.as_bytes());
+                app(b, TAG_CHARACTER_STRING, &d);
+            },
+            BacnetValue::BitString(v) => {
+                let mut d = vec![((8 - v.len() % 8) % 8) as u8];
+                for c in v.chunks(8) {
+                    d.push(c.iter().enumerate().fold(0, |a, (i, b)| a | ((*b as u8) << (7 - i))));
+                }
+                app(b, TAG_BIT_STRING, &d);
+            },
+            BacnetValue::Enumerated(v) => app(b, TAG_ENUMERATED, &unsigned_bytes(*v as u64)),
+            BacnetValue::Date(v) => app(b, TAG_DATE, v),
+            BacnetValue::Time(v) => app(b, TAG_TIME, v),
+            BacnetValue::ObjectId(v) => app(b, TAG_OBJECT_ID, &v.encode()),
+        }
+    }
+}
+
+impl std::fmt::Display for BacnetValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BacnetValue::Null => write!(f, "null"),
+            BacnetValue::Boolean(v) => write!(f, "{}", v),
+            BacnetValue::Unsigned(v) => write!(f, "{}", v),
+            BacnetValue::Signed(v) => write!(f, "{}", v),
+            BacnetValue::Real(v) => write!(f, "{}", v),
+            BacnetValue::Double(v) => write!(f, "{}", v),
+            BacnetValue::OctetString(v) => v.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+            BacnetValue::CharacterString(v) => write!(f, "{}", v),
+            BacnetValue::BitString(v) => v.iter().try_for_each(|b| write!(f, "{}", *b as u8)),
+            BacnetValue::Enumerated(v) => write!(f, "{}", v),
+            BacnetValue::Date([y, m, d, _]) => write!(f, "{}-{:02}-{:02}", 1900 + *y as u16, m, d),
+            BacnetValue::Time([h, m, s, c]) => write!(f, "{:02}:{:02}:{:02}.{:02}", h, m, s, c),
+            BacnetValue::ObjectId(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Format property values as text
+fn format_values(v: &[BacnetValue]) -> Vec<u8> {
+    let s: Vec<_> = v.iter().map(|v| v.to_string()).collect();
+    s.join(",").into_bytes()
+}
+
+/// COV subscription for a property
+struct Subscription {
+    topic: String,
+    path: BacnetPath,
+}
+
+/// Generic futures-based BACnet/IP client abstraction
+pub struct BacnetClient {
+    tx: Arc<AsyncMutex<SendHalf>>,
+    rx: Arc<AsyncMutex<RecvHalf>>,
+    device: SocketAddr,
+    request_timeout: Duration,
+    write_priority: Option<u8>,
+    cov_lifetime: Duration,
+    cov_confirmed: bool,
+    /// Subscriber process identifier for COV subscriptions
+    process_id: u32,
+    /// COV renewal timer, unset for indefinite subscriptions
+    cov_timer: Option<Delay>,
+    subs: Vec<Subscription>,
+
+    invoke_id: u8,
+    /// Received notifications pending delivery via `Stream`
+    inbox: VecDeque<(String, Vec<u8>)>,
+    /// Packets (ie. acknowledgements) pending transmission
+    outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    sending: Option<BoxFuture<'static, Result<(), Error>>>,
+    receiving: Option<BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>>>,
+
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Cleared on disconnect
+    connected: bool,
+}
+
+impl BacnetClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<BacnetOptions>>(opts: O) -> Result<BacnetClient, Error> {
+        let o = opts.into();
+
+        if let Some(p) = o.bacnet_write_priority {
+            if p < 1 || p > 16 {
+                return Err(Error::msg(format!("Invalid BACnet write priority: {} (expected 1 to 16)", p)))
+            }
+        }
+
+        let socket = UdpSocket::bind(o.bacnet_bind.as_deref().unwrap_or("0.0.0.0:0")).await?;
+        let (rx, tx) = socket.split();
+
+        let device = resolve(&o.bacnet_device).await?;
+        let cov_lifetime = o.bacnet_cov_lifetime.unwrap_or(DEFAULT_COV_LIFETIME);
+
+        debug!("Created BACnet/IP client for device {}", device);
+
+        Ok(BacnetClient{
+            tx: Arc::new(AsyncMutex::new(tx)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            device,
+            request_timeout: o.bacnet_request_timeout.unwrap_or(o.defaults.request_timeout),
+            write_priority: o.bacnet_write_priority,
+            cov_lifetime,
+            cov_confirmed: o.bacnet_cov_confirmed,
+            process_id: rand::random::<u16>() as u32,
+            cov_timer: match cov_lifetime.as_secs() {
+                0 => None,
+                _ => Some(delay_until(Instant::now() + cov_lifetime / 2)),
+            },
+            subs: vec![],
+            invoke_id: rand::random(),
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            sending: None,
+            receiving: None,
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Read a property
+    pub async fn read_property(&mut self, path: &BacnetPath) -> Result<Vec<BacnetValue>, Error> {
+        let mut b = vec![];
+        ctx(&mut b, 0, &path.object.encode());
+        ctx(&mut b, 1, &unsigned_bytes(path.property as u64));
+        if let Some(i) = path.index {
+            ctx(&mut b, 2, &unsigned_bytes(i as u64));
+        }
+
+        let r = self.transact(SERVICE_READ_PROPERTY, b).await?;
+
+        let mut d = Decoder::new(&r);
+        d.ctx_object(0)?;
+        d.ctx_unsigned(1)?;
+        if let Ok(Tag::Ctx(2, _)) = d.peek() {
+            d.ctx_unsigned(2)?;
+        }
+        d.values(3)
+    }
+
+    /// Write a property, with an optional priority for commandable properties
+    pub async fn write_property(&mut self, path: &BacnetPath, values: &[BacnetValue], priority: Option<u8>) -> Result<(), Error> {
+        let mut b = vec![];
+        ctx(&mut b, 0, &path.object.encode());
+        ctx(&mut b, 1, &unsigned_bytes(path.property as u64));
+        if let Some(i) = path.index {
+            ctx(&mut b, 2, &unsigned_bytes(i as u64));
+        }
+        open(&mut b, 3);
+        for v in values {
+            v.encode(&mut b);
+        }
+        close(&mut b, 3);
+        if let Some(p) = priority {
+            ctx(&mut b, 4, &[p]);
+        }
+
+        self.transact(SERVICE_WRITE_PROPERTY, b).await?;
+
+        Ok(())
+    }
+
+    /// Encode a SubscribeCOV request for an object, cancelling the subscription where
+    /// `lifetime` is not set
+    fn subscribe_cov(&self, object: &ObjectId, lifetime: Option<Duration>) -> Vec<u8> {
+        let mut b = vec![];
+        ctx(&mut b, 0, &unsigned_bytes(self.process_id as u64));
+        ctx(&mut b, 1, &object.encode());
+        if let Some(l) = lifetime {
+            ctx(&mut b, 2, &[self.cov_confirmed as u8]);
+            ctx(&mut b, 3, &unsigned_bytes(l.as_secs()));
+        }
+        b
+    }
+
+    /// Deliver COV notification values for subscribed properties
+    fn notify(&mut self, d: &[u8]) {
+        let (process_id, object, values) = match parse_cov(d) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Invalid BACnet COV notification: {:?}", e);
+                return
+            },
+        };
+
+        if process_id != self.process_id {
+            debug!("Ignoring BACnet COV notification for process {}", process_id);
+            return
+        }
+
+        for (property, v) in values {
+            for s in self.subs.iter().filter(|s| s.path.object == object && s.path.property == property) {
+                self.inbox.push_back((s.topic.clone(), format_values(&v)));
+            }
+        }
+    }
+
+    /// Handle an unsolicited packet
+    fn handle(&mut self, from: SocketAddr, d: &[u8]) {
+        let apdu = match parse_frame(d) {
+            Ok(Some(a)) if a.len() >= 2 => a,
+            Ok(_) => return,
+            Err(e) => {
+                warn!("Invalid BACnet/IP packet from {}: {:?}", from, e);
+                return
+            },
+        };
+
+        match apdu[0] >> 4 {
+            PDU_CONFIRMED_REQUEST if apdu.len() >= 4 => {
+                let (invoke_id, service) = (apdu[2], apdu[3]);
+
+                let resp = if apdu[0] & 0x08 != 0 {
+                    vec![PDU_ABORT << 4 | 0x01, invoke_id, ABORT_SEGMENTATION_NOT_SUPPORTED]
+                } else if service == SERVICE_CONFIRMED_COV_NOTIFICATION {
+                    self.notify(&apdu[4..]);
+                    vec![PDU_SIMPLE_ACK << 4, invoke_id, service]
+                } else {
+                    vec![PDU_REJECT << 4, invoke_id, REJECT_UNRECOGNIZED_SERVICE]
+                };
+
+                self.outbox.push_back((from, frame(&resp, false)));
+            },
+            PDU_UNCONFIRMED_REQUEST if apdu[1] == SERVICE_UNCONFIRMED_COV_NOTIFICATION => {
+                self.notify(&apdu[2..]);
+            },
+            t => debug!("Ignoring BACnet APDU (type: {}) from {}", t, from),
+        }
+    }
+
+    /// Send a confirmed request and wait for the response, retransmitting until the request timeout
+    ///
+    /// Notifications received while waiting are handled as usual.
+    async fn transact(&mut self, service: u8, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let invoke_id = self.next_invoke_id();
+
+        let mut apdu = vec![PDU_CONFIRMED_REQUEST << 4, MAX_APDU, invoke_id, service];
+        apdu.extend_from_slice(&data);
+        let req = frame(&apdu, true);
+
+        let deadline = Instant::now() + self.request_timeout;
+
+        while Instant::now() < deadline {
+            self.outbox.push_back((self.device, req.clone()));
+            self.flush().await?;
+
+            let retry = std::cmp::min(Instant::now() + APDU_TIMEOUT, deadline);
+            loop {
+                let (from, d) = match timeout_at(retry, self.recv()).await {
+                    Ok(r) => r?,
+                    Err(_) => break,
+                };
+
+                let is_resp = match parse_frame(&d) {
+                    Ok(Some(a)) if a.len() >= 3 && from == self.device && a[1] == invoke_id => {
+                        match a[0] >> 4 {
+                            PDU_SIMPLE_ACK | PDU_COMPLEX_ACK | PDU_ERROR | PDU_REJECT | PDU_ABORT => Some(response(service, a)),
+                            _ => None,
+                        }
+                    },
+                    _ => None,
+                };
+
+                match is_resp {
+                    Some(r) => return r,
+                    None => {
+                        self.handle(from, &d);
+                        self.flush().await?;
+                    },
+                }
+            }
+        }
+
+        Err(PalError::Timeout{ operation: format!("BACnet {} to {}", service_name(service), self.device), timeout: self.request_timeout }.into())
+    }
+
+    /// Send queued packets
+    async fn flush(&mut self) -> Result<(), Error> {
+        if let Some(f) = self.sending.take() {
+            f.await?;
+        }
+        while let Some((addr, d)) = self.outbox.pop_front() {
+            send_packet(self.tx.clone(), addr, d).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive a packet, resuming any receive started by `Stream`
+    async fn recv(&mut self) -> Result<(SocketAddr, Vec<u8>), Error> {
+        let f = match self.receiving.take() {
+            Some(f) => f,
+            None => recv_packet(self.rx.clone()),
+        };
+
+        f.await
+    }
+
+    fn next_invoke_id(&mut self) -> u8 {
+        self.invoke_id = self.invoke_id.wrapping_add(1);
+        self.invoke_id
+    }
+}
+
+fn service_name(service: u8) -> &'static str {
+    match service {
+        SERVICE_SUBSCRIBE_COV => "SubscribeCOV",
+        SERVICE_READ_PROPERTY => "ReadProperty",
+        SERVICE_WRITE_PROPERTY => "WriteProperty",
+        _ => "request",
+    }
+}
+
+/// Decode the response to a confirmed request, returning ComplexACK service data
+fn response(service: u8, apdu: &[u8]) -> Result<Vec<u8>, Error> {
+    let name = service_name(service);
+
+    match apdu[0] >> 4 {
+        PDU_SIMPLE_ACK => Ok(vec![]),
+        PDU_COMPLEX_ACK if apdu[0] & 0x08 == 0 => Ok(apdu[3..].to_vec()),
+        PDU_COMPLEX_ACK => Err(Error::msg(format!("Segmented BACnet {} responses are not supported", name))),
+        PDU_ERROR => {
+            let mut d = Decoder::new(&apdu[3..]);
+            let (class, code) = (d.app_unsigned()?, d.app_unsigned()?);
+            Err(Error::msg(format!("BACnet {} failed (error class: {}, code: {})", name, class, code)))
+        },
+        PDU_REJECT => Err(Error::msg(format!("BACnet {} rejected (reason: {})", name, apdu[2]))),
+        _ => Err(Error::msg(format!("BACnet {} aborted (reason: {})", name, apdu[2]))),
+    }
+}
+
+/// Decode a COV notification, returning the subscriber process, monitored object and property values
+fn parse_cov(data: &[u8]) -> Result<(u32, ObjectId, Vec<(u32, Vec<BacnetValue>)>), Error> {
+    let mut d = Decoder::new(data);
+
+    let process_id = d.ctx_unsigned(0)? as u32;
+    d.ctx_object(1)?;
+    let object = d.ctx_object(2)?;
+    d.ctx_unsigned(3)?;
+
+    d.opening(4)?;
+    let mut values = vec![];
+    while d.peek()? != Tag::Close(4) {
+        let property = d.ctx_unsigned(0)? as u32;
+        if let Tag::Ctx(1, _) = d.peek()? {
+            d.ctx_unsigned(1)?;
+        }
+        let v = d.values(2)?;
+        if let Tag::Ctx(3, _) = d.peek()? {
+            d.ctx_unsigned(3)?;
+        }
+        values.push((property, v));
+    }
+
+    Ok((process_id, object, values))
+}
+
+/// Wrap an APDU in BVLC (original unicast) and NPDU headers
+fn frame(apdu: &[u8], expect_reply: bool) -> Vec<u8> {
+    let len = (6 + apdu.len()) as u16;
+
+    let mut b = vec![0x81, 0x0a];
+    b.extend_from_slice(&len.to_be_bytes());
+    b.push(0x01);
+    b.push(if expect_reply { 0x04 } else { 0x00 });
+    b.extend_from_slice(apdu);
+    b
+}
+
+/// Strip BVLC and NPDU headers, returning the APDU (or None for network layer messages)
+fn parse_frame(d: &[u8]) -> Result<Option<&[u8]>, Error> {
+    if d.len() < 6 || d[0] != 0x81 {
+        return Err(Error::msg("Invalid BVLC header"))
+    }
+
+    let mut i = match d[1] {
+        // Original unicast / broadcast
+        0x0a | 0x0b => 4,
+        // Forwarded NPDUs include the original source address
+        0x04 => 10,
+        _ => return Ok(None),
+    };
+
+    let short = || Error::msg("Truncated BACnet NPDU");
+
+    if *d.get(i).ok_or_else(short)? != 0x01 {
+        return Err(Error::msg("Unsupported BACnet NPDU version"))
+    }
+    let control = *d.get(i + 1).ok_or_else(short)?;
+    i += 2;
+
+    // Network layer messages
+    if control & 0x80 != 0 {
+        return Ok(None)
+    }
+
+    // Skip destination, source and hop count
+    if control & 0x20 != 0 {
+        let len = *d.get(i + 2).ok_or_else(short)? as usize;
+        i += 3 + len;
+    }
+    if control & 0x08 != 0 {
+        let len = *d.get(i + 2).ok_or_else(short)? as usize;
+        i += 3 + len;
+    }
+    if control & 0x20 != 0 {
+        i += 1;
+    }
+
+    d.get(i..).map(Some).ok_or_else(short)
+}
+
+/// Resolve a bacnet:// address to a socket address (defaulting to port 47808)
+async fn resolve(url: &str) -> Result<SocketAddr, Error> {
+    let host = url.trim_start_matches("bacnet://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:{}", host, DEFAULT_PORT),
+    };
+
+    match tokio::net::lookup_host(host.as_str()).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(Error::msg(format!("Could not resolve BACnet device {:?}", url))),
+    }
+}
+
+fn send_packet(tx: Arc<AsyncMutex<SendHalf>>, addr: SocketAddr, data: Vec<u8>) -> BoxFuture<'static, Result<(), Error>> {
+    async move {
+        tx.lock().await.send_to(&data, &addr).await?;
+        Ok(())
+    }.boxed()
+}
+
+fn recv_packet(rx: Arc<AsyncMutex<RecvHalf>>) -> BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>> {
+    async move {
+        let mut buff = vec![0u8; MAX_PACKET_LEN];
+        let (n, from) = rx.lock().await.recv_from(&mut buff).await?;
+        buff.truncate(n);
+        Ok((from, buff))
+    }.boxed()
+}
+
+fn unsigned(d: &[u8]) -> u64 {
+    d.iter().fold(0, |a, b| (a << 8) | *b as u64)
+}
+
+fn signed(d: &[u8]) -> i64 {
+    match d.first() {
+        Some(b) if b & 0x80 != 0 => d.iter().fold(-1, |a, b| (a << 8) | *b as i64),
+        _ => unsigned(d) as i64,
+    }
+}
+
+fn unsigned_bytes(v: u64) -> Vec<u8> {
+    let b = v.to_be_bytes();
+    let i = b.iter().position(|b| *b != 0).unwrap_or(7);
+    b[i..].to_vec()
+}
+
+fn signed_bytes(v: i64) -> Vec<u8> {
+    let mut b = v.to_be_bytes().to_vec();
+    while b.len() > 1 && ((b[0] == 0x00 && b[1] & 0x80 == 0) || (b[0] == 0xff && b[1] & 0x80 != 0)) {
+        b.remove(0);
+    }
+    b
+}
+
+/// Write a tag header
+fn tag(b: &mut Vec<u8>, num: u8, context: bool, len: usize) {
+    let class = if context { 0x08 } else { 0x00 };
+    let lvt = std::cmp::min(len, 5) as u8;
+
+    match num < 15 {
+        true => b.push((num << 4) | class | lvt),
+        false => {
+            b.push(0xf0 | class | lvt);
+            b.push(num);
+        },
+    }
+
+    if len >= 5 {
+        if len < 254 {
+            b.push(len as u8);
+        } else if len <= 0xffff {
+            b.push(254);
+            b.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            b.push(255);
+            b.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+/// Write an application tagged value
+fn app(b: &mut Vec<u8>, num: u8, data: &[u8]) {
+    tag(b, num, false, data.len());
+    b.extend_from_slice(data);
+}
+
+/// Write a context tagged value
+fn ctx(b: &mut Vec<u8>, num: u8, data: &[u8]) {
+    tag(b, num, true, data.len());
+    b.extend_from_slice(data);
+}
+
+fn open(b: &mut Vec<u8>, num: u8) {
+    b.push((num << 4) | 0x0e);
+}
+
+fn close(b: &mut Vec<u8>, num: u8) {
+    b.push((num << 4) | 0x0f);
+}
+
+/// Decoded tag header
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tag {
+    /// Application tag with length (or value for booleans)
+    App(u8, u32),
+    /// Context tag with length
+    Ctx(u8, u32),
+    Open(u8),
+    Close(u8),
+}
+
+struct Decoder<'a> {
+    d: &'a [u8],
+}
+
+impl <'a> Decoder<'a> {
+    fn new(d: &'a [u8]) -> Self {
+        Self{ d }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.d.len() < n {
+            return Err(Error::msg("Truncated BACnet APDU"))
+        }
+        let (a, b) = self.d.split_at(n);
+        self.d = b;
+        Ok(a)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<Tag, Error> {
+        Decoder{ d: self.d }.tag()
+    }
+
+    fn tag(&mut self) -> Result<Tag, Error> {
+        let b = self.byte()?;
+
+        let mut num = b >> 4;
+        if num == 0x0f {
+            num = self.byte()?;
+        }
+        let context = b & 0x08 != 0;
+        let lvt = b & 0x07;
+
+        match (context, lvt) {
+            (true, 6) => return Ok(Tag::Open(num)),
+            (true, 7) => return Ok(Tag::Close(num)),
+            // Application booleans encode the value in the length field
+            (false, _) if num == TAG_BOOLEAN => return Ok(Tag::App(num, lvt as u32)),
+            _ => (),
+        }
+
+        let len = match lvt {
+            5 => match self.byte()? {
+                254 => unsigned(self.take(2)?) as u32,
+                255 => unsigned(self.take(4)?) as u32,
+                l => l as u32,
+            },
+            l => l as u32,
+        };
+
+        match context {
+            true => Ok(Tag::Ctx(num, len)),
+            false => Ok(Tag::App(num, len)),
+        }
+    }
+
+    fn ctx_data(&mut self, num: u8) -> Result<&'a [u8], Error> {
+        match self.tag()? {
+            Tag::Ctx(n, l) if n == num => self.take(l as usize),
+            t => Err(Error::msg(format!("Unexpected BACnet tag {:?} (expected context tag {})", t, num))),
+        }
+    }
+
+    fn ctx_unsigned(&mut self, num: u8) -> Result<u64, Error> {
+        Ok(unsigned(self.ctx_data(num)?))
+    }
+
+    fn ctx_object(&mut self, num: u8) -> Result<ObjectId, Error> {
+        ObjectId::decode(self.ctx_data(num)?)
+    }
+
+    fn app_unsigned(&mut self) -> Result<u64, Error> {
+        match self.tag()? {
+            Tag::App(TAG_UNSIGNED, l) | Tag::App(TAG_ENUMERATED, l) => Ok(unsigned(self.take(l as usize)?)),
+            t => Err(Error::msg(format!("Unexpected BACnet tag {:?} (expected unsigned or enumerated)", t))),
+        }
+    }
+
+    fn opening(&mut self, num: u8) -> Result<(), Error> {
+        match self.tag()? {
+            Tag::Open(n) if n == num => Ok(()),
+            t => Err(Error::msg(format!("Unexpected BACnet tag {:?} (expected opening tag {})", t, num))),
+        }
+    }
+
+    /// Decode application tagged values within an opening / closing tag pair,
+    /// skipping constructed values
+    fn values(&mut self, num: u8) -> Result<Vec<BacnetValue>, Error> {
+        self.opening(num)?;
+
+        let mut v = vec![];
+        let mut depth = 0;
+
+        loop {
+            match self.tag()? {
+                Tag::Close(n) if depth == 0 && n == num => return Ok(v),
+                Tag::Open(_) => depth += 1,
+                Tag::Close(_) if depth > 0 => depth -= 1,
+                Tag::Close(n) => return Err(Error::msg(format!("Unexpected BACnet closing tag {}", n))),
+                Tag::App(t, l) if depth == 0 => {
+                    let d: &[u8] = match t {
+                        TAG_BOOLEAN => &[],
+                        _ => self.take(l as usize)?,
+                    };
+                    v.push(BacnetValue::decode(t, d, l)?);
+                },
+                Tag::App(TAG_BOOLEAN, _) => (),
+                Tag::App(_, l) | Tag::Ctx(_, l) => { self.take(l as usize)?; },
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ClientBase for BacnetClient {
+    /// Cancel COV subscriptions
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        let mut objects: Vec<ObjectId> = vec![];
+        for s in self.subs.drain(..) {
+            if !objects.contains(&s.path.object) {
+                objects.push(s.path.object);
+            }
+        }
+
+        let mut res = Ok(());
+        for o in objects {
+            let r = self.subscribe_cov(&o, None);
+            if let Err(e) = self.transact(SERVICE_SUBSCRIBE_COV, r).await {
+                warn!("Failed to cancel BACnet COV subscription for {}: {:?}", o, e);
+                res = Err(e);
+            }
+        }
+
+        self.connected = false;
+
+        res
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for BacnetClient {
+    /// Write a property value (as text), using the type of the current value
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let path = BacnetPath::from_str(topic)?;
+        let text = std::str::from_utf8(data)
+            .map_err(|_| Error::msg(format!("BACnet values must be UTF-8 text (topic: {})", topic)))?;
+
+        let value = match text.trim() {
+            "null" => BacnetValue::Null,
+            _ => {
+                let current = self.read_property(&path).await?;
+                match current.first() {
+                    Some(c) => c.parse_as(text)?,
+                    None => return Err(Error::msg(format!("BACnet property {} has no value to infer a type from", path))),
+                }
+            },
+        };
+
+        let priority = self.write_priority;
+        self.write_property(&path, &[value], priority).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for BacnetClient {
+    /// Subscribe to changes of value for a property, issuing a COV subscription for the object
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|s| s.topic == topic) {
+            return Ok(())
+        }
+
+        let path = BacnetPath::from_str(topic)?;
+
+        // Objects are subscribed once, with notifications covering all properties
+        if !self.subs.iter().any(|s| s.path.object == path.object) {
+            let r = self.subscribe_cov(&path.object, Some(self.cov_lifetime));
+            self.transact(SERVICE_SUBSCRIBE_COV, r).await
+                .map_err(|e| PalError::Subscription{ topic: topic.to_string(), error: e.to_string() })?;
+        }
+
+        self.subs.push(Subscription{ topic: topic.to_string(), path });
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a property, cancelling the COV subscription once no properties
+    /// of the object remain subscribed
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let s = self.subs.remove(i);
+
+        if !self.subs.iter().any(|o| o.path.object == s.path.object) {
+            let r = self.subscribe_cov(&s.path.object, None);
+            self.transact(SERVICE_SUBSCRIBE_COV, r).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for BacnetClient
+///
+/// Polling the stream handles COV notifications and renews COV subscriptions.
+impl Stream for BacnetClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(m) = this.inbox.pop_front() {
+                return Poll::Ready(Some(m))
+            }
+
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            // Renew COV subscriptions, responses are ignored in `handle`
+            let renew = match &mut this.cov_timer {
+                Some(t) => match Pin::new(&mut *t).poll(cx) {
+                    Poll::Ready(_) => {
+                        t.reset(Instant::now() + this.cov_lifetime / 2);
+                        true
+                    },
+                    Poll::Pending => false,
+                },
+                None => false,
+            };
+
+            if renew {
+                let mut objects: Vec<ObjectId> = vec![];
+                for s in &this.subs {
+                    if !objects.contains(&s.path.object) {
+                        objects.push(s.path.object);
+                    }
+                }
+
+                for o in objects {
+                    let invoke_id = this.next_invoke_id();
+                    let mut apdu = vec![PDU_CONFIRMED_REQUEST << 4, MAX_APDU, invoke_id, SERVICE_SUBSCRIBE_COV];
+                    apdu.extend_from_slice(&this.subscribe_cov(&o, Some(this.cov_lifetime)));
+                    this.outbox.push_back((this.device, frame(&apdu, true)));
+                }
+            }
+
+            // Drive pending transmissions
+            loop {
+                if this.sending.is_none() {
+                    match this.outbox.pop_front() {
+                        Some((addr, d)) => this.sending = Some(send_packet(this.tx.clone(), addr, d)),
+                        None => break,
+                    }
+                }
+
+                match this.sending.as_mut().unwrap().poll_unpin(cx) {
+                    Poll::Ready(r) => {
+                        this.sending = None;
+                        if let Err(e) = r {
+                            warn!("BACnet send failed: {:?}", e);
+                        }
+                    },
+                    Poll::Pending => break,
+                }
+            }
+
+            if this.receiving.is_none() {
+                this.receiving = Some(recv_packet(this.rx.clone()));
+            }
+
+            match this.receiving.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok((from, d))) => {
+                    this.receiving = None;
+                    this.handle(from, &d);
+                },
+                Poll::Ready(Err(e)) => {
+                    this.receiving = None;
+                    warn!("BACnet receive failed: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+//! Bluetooth LE GATT client, via btleplug
+//!
+//! Characteristic UUIDs are used as topics, optionally prefixed with a service UUID
+//! (ie. `180d/2a37` or `0000180d-0000-1000-8000-00805f9b34fb/00002a37-0000-1000-8000-00805f9b34fb`).
+//! Subscribing enables characteristic notifications, which are emitted using the subscribed topic,
+//! and publishing writes the characteristic value.
+//!
+//! Characteristics are matched by UUID only, as btleplug does not expose owning services.
+
+use std::pin::Pin;
+use std::collections::BTreeSet;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use btleplug::api::{Central, Peripheral, Characteristic, CharPropFlags, ValueNotification, BDAddr, UUID};
+
+#[cfg(target_os = "linux")]
+use btleplug::bluez::{manager::Manager, adapter::ConnectedAdapter as Adapter, adapter::peripheral::Peripheral as Device};
+#[cfg(target_os = "windows")]
+use btleplug::winrtble::{manager::Manager, adapter::Adapter, peripheral::Peripheral as Device};
+#[cfg(target_os = "macos")]
+use btleplug::corebluetooth::{manager::Manager, adapter::Adapter, peripheral::Peripheral as Device};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+/// Interval between checks for the target device while scanning
+const SCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Bluetooth base UUID (0000xxxx-0000-1000-8000-00805f9b34fb), big-endian
+const BASE_UUID: [u8; 16] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5f, 0x9b, 0x34, 0xfb];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BleOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Device address (ie. AA:BB:CC:DD:EE:FF) or advertised local name
+    pub ble_device: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "0"))]
+    /// Bluetooth adapter index
+    pub ble_adapter: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for scanning for and connecting to the device (defaults to the transport connect timeout)
+    pub ble_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Write characteristics without requesting a response
+    pub ble_write_without_response: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for BleOptions {
+    fn from(device: &str) -> Self {
+        Self {
+            ble_device: device.to_string(),
+            ble_adapter: 0,
+            ble_connect_timeout: None,
+            ble_write_without_response: false,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// Generic futures-based Bluetooth LE GATT client abstraction
+pub struct BleClient {
+    // Adapter retained to keep the connection (and notification handling) alive
+    _adapter: Adapter,
+    device: Device,
+    characteristics: BTreeSet<Characteristic>,
+    write_without_response: bool,
+    request_timeout: Duration,
+
+    notifications: UnboundedReceiver<ValueNotification>,
+    /// Subscribed topics and their normalised characteristic UUIDs
+    subs: Vec<(String, [u8; 16])>,
+
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect or when the device is lost
+    connected: bool,
+}
+
+impl BleClient {
+    /// Create a new client, scanning for and connecting to the configured device
+    pub async fn new<O: Into<BleOptions>>(opts: O) -> Result<BleClient, Error> {
+        let o = opts.into();
+        let connect_timeout = o.ble_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        let (device_id, adapter_idx) = (o.ble_device.clone(), o.ble_adapter);
+
+        // btleplug is blocking, scan and connect on the blocking pool
+        let (adapter, device, characteristics) = tokio::task::spawn_blocking(move || {
+            let manager = Manager::new()
+                .map_err(|e| Error::msg(format!("Failed to create BLE manager: {:?}", e)))?;
+
+            let adapter = manager.adapters()
+                .map_err(|e| Error::msg(format!("Failed to list BLE adapters: {:?}", e)))?
+                .into_iter().nth(adapter_idx)
+                .ok_or_else(|| Error::msg(format!("BLE adapter {} not found", adapter_idx)))?;
+
+            #[cfg(target_os = "linux")]
+            let adapter = adapter.connect()
+                .map_err(|e| Error::msg(format!("Failed to connect to BLE adapter: {:?}", e)))?;
+
+            adapter.start_scan()
+                .map_err(|e| Error::msg(format!("Failed to start BLE scan: {:?}", e)))?;
+
+            let address = device_id.parse::<BDAddr>().ok();
+            let deadline = Instant::now() + connect_timeout;
+
+            let device = loop {
+                let found = adapter.peripherals().into_iter().find(|p| {
+                    match address {
+                        Some(a) => p.address() == a,
+                        None => p.properties().local_name.as_deref() == Some(device_id.as_str()),
+                    }
+                });
+
+                if let Some(d) = found {
+                    break d;
+                }
+
+                if Instant::now() > deadline {
+                    let _ = adapter.stop_scan();
+                    return Err(PalError::Timeout{ operation: format!("BLE scan for {}", device_id), timeout: connect_timeout }.into());
+                }
+
+                std::thread::sleep(SCAN_INTERVAL);
+            };
+
+            let _ = adapter.stop_scan();
+
+            debug!("Found BLE device {} ({})", device_id, device.address());
+
+            device.connect()
+                .map_err(|e| Error::msg(format!("Failed to connect to BLE device {}: {:?}", device_id, e)))?;
+
+            device.discover_characteristics()
+                .map_err(|e| Error::msg(format!("Failed to discover BLE characteristics: {:?}", e)))?;
+
+            let characteristics = device.characteristics();
+
+            Ok::<_, Error>((adapter, device, characteristics))
+        }).await??;
+
+        debug!("Connected to BLE device {} ({} characteristics)", o.ble_device, characteristics.len());
+
+        let (tx, rx) = unbounded();
+        device.on_notification(Box::new(move |n: ValueNotification| {
+            let _ = tx.unbounded_send(n);
+        }));
+
+        Ok(BleClient{
+            _adapter: adapter,
+            device,
+            characteristics,
+            write_without_response: o.ble_write_without_response,
+            request_timeout: o.defaults.request_timeout,
+            notifications: rx,
+            subs: vec![],
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Fetch the characteristic for a topic
+    fn characteristic(&self, topic: &str) -> Result<Characteristic, Error> {
+        let uuid = topic_uuid(topic)?;
+
+        self.characteristics.iter().find(|c| normalise_uuid(&c.uuid) == uuid).cloned()
+            .ok_or_else(|| Error::msg(format!("BLE characteristic not found: {}", topic)))
+    }
+
+    /// Run a blocking device operation with the request timeout
+    async fn device_op<F>(&self, name: &str, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Device) -> Result<(), Error> + Send + 'static,
+    {
+        let device = self.device.clone();
+        let op = tokio::task::spawn_blocking(move || f(&device) );
+
+        match tokio::time::timeout(self.request_timeout, op).await {
+            Ok(r) => r?,
+            Err(_) => Err(PalError::Timeout{ operation: format!("BLE {}", name), timeout: self.request_timeout }.into()),
+        }
+    }
+}
+
+/// Parse a characteristic UUID from a topic, using the last segment where prefixed with a service
+fn topic_uuid(topic: &str) -> Result<[u8; 16], Error> {
+    let s = topic.rsplit('/').next().unwrap_or(topic).replace('-', "");
+
+    let bytes = (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i+2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| Error::msg(format!("Invalid BLE UUID: {:?}", topic)))?;
+
+    let mut uuid = BASE_UUID;
+    match bytes.len() {
+        2 => uuid[2..4].copy_from_slice(&bytes),
+        4 => uuid[..4].copy_from_slice(&bytes),
+        16 => uuid.copy_from_slice(&bytes),
+        _ => return Err(Error::msg(format!("Invalid BLE UUID: {:?} (expected 16, 32 or 128-bit)", topic))),
+    }
+
+    Ok(uuid)
+}
+
+/// Normalise a btleplug UUID (16-bit or little-endian 128-bit) to a big-endian 128-bit UUID
+fn normalise_uuid(uuid: &UUID) -> [u8; 16] {
+    match uuid {
+        UUID::B16(v) => {
+            let mut u = BASE_UUID;
+            u[2..4].copy_from_slice(&v.to_be_bytes());
+            u
+        },
+        UUID::B128(v) => {
+            let mut u = *v;
+            u.reverse();
+            u
+        },
+    }
+}
+
+#[async_trait]
+impl ClientBase for BleClient {
+    /// Disconnect from the device
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.subs.clear();
+
+        self.device_op("disconnect", |d| {
+            d.disconnect().map_err(|e| Error::msg(format!("Failed to disconnect BLE device: {:?}", e)))
+        }).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected && self.device.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for BleClient {
+    /// Write a characteristic value
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.is_connected() {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let c = self.characteristic(topic)?;
+        let data = data.to_vec();
+
+        // Fall back to the supported write type where only one is available
+        let without_response = match (c.properties.contains(CharPropFlags::WRITE), c.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE)) {
+            (true, false) => false,
+            (false, true) => true,
+            (false, false) => return Err(Error::msg(format!("BLE characteristic {} is not writable", topic))),
+            _ => self.write_without_response,
+        };
+
+        self.device_op(&format!("write to {}", topic), move |d| {
+            let r = match without_response {
+                true => d.command(&c, &data),
+                false => d.request(&c, &data),
+            };
+            r.map_err(|e| Error::msg(format!("BLE write failed: {:?}", e)))
+        }).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for BleClient {
+    /// Subscribe to characteristic notifications
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|(t, _)| t == topic) {
+            return Ok(())
+        }
+
+        let c = self.characteristic(topic)?;
+        let uuid = normalise_uuid(&c.uuid);
+
+        if !c.properties.intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE) {
+            return Err(PalError::Subscription{ topic: topic.to_string(), error: "Characteristic does not support notifications".to_string() }.into())
+        }
+
+        // Notifications are already enabled where another topic maps to the same characteristic
+        if !self.subs.iter().any(|(_, u)| *u == uuid) {
+            self.device_op(&format!("subscribe to {}", topic), move |d| {
+                d.subscribe(&c).map_err(|e| Error::msg(format!("{:?}", e)))
+            }).await.map_err(|e| PalError::Subscription{ topic: topic.to_string(), error: e.to_string() })?;
+        }
+
+        self.subs.push((topic.to_string(), uuid));
+
+        Ok(())
+    }
+
+    /// Unsubscribe from characteristic notifications
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|(t, _)| t == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let (_, uuid) = self.subs.remove(i);
+        if self.subs.iter().any(|(_, u)| *u == uuid) {
+            return Ok(())
+        }
+
+        let c = self.characteristic(topic)?;
+        self.device_op(&format!("unsubscribe from {}", topic), move |d| {
+            d.unsubscribe(&c).map_err(|e| Error::msg(format!("BLE unsubscribe failed: {:?}", e)))
+        }).await
+    }
+}
+
+/// Stream implementation for BleClient, emitting characteristic notifications for subscribed topics
+impl Stream for BleClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            let n = match this.notifications.poll_next_unpin(cx) {
+                Poll::Ready(Some(n)) => n,
+                Poll::Ready(None) => {
+                    warn!("BLE notification channel closed");
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let uuid = normalise_uuid(&n.uuid);
+            if let Some((t, _)) = this.subs.iter().find(|(_, u)| *u == uuid) {
+                return Poll::Ready(Some((t.clone(), n.value)))
+            }
+        }
+    }
+}
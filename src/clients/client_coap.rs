@@ -1,19 +1,106 @@
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::str::FromStr;
 use std::task::{Context, Poll};
 
-use futures::stream::{Stream, StreamExt};
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{Stream, StreamExt, BoxStream};
+use futures::lock::Mutex as AsyncMutex;
 use async_trait::async_trait;
 use anyhow::Error;
 
+use tokio::time::timeout;
+
 use coap::client::{CoAPClientAsync, CoAPObserverAsync, RequestOptions};
+use coap::message::response::Status;
 
 use super::{ClientBase, ClientPub, ClientSub};
-use crate::TlsOptions;
+use crate::{TlsOptions, TransportDefaults, PalError};
+use crate::clock::{Clock, SystemClock};
+use crate::backoff::{Backoff, BackoffOptions};
+
+/// Default interval for re-registering observations, matches the default CoAP Max-Age
+pub const DEFAULT_REREGISTER_INTERVAL: Duration = Duration::from_secs(60);
+
+type Client = CoAPClientAsync<tokio::net::UdpSocket>;
+
+/// CoAP request methods
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoapMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl FromStr for CoapMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "GET" => Ok(CoapMethod::Get),
+            "POST" => Ok(CoapMethod::Post),
+            "PUT" => Ok(CoapMethod::Put),
+            "DELETE" => Ok(CoapMethod::Delete),
+            _ => Err(Error::msg(format!("Unsupported CoAP method: {:?} (expected GET, POST, PUT, or DELETE)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for CoapMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoapMethod::Get => write!(f, "GET"),
+            CoapMethod::Post => write!(f, "POST"),
+            CoapMethod::Put => write!(f, "PUT"),
+            CoapMethod::Delete => write!(f, "DELETE"),
+        }
+    }
+}
 
 /// Generic futures-based CoAP client abstraction
 pub struct CoapClient {
-    client: CoAPClientAsync<tokio::net::UdpSocket>,
-    subs: Vec<CoAPObserverAsync>,
+    client: Arc<AsyncMutex<Client>>,
+    subs: Vec<Observation>,
+    reregister: Duration,
+    poll_interval: Option<Duration>,
+    max_observations: Option<usize>,
+    request_timeout: Duration,
+    last_error: Option<(Instant, PalError)>,
+    /// Index of the next subscription to poll, rotated for fairness
+    next: usize,
+    /// Cleared on disconnect, as UDP has no session to monitor
+    connected: bool,
+    /// Send requests as confirmable (CON) messages
+    confirmable: bool,
+    backoff: BackoffOptions,
+    clock: Arc<dyn Clock>,
+}
+
+/// Active subscription, either observed (and re-registered periodically to keep it alive)
+/// or polled where the server does not support observation
+struct Observation {
+    topic: String,
+    mode: Mode,
+    timer: BoxFuture<'static, ()>,
+    /// Delay before retrying failed re-registrations or polls
+    backoff: Backoff,
+    clock: Arc<dyn Clock>,
+}
+
+enum Mode {
+    Observe {
+        observer: CoAPObserverAsync,
+        refresh: Option<BoxFuture<'static, Result<CoAPObserverAsync, Error>>>,
+        /// Cancellation of the observation replaced by re-registration
+        cancel: Option<BoxFuture<'static, Result<(), Error>>>,
+    },
+    Poll {
+        request: Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+        last: Option<Vec<u8>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -24,34 +111,227 @@ pub struct CoapOptions {
     /// URL for CoAP server (prefixed with coap://)
     pub coap_url: String,
 
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for re-registering observations (defaults to 60s).
+    ///
+    /// Servers may drop observations once the Max-Age of the last notification
+    /// expires, this should be set at or below the Max-Age of observed resources.
+    pub coap_reregister: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for polling resources where the server does not support observation.
+    ///
+    /// If not set subscribing to these resources will fail.
+    pub coap_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Maximum number of concurrent observations (unlimited if not set)
+    pub coap_max_observations: Option<usize>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for CoAP requests (defaults to `TransportDefaults::request_timeout`)
+    pub coap_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for creating the client, including address resolution (defaults to `TransportDefaults::connect_timeout`)
+    ///
+    /// CoAP over UDP is connectionless, so an unreachable server is only detected on the first
+    /// request unless `coap_probe` is set.
+    pub coap_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Probe the server on connect with a request for `/.well-known/core`, returning an error
+    /// if no response (of any status) is received within the connect timeout
+    pub coap_probe: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Send requests as non-confirmable (NON) messages, without acknowledgement or retransmission
+    pub coap_non_confirmable: bool,
+
     #[cfg_attr(feature = "structopt", structopt(flatten))]
     pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Backoff for retrying failed re-registrations and polls
+    pub backoff_opts: BackoffOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
 }
 
 impl Into<CoapOptions> for &str {
     fn into(self) -> CoapOptions {
         CoapOptions {
             coap_url: self.to_string(),
+            coap_reregister: None,
+            coap_poll_interval: None,
+            coap_max_observations: None,
+            coap_request_timeout: None,
+            coap_connect_timeout: None,
+            coap_probe: false,
+            coap_non_confirmable: false,
             tls_opts: TlsOptions::default(),
+            backoff_opts: BackoffOptions::default(),
+            defaults: TransportDefaults::default(),
         }
     }
 }
 
 
 impl CoapClient {
-    /// Create a new client using the provided driver
-    pub async fn new<O: Into<CoapOptions>>(&self, opts: O) -> Result<CoapClient, Error> {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<CoapOptions>>(opts: O) -> Result<CoapClient, Error> {
+        Self::with_clock(opts, Arc::new(SystemClock)).await
+    }
+
+    /// Create a new client using the provided clock for re-registration and polling timers
+    pub async fn with_clock<O: Into<CoapOptions>>(opts: O, clock: Arc<dyn Clock>) -> Result<CoapClient, Error> {
         let o = opts.into();
 
-        // TODO: parse out URI opts for underlying driver
-        let client = CoAPClientAsync::new_udp(o.coap_url).await?;
+        // Strip scheme and path from URL for the underlying driver
+        // TODO: parse out remaining URI opts
+        let addr = o.coap_url.trim_start_matches("coap://");
+        let addr = addr.split('/').next().unwrap_or(addr);
+
+        let connect_timeout = o.coap_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+        let mut client = match timeout(connect_timeout, CoAPClientAsync::new_udp(addr)).await {
+            Ok(c) => c?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("CoAP connect to {}", o.coap_url), timeout: connect_timeout }.into()),
+        };
+
+        // Check the server is reachable, error statuses are still a response
+        if o.coap_probe {
+            match timeout(connect_timeout, client.get("/.well-known/core", &RequestOptions::default())).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => return Err(Error::from(e).context(format!("CoAP probe of {} failed", o.coap_url))),
+                Err(_) => return Err(PalError::Timeout{ operation: format!("CoAP probe of {}", o.coap_url), timeout: connect_timeout }.into()),
+            }
+        }
+
+        Ok(CoapClient{
+            client: Arc::new(AsyncMutex::new(client)),
+            subs: vec![],
+            reregister: o.coap_reregister.unwrap_or(DEFAULT_REREGISTER_INTERVAL),
+            poll_interval: o.coap_poll_interval,
+            max_observations: o.coap_max_observations,
+            request_timeout: o.coap_request_timeout.unwrap_or(o.defaults.request_timeout),
+            last_error: None,
+            next: 0,
+            connected: true,
+            confirmable: !o.coap_non_confirmable,
+            backoff: o.backoff_opts,
+            clock,
+        })
+    }
+
+    /// Register a new observation
+    async fn observe(&mut self, topic: &str) -> Result<(), Error> {
+        // Enforce observation limit
+        if let Some(max) = self.max_observations {
+            if self.subs.len() >= max {
+                return Err(Error::msg(format!("Observation limit reached ({}), could not observe {}", max, topic)))
+            }
+        }
+
+        let (mode, timer) = match self.client.lock().await.observe(topic, &RequestOptions::default()).await {
+            Ok(observer) => {
+                (Mode::Observe{ observer, refresh: None, cancel: None }, self.clock.timer(self.clock.now() + self.reregister))
+            },
+            Err(e) if self.poll_interval.is_some() => {
+                warn!("Failed to observe {} ({:?}), falling back to polling", topic, e);
+                (Mode::Poll{ request: None, last: None }, self.clock.timer(self.clock.now()))
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        self.subs.push(Observation{
+            topic: topic.to_string(),
+            mode,
+            timer,
+            backoff: Backoff::new(self.backoff.clone()),
+            clock: self.clock.clone(),
+        });
 
-        Ok(CoapClient{client, subs: vec![]})
+        Ok(())
+    }
+
+    /// Publish data to a topic, returning the response status from the server
+    ///
+    /// Unlike `ClientPub::publish` this returns error (4.xx / 5.xx) statuses rather than mapping them to `Err`
+    pub async fn publish_confirmed(&mut self, topic: &str, data: &[u8]) -> Result<Status, Error> {
+        let opts = self.request_options();
+        let mut client = self.client.lock().await;
+        let resp = timeout(self.request_timeout, client.put(topic, data, &opts)).await
+            .map_err(|_| Error::msg(format!("CoAP publish to {} timed out", topic)))??;
+
+        Ok(resp.get_status().clone())
+    }
+
+    /// Issue a request to a resource, returning the response payload
+    ///
+    /// Payloads are only sent with POST and PUT requests, error (4.xx / 5.xx) statuses are returned as `Err`.
+    pub async fn request(&mut self, method: CoapMethod, topic: &str, data: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let data = match (method, data) {
+            (CoapMethod::Get, Some(_)) | (CoapMethod::Delete, Some(_)) => {
+                return Err(Error::msg(format!("CoAP {} requests do not carry a payload", method)))
+            },
+            (_, d) => d.unwrap_or(&[]),
+        };
+
+        let opts = self.request_options();
+        let mut client = self.client.lock().await;
+
+        let req = async {
+            match method {
+                CoapMethod::Get => client.get(topic, &opts).await,
+                CoapMethod::Post => client.post(topic, data, &opts).await,
+                CoapMethod::Put => client.put(topic, data, &opts).await,
+                CoapMethod::Delete => client.delete(topic, &opts).await,
+            }
+        };
+
+        let resp = timeout(self.request_timeout, req).await
+            .map_err(|_| Error::msg(format!("CoAP {} {} timed out", method, topic)))??;
+
+        match resp.get_status() {
+            s if is_success(s) => Ok(resp.message.payload),
+            s => Err(Error::msg(format!("CoAP {} {} failed: {:?}", method, topic, s))),
+        }
+    }
+
+    /// Set whether requests are sent as confirmable (CON) or non-confirmable (NON) messages
+    pub fn set_confirmable(&mut self, confirmable: bool) {
+        self.confirmable = confirmable;
+    }
+
+    fn request_options(&self) -> RequestOptions {
+        RequestOptions {
+            non_confirmable: !self.confirmable,
+            ..Default::default()
+        }
+    }
+
+    /// Observe a resource, returning an owned stream of notifications separate from the client
+    ///
+    /// Observations created in this manner are not re-registered or removed on disconnect,
+    /// dropping the stream causes the server to cancel the observation on the next notification.
+    pub async fn subscribe_and_stream(&mut self, topic: &str) -> Result<BoxStream<'static, (String, Vec<u8>)>, Error> {
+        let observer = self.client.lock().await.observe(topic, &RequestOptions::default()).await?;
+
+        let topic = topic.to_string();
+        let rx = observer.map(move |m| (topic.clone(), m.message.payload) );
+
+        Ok(Box::pin(rx))
     }
 
     /// Fetch inner object for raw use
-    pub fn inner<'a>(&'a mut self) -> &'a mut CoAPClientAsync<tokio::net::UdpSocket> {
-        &mut self.client
+    pub fn inner(&self) -> Arc<AsyncMutex<Client>> {
+        self.client.clone()
     }
 }
 
@@ -61,13 +341,28 @@ impl ClientBase for CoapClient {
 
     /// Disconnect from client
     async fn disconnect(&mut self) -> Result<(), Error> {
+        let mut client = self.client.lock().await;
+
         // Remove observations
         for s in self.subs.drain(..) {
-            self.client.unobserve(s).await?;
+            if let Mode::Observe{ observer, .. } = s.mode {
+                client.unobserve(observer).await?;
+            }
         }
 
+        self.connected = false;
+
         Ok(())
     }
+
+    /// CoAP over UDP is connectionless, clients are considered connected until `disconnect` is called
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
 }
 
 
@@ -76,45 +371,447 @@ impl ClientSub for CoapClient {
 
     /// Subscribe to a topic
     async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
-        let observer = self.client.observe(topic, &RequestOptions::default()).await?;
-        self.subs.push(observer);
+        // Skip duplicate observations
+        if self.subs.iter().any(|s| s.topic == topic) {
+            debug!("Already observing {}", topic);
+            return Ok(())
+        }
 
-        Ok(())
+        self.observe(topic).await
     }
 
-    /// Unsubscribe from a topic
+    /// Unsubscribe from a topic, cancelling the observation (or polling) for this resource
     async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
-        let observer = self.client.observe(topic, &RequestOptions::default()).await?;
-        self.subs.push(observer);
+        let idx = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let s = self.subs.remove(idx);
+        if self.next > idx {
+            self.next -= 1;
+        }
+
+        if let Mode::Observe{ observer, .. } = s.mode {
+            self.client.lock().await.unobserve(observer).await?;
+        }
 
         Ok(())
     }
 }
 
 /// Stream implementation for CoapSub
+///
+/// Items are tagged with the resource path of the originating subscription, subscriptions are
+/// polled round-robin (starting after the last to yield an item) so busy resources do not starve others.
 impl Stream for CoapClient {
     type Item = (String, Vec<u8>);
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        for s in &mut self.subs {
-            let m = match s.poll_next_unpin(cx) {
-                Poll::Ready(Some(m)) => m,
-                Poll::Ready(_) => return Poll::Ready(None),
-                Poll::Pending => continue,
-            };
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let n = this.subs.len();
+
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+            let s = &mut this.subs[idx];
+
+            match s.poll_next(&this.client, this.reregister, this.poll_interval, this.request_timeout, &mut this.last_error, cx) {
+                Poll::Ready(Some(d)) => {
+                    this.next = (idx + 1) % n;
+                    return Poll::Ready(Some( (s.topic.clone(), d) ))
+                },
+                // Drop ended subscriptions without ending the stream for remaining resources
+                Poll::Ready(None) => {
+                    warn!("Subscription to {} ended", s.topic);
+                    this.last_error = Some((Instant::now(), PalError::Subscription{
+                        topic: s.topic.clone(), error: "Observation ended".to_string(),
+                    }));
 
-            return Poll::Ready(Some( (s.topic().to_string(), m.message.payload)) )
+                    this.subs.remove(idx);
+                    if this.next > idx {
+                        this.next -= 1;
+                    }
+
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending
+                },
+                Poll::Pending => continue,
+            }
         }
 
         Poll::Pending
     }
 }
 
+impl Observation {
+    fn poll_next(&mut self, client: &Arc<AsyncMutex<Client>>, reregister: Duration, poll_interval: Option<Duration>, request_timeout: Duration, last_error: &mut Option<(Instant, PalError)>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        loop {
+            match &mut self.mode {
+                Mode::Observe{ observer, refresh, cancel } => {
+                    // Start re-registration when due
+                    if refresh.is_none() && self.timer.poll_unpin(cx).is_ready() {
+                        debug!("Re-registering observation: {}", self.topic);
+
+                        let client = client.clone();
+                        let topic = self.topic.clone();
+
+                        *refresh = Some(Box::pin(async move {
+                            let mut client = client.lock().await;
+                            match timeout(request_timeout, client.observe(&topic, &RequestOptions::default())).await {
+                                Ok(o) => Ok(o?),
+                                Err(_) => Err(PalError::Timeout{ operation: format!("CoAP re-registration of {}", topic), timeout: request_timeout }.into()),
+                            }
+                        }));
+                    }
+
+                    // Replace the observer once re-registration completes, cancelling the
+                    // previous observation so this is not retained by the server
+                    if let Some(r) = refresh {
+                        if let Poll::Ready(res) = r.poll_unpin(cx) {
+                            let next = match res {
+                                Ok(o) => {
+                                    let prev = std::mem::replace(observer, o);
+                                    *cancel = Some(unobserve(client.clone(), prev, request_timeout));
+                                    self.backoff.reset();
+                                    reregister
+                                },
+                                // Retry with backoff, retaining the previous observation
+                                Err(e) => {
+                                    warn!("Failed to re-register observation {}: {:?}", self.topic, e);
+                                    *last_error = Some((Instant::now(), PalError::Subscription{ topic: self.topic.clone(), error: e.to_string() }));
+                                    self.backoff.next_delay().min(reregister)
+                                },
+                            };
+
+                            *refresh = None;
+                            self.timer = self.clock.timer(self.clock.now() + next);
+                        }
+                    }
+
+                    if let Some(c) = cancel {
+                        if let Poll::Ready(res) = c.poll_unpin(cx) {
+                            if let Err(e) = res {
+                                warn!("Failed to cancel previous observation of {}: {:?}", self.topic, e);
+                            }
+                            *cancel = None;
+                        }
+                    }
+
+                    match observer.poll_next_unpin(cx) {
+                        Poll::Ready(Some(m)) => return Poll::Ready(Some(m.message.payload)),
+                        Poll::Ready(None) if poll_interval.is_some() => {
+                            warn!("Observation of {} ended, falling back to polling", self.topic);
+                        },
+                        Poll::Ready(None) => return Poll::Ready(None),
+                        Poll::Pending => return Poll::Pending,
+                    }
+
+                    // Switch to polling
+                    self.mode = Mode::Poll{ request: None, last: None };
+                    self.timer = self.clock.timer(self.clock.now());
+                },
+                Mode::Poll{ request, last } => {
+                    let interval = poll_interval.unwrap_or(reregister);
+
+                    // Start request when due
+                    if request.is_none() {
+                        match self.timer.poll_unpin(cx) {
+                            Poll::Ready(_) => *request = Some(get(client.clone(), self.topic.clone(), request_timeout)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+
+                    let res = match request.as_mut().map(|r| r.poll_unpin(cx)) {
+                        Some(Poll::Ready(r)) => r,
+                        _ => return Poll::Pending,
+                    };
+
+                    *request = None;
+
+                    // Emit changed values, mirroring observe notifications
+                    match res {
+                        Ok(d) => {
+                            self.backoff.reset();
+                            self.timer = self.clock.timer(self.clock.now() + interval);
+
+                            if last.as_ref() != Some(&d) {
+                                *last = Some(d.clone());
+                                return Poll::Ready(Some(d))
+                            }
+                        },
+                        // Retry with backoff
+                        Err(e) => {
+                            warn!("Failed to poll {}: {:?}", self.topic, e);
+                            *last_error = Some((Instant::now(), PalError::Subscription{ topic: self.topic.clone(), error: e.to_string() }));
+                            self.timer = self.clock.timer(self.clock.now() + self.backoff.next_delay().min(interval));
+                        },
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Build a GET request future for polling a resource
+fn get(client: Arc<AsyncMutex<Client>>, topic: String, limit: Duration) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+    Box::pin(async move {
+        let mut client = client.lock().await;
+        let resp = timeout(limit, client.get(&topic, &RequestOptions::default())).await
+            .map_err(|_| Error::msg(format!("CoAP poll of {} timed out", topic)))??;
+
+        match resp.get_status() {
+            s if is_success(s) => Ok(resp.message.payload),
+            s => Err(Error::msg(format!("CoAP poll of {} failed: {:?}", topic, s))),
+        }
+    })
+}
+
+/// Build a request future cancelling an observation replaced by re-registration
+fn unobserve(client: Arc<AsyncMutex<Client>>, observer: CoAPObserverAsync, limit: Duration) -> BoxFuture<'static, Result<(), Error>> {
+    Box::pin(async move {
+        let mut client = client.lock().await;
+        timeout(limit, client.unobserve(observer)).await
+            .map_err(|_| Error::msg("CoAP observation cancellation timed out"))??;
+
+        Ok(())
+    })
+}
+
 #[async_trait]
 impl ClientPub for CoapClient {
     /// Publish data to a topic
     async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
-        self.client.put(topic, data, &RequestOptions::default()).await?;
-        Ok(())
+        match self.publish_confirmed(topic, data).await? {
+            s if is_success(&s) => Ok(()),
+            s => Err(Error::msg(format!("CoAP publish to {} failed: {:?}", topic, s))),
+        }
+    }
+}
+
+/// Check whether a response status indicates success (2.xx)
+pub fn is_success(s: &Status) -> bool {
+    match s {
+        Status::Created | Status::Deleted | Status::Valid | Status::Changed | Status::Content | Status::Continue => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use coap::Server;
+    use coap::message::request::Method;
+
+    use super::*;
+    use crate::clock::TestClock;
+
+    /// Start an in-memory CoAP server on the provided port, returning the server URL
+    ///
+    /// Resources are updated with PUT / POST and removed with DELETE, observations are
+    /// handled by the server and notified on update.
+    fn server(port: u16) -> String {
+        let addr = format!("127.0.0.1:{}", port);
+        let resources = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+
+        let mut server = Server::new(addr.as_str()).unwrap();
+        tokio::spawn(async move {
+            server.run(move |req| {
+                let resources = resources.clone();
+                async move {
+                    let (method, path, payload) = (req.get_method().clone(), req.get_path(), req.message.payload.clone());
+                    let mut resp = req.response?;
+                    let mut r = resources.lock().unwrap();
+
+                    match method {
+                        Method::Get => match r.get(&path) {
+                            Some(d) => resp.message.payload = d.clone(),
+                            None => resp.set_status(Status::NotFound),
+                        },
+                        Method::Put | Method::Post => {
+                            r.insert(path, payload);
+                            resp.set_status(Status::Changed);
+                        },
+                        Method::Delete => {
+                            r.remove(&path);
+                            resp.set_status(Status::Deleted);
+                        },
+                        _ => resp.set_status(Status::MethodNotAllowed),
+                    }
+
+                    Some(resp)
+                }
+            }).await.unwrap();
+        });
+
+        format!("coap://{}", addr)
+    }
+
+    /// Collect stream items received within the provided window
+    async fn collect(client: &mut CoapClient, window: Duration) -> Vec<(String, Vec<u8>)> {
+        let mut items = vec![];
+        let _ = timeout(window, async {
+            while let Some(i) = client.next().await {
+                items.push(i);
+            }
+        }).await;
+        items
+    }
+
+    #[tokio::test]
+    async fn duplicate_subscribe() {
+        let url = server(56830);
+        let mut client = CoapClient::new(url.as_str()).await.unwrap();
+
+        client.publish("/dup", b"0").await.unwrap();
+
+        client.subscribe("/dup").await.unwrap();
+        client.subscribe("/dup").await.unwrap();
+        assert_eq!(client.subs.len(), 1);
+
+        client.publish("/dup", b"1").await.unwrap();
+
+        // Each notification is received once
+        let items = collect(&mut client, Duration::from_millis(500)).await;
+        assert_eq!(items.iter().filter(|(_, d)| d == b"1").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn reregister_with_clock() {
+        let url = server(56831);
+        let clock = TestClock::new();
+
+        let mut opts: CoapOptions = url.as_str().into();
+        opts.coap_reregister = Some(Duration::from_millis(100));
+        let mut client = CoapClient::with_clock(opts, Arc::new(clock.clone())).await.unwrap();
+
+        client.publish("/clock", b"0").await.unwrap();
+        client.subscribe("/clock").await.unwrap();
+        let _ = collect(&mut client, Duration::from_millis(200)).await;
+
+        // Re-registration is not started until the clock advances
+        assert!(client.next().now_or_never().is_none());
+        assert!(matches!(client.subs[0].mode, Mode::Observe{ refresh: None, .. }));
+
+        clock.advance(Duration::from_millis(100));
+        assert!(client.next().now_or_never().is_none());
+        assert!(matches!(client.subs[0].mode, Mode::Observe{ refresh: Some(_), .. }));
+
+        // Notifications continue on the re-registered observation
+        let _ = collect(&mut client, Duration::from_millis(200)).await;
+        client.publish("/clock", b"1").await.unwrap();
+
+        let items = collect(&mut client, Duration::from_millis(500)).await;
+        assert!(items.contains(&("/clock".to_string(), b"1".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn unsubscribe() {
+        let url = server(56832);
+        let mut client = CoapClient::new(url.as_str()).await.unwrap();
+
+        client.publish("/unsub", b"0").await.unwrap();
+        client.subscribe("/unsub").await.unwrap();
+        assert_eq!(client.subs.len(), 1);
+
+        client.unsubscribe("/unsub").await.unwrap();
+        assert!(client.subs.is_empty());
+
+        // No further payloads are received
+        client.publish("/unsub", b"1").await.unwrap();
+        let items = collect(&mut client, Duration::from_millis(500)).await;
+        assert!(items.is_empty());
+
+        // Unsubscribing again is an error
+        assert!(client.unsubscribe("/unsub").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_round_trip() {
+        let url = server(56833);
+
+        // Constructed without an existing instance
+        let mut client = CoapClient::new(url.as_str()).await.unwrap();
+
+        client.publish("/new", b"hello").await.unwrap();
+        assert_eq!(client.request(CoapMethod::Get, "/new", None).await.unwrap(), b"hello".to_vec());
+    }
+
+    #[tokio::test]
+    async fn stream_topics() {
+        let url = server(56834);
+        let mut client = CoapClient::new(url.as_str()).await.unwrap();
+
+        client.publish("/a", b"a0").await.unwrap();
+        client.publish("/b", b"b0").await.unwrap();
+        client.subscribe("/a").await.unwrap();
+        client.subscribe("/b").await.unwrap();
+        let _ = collect(&mut client, Duration::from_millis(200)).await;
+
+        client.publish("/a", b"a1").await.unwrap();
+        client.publish("/b", b"b1").await.unwrap();
+
+        // Each payload is tagged with the originating resource
+        let items = collect(&mut client, Duration::from_millis(500)).await;
+        assert!(items.contains(&("/a".to_string(), b"a1".to_vec())));
+        assert!(items.contains(&("/b".to_string(), b"b1".to_vec())));
+        for (t, d) in &items {
+            assert_eq!(d[0], t.as_bytes()[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_methods() {
+        let url = server(56835);
+        let mut client = CoapClient::new(url.as_str()).await.unwrap();
+
+        for confirmable in &[true, false] {
+            client.set_confirmable(*confirmable);
+
+            client.request(CoapMethod::Put, "/req", Some(b"put")).await.unwrap();
+            assert_eq!(client.request(CoapMethod::Get, "/req", None).await.unwrap(), b"put".to_vec());
+
+            client.request(CoapMethod::Post, "/req", Some(b"post")).await.unwrap();
+            assert_eq!(client.request(CoapMethod::Get, "/req", None).await.unwrap(), b"post".to_vec());
+
+            client.request(CoapMethod::Delete, "/req", None).await.unwrap();
+
+            // Error statuses are returned as errors
+            assert!(client.request(CoapMethod::Get, "/req", None).await.is_err());
+        }
+
+        // GET and DELETE do not carry a payload
+        assert!(client.request(CoapMethod::Get, "/req", Some(b"x")).await.is_err());
+        assert!(client.request(CoapMethod::Delete, "/req", Some(b"x")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn probe() {
+        let url = server(56836);
+
+        let mut opts: CoapOptions = url.as_str().into();
+        opts.coap_probe = true;
+        assert!(CoapClient::new(opts).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn probe_timeout() {
+        let connect_timeout = Duration::from_millis(500);
+
+        // Non-routable address, requests are dropped
+        let mut opts: CoapOptions = "coap://10.255.255.1:5683".into();
+        opts.coap_probe = true;
+        opts.coap_connect_timeout = Some(connect_timeout);
+
+        let start = Instant::now();
+        let e = CoapClient::new(opts).await.err().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed < connect_timeout * 2, "{:?}", elapsed);
+
+        // Hosts may be reported unreachable immediately where there is no route
+        if elapsed >= connect_timeout {
+            assert!(matches!(e.downcast_ref::<PalError>(), Some(PalError::Timeout{ .. })), "{:?}", e);
+        }
     }
 }
@@ -0,0 +1,375 @@
+//! DDS (Data Distribution Service) client, via rustdds
+//!
+//! Topics map to DDS topics (with an optional prefix, ie. `rt/` for ROS2) carrying
+//! byte sequence samples (`sequence<octet>`), with reliability / durability / history
+//! QoS applied to all readers and writers. Readers are polled at an interval.
+
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::future::FutureExt;
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+use serde::{Serialize, Deserialize};
+
+use tokio::time::{Delay, delay_until};
+
+use rustdds::dds::{DomainParticipant, Publisher, Subscriber, Topic};
+use rustdds::dds::data_types::{DDSDuration, TopicKind};
+use rustdds::dds::no_key::{DataReader, DataWriter};
+use rustdds::dds::qos::{QosPolicies, QosPolicyBuilder, policy};
+use rustdds::serialization::{CDRSerializerAdapter, CDRDeserializerAdapter};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+/// Default DDS type name for byte sequence samples
+pub const DEFAULT_TYPE_NAME: &str = "iot_pal::Bytes";
+
+/// Default interval for polling readers
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// DDS reliability QoS
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DdsReliability {
+    BestEffort,
+    Reliable,
+}
+
+impl FromStr for DdsReliability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "besteffort" | "best_effort" => Ok(DdsReliability::BestEffort),
+            "reliable" => Ok(DdsReliability::Reliable),
+            _ => Err(Error::msg(format!("Unsupported DDS reliability: {:?} (expected best_effort or reliable)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for DdsReliability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DdsReliability::BestEffort => write!(f, "best_effort"),
+            DdsReliability::Reliable => write!(f, "reliable"),
+        }
+    }
+}
+
+/// DDS durability QoS
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DdsDurability {
+    /// Samples are only delivered to readers present when written
+    Volatile,
+    /// Writers retain samples (up to the history depth) for late-joining readers
+    TransientLocal,
+}
+
+impl FromStr for DdsDurability {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "volatile" => Ok(DdsDurability::Volatile),
+            "transientlocal" | "transient_local" => Ok(DdsDurability::TransientLocal),
+            _ => Err(Error::msg(format!("Unsupported DDS durability: {:?} (expected volatile or transient_local)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for DdsDurability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DdsDurability::Volatile => write!(f, "volatile"),
+            DdsDurability::TransientLocal => write!(f, "transient_local"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DdsOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "0"))]
+    /// DDS domain ID
+    pub dds_domain_id: u16,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = ""))]
+    /// Prefix for DDS topic names (ie. rt/ for ROS2 topics)
+    pub dds_topic_prefix: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "iot_pal::Bytes"))]
+    /// DDS type name for samples, which must be a byte sequence (`sequence<octet>`)
+    pub dds_type_name: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "reliable"))]
+    /// Reliability QoS (best_effort or reliable)
+    pub dds_reliability: DdsReliability,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "volatile"))]
+    /// Durability QoS (volatile or transient_local)
+    pub dds_durability: DdsDurability,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "10"))]
+    /// History QoS depth (keep last)
+    pub dds_history_depth: i32,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for polling readers (defaults to 10ms)
+    pub dds_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl Default for DdsOptions {
+    fn default() -> Self {
+        Self {
+            dds_domain_id: 0,
+            dds_topic_prefix: String::new(),
+            dds_type_name: DEFAULT_TYPE_NAME.to_string(),
+            dds_reliability: DdsReliability::Reliable,
+            dds_durability: DdsDurability::Volatile,
+            dds_history_depth: 10,
+            dds_poll_interval: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl DdsOptions {
+    /// Build the QoS policies for readers and writers
+    fn qos(&self) -> QosPolicies {
+        let reliability = match self.dds_reliability {
+            DdsReliability::BestEffort => policy::Reliability::BestEffort,
+            DdsReliability::Reliable => policy::Reliability::Reliable{
+                max_blocking_time: DDSDuration::from_millis(self.defaults.request_timeout.as_millis() as i64),
+            },
+        };
+
+        let durability = match self.dds_durability {
+            DdsDurability::Volatile => policy::Durability::Volatile,
+            DdsDurability::TransientLocal => policy::Durability::TransientLocal,
+        };
+
+        QosPolicyBuilder::new()
+            .reliability(reliability)
+            .durability(durability)
+            .history(policy::History::KeepLast{ depth: self.dds_history_depth })
+            .build()
+    }
+}
+
+/// Byte sequence sample
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Bytes {
+    data: Vec<u8>,
+}
+
+type Reader = DataReader<Bytes, CDRDeserializerAdapter<Bytes>>;
+type Writer = DataWriter<Bytes, CDRSerializerAdapter<Bytes>>;
+
+/// DDS client
+pub struct DdsClient {
+    participant: DomainParticipant,
+    publisher: Publisher,
+    subscriber: Subscriber,
+    qos: QosPolicies,
+    prefix: String,
+    type_name: String,
+
+    /// Topics by DDS topic name, shared between readers and writers
+    topics: HashMap<String, Topic>,
+    writers: HashMap<String, Writer>,
+    /// Subscribed topics and readers
+    readers: Vec<(String, Reader)>,
+
+    /// Samples taken but not yet emitted
+    pending: VecDeque<(String, Vec<u8>)>,
+    poll_interval: Duration,
+    timer: Delay,
+
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect
+    connected: bool,
+}
+
+impl DdsClient {
+    /// Create a new client using the provided options
+    pub fn new(opts: DdsOptions) -> Result<DdsClient, Error> {
+        let participant = DomainParticipant::new(opts.dds_domain_id)
+            .map_err(|e| Error::msg(format!("DDS participant creation for domain {} failed: {:?}", opts.dds_domain_id, e)))?;
+
+        let qos = opts.qos();
+
+        let publisher = participant.create_publisher(&qos)
+            .map_err(|e| Error::msg(format!("DDS publisher creation failed: {:?}", e)))?;
+        let subscriber = participant.create_subscriber(&qos)
+            .map_err(|e| Error::msg(format!("DDS subscriber creation failed: {:?}", e)))?;
+
+        debug!("Joined DDS domain {} (reliability: {}, durability: {}, depth: {})",
+            opts.dds_domain_id, opts.dds_reliability, opts.dds_durability, opts.dds_history_depth);
+
+        Ok(DdsClient{
+            participant,
+            publisher,
+            subscriber,
+            qos,
+            prefix: opts.dds_topic_prefix,
+            type_name: opts.dds_type_name,
+            topics: HashMap::new(),
+            writers: HashMap::new(),
+            readers: vec![],
+            pending: VecDeque::new(),
+            poll_interval: opts.dds_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            timer: delay_until(tokio::time::Instant::now()),
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Fetch or create the DDS topic for a topic
+    fn topic(&mut self, topic: &str) -> Result<Topic, Error> {
+        let name = format!("{}{}", self.prefix, topic);
+
+        if let Some(t) = self.topics.get(&name) {
+            return Ok(t.clone())
+        }
+
+        let t = self.participant.create_topic(&name, &self.type_name, &self.qos, TopicKind::NoKey)
+            .map_err(|e| Error::msg(format!("DDS topic creation for {} failed: {:?}", name, e)))?;
+
+        self.topics.insert(name, t.clone());
+
+        Ok(t)
+    }
+}
+
+#[async_trait]
+impl ClientBase for DdsClient {
+    /// Remove readers and writers, leaving the domain when the client is dropped
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.readers.clear();
+        self.writers.clear();
+        self.topics.clear();
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for DdsClient {
+    /// Write a sample to a topic, creating a writer on first use
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        if !self.writers.contains_key(topic) {
+            let t = self.topic(topic)?;
+            let w = self.publisher.create_datawriter_no_key::<Bytes, CDRSerializerAdapter<Bytes>>(None, t, None)
+                .map_err(|e| Error::msg(format!("DDS writer creation for {} failed: {:?}", topic, e)))?;
+            self.writers.insert(topic.to_string(), w);
+        }
+
+        let w = self.writers.get_mut(topic).unwrap();
+        w.write(Bytes{ data: data.to_vec() }, None)
+            .map_err(|e| Error::msg(format!("DDS write to {} failed: {:?}", topic, e)))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for DdsClient {
+    /// Subscribe to a topic, creating a reader
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+        if self.readers.iter().any(|(t, _)| t == topic) {
+            return Ok(())
+        }
+
+        let t = self.topic(topic)?;
+        let r = self.subscriber.create_datareader_no_key::<Bytes, CDRDeserializerAdapter<Bytes>>(t, None, None)
+            .map_err(|e| PalError::Subscription{ topic: topic.to_string(), error: format!("{:?}", e) })?;
+
+        self.readers.push((topic.to_string(), r));
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic, removing the reader
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.readers.iter().position(|(t, _)| t == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.readers.remove(i);
+        self.pending.retain(|(t, _)| t != topic);
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for DdsClient, polling readers at the configured interval
+impl Stream for DdsClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(m) = this.pending.pop_front() {
+                return Poll::Ready(Some(m))
+            }
+
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            if let Poll::Pending = this.timer.poll_unpin(cx) {
+                return Poll::Pending
+            }
+            this.timer.reset(tokio::time::Instant::now() + this.poll_interval);
+
+            for (topic, reader) in this.readers.iter_mut() {
+                loop {
+                    match reader.take_next_sample() {
+                        Ok(Some(s)) => match s.into_value() {
+                            Ok(v) => this.pending.push_back((topic.clone(), v.data)),
+                            // Disposed / unregistered instances carry no data
+                            Err(_) => (),
+                        },
+                        Ok(None) => break,
+                        Err(e) => {
+                            warn!("DDS read from {} failed: {:?}", topic, e);
+                            this.last_error = Some((Instant::now(), PalError::Subscription{ topic: topic.clone(), error: format!("{:?}", e) }));
+                            break
+                        },
+                    }
+                }
+            }
+        }
+    }
+}
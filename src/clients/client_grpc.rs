@@ -0,0 +1,286 @@
+//! gRPC bidirectional streaming client
+//!
+//! This connects to a single bidirectional streaming method exchanging `Envelope` messages,
+//! described by the following protobuf definition (the method path is configurable):
+//!
+//! ```proto
+//! syntax = "proto3";
+//! package iotpal;
+//!
+//! message Envelope {
+//!   enum Kind {
+//!     PUBLISH = 0;
+//!     SUBSCRIBE = 1;
+//!     UNSUBSCRIBE = 2;
+//!   }
+//!   string topic = 1;
+//!   bytes payload = 2;
+//!   Kind kind = 3;
+//! }
+//!
+//! service Bridge {
+//!   rpc Stream(stream Envelope) returns (stream Envelope);
+//! }
+//! ```
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::timeout;
+
+use tonic::{Request, Streaming};
+use tonic::client::Grpc;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint, ClientTlsConfig};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, TransportDefaults, PalError};
+use crate::topics::topic_matches;
+
+/// Default method path for the bidirectional stream
+pub const DEFAULT_METHOD: &str = "/iotpal.Bridge/Stream";
+
+/// Default length of the outgoing message queue
+pub const DEFAULT_QUEUE_LEN: usize = 64;
+
+/// Message envelope exchanged over the stream
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(string, tag = "1")]
+    pub topic: String,
+    #[prost(bytes, tag = "2")]
+    pub payload: Vec<u8>,
+    #[prost(enumeration = "Kind", tag = "3")]
+    pub kind: i32,
+}
+
+/// Envelope kinds, subscriptions are forwarded to allow server-side filtering
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Kind {
+    Publish = 0,
+    Subscribe = 1,
+    Unsubscribe = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GrpcOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for gRPC server (prefixed with http:// or https://)
+    pub grpc_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "/iotpal.Bridge/Stream"))]
+    /// Path of the bidirectional streaming method
+    pub grpc_method: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Length of the outgoing message queue (defaults to 64)
+    pub grpc_queue_len: Option<usize>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// gRPC connect timeout, including opening the stream (defaults to `TransportDefaults::connect_timeout`)
+    pub grpc_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for GrpcOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            grpc_url: url.to_string(),
+            grpc_method: DEFAULT_METHOD.to_string(),
+            grpc_queue_len: None,
+            grpc_connect_timeout: None,
+            tls_opts: TlsOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for GrpcOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Generic futures-based gRPC client abstraction, over a single bidirectional stream
+///
+/// Subscriptions are forwarded to the server and received messages are also filtered
+/// locally (supporting MQTT-style wildcards), so servers may broadcast all messages.
+pub struct GrpcClient {
+    tx: mpsc::Sender<Envelope>,
+    rx: Streaming<Envelope>,
+    subs: Vec<String>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect or when the stream ends
+    connected: bool,
+}
+
+impl GrpcClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<GrpcOptions>>(opts: O) -> Result<GrpcClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.grpc_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        match timeout(connect_timeout, Self::connect(&o)).await {
+            Ok(r) => r.map_err(|e| o.tls_opts.with_diagnostics(&o.grpc_url, e)),
+            Err(_) => Err(PalError::Timeout{ operation: format!("gRPC connect to {}", o.grpc_url), timeout: connect_timeout }.into()),
+        }
+    }
+
+    async fn connect(o: &GrpcOptions) -> Result<GrpcClient, Error> {
+        let mut endpoint = Endpoint::from_shared(o.grpc_url.clone())?
+            .tcp_keepalive(Some(o.defaults.keepalive));
+
+        if o.grpc_url.starts_with("https://") {
+            let mut config = o.tls_opts.build_rustls_config()?;
+            config.set_protocols(&[b"h2".to_vec()]);
+
+            endpoint = endpoint.tls_config(ClientTlsConfig::new().rustls_client_config(config));
+        }
+
+        let channel: Channel = endpoint.connect().await?;
+        let mut grpc = Grpc::new(channel);
+        grpc.ready().await?;
+
+        let path = PathAndQuery::from_str(&o.grpc_method)?;
+        let (tx, outgoing) = mpsc::channel(o.grpc_queue_len.unwrap_or(DEFAULT_QUEUE_LEN));
+
+        let resp = grpc.streaming(Request::new(outgoing), path, ProstCodec::default()).await?;
+
+        debug!("Opened gRPC stream {} on {}", o.grpc_method, o.grpc_url);
+
+        Ok(GrpcClient{
+            tx,
+            rx: resp.into_inner(),
+            subs: vec![],
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Send an envelope on the outgoing stream
+    async fn send(&mut self, topic: &str, payload: &[u8], kind: Kind) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let e = Envelope{ topic: topic.to_string(), payload: payload.to_vec(), kind: kind as i32 };
+
+        if self.tx.send(e).await.is_err() {
+            self.connected = false;
+            return Err(PalError::ConnectionLost.into())
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientBase for GrpcClient {
+    /// Close the outgoing stream, ending the call
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.tx.close_channel();
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for GrpcClient {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.send(topic, data, Kind::Publish).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for GrpcClient {
+    /// Subscribe to a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.send(topic, &[], Kind::Subscribe).await?;
+
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.subs.remove(i);
+        self.send(topic, &[], Kind::Unsubscribe).await
+    }
+}
+
+/// Stream implementation for GrpcClient, ending when the call ends
+impl Stream for GrpcClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let e = match this.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(e))) => e,
+                Poll::Ready(Some(Err(s))) => {
+                    warn!("gRPC stream error: {:?}", s);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Ready(None) => {
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if e.kind != Kind::Publish as i32 {
+                debug!("Ignoring gRPC envelope of kind {} for {}", e.kind, e.topic);
+                continue
+            }
+
+            if this.subs.iter().any(|s| topic_matches(s, &e.topic)) {
+                return Poll::Ready(Some((e.topic, e.payload)))
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+use std::task::{Context, Poll};
+
+use log::{debug, warn};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{Stream, TryStreamExt};
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use async_trait::async_trait;
+use anyhow::{Context as _, Error};
+
+use reqwest::{Method, StatusCode};
+use reqwest::r#async::{Client, ClientBuilder};
+use reqwest::header::{AUTHORIZATION, HeaderValue};
+
+use tokio::time::{Delay, Instant, delay_until};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+
+/// Delay before retrying a failed subscription request where no poll interval is set
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// HTTP methods used for publishing
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HttpMethod {
+    Post,
+    Put,
+}
+
+impl Default for HttpMethod {
+    fn default() -> Self {
+        HttpMethod::Post
+    }
+}
+
+impl FromStr for HttpMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "POST" => Ok(HttpMethod::Post),
+            "PUT" => Ok(HttpMethod::Put),
+            _ => Err(Error::msg(format!("Unsupported HTTP publish method: {:?} (expected POST or PUT)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpMethod::Post => write!(f, "POST"),
+            HttpMethod::Put => write!(f, "PUT"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HttpOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Base URL for HTTP server (prefixed with http:// or https://), topics are appended as paths
+    pub http_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "POST"))]
+    /// Method used for publishing (POST or PUT)
+    pub http_method: HttpMethod,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval between subscription requests.
+    ///
+    /// If not set subscriptions long-poll, issuing the next request as soon as the server responds.
+    pub http_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// HTTP connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub http_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// HTTP request timeout (defaults to `TransportDefaults::request_timeout`),
+    /// this must exceed the time the server holds long-polling requests
+    pub http_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for HttpOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            http_url: url.to_string(),
+            http_method: HttpMethod::default(),
+            http_poll_interval: None,
+            http_connect_timeout: None,
+            http_request_timeout: None,
+            tls_opts: TlsOptions::default(),
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// Generic futures-based HTTP client abstraction
+///
+/// Publishing issues a POST or PUT with the payload to `{url}/{topic}`, subscriptions
+/// issue GET requests to `{url}/{topic}` and emit the body of each successful (200) response.
+/// `204 No Content` and `304 Not Modified` responses are skipped, allowing servers to end
+/// long-polling requests without new data. Server-Sent Events are not supported.
+pub struct HttpClient {
+    http: Client,
+    url: String,
+    method: HttpMethod,
+    auth: Option<HeaderValue>,
+    poll_interval: Option<Duration>,
+    subs: Vec<Subscription>,
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Index of the next subscription to poll, rotated for fairness
+    next: usize,
+    /// Cleared on disconnect, as HTTP has no session to monitor
+    connected: bool,
+}
+
+/// Active long-polling subscription
+struct Subscription {
+    topic: String,
+    request: Option<BoxFuture<'static, Result<Option<Vec<u8>>, Error>>>,
+    timer: Option<Delay>,
+}
+
+impl HttpClient {
+    /// Create a new client using the provided options
+    pub fn new<O: Into<HttpOptions>>(opts: O) -> Result<HttpClient, Error> {
+        let o = opts.into();
+
+        // Setup HTTP client
+        let builder = ClientBuilder::new()
+            .connect_timeout(o.http_connect_timeout.unwrap_or(o.defaults.connect_timeout))
+            .timeout(o.http_request_timeout.unwrap_or(o.defaults.request_timeout));
+
+        let builder = o.tls_opts.configure_reqwest(&o.http_url, builder)?;
+
+        let http = builder.build()
+            .map_err(|e| o.tls_opts.with_diagnostics(&o.http_url, e.into()))
+            .with_context(|| format!("Failed to build HTTP client for {}", o.http_url))?;
+
+        // Load username / password if provided for HTTP basic auth
+        let auth = match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(username), Some(password)) => {
+                let v = format!("Basic {}", base64::encode(&format!("{}:{}", username, password)));
+                Some(HeaderValue::from_str(&v).context("Invalid username / password for HTTP basic auth")?)
+            },
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(Error::msg("User auth requires both username and password arguments"))
+            },
+            _ => None,
+        };
+
+        Ok(HttpClient{
+            http,
+            url: o.http_url.trim_end_matches('/').to_string(),
+            method: o.http_method,
+            auth,
+            poll_interval: o.http_poll_interval,
+            subs: vec![],
+            last_error: None,
+            next: 0,
+            connected: true,
+        })
+    }
+
+    /// Publish data to a topic, returning the response status and body from the server
+    ///
+    /// Unlike `ClientPub::publish` this returns error (4xx / 5xx) statuses rather than mapping them to `Err`
+    pub async fn publish_confirmed(&mut self, topic: &str, data: &[u8]) -> Result<(StatusCode, Vec<u8>), Error> {
+        let method = match self.method {
+            HttpMethod::Post => Method::POST,
+            HttpMethod::Put => Method::PUT,
+        };
+
+        let mut req = self.http.request(method, &self.resource(topic)).body(data.to_vec());
+        if let Some(a) = &self.auth {
+            req = req.header(AUTHORIZATION, a.clone());
+        }
+
+        let resp = req.send().compat().await?;
+        let status = resp.status();
+        let body = read_body(resp).await?;
+
+        Ok((status, body))
+    }
+
+    /// Fetch inner object for raw use
+    pub fn inner(&self) -> Client {
+        self.http.clone()
+    }
+
+    fn resource(&self, topic: &str) -> String {
+        format!("{}/{}", self.url, topic.trim_start_matches('/'))
+    }
+}
+
+impl Subscription {
+    /// Poll the subscription for the next payload, issuing requests as required
+    fn poll_next(&mut self, client: &Client, url: &str, auth: &Option<HeaderValue>, poll_interval: Option<Duration>,
+            last_error: &mut Option<(std::time::Instant, PalError)>, cx: &mut Context) -> Poll<Vec<u8>> {
+        loop {
+            // Wait for the poll or retry interval to elapse
+            if let Some(t) = &mut self.timer {
+                match t.poll_unpin(cx) {
+                    Poll::Ready(_) => self.timer = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            let req = self.request.get_or_insert_with(|| get(client.clone(), url.to_string(), auth.clone()));
+
+            let res = match req.poll_unpin(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.request = None;
+
+            match res {
+                Ok(d) => {
+                    self.timer = poll_interval.map(|p| delay_until(Instant::now() + p));
+
+                    if let Some(d) = d {
+                        return Poll::Ready(d)
+                    }
+                },
+                Err(e) => {
+                    warn!("HTTP subscription to {} failed: {:?}", self.topic, e);
+                    *last_error = Some((std::time::Instant::now(), PalError::Subscription{
+                        topic: self.topic.clone(), error: e.to_string(),
+                    }));
+
+                    let retry = poll_interval.unwrap_or(DEFAULT_RETRY_INTERVAL);
+                    self.timer = Some(delay_until(Instant::now() + retry));
+                },
+            }
+        }
+    }
+}
+
+/// Build a GET request future for a subscription, returning `None` where no new data is available
+fn get(client: Client, url: String, auth: Option<HeaderValue>) -> BoxFuture<'static, Result<Option<Vec<u8>>, Error>> {
+    Box::pin(async move {
+        let mut req = client.get(&url);
+        if let Some(a) = auth {
+            req = req.header(AUTHORIZATION, a);
+        }
+
+        let resp = req.send().compat().await?;
+
+        match resp.status() {
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED => Ok(None),
+            s if s.is_success() => Ok(Some(read_body(resp).await?)),
+            s => Err(Error::msg(format!("HTTP GET {} failed: {}", url, s))),
+        }
+    })
+}
+
+/// Read a response body
+async fn read_body(resp: reqwest::r#async::Response) -> Result<Vec<u8>, Error> {
+    let body = resp.into_body().compat()
+        .try_fold(Vec::new(), |mut v, c| {
+            v.extend_from_slice(&c);
+            future::ready(Ok(v))
+        }).await?;
+
+    Ok(body)
+}
+
+#[async_trait]
+impl ClientBase for HttpClient {
+    /// Disconnect from client, removing all subscriptions
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.subs.clear();
+        self.connected = false;
+
+        Ok(())
+    }
+
+    /// HTTP is connectionless, clients are considered connected until `disconnect` is called
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for HttpClient {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        match self.publish_confirmed(topic, data).await? {
+            (s, _) if s.is_success() => Ok(()),
+            (s, _) => Err(Error::msg(format!("HTTP publish to {} failed: {}", topic, s))),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientSub for HttpClient {
+    /// Subscribe to a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        // Skip duplicate subscriptions
+        if self.subs.iter().any(|s| s.topic == topic) {
+            debug!("Already subscribed to {}", topic);
+            return Ok(())
+        }
+
+        self.subs.push(Subscription{
+            topic: topic.to_string(),
+            request: None,
+            timer: None,
+        });
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic, cancelling any outstanding request
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let idx = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.subs.remove(idx);
+        if self.next > idx {
+            self.next -= 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for HttpClient
+///
+/// Items are tagged with the topic of the originating subscription, subscriptions are
+/// polled round-robin so a busy resource cannot starve others.
+impl Stream for HttpClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let n = this.subs.len();
+
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+            let url = this.resource(&this.subs[idx].topic);
+            let s = &mut this.subs[idx];
+
+            if let Poll::Ready(d) = s.poll_next(&this.http, &url, &this.auth, this.poll_interval, &mut this.last_error, cx) {
+                this.next = (idx + 1) % n;
+                return Poll::Ready(Some( (s.topic.clone(), d) ))
+            }
+        }
+
+        Poll::Pending
+    }
+}
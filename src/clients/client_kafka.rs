@@ -0,0 +1,357 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use std::task::{Context, Poll};
+
+use log::{debug, warn};
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::{Delay, Instant, delay_until};
+
+use rdkafka::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::Message;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// Default consumer group
+pub const DEFAULT_GROUP_ID: &str = "iot-pal";
+
+/// Default interval for polling the consumer when no messages are pending
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KafkaOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Bootstrap brokers, comma separated (optionally prefixed with kafka:// or kafkas:// for TLS)
+    pub kafka_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "iot-pal"))]
+    /// Consumer group for subscriptions
+    pub kafka_group_id: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Static group member id, avoids rebalancing on restart (requires Kafka 2.3+)
+    pub kafka_group_instance_id: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "latest"))]
+    /// Offset to start consuming from where the group has no committed offset (earliest or latest)
+    pub kafka_offset_reset: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Disable automatic offset commits for the consumer group
+    pub kafka_no_auto_commit: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Consumer session timeout, after which the group is rebalanced (defaults to the broker setting)
+    pub kafka_session_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for polling the consumer when no messages are pending (defaults to 100ms)
+    pub kafka_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for producing messages (defaults to `TransportDefaults::request_timeout`)
+    pub kafka_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for KafkaOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            kafka_url: url.to_string(),
+            kafka_group_id: DEFAULT_GROUP_ID.to_string(),
+            kafka_group_instance_id: None,
+            kafka_offset_reset: "latest".to_string(),
+            kafka_no_auto_commit: false,
+            kafka_session_timeout: None,
+            kafka_poll_interval: None,
+            kafka_request_timeout: None,
+            tls_opts: TlsOptions::default(),
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for KafkaOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Generic futures-based Kafka client abstraction
+///
+/// Publishing produces to the topic, subscriptions join the configured consumer group.
+/// Kafka topics are not hierarchical, topics prefixed with `^` are matched as regular expressions.
+///
+/// Connections to brokers are established lazily, so an unreachable cluster is
+/// only detected on the first publish or poll.
+pub struct KafkaClient {
+    producer: FutureProducer,
+    consumer: BaseConsumer,
+    subs: Vec<String>,
+    poll_interval: Duration,
+    request_timeout: Duration,
+    timer: Delay,
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Cleared on disconnect, as the underlying driver manages broker connections
+    connected: bool,
+}
+
+impl KafkaClient {
+    /// Create a new client using the provided options
+    pub fn new<O: Into<KafkaOptions>>(opts: O) -> Result<KafkaClient, Error> {
+        let o = opts.into();
+
+        let config = Self::config(&o)?;
+
+        let producer: FutureProducer = config.create()?;
+        let consumer: BaseConsumer = config.create()?;
+
+        debug!("Created Kafka client for {}", o.kafka_url);
+
+        Ok(KafkaClient{
+            producer,
+            consumer,
+            subs: vec![],
+            poll_interval: o.kafka_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            request_timeout: o.kafka_request_timeout.unwrap_or(o.defaults.request_timeout),
+            timer: delay_until(Instant::now()),
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Build the librdkafka configuration, shared by the producer and consumer
+    fn config(o: &KafkaOptions) -> Result<ClientConfig, Error> {
+        let (tls, brokers) = match o.kafka_url.find("://") {
+            Some(i) => match &o.kafka_url[..i] {
+                "kafka" => (false, &o.kafka_url[i+3..]),
+                "kafkas" => (true, &o.kafka_url[i+3..]),
+                s => return Err(Error::msg(format!("Unsupported Kafka URL scheme: {:?}", s))),
+            },
+            None => (false, o.kafka_url.as_str()),
+        };
+        let tls = tls || o.tls_opts.is_configured();
+
+        match o.kafka_offset_reset.as_str() {
+            "earliest" | "latest" => (),
+            v => return Err(Error::msg(format!("Unsupported Kafka offset reset: {:?} (expected earliest or latest)", v))),
+        }
+
+        let mut c = ClientConfig::new();
+        c.set("bootstrap.servers", brokers)
+            .set("group.id", &o.kafka_group_id)
+            .set("auto.offset.reset", &o.kafka_offset_reset)
+            .set("enable.auto.commit", if o.kafka_no_auto_commit { "false" } else { "true" })
+            .set("socket.keepalive.enable", "true");
+
+        if let Some(id) = &o.kafka_group_instance_id {
+            c.set("group.instance.id", id);
+        }
+        if let Some(t) = o.kafka_session_timeout {
+            c.set("session.timeout.ms", &t.as_millis().to_string());
+        }
+
+        // Credentials use SASL PLAIN
+        let sasl = match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(u), Some(p)) => {
+                c.set("sasl.mechanisms", "PLAIN")
+                    .set("sasl.username", u)
+                    .set("sasl.password", p);
+                true
+            },
+            (None, None) => false,
+            _ => return Err(Error::msg("Kafka authentication requires both username and password")),
+        };
+
+        let protocol = match (tls, sasl) {
+            (false, false) => "plaintext",
+            (false, true) => "sasl_plaintext",
+            (true, false) => "ssl",
+            (true, true) => "sasl_ssl",
+        };
+        c.set("security.protocol", protocol);
+
+        if tls {
+            Self::tls_config(&o.tls_opts, &mut c)?;
+        }
+
+        Ok(c)
+    }
+
+    /// Apply TLS options to the librdkafka configuration
+    fn tls_config(tls: &TlsOptions, c: &mut ClientConfig) -> Result<(), Error> {
+        tls.validate()?;
+
+        if tls.tls_min_version.is_some() {
+            return Err(Error::msg("Kafka client does not support tls_min_version"))
+        }
+
+        // librdkafka loads CAs from files only
+        if tls.tls_ca_pem.is_some() {
+            return Err(Error::msg("Kafka client does not support inline CA PEM data, use tls_ca_file"))
+        }
+        if let Some(f) = &tls.tls_ca_file {
+            c.set("ssl.ca.location", f);
+        }
+        match (&tls.tls_cert_pem, &tls.tls_cert_file) {
+            (Some(d), _) => { c.set("ssl.certificate.pem", d); },
+            (None, Some(f)) => { c.set("ssl.certificate.location", f); },
+            _ => (),
+        }
+        match (&tls.tls_key_pem, &tls.tls_key_file) {
+            (Some(d), _) => { c.set("ssl.key.pem", d); },
+            (None, Some(f)) => { c.set("ssl.key.location", f); },
+            _ => (),
+        }
+        if let Some(p) = &tls.tls_key_password {
+            c.set("ssl.key.password", p);
+        }
+        if tls.tls_insecure {
+            c.set("enable.ssl.certificate.verification", "false");
+        }
+
+        Ok(())
+    }
+
+    /// Update the consumer subscription to the current topic set
+    fn resubscribe(&mut self) -> Result<(), Error> {
+        if self.subs.is_empty() {
+            self.consumer.unsubscribe();
+            return Ok(())
+        }
+
+        let topics: Vec<&str> = self.subs.iter().map(|s| s.as_str()).collect();
+        self.consumer.subscribe(&topics)?;
+
+        Ok(())
+    }
+
+    /// Commit the current consumer offsets, for use with `kafka_no_auto_commit`
+    pub fn commit(&mut self) -> Result<(), Error> {
+        self.consumer.commit_consumer_state(rdkafka::consumer::CommitMode::Async)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientBase for KafkaClient {
+    /// Flush pending messages and leave the consumer group
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.subs.clear();
+
+        self.producer.flush(self.request_timeout);
+        self.consumer.unsubscribe();
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for KafkaClient {
+    /// Produce data to a topic, waiting for delivery
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let record = FutureRecord::<(), [u8]>::to(topic).payload(data);
+
+        match self.producer.send(record, self.request_timeout).await {
+            Ok((partition, offset)) => {
+                debug!("Delivered to {} (partition: {} offset: {})", topic, partition, offset);
+                Ok(())
+            },
+            Err((e, _m)) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientSub for KafkaClient {
+    /// Subscribe to a topic (or regular expression prefixed with `^`)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+
+        self.resubscribe()
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        match self.subs.iter().position(|s| s == topic) {
+            Some(i) => { self.subs.remove(i); },
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        }
+
+        self.resubscribe()
+    }
+}
+
+/// Stream implementation for KafkaClient, polling the consumer at the configured interval
+impl Stream for KafkaClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.subs.is_empty() {
+            return Poll::Pending
+        }
+
+        loop {
+            match this.consumer.poll(Duration::from_secs(0)) {
+                Some(Ok(m)) => {
+                    let data = m.payload().map(|p| p.to_vec()).unwrap_or_default();
+                    return Poll::Ready(Some((m.topic().to_string(), data)))
+                },
+                Some(Err(e)) => {
+                    warn!("Kafka consumer error: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::Subscription{
+                        topic: this.subs.join(","),
+                        error: e.to_string(),
+                    }));
+                },
+                None => {
+                    // No messages pending, wait for the next poll
+                    this.timer.reset(Instant::now() + this.poll_interval);
+
+                    if let Poll::Pending = Pin::new(&mut this.timer).poll(cx) {
+                        return Poll::Pending
+                    }
+                },
+            }
+        }
+    }
+}
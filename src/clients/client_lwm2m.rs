@@ -0,0 +1,1010 @@
+//! LwM2M (v1.0) client over CoAP / UDP
+//!
+//! The client registers with an LwM2M server (optionally via a bootstrap server) and serves
+//! read, write, execute, delete and observe requests for locally held resources.
+//!
+//! Resource paths (ie. `3303/0/5700`) are used as topics, publishing sets a resource value
+//! (notifying observers) and subscribing delivers server writes and executes for matching
+//! paths via `Stream`. Resource values are opaque and served as received or published,
+//! so should be published in the format the server expects (ie. plain text).
+//!
+//! Server requests are only handled while the client is polled as a `Stream`
+//! or while awaiting the response to a client request.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use futures::lock::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::time::{Delay, Instant, delay_until, timeout_at};
+
+use coap::message::packet::{Packet, CoAPOption};
+use coap::message::header::MessageType;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+use crate::id::{IdGenerator, SharedIdGenerator};
+
+/// Default registration lifetime
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(86400);
+
+/// Interval before retransmitting confirmable requests (CoAP ACK_TIMEOUT)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum CoAP datagram size
+const MAX_PACKET_LEN: usize = 1500;
+
+const CF_TEXT: u32 = 0;
+const CF_LINK: u32 = 40;
+const CF_TLV: u32 = 11542;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Lwm2mOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for LwM2M server (prefixed with coap://), replaced by the bootstrapped server where
+    /// `lwm2m_bootstrap` is set
+    pub lwm2m_server: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Endpoint client name (generated using `id_generator` if not set)
+    pub lwm2m_endpoint: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for LwM2M bootstrap server (prefixed with coap://), bootstrapping prior to registration
+    pub lwm2m_bootstrap: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Registration lifetime, registrations are updated at half this interval (defaults to 24h)
+    pub lwm2m_lifetime: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Object instances to register in addition to those with published resources (ie. 3/0)
+    pub lwm2m_objects: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for requests to the server (defaults to `TransportDefaults::request_timeout`)
+    pub lwm2m_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    /// Generator for endpoint names and request tokens (defaults to `UuidGenerator`)
+    pub id_generator: SharedIdGenerator,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for Lwm2mOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            lwm2m_server: url.to_string(),
+            lwm2m_endpoint: None,
+            lwm2m_bootstrap: None,
+            lwm2m_lifetime: None,
+            lwm2m_objects: vec![],
+            lwm2m_request_timeout: None,
+            id_generator: SharedIdGenerator::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// LwM2M object, object instance, or resource path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Lwm2mPath {
+    pub object: u16,
+    pub instance: Option<u16>,
+    pub resource: Option<u16>,
+}
+
+impl Lwm2mPath {
+    /// Create a resource path
+    pub fn resource(object: u16, instance: u16, resource: u16) -> Self {
+        Self { object, instance: Some(instance), resource: Some(resource) }
+    }
+
+    /// Check whether this path is equal to or a parent of another path
+    pub fn contains(&self, other: &Lwm2mPath) -> bool {
+        self.object == other.object
+            && (self.instance.is_none() || self.instance == other.instance)
+            && (self.resource.is_none() || self.resource == other.resource)
+    }
+
+    /// Parse a path from CoAP Uri-Path segments
+    fn from_segments(s: &[String]) -> Result<Self, Error> {
+        let ids = s.iter().map(|v| v.parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::msg(format!("Invalid LwM2M path: {:?}", s.join("/"))))?;
+
+        match ids.as_slice() {
+            [o] => Ok(Self{ object: *o, instance: None, resource: None }),
+            [o, i] => Ok(Self{ object: *o, instance: Some(*i), resource: None }),
+            [o, i, r] => Ok(Self{ object: *o, instance: Some(*i), resource: Some(*r) }),
+            _ => Err(Error::msg(format!("Invalid LwM2M path: {:?}", s.join("/")))),
+        }
+    }
+}
+
+impl FromStr for Lwm2mPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments: Vec<_> = s.split('/').filter(|v| !v.is_empty()).map(|v| v.to_string()).collect();
+        Self::from_segments(&segments)
+    }
+}
+
+impl std::fmt::Display for Lwm2mPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.object)?;
+        if let Some(i) = self.instance {
+            write!(f, "/{}", i)?;
+        }
+        if let Some(r) = self.resource {
+            write!(f, "/{}", r)?;
+        }
+        Ok(())
+    }
+}
+
+/// Server observation of a path
+struct Observation {
+    path: Lwm2mPath,
+    addr: SocketAddr,
+    token: Vec<u8>,
+    seq: u32,
+    /// Message ID of the last notification, a reset cancels the observation
+    msg_id: u16,
+}
+
+/// Generic futures-based LwM2M client abstraction
+pub struct Lwm2mClient {
+    tx: Arc<AsyncMutex<SendHalf>>,
+    rx: Arc<AsyncMutex<RecvHalf>>,
+    server: SocketAddr,
+    endpoint: String,
+    lifetime: Duration,
+    request_timeout: Duration,
+
+    /// Registration location (Location-Path) while registered
+    location: Option<Vec<String>>,
+    /// Message ID of the pending registration update sent by `Stream`
+    update_msg_id: Option<u16>,
+    update_timer: Delay,
+    /// Set when object instances change, updating the registered object list
+    objects_changed: bool,
+    bootstrap_finished: bool,
+
+    resources: BTreeMap<Lwm2mPath, Vec<u8>>,
+    instances: BTreeSet<(u16, u16)>,
+    observations: Vec<Observation>,
+    subs: Vec<(String, Lwm2mPath)>,
+
+    msg_id: u16,
+    /// Generator for request tokens
+    ids: SharedIdGenerator,
+    /// Received writes / executes pending delivery via `Stream`
+    inbox: VecDeque<(String, Vec<u8>)>,
+    /// Packets (ie. responses) pending transmission
+    outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    sending: Option<BoxFuture<'static, Result<(), Error>>>,
+    receiving: Option<BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>>>,
+
+    last_error: Option<(std::time::Instant, PalError)>,
+}
+
+impl Lwm2mClient {
+    /// Create a new client using the provided options, bootstrapping (where configured)
+    /// and registering with the server
+    pub async fn new<O: Into<Lwm2mOptions>>(opts: O) -> Result<Lwm2mClient, Error> {
+        let o = opts.into();
+
+        let mut instances = BTreeSet::new();
+        for i in &o.lwm2m_objects {
+            match Lwm2mPath::from_str(i)? {
+                Lwm2mPath{ object, instance: Some(instance), resource: None } => { instances.insert((object, instance)); },
+                _ => return Err(Error::msg(format!("Invalid LwM2M object instance: {:?} (expected object/instance)", i))),
+            }
+        }
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let (rx, tx) = socket.split();
+
+        let lifetime = o.lwm2m_lifetime.unwrap_or(DEFAULT_LIFETIME);
+
+        let mut c = Lwm2mClient{
+            tx: Arc::new(AsyncMutex::new(tx)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            server: resolve(&o.lwm2m_server).await?,
+            endpoint: o.lwm2m_endpoint.clone().unwrap_or_else(|| format!("iot-pal-{}", o.id_generator.generate())),
+            lifetime,
+            request_timeout: o.lwm2m_request_timeout.unwrap_or(o.defaults.request_timeout),
+            location: None,
+            update_msg_id: None,
+            update_timer: delay_until(Instant::now() + lifetime / 2),
+            objects_changed: false,
+            bootstrap_finished: false,
+            resources: BTreeMap::new(),
+            instances,
+            observations: vec![],
+            subs: vec![],
+            msg_id: rand::random(),
+            ids: o.id_generator.clone(),
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            sending: None,
+            receiving: None,
+            last_error: None,
+        };
+
+        if let Some(bs) = &o.lwm2m_bootstrap {
+            c.bootstrap(bs, o.defaults.connect_timeout).await?;
+        }
+
+        c.register().await?;
+
+        Ok(c)
+    }
+
+    /// Fetch the endpoint client name
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Fetch a resource value
+    pub fn resource(&self, path: &Lwm2mPath) -> Option<&[u8]> {
+        self.resources.get(path).map(|v| v.as_slice())
+    }
+
+    /// Request bootstrapping from the provided bootstrap server, waiting up to `timeout`
+    /// for the bootstrap server to write security / server objects and finish.
+    ///
+    /// The LwM2M server is then set from the first non-bootstrap security object instance.
+    pub async fn bootstrap(&mut self, url: &str, timeout: Duration) -> Result<(), Error> {
+        let addr = resolve(url).await?;
+        self.bootstrap_finished = false;
+
+        let query = vec![format!("ep={}", self.endpoint)];
+        let resp = self.transact(addr, "0.02", &["bs".to_string()], &query, None, vec![]).await?;
+        check_code(&resp, "2.04", "LwM2M bootstrap request")?;
+
+        // Serve bootstrap writes until finished
+        let deadline = Instant::now() + timeout;
+        while !self.bootstrap_finished {
+            let (from, p) = match timeout_at(deadline, self.recv()).await {
+                Ok(r) => r?,
+                Err(_) => return Err(PalError::Timeout{ operation: format!("LwM2M bootstrap from {}", url), timeout }.into()),
+            };
+            self.handle(from, p);
+            self.flush().await?;
+        }
+
+        // Locate the LwM2M server URI (security object resource 0) where the bootstrap flag (resource 1) is unset
+        let server = self.instances.iter()
+            .filter(|(o, _)| *o == 0)
+            .filter(|(_, i)| !is_true(self.resources.get(&Lwm2mPath::resource(0, *i, 1))))
+            .filter_map(|(_, i)| self.resources.get(&Lwm2mPath::resource(0, *i, 0)))
+            .next()
+            .map(|v| String::from_utf8_lossy(v).to_string());
+
+        match server {
+            Some(s) => {
+                debug!("LwM2M bootstrap complete, server: {}", s);
+                self.server = resolve(&s).await?;
+                Ok(())
+            },
+            None => Err(Error::msg("LwM2M bootstrap did not provision a server")),
+        }
+    }
+
+    /// Register with the LwM2M server
+    pub async fn register(&mut self) -> Result<(), Error> {
+        let query = vec![
+            format!("ep={}", self.endpoint),
+            format!("lt={}", self.lifetime.as_secs()),
+            "lwm2m=1.0".to_string(),
+            "b=U".to_string(),
+        ];
+
+        let resp = self.transact(self.server, "0.02", &["rd".to_string()], &query, Some(CF_LINK), self.links()).await?;
+        check_code(&resp, "2.01", "LwM2M registration")?;
+
+        let location: Vec<_> = resp.get_option(CoAPOption::LocationPath)
+            .map(|l| l.iter().map(|v| String::from_utf8_lossy(v).to_string()).collect())
+            .unwrap_or_default();
+        if location.is_empty() {
+            return Err(Error::msg("LwM2M registration response missing location"))
+        }
+
+        debug!("LwM2M registered {} at /{}", self.endpoint, location.join("/"));
+
+        self.location = Some(location);
+        self.objects_changed = false;
+        self.update_timer.reset(Instant::now() + self.lifetime / 2);
+
+        Ok(())
+    }
+
+    /// Update the registration, including the object list where this has changed
+    pub async fn update(&mut self) -> Result<(), Error> {
+        let location = match &self.location {
+            Some(l) => l.clone(),
+            None => return Err(PalError::NotConnected.into()),
+        };
+
+        let (cf, payload) = match self.objects_changed {
+            true => (Some(CF_LINK), self.links()),
+            false => (None, vec![]),
+        };
+
+        let resp = self.transact(self.server, "0.02", &location, &[], cf, payload).await?;
+
+        // Re-register where the registration has expired
+        if resp.header.get_code() == "4.04" {
+            warn!("LwM2M registration expired, re-registering");
+            return self.register().await
+        }
+        check_code(&resp, "2.04", "LwM2M registration update")?;
+
+        self.objects_changed = false;
+        self.update_timer.reset(Instant::now() + self.lifetime / 2);
+
+        Ok(())
+    }
+
+    /// Deregister from the LwM2M server
+    pub async fn deregister(&mut self) -> Result<(), Error> {
+        let location = match self.location.take() {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+
+        let resp = self.transact(self.server, "0.04", &location, &[], None, vec![]).await?;
+        check_code(&resp, "2.02", "LwM2M deregistration")
+    }
+
+    /// Registered object instances in CoRE link format
+    fn links(&self) -> Vec<u8> {
+        let links: Vec<_> = self.instances.iter()
+            .filter(|(o, _)| *o != 0)
+            .map(|(o, i)| format!("</{}/{}>", o, i))
+            .collect();
+
+        links.join(",").into_bytes()
+    }
+
+    /// Set a resource value, notifying observers of the resource or parents
+    async fn set_resource(&mut self, path: Lwm2mPath, data: &[u8]) -> Result<(), Error> {
+        let instance = match (path.instance, path.resource) {
+            (Some(i), Some(_)) => i,
+            _ => return Err(Error::msg(format!("LwM2M publish requires a resource path (object/instance/resource), not {}", path))),
+        };
+
+        self.resources.insert(path, data.to_vec());
+        if self.instances.insert((path.object, instance)) {
+            self.objects_changed = true;
+        }
+
+        for i in 0..self.observations.len() {
+            if !self.observations[i].path.contains(&path) {
+                continue
+            }
+
+            let (cf, payload) = self.read(&self.observations[i].path)
+                .unwrap_or_else(|| (CF_TEXT, vec![]));
+
+            let msg_id = self.next_msg_id();
+            let o = &mut self.observations[i];
+            o.seq = (o.seq + 1) & 0x00FF_FFFF;
+            o.msg_id = msg_id;
+
+            let mut p = packet(MessageType::NonConfirmable, "2.05", msg_id, o.token.clone());
+            p.add_option(CoAPOption::Observe, uint_opt(o.seq));
+            p.add_option(CoAPOption::ContentFormat, uint_opt(cf));
+            p.set_payload(payload);
+
+            self.outbox.push_back((o.addr, encode(&p)?));
+        }
+
+        self.flush().await?;
+
+        // Advertise new object instances
+        if self.objects_changed && self.location.is_some() {
+            self.update().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a resource (as stored) or an object / object instance (as TLV)
+    fn read(&self, path: &Lwm2mPath) -> Option<(u32, Vec<u8>)> {
+        if path.resource.is_some() {
+            return self.resources.get(path).map(|v| (CF_TEXT, v.clone()))
+        }
+
+        let instance_tlv = |o: u16, i: u16| {
+            let mut b = vec![];
+            for (p, v) in self.resources.range(Lwm2mPath::resource(o, i, 0)..=Lwm2mPath::resource(o, i, u16::MAX)) {
+                tlv_encode(TLV_RESOURCE, p.resource.unwrap_or(0), v, &mut b);
+            }
+            b
+        };
+
+        match path.instance {
+            Some(i) if self.instances.contains(&(path.object, i)) => Some((CF_TLV, instance_tlv(path.object, i))),
+            Some(_) => None,
+            None => {
+                let mut b = vec![];
+                for (_, i) in self.instances.iter().filter(|(o, _)| *o == path.object) {
+                    tlv_encode(TLV_OBJECT_INSTANCE, *i, &instance_tlv(path.object, *i), &mut b);
+                }
+                Some((CF_TLV, b))
+            },
+        }
+    }
+
+    /// Write a resource, object instance, or object from the server
+    fn write(&mut self, path: Lwm2mPath, cf: Option<u32>, data: &[u8]) -> Result<Vec<Lwm2mPath>, Error> {
+        let mut written = vec![];
+
+        match (path.instance, path.resource, cf) {
+            // Single resource, plain value or TLV encoded
+            (Some(i), Some(r), Some(CF_TLV)) => {
+                for t in tlv_decode(data)?.into_iter().filter(|t| t.id == r) {
+                    written.push((Lwm2mPath::resource(path.object, i, r), t.value));
+                }
+            },
+            (Some(i), Some(r), _) => written.push((Lwm2mPath::resource(path.object, i, r), data.to_vec())),
+            // Object instance, TLV encoded resources
+            (Some(i), None, Some(CF_TLV)) => {
+                for t in tlv_decode(data)? {
+                    written.push((Lwm2mPath::resource(path.object, i, t.id), t.value));
+                }
+            },
+            // Object, TLV encoded instances
+            (None, None, Some(CF_TLV)) => {
+                for inst in tlv_decode(data)?.into_iter().filter(|t| t.kind == TLV_OBJECT_INSTANCE) {
+                    for t in tlv_decode(&inst.value)? {
+                        written.push((Lwm2mPath::resource(path.object, inst.id, t.id), t.value));
+                    }
+                }
+            },
+            _ => return Err(Error::msg(format!("Unsupported content format {:?} for write to {}", cf, path))),
+        }
+
+        let mut paths = vec![];
+        for (p, v) in written {
+            if let Some(i) = p.instance {
+                self.objects_changed |= self.instances.insert((p.object, i));
+            }
+            self.resources.insert(p, v);
+            paths.push(p);
+        }
+
+        Ok(paths)
+    }
+
+    /// Delete an object instance, or all instances of an object
+    fn delete(&mut self, path: Lwm2mPath) -> bool {
+        let before = self.instances.len();
+
+        self.instances.retain(|(o, i)| !path.contains(&Lwm2mPath{ object: *o, instance: Some(*i), resource: None }));
+        self.resources.retain(|p, _| !path.contains(p));
+        self.observations.retain(|o| !path.contains(&o.path));
+
+        let deleted = self.instances.len() != before;
+        self.objects_changed |= deleted;
+        deleted
+    }
+
+    /// Queue delivery of written / executed resources for matching subscriptions
+    fn deliver(&mut self, path: &Lwm2mPath, data: Vec<u8>) {
+        if self.subs.iter().any(|(_, s)| s.contains(path)) {
+            self.inbox.push_back((path.to_string(), data));
+        }
+    }
+
+    /// Handle a received packet, queuing responses to server requests
+    fn handle(&mut self, from: SocketAddr, p: Packet) {
+        let code = p.header.get_code();
+
+        // Responses and acknowledgements
+        if !code.starts_with("0.") || code == "0.00" {
+            if let MessageType::Reset = p.header.get_type() {
+                let msg_id = p.header.get_message_id();
+                self.observations.retain(|o| o.msg_id != msg_id);
+            }
+
+            if Some(p.header.get_message_id()) == self.update_msg_id {
+                self.update_msg_id = None;
+
+                match code.as_str() {
+                    "2.04" => self.update_timer.reset(Instant::now() + self.lifetime / 2),
+                    c => {
+                        warn!("LwM2M registration update failed ({}), re-register required", c);
+                        self.location = None;
+                        self.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    },
+                }
+            }
+
+            return
+        }
+
+        let segments: Vec<String> = p.get_option(CoAPOption::UriPath)
+            .map(|l| l.iter().map(|v| String::from_utf8_lossy(v).to_string()).collect())
+            .unwrap_or_default();
+        let cf = opt_uint(&p, CoAPOption::ContentFormat);
+        let observe = opt_uint(&p, CoAPOption::Observe);
+
+        let (resp_code, resp_cf, resp_payload, resp_observe) = match (code.as_str(), segments.as_slice()) {
+            // Bootstrap finish
+            ("0.02", [bs]) if bs == "bs" => {
+                self.bootstrap_finished = true;
+                ("2.04", None, vec![], None)
+            },
+            // Bootstrap delete of all objects
+            ("0.04", []) => {
+                self.instances.clear();
+                self.resources.clear();
+                ("2.02", None, vec![], None)
+            },
+            (c, _) => match (c, Lwm2mPath::from_segments(&segments)) {
+                (_, Err(_)) => ("4.00", None, vec![], None),
+                // Read / observe
+                ("0.01", Ok(path)) => match self.read(&path) {
+                    Some((cf, payload)) => {
+                        let seq = match observe {
+                            Some(0) => {
+                                self.observations.retain(|o| o.path != path || o.addr != from);
+                                self.observations.push(Observation{ path, addr: from, token: p.get_token().clone(), seq: 0, msg_id: 0 });
+                                Some(0)
+                            },
+                            Some(1) => {
+                                self.observations.retain(|o| o.path != path || o.addr != from);
+                                None
+                            },
+                            _ => None,
+                        };
+                        ("2.05", Some(cf), payload, seq)
+                    },
+                    None => ("4.04", None, vec![], None),
+                },
+                // Execute
+                ("0.02", Ok(path)) if path.resource.is_some() => {
+                    self.deliver(&path, p.payload.clone());
+                    ("2.04", None, vec![], None)
+                },
+                // Write (replace or partial update)
+                ("0.02", Ok(path)) | ("0.03", Ok(path)) => match self.write(path, cf, &p.payload) {
+                    Ok(paths) => {
+                        for w in paths {
+                            let v = self.resources.get(&w).cloned().unwrap_or_default();
+                            self.deliver(&w, v);
+                        }
+                        ("2.04", None, vec![], None)
+                    },
+                    Err(e) => {
+                        warn!("LwM2M write to {} failed: {:?}", path, e);
+                        ("4.15", None, vec![], None)
+                    },
+                },
+                // Delete
+                ("0.04", Ok(path)) if path.resource.is_none() => match self.delete(path) {
+                    true => ("2.02", None, vec![], None),
+                    false => ("4.04", None, vec![], None),
+                },
+                _ => ("4.05", None, vec![], None),
+            },
+        };
+
+        // Piggyback responses on acknowledgements for confirmable requests
+        let (mtype, msg_id) = match p.header.get_type() {
+            MessageType::Confirmable => (MessageType::Acknowledgement, p.header.get_message_id()),
+            _ => (MessageType::NonConfirmable, self.next_msg_id()),
+        };
+
+        let mut r = packet(mtype, resp_code, msg_id, p.get_token().clone());
+        if let Some(seq) = resp_observe {
+            r.add_option(CoAPOption::Observe, uint_opt(seq));
+        }
+        if let Some(cf) = resp_cf {
+            r.add_option(CoAPOption::ContentFormat, uint_opt(cf));
+        }
+        r.set_payload(resp_payload);
+
+        match encode(&r) {
+            Ok(d) => self.outbox.push_back((from, d)),
+            Err(e) => warn!("Failed to encode LwM2M response: {:?}", e),
+        }
+    }
+
+    /// Send a confirmable request and wait for the response, retransmitting until the request timeout
+    ///
+    /// Server requests received while waiting are handled as usual.
+    async fn transact(&mut self, addr: SocketAddr, code: &str, path: &[String], query: &[String], cf: Option<u32>, payload: Vec<u8>) -> Result<Packet, Error> {
+        let msg_id = self.next_msg_id();
+        let token = self.ids.generate_token();
+
+        let mut p = packet(MessageType::Confirmable, code, msg_id, token.clone());
+        for s in path {
+            p.add_option(CoAPOption::UriPath, s.as_bytes().to_vec());
+        }
+        if let Some(cf) = cf {
+            p.add_option(CoAPOption::ContentFormat, uint_opt(cf));
+        }
+        for q in query {
+            p.add_option(CoAPOption::UriQuery, q.as_bytes().to_vec());
+        }
+        p.set_payload(payload);
+        let data = encode(&p)?;
+
+        let deadline = Instant::now() + self.request_timeout;
+        let mut acked = false;
+
+        while Instant::now() < deadline {
+            if !acked {
+                self.outbox.push_back((addr, data.clone()));
+                self.flush().await?;
+            }
+
+            let retry = std::cmp::min(Instant::now() + ACK_TIMEOUT, deadline);
+            loop {
+                let (from, r) = match timeout_at(retry, self.recv()).await {
+                    Ok(r) => r?,
+                    Err(_) => break,
+                };
+
+                let is_resp = r.get_token() == &token && !r.header.get_code().starts_with("0.");
+                let is_ack = r.header.get_message_id() == msg_id && r.header.get_code() == "0.00";
+
+                // Separate responses follow an empty acknowledgement
+                if is_ack {
+                    acked = true;
+                    continue
+                }
+                if !is_resp {
+                    self.handle(from, r);
+                    self.flush().await?;
+                    continue
+                }
+
+                // Acknowledge confirmable separate responses
+                if let MessageType::Confirmable = r.header.get_type() {
+                    let ack = packet(MessageType::Acknowledgement, "0.00", r.header.get_message_id(), vec![]);
+                    self.outbox.push_back((from, encode(&ack)?));
+                    self.flush().await?;
+                }
+
+                return Ok(r)
+            }
+        }
+
+        Err(PalError::Timeout{ operation: format!("LwM2M request {} /{}", code, path.join("/")), timeout: self.request_timeout }.into())
+    }
+
+    /// Send queued packets
+    async fn flush(&mut self) -> Result<(), Error> {
+        if let Some(f) = self.sending.take() {
+            f.await?;
+        }
+        while let Some((addr, d)) = self.outbox.pop_front() {
+            send_packet(self.tx.clone(), addr, d).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive and decode a packet, resuming any receive started by `Stream`
+    async fn recv(&mut self) -> Result<(SocketAddr, Packet), Error> {
+        let f = match self.receiving.take() {
+            Some(f) => f,
+            None => recv_packet(self.rx.clone()),
+        };
+
+        let (from, d) = f.await?;
+        Ok((from, decode(&d)?))
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        self.msg_id = self.msg_id.wrapping_add(1);
+        self.msg_id
+    }
+}
+
+/// Resolve a coap:// URL to a socket address (defaulting to port 5683)
+async fn resolve(url: &str) -> Result<SocketAddr, Error> {
+    let host = url.trim_start_matches("coap://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:5683", host),
+    };
+
+    match tokio::net::lookup_host(host.as_str()).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(Error::msg(format!("Could not resolve LwM2M server {:?}", url))),
+    }
+}
+
+fn send_packet(tx: Arc<AsyncMutex<SendHalf>>, addr: SocketAddr, data: Vec<u8>) -> BoxFuture<'static, Result<(), Error>> {
+    async move {
+        tx.lock().await.send_to(&data, &addr).await?;
+        Ok(())
+    }.boxed()
+}
+
+fn recv_packet(rx: Arc<AsyncMutex<RecvHalf>>) -> BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>> {
+    async move {
+        let mut buff = vec![0u8; MAX_PACKET_LEN];
+        let (n, from) = rx.lock().await.recv_from(&mut buff).await?;
+        buff.truncate(n);
+        Ok((from, buff))
+    }.boxed()
+}
+
+fn packet(mtype: MessageType, code: &str, msg_id: u16, token: Vec<u8>) -> Packet {
+    let mut p = Packet::new();
+    p.header.set_type(mtype);
+    p.header.set_code(code);
+    p.header.set_message_id(msg_id);
+    p.set_token(token);
+    p
+}
+
+fn encode(p: &Packet) -> Result<Vec<u8>, Error> {
+    p.to_bytes().map_err(|e| Error::msg(format!("Failed to encode CoAP packet: {:?}", e)))
+}
+
+fn decode(d: &[u8]) -> Result<Packet, Error> {
+    Packet::from_bytes(d).map_err(|e| Error::msg(format!("Failed to decode CoAP packet: {:?}", e)))
+}
+
+fn check_code(p: &Packet, expected: &str, operation: &str) -> Result<(), Error> {
+    match p.header.get_code() {
+        c if c == expected => Ok(()),
+        c => Err(Error::msg(format!("{} failed: {}", operation, c))),
+    }
+}
+
+/// Encode an unsigned integer option value (minimal length, big-endian)
+fn uint_opt(v: u32) -> Vec<u8> {
+    let b = v.to_be_bytes();
+    let n = b.iter().take_while(|b| **b == 0).count();
+    b[n..].to_vec()
+}
+
+/// Decode an unsigned integer option value
+fn opt_uint(p: &Packet, o: CoAPOption) -> Option<u32> {
+    p.get_option(o)
+        .and_then(|l| l.front())
+        .map(|v| v.iter().fold(0u32, |a, b| (a << 8) | *b as u32))
+}
+
+/// Check a boolean resource value (TLV or plain text encoded)
+fn is_true(v: Option<&Vec<u8>>) -> bool {
+    match v.map(|v| v.as_slice()) {
+        Some([1]) | Some(b"1") | Some(b"true") => true,
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl ClientBase for Lwm2mClient {
+    /// Deregister from the LwM2M server
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.observations.clear();
+        self.deregister().await
+    }
+
+    /// Check whether the client is registered with the server
+    fn is_connected(&self) -> bool {
+        self.location.is_some()
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for Lwm2mClient {
+    /// Set a resource value (object/instance/resource), notifying observers
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.set_resource(Lwm2mPath::from_str(topic)?, data).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for Lwm2mClient {
+    /// Subscribe to server writes and executes for a path (and any child paths)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let path = Lwm2mPath::from_str(topic)?;
+
+        if !self.subs.iter().any(|(t, _)| t == topic) {
+            self.subs.push((topic.to_string(), path));
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a path
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        match self.subs.iter().position(|(t, _)| t == topic) {
+            Some(i) => {
+                self.subs.remove(i);
+                Ok(())
+            },
+            None => Err(Error::msg(format!("Not subscribed to {}", topic))),
+        }
+    }
+}
+
+/// Stream implementation for Lwm2mClient
+///
+/// Polling the stream serves server requests and sends registration updates.
+impl Stream for Lwm2mClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(m) = this.inbox.pop_front() {
+                return Poll::Ready(Some(m))
+            }
+
+            // Send registration updates while registered, responses are matched in `handle`
+            if let Some(location) = &this.location {
+                if let Poll::Ready(_) = Pin::new(&mut this.update_timer).poll(cx) {
+                    let location = location.clone();
+                    let msg_id = this.next_msg_id();
+                    this.update_msg_id = Some(msg_id);
+
+                    let mut p = packet(MessageType::Confirmable, "0.02", msg_id, vec![]);
+                    for s in &location {
+                        p.add_option(CoAPOption::UriPath, s.as_bytes().to_vec());
+                    }
+                    if let Ok(d) = encode(&p) {
+                        this.outbox.push_back((this.server, d));
+                    }
+
+                    this.update_timer.reset(Instant::now() + this.lifetime / 2);
+                }
+            }
+
+            // Drive pending transmissions
+            loop {
+                if this.sending.is_none() {
+                    match this.outbox.pop_front() {
+                        Some((addr, d)) => this.sending = Some(send_packet(this.tx.clone(), addr, d)),
+                        None => break,
+                    }
+                }
+
+                match this.sending.as_mut().unwrap().poll_unpin(cx) {
+                    Poll::Ready(r) => {
+                        this.sending = None;
+                        if let Err(e) = r {
+                            warn!("LwM2M send failed: {:?}", e);
+                        }
+                    },
+                    Poll::Pending => break,
+                }
+            }
+
+            if this.receiving.is_none() {
+                this.receiving = Some(recv_packet(this.rx.clone()));
+            }
+
+            match this.receiving.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok((from, d))) => {
+                    this.receiving = None;
+
+                    match decode(&d) {
+                        Ok(p) => this.handle(from, p),
+                        Err(e) => warn!("{:?}", e),
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    this.receiving = None;
+                    warn!("LwM2M receive failed: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+const TLV_OBJECT_INSTANCE: u8 = 0;
+const TLV_RESOURCE: u8 = 3;
+
+/// Decoded TLV entry (values of nested entries are left encoded)
+struct Tlv {
+    kind: u8,
+    id: u16,
+    value: Vec<u8>,
+}
+
+/// Decode a sequence of OMA-TLV entries
+fn tlv_decode(mut d: &[u8]) -> Result<Vec<Tlv>, Error> {
+    let truncated = || Error::msg("Truncated LwM2M TLV data");
+    let mut entries = vec![];
+
+    while let Some(t) = d.first().copied() {
+        let kind = t >> 6;
+        let id_len = if t & 0x20 != 0 { 2 } else { 1 };
+        let len_len = ((t >> 3) & 0x03) as usize;
+
+        let mut i = 1;
+        let id = match d.get(i..i+id_len).ok_or_else(truncated)? {
+            [h, l] => u16::from_be_bytes([*h, *l]),
+            [v] => *v as u16,
+            _ => unreachable!(),
+        };
+        i += id_len;
+
+        let len = match len_len {
+            0 => (t & 0x07) as usize,
+            n => {
+                let l = d.get(i..i+n).ok_or_else(truncated)?
+                    .iter().fold(0usize, |a, b| (a << 8) | *b as usize);
+                i += n;
+                l
+            },
+        };
+
+        let value = d.get(i..i+len).ok_or_else(truncated)?.to_vec();
+        entries.push(Tlv{ kind, id, value });
+
+        d = &d[i+len..];
+    }
+
+    Ok(entries)
+}
+
+/// Encode an OMA-TLV entry
+fn tlv_encode(kind: u8, id: u16, value: &[u8], b: &mut Vec<u8>) {
+    let mut t = kind << 6;
+    if id > 0xFF {
+        t |= 0x20;
+    }
+
+    let len = value.len();
+    let len_bytes = match len {
+        0..=7 => { t |= len as u8; vec![] },
+        8..=0xFF => { t |= 0x08; vec![len as u8] },
+        0x100..=0xFFFF => { t |= 0x10; (len as u16).to_be_bytes().to_vec() },
+        _ => { t |= 0x18; (len as u32).to_be_bytes()[1..].to_vec() },
+    };
+
+    b.push(t);
+    if id > 0xFF {
+        b.extend_from_slice(&id.to_be_bytes());
+    } else {
+        b.push(id as u8);
+    }
+    b.extend_from_slice(&len_bytes);
+    b.extend_from_slice(value);
+}
@@ -0,0 +1,412 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use futures::lock::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::{Delay, Instant, delay_until, timeout};
+
+use tokio_modbus::prelude::*;
+use tokio_modbus::client::Context as ModbusContext;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+/// Default Modbus TCP port
+pub const DEFAULT_PORT: u16 = 502;
+
+/// Default interval for polling subscribed registers
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Modbus register tables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterKind {
+    /// Read / write holding registers
+    Holding,
+    /// Read-only input registers
+    Input,
+}
+
+impl FromStr for RegisterKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "holding" => Ok(RegisterKind::Holding),
+            "input" => Ok(RegisterKind::Input),
+            _ => Err(Error::msg(format!("Unsupported Modbus register kind: {:?} (expected holding or input)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for RegisterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterKind::Holding => write!(f, "holding"),
+            RegisterKind::Input => write!(f, "input"),
+        }
+    }
+}
+
+/// Range of registers, parsed from topics of the form `kind/address[/count]` (ie. `holding/100/4`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub kind: RegisterKind,
+    pub address: u16,
+    pub count: u16,
+}
+
+impl FromStr for Registers {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::msg(format!("Invalid Modbus registers: {:?} (expected kind/address[/count])", s));
+
+        let parts: Vec<_> = s.trim_matches('/').split('/').collect();
+        let (kind, address, count) = match parts.as_slice() {
+            [k, a] => (k, a.parse().map_err(|_| invalid())?, 1),
+            [k, a, c] => (k, a.parse().map_err(|_| invalid())?, c.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+
+        // Modbus limits reads to 125 registers per request
+        if count == 0 || count > 125 {
+            return Err(Error::msg(format!("Invalid Modbus register count: {} (expected 1 to 125)", count)))
+        }
+
+        Ok(Self{ kind: RegisterKind::from_str(kind)?, address, count })
+    }
+}
+
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.kind, self.address, self.count)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModbusOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Modbus TCP server address (optionally prefixed with modbus://, port defaults to 502)
+    pub modbus_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "1"))]
+    /// Unit (slave) identifier
+    pub modbus_unit: u8,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Registers to poll on connection (kind/address[/count], ie. holding/100/4)
+    pub modbus_registers: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for polling subscribed registers (defaults to 1s)
+    pub modbus_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Only emit registers when values change
+    pub modbus_on_change: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Modbus connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub modbus_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for register reads and writes (defaults to `TransportDefaults::request_timeout`)
+    pub modbus_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for ModbusOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            modbus_url: url.to_string(),
+            modbus_unit: 1,
+            modbus_registers: vec![],
+            modbus_poll_interval: None,
+            modbus_on_change: false,
+            modbus_connect_timeout: None,
+            modbus_request_timeout: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+struct Subscription {
+    topic: String,
+    registers: Registers,
+    /// Last value, for change detection
+    last: Option<Vec<u8>>,
+}
+
+/// Generic futures-based Modbus TCP client abstraction
+///
+/// Subscribing to a register range (ie. `holding/100/4`) polls the registers each poll interval,
+/// emitting the subscribed topic with register values as big-endian bytes.
+/// Publishing to a holding register (ie. `holding/100`) writes big-endian register values
+/// starting from the address, so data length must be a multiple of two.
+///
+/// Registers are only polled while the client is polled as a `Stream`.
+pub struct ModbusClient {
+    ctx: Arc<AsyncMutex<ModbusContext>>,
+    subs: Vec<Subscription>,
+    poll_interval: Duration,
+    request_timeout: Duration,
+    on_change: bool,
+    timer: Delay,
+    /// Index of the next subscription to read in the current poll cycle
+    next: usize,
+    reading: Option<BoxFuture<'static, Result<Vec<u16>, Error>>>,
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Cleared on disconnect or when the connection fails
+    connected: bool,
+}
+
+impl ModbusClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<ModbusOptions>>(opts: O) -> Result<ModbusClient, Error> {
+        let o = opts.into();
+
+        let host = o.modbus_url.trim_start_matches("modbus://").trim_end_matches('/');
+        let host = match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:{}", host, DEFAULT_PORT),
+        };
+
+        let addr = match tokio::net::lookup_host(host.as_str()).await?.next() {
+            Some(a) => a,
+            None => return Err(Error::msg(format!("Could not resolve Modbus server {:?}", o.modbus_url))),
+        };
+
+        let connect_timeout = o.modbus_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        let ctx = match timeout(connect_timeout, tcp::connect_slave(addr, Slave(o.modbus_unit))).await {
+            Ok(r) => r?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("Modbus connect to {}", o.modbus_url), timeout: connect_timeout }.into()),
+        };
+
+        debug!("Connected to Modbus server {} (unit {})", addr, o.modbus_unit);
+
+        let mut subs = vec![];
+        for r in &o.modbus_registers {
+            subs.push(Subscription{ topic: r.clone(), registers: Registers::from_str(r)?, last: None });
+        }
+
+        Ok(ModbusClient{
+            ctx: Arc::new(AsyncMutex::new(ctx)),
+            subs,
+            poll_interval: o.modbus_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            request_timeout: o.modbus_request_timeout.unwrap_or(o.defaults.request_timeout),
+            on_change: o.modbus_on_change,
+            timer: delay_until(Instant::now()),
+            next: 0,
+            reading: None,
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Read a register range
+    pub async fn read(&mut self, registers: Registers) -> Result<Vec<u16>, Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        read(self.ctx.clone(), registers, self.request_timeout).await
+    }
+
+    /// Write holding registers, starting at the provided address
+    pub async fn write(&mut self, address: u16, values: &[u16]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let mut ctx = self.ctx.lock().await;
+
+        let r = match values {
+            [v] => timeout(self.request_timeout, ctx.write_single_register(address, *v)).await,
+            _ => timeout(self.request_timeout, ctx.write_multiple_registers(address, values)).await,
+        };
+
+        match r {
+            Ok(r) => Ok(r?),
+            Err(_) => Err(PalError::Timeout{ operation: format!("Modbus write to {}", address), timeout: self.request_timeout }.into()),
+        }
+    }
+}
+
+fn read(ctx: Arc<AsyncMutex<ModbusContext>>, r: Registers, t: Duration) -> BoxFuture<'static, Result<Vec<u16>, Error>> {
+    async move {
+        let mut ctx = ctx.lock().await;
+
+        let v = match r.kind {
+            RegisterKind::Holding => timeout(t, ctx.read_holding_registers(r.address, r.count)).await,
+            RegisterKind::Input => timeout(t, ctx.read_input_registers(r.address, r.count)).await,
+        };
+
+        match v {
+            Ok(v) => Ok(v?),
+            Err(_) => Err(PalError::Timeout{ operation: format!("Modbus read of {}", r), timeout: t }.into()),
+        }
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for ModbusClient {
+    /// Close the connection
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.reading = None;
+
+        self.ctx.lock().await.disconnect().await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for ModbusClient {
+    /// Write big-endian register values to holding registers (ie. `holding/100`)
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let r = Registers::from_str(topic)?;
+
+        if r.kind != RegisterKind::Holding {
+            return Err(Error::msg(format!("Modbus {} registers are read-only", r.kind)))
+        }
+        if data.is_empty() || data.len() % 2 != 0 {
+            return Err(Error::msg(format!("Modbus register data must be a non-zero multiple of two bytes (got {})", data.len())))
+        }
+
+        let values: Vec<u16> = data.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+
+        self.write(r.address, &values).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for ModbusClient {
+    /// Subscribe to a register range (kind/address[/count]), polled each poll interval
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let registers = Registers::from_str(topic)?;
+
+        if !self.subs.iter().any(|s| s.topic == topic) {
+            self.subs.push(Subscription{ topic: topic.to_string(), registers, last: None });
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a register range
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.subs.remove(i);
+
+        // Restart any read for the removed (or shifted) subscription
+        if self.next > i {
+            self.next -= 1;
+        } else if self.next == i {
+            self.reading = None;
+        }
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for ModbusClient, reading subscribed registers in turn each poll interval
+impl Stream for ModbusClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if !this.connected {
+            return Poll::Ready(None)
+        }
+
+        loop {
+            // Wait for the next poll cycle
+            if this.next >= this.subs.len() {
+                if let Poll::Pending = Pin::new(&mut this.timer).poll(cx) {
+                    return Poll::Pending
+                }
+
+                this.timer.reset(Instant::now() + this.poll_interval);
+                this.next = 0;
+
+                if this.subs.is_empty() {
+                    continue
+                }
+            }
+
+            let idx = this.next;
+
+            if this.reading.is_none() {
+                this.reading = Some(read(this.ctx.clone(), this.subs[idx].registers, this.request_timeout));
+            }
+
+            let r = match this.reading.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+
+            this.reading = None;
+            this.next += 1;
+
+            let sub = &mut this.subs[idx];
+
+            let values = match r {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Modbus read of {} failed: {:?}", sub.topic, e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::Subscription{ topic: sub.topic.clone(), error: format!("{}", e) }));
+
+                    // IO errors other than Modbus exceptions indicate the connection has failed
+                    let failed = e.downcast_ref::<std::io::Error>()
+                        .map(|e| e.kind() != std::io::ErrorKind::Other)
+                        .unwrap_or(false);
+                    if failed {
+                        this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                        this.connected = false;
+                        return Poll::Ready(None)
+                    }
+
+                    continue
+                },
+            };
+
+            let data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect();
+
+            if this.on_change && sub.last.as_ref() == Some(&data) {
+                continue
+            }
+            sub.last = Some(data.clone());
+
+            return Poll::Ready(Some((sub.topic.clone(), data)))
+        }
+    }
+}
@@ -1,24 +1,193 @@
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::str::FromStr;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
 
-use log::{debug};
-use futures::stream::{Stream, StreamExt};
+use log::{debug, warn};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, Stream, StreamExt, BoxStream};
+use futures::lock::Mutex as AsyncMutex;
 
 use async_trait::async_trait;
 use anyhow::Error;
 
-use paho_mqtt::{AsyncClient, Message};
+use paho_mqtt::{AsyncClient, Message, PropertyCode};
 
-use super::{ClientBase, ClientPub, ClientSub};
-use crate::TlsOptions;
+use super::{ClientBase, ClientPub, ClientReq, ClientSub, ClientTryPub, TryPublishError};
+use crate::{TlsOptions, TlsMode, TlsVersion, UserOptions, TransportDefaults, PalError};
+use crate::id::{IdGenerator, SharedIdGenerator, UuidGenerator};
+use crate::budget::{MemoryBudget, BudgetPolicy, Usage};
+use crate::backoff::{Backoff, BackoffOptions, Jitter};
+use crate::topics::topic_matches;
 
 
 /// Generic futures-based MQTT client abstraction
 pub struct MqttClient {
-    client: AsyncClient,
+    handle: MqttHandle,
     rx: Box<dyn Stream<Item = Option<Message>> + Unpin + Send>,
+    /// Messages received while waiting on other operations (ie. `get_retained`), emitted before `rx`
+    pending: VecDeque<Message>,
+    /// Options used for (re)connecting
+    opts: MqttOptions,
+    /// Generator for request correlation IDs
+    ids: SharedIdGenerator,
+    /// Delay between automatic reconnect attempts
+    backoff: Backoff,
+    /// In-progress automatic reconnection
+    reconnecting: Option<Reconnect>,
+    /// Set on disconnect to disable automatic reconnection
+    closed: bool,
+}
+
+/// Automatic reconnection state, driven by polling the client stream
+enum Reconnect {
+    /// Waiting for the backoff delay to elapse
+    Waiting(BoxFuture<'static, ()>),
+    /// Waiting for a reconnect attempt to complete
+    Connecting(BoxFuture<'static, Result<(), Error>>),
+}
+
+/// Cloneable handle for publishing and managing subscriptions on a shared MqttClient
+#[derive(Clone)]
+pub struct MqttHandle {
+    client: AsyncClient,
+    /// Active subscriptions (topic to QoS), kept consistent with the broker
+    subs: Arc<Mutex<HashMap<String, i32>>>,
+    /// Subscription groups (group name to topics)
+    groups: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Serialises subscription changes across handles
+    sub_lock: Arc<AsyncMutex<()>>,
+    /// Most recent background error (ie. connection lost)
+    last_error: Arc<Mutex<Option<(Instant, PalError)>>>,
+    /// Count of messages dropped due to a full inbox
+    dropped: Arc<AtomicU64>,
+    /// Inline TLS PEM data written to files for paho, retained for reconnection
+    pem_files: Arc<PemFiles>,
+}
+
+/// Policy for dropping received messages when the inbox is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DropPolicy {
+    /// Drop newly received messages, retaining those already buffered
+    DropNewest,
+    /// Drop the oldest buffered message to make room for new messages
+    DropOldest,
+}
+
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::DropNewest
+    }
+}
+
+impl FromStr for DropPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim_start_matches("drop-") {
+            "newest" => Ok(DropPolicy::DropNewest),
+            "oldest" => Ok(DropPolicy::DropOldest),
+            _ => Err(Error::msg(format!("Unsupported drop policy: {:?} (expected newest or oldest)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for DropPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropPolicy::DropNewest => write!(f, "newest"),
+            DropPolicy::DropOldest => write!(f, "oldest"),
+        }
+    }
+}
+
+/// MQTT protocol version
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MqttVersion {
+    /// MQTT v3.1.1
+    V3_1_1,
+    /// MQTT v5, enabling reason codes, session expiry, subscription identifiers and requests
+    V5,
+}
+
+impl MqttVersion {
+    /// Fetch the paho version code
+    fn code(&self) -> u32 {
+        match self {
+            MqttVersion::V3_1_1 => paho_mqtt::MQTT_VERSION_3_1_1,
+            MqttVersion::V5 => paho_mqtt::MQTT_VERSION_5,
+        }
+    }
+}
+
+impl Default for MqttVersion {
+    fn default() -> Self {
+        MqttVersion::V3_1_1
+    }
+}
+
+impl FromStr for MqttVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim_start_matches('v') {
+            "3" | "3.1.1" => Ok(MqttVersion::V3_1_1),
+            "5" | "5.0" => Ok(MqttVersion::V5),
+            _ => Err(Error::msg(format!("Unsupported MQTT version: {:?} (expected 3.1.1 or 5)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for MqttVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MqttVersion::V3_1_1 => write!(f, "3.1.1"),
+            MqttVersion::V5 => write!(f, "5"),
+        }
+    }
+}
+
+/// Default number of received messages buffered before dropping
+pub const DEFAULT_INBOX_CAPACITY: usize = 10;
+
+/// Maximum interval the receive callback waits for inbox space with `BudgetPolicy::Block`,
+/// after which the message is dropped
+pub const INBOX_BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default minimum interval between automatic reconnect attempts
+pub const DEFAULT_RECONNECT_MIN: Duration = Duration::from_secs(1);
+
+/// Default maximum interval between automatic reconnect attempts
+pub const DEFAULT_RECONNECT_MAX: Duration = Duration::from_secs(120);
+
+/// Per-topic error returned by `MqttHandle::restore_subscriptions`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscribeError {
+    /// The broker rejected the subscription with the provided return / reason code
+    Rejected(u8),
+    /// The subscription request could not be made
+    Failed(String),
+}
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeError::Rejected(rc) => write!(f, "Subscription rejected by broker (0x{:02x})", rc),
+            SubscribeError::Failed(e) => write!(f, "Subscription failed: {}", e),
+        }
+    }
 }
 
+impl std::error::Error for SubscribeError {}
+
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -31,8 +200,94 @@ pub struct MqttOptions {
     /// Client ID for MQTT connection
     pub mqtt_id: Option<String>,
 
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "3.1.1"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// MQTT protocol version (3.1.1 or 5)
+    pub mqtt_version: MqttVersion,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Session expiry interval (MQTT v5 only), the broker retains the session (subscriptions
+    /// and queued messages) for this interval after disconnecting and resumes it on connect.
+    ///
+    /// If not set a clean session is started on each connect.
+    pub mqtt_session_expiry: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// MQTT keepalive interval (defaults to `TransportDefaults::keepalive`)
+    pub mqtt_keepalive: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// MQTT connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub mqtt_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Number of received messages buffered before dropping (defaults to 10)
+    pub mqtt_inbox_capacity: Option<usize>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "newest"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Policy for dropping received messages when the inbox is full (newest or oldest)
+    pub mqtt_drop_policy: DropPolicy,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Last will topic, published by the broker if the client disconnects unexpectedly
+    pub mqtt_will_topic: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Last will payload (required with `mqtt_will_topic`)
+    pub mqtt_will_payload: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Last will QoS (defaults to 0)
+    pub mqtt_will_qos: Option<u8>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Retain the last will message
+    pub mqtt_will_retain: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Automatically reconnect and restore subscriptions when the connection is lost
+    ///
+    /// Reconnection is driven by polling the client stream, publish-only applications
+    /// should also poll the stream or call `connect_and_subscribe` to reconnect.
+    /// Messages published while disconnected (and in-flight QoS 0 messages) may be lost.
+    pub mqtt_reconnect: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Minimum interval between reconnect attempts, growing on each failure (defaults to 1s)
+    pub mqtt_reconnect_min: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Maximum interval between reconnect attempts (defaults to 2m)
+    pub mqtt_reconnect_max: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "decorrelated"))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Jitter applied to reconnect intervals (none, full, equal, decorrelated)
+    pub mqtt_reconnect_jitter: Jitter,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// ALPN protocols to negotiate for TLS connections (ie. x-amzn-mqtt-ca for AWS IoT on port 443)
+    pub mqtt_alpn: Vec<String>,
+
     #[cfg_attr(feature = "structopt", structopt(flatten))]
     pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    /// Generator for request correlation IDs (defaults to `UuidGenerator`)
+    pub id_generator: SharedIdGenerator,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
 }
 
 /// Create MqttOptions from a connection URL
@@ -41,7 +296,25 @@ impl From<&str> for MqttOptions {
         Self {
             mqtt_url: url.to_string(),
             mqtt_id: None,
+            mqtt_version: MqttVersion::default(),
+            mqtt_session_expiry: None,
+            mqtt_keepalive: None,
+            mqtt_connect_timeout: None,
+            mqtt_inbox_capacity: None,
+            mqtt_drop_policy: DropPolicy::default(),
+            mqtt_will_topic: None,
+            mqtt_will_payload: None,
+            mqtt_will_qos: None,
+            mqtt_will_retain: false,
+            mqtt_reconnect: false,
+            mqtt_reconnect_min: None,
+            mqtt_reconnect_max: None,
+            mqtt_reconnect_jitter: Jitter::default(),
+            mqtt_alpn: vec![],
             tls_opts: Default::default(),
+            user_opts: Default::default(),
+            id_generator: Default::default(),
+            defaults: Default::default(),
         }
     }
 }
@@ -52,7 +325,25 @@ impl From<(&str, TlsOptions)> for MqttOptions {
         Self {
             mqtt_url: c.0.to_string(),
             mqtt_id: None,
+            mqtt_version: MqttVersion::default(),
+            mqtt_session_expiry: None,
+            mqtt_keepalive: None,
+            mqtt_connect_timeout: None,
+            mqtt_inbox_capacity: None,
+            mqtt_drop_policy: DropPolicy::default(),
+            mqtt_will_topic: None,
+            mqtt_will_payload: None,
+            mqtt_will_qos: None,
+            mqtt_will_retain: false,
+            mqtt_reconnect: false,
+            mqtt_reconnect_min: None,
+            mqtt_reconnect_max: None,
+            mqtt_reconnect_jitter: Jitter::default(),
+            mqtt_alpn: vec![],
             tls_opts: c.1,
+            user_opts: Default::default(),
+            id_generator: Default::default(),
+            defaults: Default::default(),
         }
     }
 }
@@ -62,7 +353,36 @@ impl From<(String, TlsOptions)> for MqttOptions {
         Self {
             mqtt_url: c.0,
             mqtt_id: None,
+            mqtt_version: MqttVersion::default(),
+            mqtt_session_expiry: None,
+            mqtt_keepalive: None,
+            mqtt_connect_timeout: None,
+            mqtt_inbox_capacity: None,
+            mqtt_drop_policy: DropPolicy::default(),
+            mqtt_will_topic: None,
+            mqtt_will_payload: None,
+            mqtt_will_qos: None,
+            mqtt_will_retain: false,
+            mqtt_reconnect: false,
+            mqtt_reconnect_min: None,
+            mqtt_reconnect_max: None,
+            mqtt_reconnect_jitter: Jitter::default(),
+            mqtt_alpn: vec![],
             tls_opts: c.1,
+            user_opts: Default::default(),
+            id_generator: Default::default(),
+            defaults: Default::default(),
+        }
+    }
+}
+
+impl MqttOptions {
+    /// Build backoff options for automatic reconnection
+    fn reconnect_backoff(&self) -> BackoffOptions {
+        BackoffOptions {
+            backoff_min: self.mqtt_reconnect_min.unwrap_or(DEFAULT_RECONNECT_MIN),
+            backoff_max: self.mqtt_reconnect_max.unwrap_or(DEFAULT_RECONNECT_MAX),
+            backoff_jitter: self.mqtt_reconnect_jitter,
         }
     }
 }
@@ -70,126 +390,1504 @@ impl From<(String, TlsOptions)> for MqttOptions {
 impl MqttClient {
     /// Create a new client using the provided options
     pub async fn new<O: Into<MqttOptions>>(opts: O) -> Result<MqttClient, Error> {
-        let o = opts.into();
+        Self::create(opts.into(), None).await
+    }
+
+    /// Create a new client with the receive inbox bound and tracked by the provided memory budget
+    ///
+    /// Where the budget bounds stream messages this overrides the `mqtt_inbox_capacity`
+    /// and `mqtt_drop_policy` options.
+    pub async fn with_budget<O: Into<MqttOptions>>(opts: O, budget: &MemoryBudget) -> Result<MqttClient, Error> {
+        Self::create(opts.into(), Some(budget)).await
+    }
+
+    async fn create(o: MqttOptions, budget: Option<&MemoryBudget>) -> Result<MqttClient, Error> {
 
         debug!("MQTT client connect opts: {:?}", o);
 
         // Create client with URI and ID
         let mut client_opts = paho_mqtt::CreateOptionsBuilder::new()
-            .server_uri(o.mqtt_url)
+            .server_uri(&o.mqtt_url)
             .persistence(paho_mqtt::PersistenceType::None);
 
-        if let Some(id) = o.mqtt_id {
+        if let Some(id) = &o.mqtt_id {
             client_opts = client_opts.client_id(id);
         }
+
+        client_opts = client_opts.mqtt_version(o.mqtt_version.code());
             
         let mut client = AsyncClient::new(client_opts.finalize())?;
 
-        // Setup TLS
-        let mut tls_options = None;
+        // Setup inbox for received messages, recording lost connections for diagnostics
+        let last_error = Arc::new(Mutex::new(None));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let capacity = o.mqtt_inbox_capacity.unwrap_or(DEFAULT_INBOX_CAPACITY);
+        let rx = Inbox::attach(&mut client, capacity, o.mqtt_drop_policy, o.mqtt_reconnect, budget, last_error.clone(), dropped.clone());
 
-        // Check listed files are accessible
-        o.tls_opts.validate()?;
+        // Restore subscriptions following automatic reconnection (the session is not persisted)
+        let subs = Arc::new(Mutex::new(HashMap::<String, i32>::new()));
+        if o.mqtt_reconnect {
+            let s = subs.clone();
+            client.set_connected_callback(move |c| {
+                let subs: Vec<_> = s.lock().unwrap().iter().map(|(t, q)| (t.clone(), *q)).collect();
+                if subs.is_empty() {
+                    return;
+                }
 
-        // Set TLS CA file if provided
-        if let Some(ca_file) = &o.tls_opts.tls_ca_file {
-            let mut tls_opts = paho_mqtt::SslOptionsBuilder::new();
+                debug!("MQTT reconnected, restoring {} subscriptions", subs.len());
 
-            tls_opts.trust_store(ca_file)?;
-            tls_options = Some(tls_opts);
-        }
-        
-        // Set TLS certificate / key files if provided
-        match (&mut tls_options, &o.tls_opts.tls_cert_file, &o.tls_opts.tls_key_file) {
-            (Some(tls_opts), Some(cert_file), Some(key_file)) => {
-                tls_opts.key_store(cert_file)?;
-                tls_opts.private_key(key_file)?;
-            },
-            (None, Some(cert_file), Some(key_file)) => {
-                let mut tls_opts = paho_mqtt::SslOptionsBuilder::new();
-                tls_opts.key_store(cert_file)?;
-                tls_opts.private_key(key_file)?;
-                tls_options = Some(tls_opts);
-            },
-            (_, Some(_), None) | (_, None, Some(_)) => {
-                return Err(Error::msg("TLS requires both tls-cert and tls-key arguments"))
-            },
-            _ => (),
+                let (topics, qos): (Vec<_>, Vec<_>) = subs.into_iter().unzip();
+                let _ = c.subscribe_many(&topics, &qos);
+            });
         }
 
         // Setup connection options and connect
-        let mut connect_options = paho_mqtt::ConnectOptionsBuilder::new();
-        connect_options.clean_session(true);
-        
-        if let Some(tls_opts) = tls_options {
-            connect_options.ssl_options(tls_opts.finalize());
-        }
+        let (connect_options, pem_files) = connect_options(&o)?;
 
         // Connect!
-        client.connect(connect_options.finalize()).await?;
+        let connect_timeout = o.mqtt_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+        match tokio::time::timeout(connect_timeout, client.connect(connect_options)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(o.tls_opts.with_diagnostics(&o.mqtt_url, e.into())),
+            Err(_) => return Err(PalError::Timeout{ operation: format!("MQTT connect to {}", o.mqtt_url), timeout: connect_timeout }.into()),
+        }
+
+        let handle = MqttHandle {
+            client,
+            subs,
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            sub_lock: Arc::new(AsyncMutex::new(())),
+            last_error,
+            dropped,
+            pem_files: Arc::new(pem_files),
+        };
 
-        // Build incoming stream
-        let rx = Box::new(client.get_stream(10));
+        let backoff = Backoff::new(o.reconnect_backoff());
+        let ids = o.id_generator.clone();
 
-        Ok(MqttClient{client, rx})
+        Ok(MqttClient{handle, rx: Box::new(rx), pending: VecDeque::new(), opts: o, ids, backoff, reconnecting: None, closed: false})
+    }
+
+    /// Update client options, disconnecting and reconnecting with the new options and
+    /// restoring active subscriptions (ie. for credential rotation).
+    ///
+    /// Changes to the URL, client ID, protocol version, inbox, or automatic reconnection options require creating a new client and are rejected.
+    pub async fn update_options<O: Into<MqttOptions>>(&mut self, opts: O) -> Result<(), Error> {
+        let o = opts.into();
+
+        if o.mqtt_url != self.opts.mqtt_url || o.mqtt_id != self.opts.mqtt_id || o.mqtt_version != self.opts.mqtt_version {
+            return Err(Error::msg("Changing the MQTT URL, client ID, or protocol version requires creating a new client"))
+        }
+        if o.mqtt_inbox_capacity != self.opts.mqtt_inbox_capacity || o.mqtt_drop_policy != self.opts.mqtt_drop_policy {
+            return Err(Error::msg("Changing MQTT inbox options requires creating a new client"))
+        }
+        if o.mqtt_reconnect != self.opts.mqtt_reconnect {
+            return Err(Error::msg("Enabling or disabling MQTT automatic reconnection requires creating a new client"))
+        }
+
+        // Validate new options prior to disconnecting
+        let (connect_options, pem_files) = connect_options(&o)?;
+
+        debug!("Reconnecting MQTT client with updated options: {:?}", o);
+
+        let _l = self.handle.sub_lock.lock().await;
+
+        if self.handle.client.is_connected() {
+            self.handle.client.disconnect(None).await?;
+        }
+
+        let connect_timeout = o.mqtt_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+        match tokio::time::timeout(connect_timeout, self.handle.client.connect(connect_options)).await {
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(o.tls_opts.with_diagnostics(&o.mqtt_url, e.into())),
+            Err(_) => return Err(PalError::Timeout{ operation: format!("MQTT connect to {}", o.mqtt_url), timeout: connect_timeout }.into()),
+        }
+        self.backoff = Backoff::new(o.reconnect_backoff());
+        self.reconnecting = None;
+        self.closed = false;
+        self.opts = o;
+        self.handle.pem_files = Arc::new(pem_files);
+
+        // Restore subscriptions (the session is not persisted), where automatic reconnection
+        // is enabled this is handled by the connected callback
+        let subs: Vec<_> = self.handle.subs.lock().unwrap().iter().map(|(t, q)| (t.clone(), *q)).collect();
+        if !self.opts.mqtt_reconnect && !subs.is_empty() {
+            let (topics, qos): (Vec<_>, Vec<_>) = subs.into_iter().unzip();
+            self.handle.client.subscribe_many(&topics, &qos).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drive automatic reconnection, waiting for the backoff delay between attempts
+    fn poll_reconnect(&mut self, cx: &mut Context) {
+        if !self.opts.mqtt_reconnect || self.closed {
+            return
+        }
+
+        loop {
+            match &mut self.reconnecting {
+                None if self.handle.client.is_connected() => return,
+                None => {
+                    let delay = self.backoff.next_delay();
+                    debug!("MQTT connection lost, reconnecting in {:?} (attempt {})", delay, self.backoff.attempts());
+                    self.reconnecting = Some(Reconnect::Waiting(tokio::time::delay_for(delay).boxed()));
+                },
+                Some(Reconnect::Waiting(t)) => match t.poll_unpin(cx) {
+                    Poll::Ready(_) => {
+                        let r = self.handle.client.reconnect();
+                        self.reconnecting = Some(Reconnect::Connecting(async move {
+                            r.await?;
+                            Ok(())
+                        }.boxed()));
+                    },
+                    Poll::Pending => return,
+                },
+                Some(Reconnect::Connecting(f)) => match f.poll_unpin(cx) {
+                    Poll::Ready(Ok(_)) => {
+                        debug!("MQTT reconnected to {}", self.opts.mqtt_url);
+                        self.backoff.reset();
+                        self.reconnecting = None;
+                        return
+                    },
+                    Poll::Ready(Err(e)) => {
+                        warn!("MQTT reconnect to {} failed: {:?}", self.opts.mqtt_url, e);
+                        self.reconnecting = None;
+                    },
+                    Poll::Pending => return,
+                },
+            }
+        }
+    }
+
+    /// Set the ID generator used for request correlation data and response topics,
+    /// replacing `MqttOptions::id_generator`
+    pub fn set_id_generator(&mut self, ids: Arc<dyn IdGenerator>) {
+        self.ids = ids.into();
+    }
+
+    /// Fetch a cloneable handle for publishing / subscribing from other tasks
+    pub fn handle(&self) -> MqttHandle {
+        self.handle.clone()
     }
 
     /// Fetch inner object for raw use
     pub fn inner<'a>(&'a mut self) -> &'a mut AsyncClient {
-        &mut self.client
+        &mut self.handle.client
     }
-}
 
-#[async_trait]
-impl ClientBase for MqttClient {
+    /// Split the client into a cloneable control handle and an owned stream of received messages
+    pub fn into_split(self) -> (MqttHandle, BoxStream<'static, (String, Vec<u8>)>) {
+        let rx = stream::iter(self.pending.into_iter().map(Some))
+            .chain(self.rx)
+            .take_while(|m| future::ready(m.is_some()) )
+            .filter_map(|m| future::ready(m.map(|m| (m.topic().to_string(), m.payload().to_vec())) ));
 
-    #[cfg(disabled)]
-    async fn connected(&mut self) -> Result<bool, Error> {
-        Ok(self.client.is_connected())
+        (self.handle, Box::pin(rx))
     }
 
-    async fn disconnect(&mut self) -> Result<(), Error> {
-        self.client.disconnect(None).await?;
-        Ok(())
+    /// Split the client into a cloneable control handle and an owned stream of received paho messages,
+    /// for access to MQTT v5 properties (ie. `subscription_ids`)
+    pub fn into_message_split(self) -> (MqttHandle, BoxStream<'static, Message>) {
+        let rx = stream::iter(self.pending.into_iter().map(Some))
+            .chain(self.rx)
+            .take_while(|m| future::ready(m.is_some()) )
+            .filter_map(future::ready);
+
+        (self.handle, Box::pin(rx))
+    }
+
+    /// Fetch the current (retained) value of a topic without maintaining a subscription.
+    ///
+    /// This subscribes to the topic, waits up to `timeout` for the first message, then unsubscribes,
+    /// returning `None` if no message was received. Messages received on other topics while waiting
+    /// are buffered and emitted by the stream as usual. If the topic is already subscribed the
+    /// subscription is left in place and the retained message is also emitted by the stream.
+    pub async fn get_retained(&mut self, topic: &str, timeout: Duration) -> Result<Option<Vec<u8>>, Error> {
+        if topic.contains(&['+', '#'][..]) {
+            return Err(Error::msg(format!("Cannot fetch retained value for wildcard topic {}", topic)))
+        }
+
+        self.handle.check_connected()?;
+
+        // Hold the subscription lock so concurrent subscribers are not removed
+        let _l = self.handle.sub_lock.lock().await;
+        let subscribed = self.handle.subs.lock().unwrap().get(topic).cloned();
+
+        // (Re)subscribing causes the broker to send any retained message,
+        // existing subscriptions are re-requested with their current QoS
+        let rsp = self.handle.client.subscribe(topic, subscribed.unwrap_or(0)).await?;
+        if let Some(rc) = rsp.subscribe_response() {
+            if rc >= 0x80 {
+                return Err(SubscribeError::Rejected(rc as u8).into())
+            }
+        }
+
+        let (rx, pending) = (&mut self.rx, &mut self.pending);
+        let wait = async {
+            loop {
+                match rx.next().await {
+                    Some(Some(m)) if m.topic() == topic => return Ok(m),
+                    Some(Some(m)) => pending.push_back(m),
+                    _ => return Err(Error::msg("MQTT client disconnected")),
+                }
+            }
+        };
+
+        let res = match tokio::time::timeout(timeout, wait).await {
+            Ok(Ok(m)) => Some(m),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                debug!("No retained message for {} within {:?}", topic, timeout);
+                None
+            },
+        };
+
+        if subscribed.is_some() {
+            if let Some(m) = &res {
+                self.pending.push_back(m.clone());
+            }
+        } else {
+            self.handle.client.unsubscribe(topic).await?;
+        }
+
+        Ok(res.map(|m| m.payload().to_vec()))
+    }
+
+    /// Ensure the client is connected and subscribed to the provided (topic, QoS) pairs,
+    /// returning once all subscriptions have been granted by the broker.
+    ///
+    /// This reconnects if the connection has been lost and fails if any subscription is
+    /// rejected or the connection and subscriptions are not complete within `timeout`,
+    /// providing a readiness gate for application startup.
+    pub async fn connect_and_subscribe(&mut self, topics: &[(&str, u8)], timeout: Duration) -> Result<(), Error> {
+        self.closed = false;
+        self.reconnecting = None;
+
+        let handle = &self.handle;
+
+        let ready = async {
+            if !handle.client.is_connected() {
+                debug!("Reconnecting MQTT client");
+                handle.client.reconnect().await?;
+            }
+
+            let topics: Vec<_> = topics.iter().map(|(t, qos)| (t.to_string(), *qos)).collect();
+
+            for (t, r) in handle.restore_subscriptions(&topics).await? {
+                if let Err(e) = r {
+                    return Err(Error::msg(format!("Subscription to {} failed: {}", t, e)))
+                }
+            }
+
+            Ok(())
+        };
+
+        match tokio::time::timeout(timeout, ready).await {
+            Ok(r) => r,
+            Err(_) => Err(Error::msg(format!("MQTT connect and subscribe timed out after {:?}", timeout))),
+        }
+    }
+
+    /// Restore a set of (topic, QoS) subscriptions (see `MqttHandle::restore_subscriptions`)
+    pub async fn restore_subscriptions(&mut self, topics: &[(String, u8)]) -> Result<Vec<(String, Result<u8, SubscribeError>)>, Error> {
+        self.handle.restore_subscriptions(topics).await
+    }
+
+    /// Subscribe to a named group of topics (see `MqttHandle::subscribe_group`)
+    pub async fn subscribe_group(&mut self, name: &str, topics: &[&str]) -> Result<(), Error> {
+        self.handle.subscribe_group(name, topics).await
+    }
+
+    /// Unsubscribe from a named group of topics (see `MqttHandle::unsubscribe_group`)
+    pub async fn unsubscribe_group(&mut self, name: &str) -> Result<(), Error> {
+        self.handle.unsubscribe_group(name).await
+    }
+
+    /// Unsubscribe from subscriptions matching a topic filter (see `MqttHandle::unsubscribe_matching`)
+    pub async fn unsubscribe_matching(&mut self, filter: &str) -> Result<Vec<String>, Error> {
+        self.handle.unsubscribe_matching(filter).await
+    }
+
+    /// Subscribe to a topic with the MQTT v5 no-local option set (see `MqttHandle::subscribe_no_local`)
+    pub async fn subscribe_no_local(&mut self, topic: &str) -> Result<(), Error> {
+        self.handle.subscribe_no_local(topic).await
+    }
+
+    /// Subscribe to a topic with the provided QoS (0, 1, or 2)
+    pub async fn subscribe_qos(&mut self, topic: &str, qos: u8) -> Result<(), Error> {
+        self.handle.subscribe_qos(topic, qos).await
+    }
+
+    /// Subscribe to a topic with an MQTT v5 subscription identifier (see `MqttHandle::subscribe_with_id`)
+    pub async fn subscribe_with_id(&mut self, topic: &str, qos: u8, id: u32) -> Result<(), Error> {
+        self.handle.subscribe_with_id(topic, qos, id).await
+    }
+
+    /// Publish data to a topic with the provided QoS (0, 1, or 2)
+    pub async fn publish_qos(&mut self, topic: &str, data: &[u8], qos: u8) -> Result<(), Error> {
+        self.handle.publish_qos(topic, data, qos).await
+    }
+
+    /// Publish a retained message to a topic with the provided QoS (see `MqttHandle::publish_retained`)
+    pub async fn publish_retained(&mut self, topic: &str, data: &[u8], qos: u8) -> Result<(), Error> {
+        self.handle.publish_retained(topic, data, qos).await
+    }
+
+    /// Subscribe to a topic, splitting the client into a control handle and an owned stream
+    /// of messages matching the subscribed topic
+    pub async fn subscribe_and_stream(self, topic: &str) -> Result<(MqttHandle, BoxStream<'static, (String, Vec<u8>)>), Error> {
+        self.handle.subscribe(topic).await?;
+
+        let (handle, rx) = self.into_split();
+
+        let pattern = topic.to_string();
+        let rx = rx.filter(move |(t, _)| future::ready(topic_matches(&pattern, t)) );
+
+        Ok((handle, Box::pin(rx)))
     }
 }
 
-#[async_trait]
-impl ClientSub for MqttClient {
+impl MqttHandle {
     /// Subscribe to a topic
-    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
-        self.client.subscribe(topic, 0).await?;
+    pub async fn subscribe(&self, topic: &str) -> Result<(), Error> {
+        self.subscribe_qos(topic, 0).await
+    }
+
+    /// Subscribe to a topic with the MQTT v5 no-local option set, so messages published
+    /// by this client are not delivered back to it.
+    ///
+    /// The no-local option is ignored for MQTT v3.x connections.
+    pub async fn subscribe_no_local(&self, topic: &str) -> Result<(), Error> {
+        self.subscribe_with(topic, 0, true, None).await
+    }
+
+    /// Subscribe to a topic with the provided QoS (0, 1, or 2)
+    ///
+    /// Re-subscribing to an existing topic with a different QoS updates the subscription.
+    pub async fn subscribe_qos(&self, topic: &str, qos: u8) -> Result<(), Error> {
+        self.subscribe_with(topic, check_qos(qos)?, false, None).await
+    }
+
+    /// Subscribe to a topic with the provided QoS and an MQTT v5 subscription identifier,
+    /// included in messages delivered for this subscription (see `subscription_ids`)
+    ///
+    /// Subscription identifiers are not restored on automatic reconnection.
+    pub async fn subscribe_with_id(&self, topic: &str, qos: u8, id: u32) -> Result<(), Error> {
+        if self.client.mqtt_version() < paho_mqtt::MQTT_VERSION_5 {
+            return Err(Error::msg("MQTT subscription identifiers require an MQTT v5 connection"))
+        }
+        // Identifiers are variable byte integers, 1 to 268,435,455
+        if id == 0 || id > 0x0FFF_FFFF {
+            return Err(Error::msg(format!("Invalid MQTT subscription identifier: {}", id)))
+        }
+
+        self.subscribe_with(topic, check_qos(qos)?, false, Some(id)).await
+    }
+
+    async fn subscribe_with(&self, topic: &str, qos: i32, no_local: bool, id: Option<u32>) -> Result<(), Error> {
+        self.check_connected()?;
+
+        // Hold the subscription lock across the broker request so concurrent
+        // changes are applied in await order (last writer wins)
+        let _l = self.sub_lock.lock().await;
+
+        // Skip duplicate subscriptions, re-subscribing only to update QoS
+        if self.subs.lock().unwrap().get(topic) == Some(&qos) {
+            debug!("Already subscribed to {} (qos: {})", topic, qos);
+            return Ok(())
+        }
+
+        // Subscription options and properties are only supported for MQTT v5
+        let rsp = if (no_local || id.is_some()) && self.client.mqtt_version() >= paho_mqtt::MQTT_VERSION_5 {
+            let opts = paho_mqtt::SubscribeOptions::new(no_local);
+
+            let props = match id {
+                Some(id) => {
+                    let mut p = paho_mqtt::Properties::new();
+                    p.push_int(PropertyCode::SubscriptionIdentifier, id as i32)?;
+                    Some(p)
+                },
+                None => None,
+            };
+
+            self.client.subscribe_with_options(topic, qos, opts, props).await?
+        } else {
+            self.client.subscribe(topic, qos).await?
+        };
+
+        // Granted QoS, or a failure return / reason code (>= 0x80)
+        if let Some(rc) = rsp.subscribe_response() {
+            if rc >= 0x80 {
+                return Err(SubscribeError::Rejected(rc as u8).into())
+            }
+        }
+
+        self.subs.lock().unwrap().insert(topic.to_string(), qos);
+
         Ok(())
     }
 
+    /// Restore a set of (topic, QoS) subscriptions in a single request, for example from
+    /// subscription state persisted externally across restarts.
+    ///
+    /// Returns the granted QoS or error for each topic, in the order provided.
+    /// Errors are only returned where no request could be made (ie. when not connected).
+    pub async fn restore_subscriptions(&self, topics: &[(String, u8)]) -> Result<Vec<(String, Result<u8, SubscribeError>)>, Error> {
+        self.check_connected()?;
+
+        let _l = self.sub_lock.lock().await;
+
+        // Reject invalid QoS locally, requesting the remainder from the broker
+        let mut results: Vec<_> = topics.iter().map(|(t, qos)| match qos {
+            0..=2 => (t.clone(), Ok(*qos)),
+            _ => (t.clone(), Err(SubscribeError::Failed(format!("Invalid QoS: {}", qos)))),
+        }).collect();
+
+        let valid: Vec<_> = results.iter().enumerate()
+            .filter(|(_, (_, r))| r.is_ok())
+            .map(|(i, _)| i).collect();
+        if valid.is_empty() {
+            return Ok(results)
+        }
+
+        let req_topics: Vec<_> = valid.iter().map(|i| topics[*i].0.clone()).collect();
+        let req_qos: Vec<_> = valid.iter().map(|i| topics[*i].1 as i32).collect();
+
+        debug!("Restoring subscriptions: {:?}", req_topics);
+
+        let granted = match self.client.subscribe_many(&req_topics, &req_qos).await {
+            Ok(r) => r.subscribe_many_response().unwrap_or(req_qos),
+            Err(e) => {
+                let e = SubscribeError::Failed(e.to_string());
+                for i in valid {
+                    results[i].1 = Err(e.clone());
+                }
+                return Ok(results)
+            },
+        };
+
+        // Apply per-topic results, codes >= 0x80 indicate failure
+        let mut subs = self.subs.lock().unwrap();
+        for (i, rc) in valid.iter().zip(granted.iter()) {
+            let (topic, res) = &mut results[*i];
+
+            match *rc {
+                0..=2 => {
+                    subs.insert(topic.clone(), *rc);
+                    *res = Ok(*rc as u8);
+                },
+                rc => {
+                    *res = Err(SubscribeError::Rejected(rc as u8));
+                },
+            }
+        }
+
+        Ok(results)
+    }
+
     /// Unsubscribe from a topic
-    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), Error> {
+        self.check_connected()?;
+
+        let _l = self.sub_lock.lock().await;
+
         self.client.unsubscribe(topic).await?;
+        self.subs.lock().unwrap().remove(topic);
+
         Ok(())
     }
-}
 
-/// Impl stream for ClientSub
-impl Stream for MqttClient {
-    type Item = (String, Vec<u8>);
+    /// Unsubscribe from all active subscriptions matching the provided (wildcard) topic filter,
+    /// returning the removed subscriptions
+    pub async fn unsubscribe_matching(&self, filter: &str) -> Result<Vec<String>, Error> {
+        self.check_connected()?;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        let m = match self.rx.poll_next_unpin(cx) {
-            Poll::Ready(Some(Some(m))) => m,
-            Poll::Ready(_) => return Poll::Ready(None),
-            Poll::Pending => return Poll::Pending,
-        };
+        let _l = self.sub_lock.lock().await;
 
-        Poll::Ready(Some( (m.topic().to_string(), m.payload().to_vec()) ))
+        let topics: Vec<_> = self.subs.lock().unwrap().keys()
+            .filter(|t| topic_matches(filter, t))
+            .cloned().collect();
+        if topics.is_empty() {
+            return Ok(topics)
+        }
+
+        self.client.unsubscribe_many(&topics).await?;
+
+        let mut subs = self.subs.lock().unwrap();
+        for t in &topics {
+            subs.remove(t);
+        }
+
+        Ok(topics)
     }
-}
 
-#[async_trait]
-impl ClientPub for MqttClient {
-    /// Publish data to a topic
-    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
-        let m = paho_mqtt::Message::new(topic, data, 0);
-        self.client.publish(m).await?;
+    /// Subscribe to a named group of topics in a single request, allowing these to be
+    /// removed together with `unsubscribe_group`
+    pub async fn subscribe_group(&self, name: &str, topics: &[&str]) -> Result<(), Error> {
+        self.check_connected()?;
+
+        let _l = self.sub_lock.lock().await;
+
+        if self.groups.lock().unwrap().contains_key(name) {
+            return Err(Error::msg(format!("Subscription group {} already exists", name)))
+        }
+
+        let topics: Vec<_> = topics.iter().map(|t| t.to_string()).collect();
+        let qos = vec![0; topics.len()];
+
+        self.client.subscribe_many(&topics, &qos).await?;
+
+        let mut subs = self.subs.lock().unwrap();
+        for t in &topics {
+            subs.entry(t.clone()).or_insert(0);
+        }
+        self.groups.lock().unwrap().insert(name.to_string(), topics);
+
         Ok(())
     }
+
+    /// Unsubscribe from a named group of topics in a single request
+    ///
+    /// Topics also in other groups remain subscribed.
+    pub async fn unsubscribe_group(&self, name: &str) -> Result<(), Error> {
+        self.check_connected()?;
+
+        let _l = self.sub_lock.lock().await;
+
+        let topics = match self.groups.lock().unwrap().remove(name) {
+            Some(t) => t,
+            None => return Err(Error::msg(format!("Unknown subscription group {}", name))),
+        };
+
+        // Retain topics shared with other groups
+        let topics: Vec<_> = {
+            let groups = self.groups.lock().unwrap();
+            topics.into_iter().filter(|t| !groups.values().any(|g| g.contains(t)) ).collect()
+        };
+        if topics.is_empty() {
+            return Ok(())
+        }
+
+        self.client.unsubscribe_many(&topics).await?;
+
+        let mut subs = self.subs.lock().unwrap();
+        for t in &topics {
+            subs.remove(t);
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the topics for a named subscription group
+    pub fn group(&self, name: &str) -> Option<Vec<String>> {
+        self.groups.lock().unwrap().get(name).cloned()
+    }
+
+    /// Check whether the client is currently connected to the broker
+    pub fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    /// Return `PalError::NotConnected` if the client is not connected
+    fn check_connected(&self) -> Result<(), Error> {
+        match self.client.is_connected() {
+            true => Ok(()),
+            false => Err(PalError::NotConnected.into()),
+        }
+    }
+
+    /// Fetch the most recent background error (see `ClientBase::last_error`)
+    pub fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Fetch the number of received messages dropped due to a full inbox
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Fetch the currently active subscriptions
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.subs.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Publish data to a topic (at QoS 0)
+    pub async fn publish(&self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.publish_qos(topic, data, 0).await
+    }
+
+    /// Publish data to a topic with the provided QoS (0, 1, or 2)
+    ///
+    /// For QoS 1 and 2 this resolves once the delivery handshake with the broker completes.
+    pub async fn publish_qos(&self, topic: &str, data: &[u8], qos: u8) -> Result<(), Error> {
+        self.publish_with(topic, data, qos, false).await
+    }
+
+    /// Publish a retained message to a topic with the provided QoS (0, 1, or 2)
+    ///
+    /// The broker stores the message and delivers it to new subscribers on subscribe,
+    /// publishing an empty retained payload clears the retained message for a topic.
+    pub async fn publish_retained(&self, topic: &str, data: &[u8], qos: u8) -> Result<(), Error> {
+        self.publish_with(topic, data, qos, true).await
+    }
+
+    async fn publish_with(&self, topic: &str, data: &[u8], qos: u8, retain: bool) -> Result<(), Error> {
+        let qos = check_qos(qos)?;
+        self.check_connected()?;
+
+        let m = match retain {
+            true => paho_mqtt::Message::new_retained(topic, data, qos),
+            false => paho_mqtt::Message::new(topic, data, qos),
+        };
+        self.client.publish(m).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientBase for MqttClient {
+
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.closed = true;
+        self.reconnecting = None;
+        self.handle.client.disconnect(None).await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.handle.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.handle.last_error()
+    }
+}
+
+#[async_trait]
+impl ClientSub for MqttClient {
+    /// Subscribe to a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.handle.subscribe(topic).await
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.handle.unsubscribe(topic).await
+    }
+}
+
+/// Impl stream for ClientSub
+impl Stream for MqttClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(m) = self.pending.pop_front() {
+            return Poll::Ready(Some( (m.topic().to_string(), m.payload().to_vec()) ))
+        }
+
+        self.poll_reconnect(cx);
+
+        let m = match self.rx.poll_next_unpin(cx) {
+            Poll::Ready(Some(Some(m))) => m,
+            Poll::Ready(_) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        Poll::Ready(Some( (m.topic().to_string(), m.payload().to_vec()) ))
+    }
+}
+
+#[async_trait]
+impl ClientPub for MqttClient {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.handle.publish(topic, data).await
+    }
+}
+
+#[async_trait]
+impl ClientReq for MqttClient {
+    /// Issue an MQTT v5 request, publishing to the request topic with a generated response topic
+    /// and correlation data then awaiting the correlated response.
+    ///
+    /// The response topic is subscribed for the duration of the request. Messages received
+    /// on other topics while waiting are buffered and emitted by the stream as usual.
+    async fn request(&mut self, topic: &str, data: &[u8], timeout: Duration) -> Result<Vec<u8>, Error> {
+        if self.handle.client.mqtt_version() < paho_mqtt::MQTT_VERSION_5 {
+            return Err(Error::msg("MQTT requests require an MQTT v5 connection"))
+        }
+
+        // Generate correlation data and a unique response topic
+        let id = self.ids.generate();
+        let corr = id.as_bytes().to_vec();
+        let resp_topic = format!("iot-pal/response/{}", id);
+
+        self.handle.subscribe_qos(&resp_topic, 1).await?;
+
+        let res = self.await_response(topic, data, &resp_topic, &corr, timeout).await;
+
+        // Always remove the response subscription
+        if let Err(e) = self.handle.unsubscribe(&resp_topic).await {
+            debug!("Failed to unsubscribe from response topic {}: {:?}", resp_topic, e);
+        }
+
+        res
+    }
+}
+
+impl MqttClient {
+    /// Publish a request and await the correlated response
+    async fn await_response(&mut self, topic: &str, data: &[u8], resp_topic: &str, corr: &[u8], timeout: Duration) -> Result<Vec<u8>, Error> {
+        let mut props = paho_mqtt::Properties::new();
+        props.push_string(PropertyCode::ResponseTopic, resp_topic)?;
+        props.push_binary(PropertyCode::CorrelationData, corr)?;
+
+        let m = paho_mqtt::MessageBuilder::new()
+            .topic(topic)
+            .payload(data)
+            .qos(1)
+            .properties(props)
+            .finalize();
+
+        self.handle.client.publish(m).await?;
+
+        let (rx, pending) = (&mut self.rx, &mut self.pending);
+        let wait = async {
+            loop {
+                match rx.next().await {
+                    Some(Some(m)) if m.topic() == resp_topic => {
+                        match m.properties().get_binary(PropertyCode::CorrelationData) {
+                            Some(c) if c == corr => return Ok(m.payload().to_vec()),
+                            _ => debug!("Ignoring uncorrelated response on {}", resp_topic),
+                        }
+                    },
+                    Some(Some(m)) => pending.push_back(m),
+                    _ => return Err(Error::msg("MQTT client disconnected")),
+                }
+            }
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(r) => r,
+            Err(_) => Err(Error::msg(format!("MQTT request to {} timed out after {:?}", topic, timeout))),
+        }
+    }
+}
+
+impl ClientTryPub for MqttClient {
+    /// Attempt to publish data to a topic without waiting
+    fn try_publish(&mut self, topic: &str, data: &[u8]) -> Result<(), TryPublishError> {
+        self.handle.try_publish(topic, data)
+    }
+}
+
+impl ClientTryPub for MqttHandle {
+    /// Attempt to publish data to a topic without waiting
+    fn try_publish(&mut self, topic: &str, data: &[u8]) -> Result<(), TryPublishError> {
+        self.check_connected()?;
+
+        let m = paho_mqtt::Message::new(topic, data, 0);
+
+        // Enqueue without awaiting delivery
+        match self.client.try_publish(m) {
+            Ok(_) => Ok(()),
+            Err(paho_mqtt::Error::Paho(rc)) | Err(paho_mqtt::Error::PahoDescr(rc, _)) if would_block(rc) => {
+                Err(TryPublishError::WouldBlock)
+            },
+            Err(e) => Err(TryPublishError::Other(e.into())),
+        }
+    }
+}
+
+/// Build connection (and TLS) options from the provided client options
+fn connect_options(o: &MqttOptions) -> Result<(paho_mqtt::ConnectOptions, PemFiles), Error> {
+    // Setup TLS
+    let mut tls_options = None;
+
+    // Check listed files are accessible and options are coherent
+    o.tls_opts.validate()?;
+    let tls_mode = o.tls_opts.mode()?;
+
+    // paho only loads TLS files, so inline PEM data is written out to temporary files
+    let mut pem_files = PemFiles(vec![]);
+    let ca_file = pem_files.resolve(&o.tls_opts.tls_ca_pem, &o.tls_opts.tls_ca_file, "ca")?;
+    let cert_file = pem_files.resolve(&o.tls_opts.tls_cert_pem, &o.tls_opts.tls_cert_file, "cert")?;
+    let key_file = pem_files.resolve(&o.tls_opts.tls_key_pem, &o.tls_opts.tls_key_file, "key")?;
+
+    // Set TLS CA file if provided
+    if let Some(ca_file) = &ca_file {
+        let mut tls_opts = paho_mqtt::SslOptionsBuilder::new();
+
+        tls_opts.trust_store(ca_file)?;
+        tls_options = Some(tls_opts);
+    }
+    
+    // Set TLS certificate / key files if provided
+    if let (Some(cert_file), Some(key_file)) = (&cert_file, &key_file) {
+        let tls_opts = tls_options.get_or_insert_with(paho_mqtt::SslOptionsBuilder::new);
+        tls_opts.key_store(cert_file)?;
+        tls_opts.private_key(key_file)?;
+
+        if let Some(p) = &o.tls_opts.tls_key_password {
+            tls_opts.private_key_password(p);
+        }
+    }
+
+    // Restrict TLS version
+    match o.tls_opts.tls_min_version {
+        Some(TlsVersion::Tls12) => {
+            tls_options.get_or_insert_with(paho_mqtt::SslOptionsBuilder::new)
+                .ssl_version(paho_mqtt::SslVersion::Tls_1_2);
+        },
+        Some(TlsVersion::Tls13) => {
+            return Err(Error::msg("TLS v1.3 minimum version is not supported for MQTT"))
+        },
+        None => (),
+    }
+
+    // Negotiate application protocols
+    if !o.mqtt_alpn.is_empty() {
+        let protos: Vec<_> = o.mqtt_alpn.iter().map(|p| p.as_str()).collect();
+        tls_options.get_or_insert_with(paho_mqtt::SslOptionsBuilder::new)
+            .alpn_protos(&protos);
+    }
+
+    // Disable server certificate verification
+    if tls_mode == TlsMode::Insecure {
+        tls_options.get_or_insert_with(paho_mqtt::SslOptionsBuilder::new)
+            .enable_server_cert_auth(false);
+    }
+
+    // Setup connection options
+    let mut connect_options = paho_mqtt::ConnectOptionsBuilder::new();
+    connect_options.mqtt_version(o.mqtt_version.code());
+
+    match (o.mqtt_version, o.mqtt_session_expiry) {
+        (MqttVersion::V5, expiry) => {
+            // Resume existing sessions only where these are retained
+            connect_options.clean_start(expiry.is_none());
+
+            if let Some(e) = expiry {
+                let mut props = paho_mqtt::Properties::new();
+                props.push_int(PropertyCode::SessionExpiryInterval, e.as_secs() as i32)?;
+                connect_options.properties(props);
+            }
+        },
+        (MqttVersion::V3_1_1, None) => {
+            connect_options.clean_session(true);
+        },
+        (MqttVersion::V3_1_1, Some(_)) => {
+            return Err(Error::msg("MQTT session expiry requires MQTT v5"))
+        },
+    }
+    connect_options.keep_alive_interval(o.mqtt_keepalive.unwrap_or(o.defaults.keepalive));
+
+    // Setup credentials
+    match (&o.user_opts.username, &o.user_opts.password) {
+        (Some(u), p) => {
+            connect_options.user_name(u.as_str());
+            if let Some(p) = p {
+                connect_options.password(p.as_str());
+            }
+        },
+        (None, Some(_)) => return Err(Error::msg("MQTT password requires a username")),
+        (None, None) => (),
+    }
+    connect_options.connect_timeout(o.mqtt_connect_timeout.unwrap_or(o.defaults.connect_timeout));
+
+    // Setup last will
+    match (&o.mqtt_will_topic, &o.mqtt_will_payload) {
+        (Some(topic), Some(payload)) => {
+            let qos = check_qos(o.mqtt_will_qos.unwrap_or(0))?;
+            let will = match o.mqtt_will_retain {
+                true => paho_mqtt::Message::new_retained(topic.as_str(), payload.as_bytes(), qos),
+                false => paho_mqtt::Message::new(topic.as_str(), payload.as_bytes(), qos),
+            };
+            connect_options.will_message(will);
+        },
+        (Some(_), None) => return Err(Error::msg("MQTT last will topic requires a will payload")),
+        (None, Some(_)) => return Err(Error::msg("MQTT last will payload requires a will topic")),
+        (None, None) => (),
+    }
+    
+    if let Some(tls_opts) = tls_options {
+        connect_options.ssl_options(tls_opts.finalize());
+    }
+
+    Ok((connect_options.finalize(), pem_files))
+}
+
+/// Temporary files holding inline TLS PEM data, removed on drop
+struct PemFiles(Vec<PathBuf>);
+
+impl PemFiles {
+    /// Resolve the file for a PEM option, writing inline data to a temporary file
+    /// (readable only by the current user) where provided
+    fn resolve(&mut self, inline: &Option<String>, file: &Option<String>, name: &str) -> Result<Option<PathBuf>, Error> {
+        let data = match (inline, file) {
+            (Some(d), _) => d,
+            (None, Some(f)) => return Ok(Some(PathBuf::from(f))),
+            (None, None) => return Ok(None),
+        };
+
+        let path = std::env::temp_dir().join(format!("iot-pal-{}-{}.pem", name, UuidGenerator.generate()));
+
+        let mut opts = fs::OpenOptions::new();
+        opts.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+
+        opts.open(&path)
+            .and_then(|mut f| f.write_all(data.as_bytes()))
+            .map_err(|e| Error::msg(format!("Failed to write TLS {} to {:?}: {}", name, path, e)))?;
+
+        self.0.push(path.clone());
+
+        Ok(Some(path))
+    }
+}
+
+impl Drop for PemFiles {
+    fn drop(&mut self) {
+        for p in &self.0 {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
+/// Check whether a paho return code indicates publish buffers are full
+fn would_block(rc: i32) -> bool {
+    // MQTTASYNC_MAX_MESSAGES_INFLIGHT, MQTTASYNC_NO_MORE_MSGIDS, MQTTASYNC_MAX_BUFFERED_MESSAGES
+    rc == -4 || rc == -10 || rc == -12
+}
+
+#[async_trait]
+impl ClientPub for MqttHandle {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        MqttHandle::publish(self, topic, data).await
+    }
+}
+
+/// Check a QoS level is valid (0, 1, or 2)
+fn check_qos(qos: u8) -> Result<i32, Error> {
+    match qos {
+        0..=2 => Ok(qos as i32),
+        _ => Err(Error::msg(format!("Invalid MQTT QoS: {} (expected 0, 1, or 2)", qos))),
+    }
+}
+
+/// Fetch the MQTT v5 subscription identifiers for a received message,
+/// a message matching multiple subscriptions may include more than one
+pub fn subscription_ids(m: &Message) -> Vec<u32> {
+    let props = m.properties();
+
+    (0..).map(|i| props.get_int_at(PropertyCode::SubscriptionIdentifier, i))
+        .take_while(|id| id.is_some())
+        .filter_map(|id| id.map(|v| v as u32))
+        .collect()
+}
+
+/// Bounded inbox for received messages, applying the configured drop policy when full
+///
+/// This replaces paho's `get_stream` which silently drops messages once full.
+/// Lost connections are signalled with `None` as with `get_stream`, unless automatic
+/// reconnection is enabled.
+struct Inbox {
+    queue: VecDeque<Option<Message>>,
+    capacity: usize,
+    policy: DropPolicy,
+    /// Memory budget policy (overriding `policy`) and usage tracking
+    budget: Option<(Option<BudgetPolicy>, Arc<Usage>)>,
+    waker: Option<Waker>,
+    /// Set when the stream is dropped, releasing blocked callbacks
+    closed: bool,
+}
+
+/// Inbox shared between paho callbacks and the stream, signalling consumed messages for blocking budgets
+struct Shared {
+    inbox: Mutex<Inbox>,
+    space: Condvar,
+}
+
+/// Stream of messages from an Inbox
+struct InboxStream(Arc<Shared>);
+
+impl Shared {
+    /// Wait up to `timeout` for inbox space, returning early if the stream is dropped
+    fn wait_space<'a>(&'a self, mut i: MutexGuard<'a, Inbox>, timeout: Duration) -> MutexGuard<'a, Inbox> {
+        let deadline = Instant::now() + timeout;
+
+        while i.is_full() && !i.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("MQTT inbox full for {:?}, dropping message", timeout);
+                break;
+            }
+
+            i = self.space.wait_timeout(i, deadline - now).unwrap().0;
+        }
+
+        i
+    }
+}
+
+impl Inbox {
+    /// Attach an inbox to the provided client, returning the stream of received messages
+    fn attach(client: &mut AsyncClient, capacity: usize, policy: DropPolicy, reconnect: bool, budget: Option<&MemoryBudget>, last_error: Arc<Mutex<Option<(Instant, PalError)>>>, dropped: Arc<AtomicU64>) -> InboxStream {
+        let capacity = budget.and_then(|b| b.stream_limit()).unwrap_or(capacity);
+        let budget = budget.map(|b| (b.stream_limit().map(|_| b.policy()), b.tracker()));
+
+        let shared = Arc::new(Shared{
+            inbox: Mutex::new(Inbox{
+                queue: VecDeque::new(),
+                capacity: capacity.max(1),
+                policy,
+                budget,
+                waker: None,
+                closed: false,
+            }),
+            space: Condvar::new(),
+        });
+
+        let s = shared.clone();
+        client.set_message_callback(move |_c, m| {
+            let m = match m {
+                Some(m) => m,
+                None => return,
+            };
+
+            let mut i = s.inbox.lock().unwrap();
+
+            // Block the callback (and thus the connection) until messages are consumed,
+            // falling back to dropping if the stream is not polled or is dropped
+            if i.budget_policy() == Some(BudgetPolicy::Block) {
+                i = s.wait_space(i, INBOX_BLOCK_TIMEOUT);
+            }
+
+            if i.push(m) {
+                let n = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("MQTT inbox full, dropped {} messages", n);
+
+                if i.budget_policy() == Some(BudgetPolicy::Error) {
+                    let e = PalError::BudgetExceeded{ buffer: "mqtt_inbox".to_string(), limit: i.capacity };
+                    *last_error.lock().unwrap() = Some((Instant::now(), e));
+                }
+
+                #[cfg(feature = "metrics")]
+                metrics::counter!("iot_pal_client_dropped", 1, "client" => "mqtt");
+            }
+        });
+
+        let s = shared.clone();
+        client.set_connection_lost_callback(move |_c| {
+            debug!("MQTT connection lost");
+            *last_error.lock().unwrap() = Some((Instant::now(), PalError::ConnectionLost));
+
+            // Keep the stream open while automatically reconnecting, waking the
+            // stream to start reconnection
+            let mut i = s.inbox.lock().unwrap();
+            if !reconnect {
+                i.queue.push_back(None);
+            }
+            i.wake();
+        });
+
+        InboxStream(shared)
+    }
+
+    fn budget_policy(&self) -> Option<BudgetPolicy> {
+        self.budget.as_ref().and_then(|b| b.0)
+    }
+
+    fn is_full(&self) -> bool {
+        self.queue.iter().filter(|m| m.is_some()).count() >= self.capacity
+    }
+
+    /// Push a message to the inbox, returning true if a message was dropped
+    fn push(&mut self, m: Message) -> bool {
+        let full = self.is_full();
+
+        let policy = match self.budget_policy() {
+            Some(_) => DropPolicy::DropNewest,
+            None => self.policy,
+        };
+
+        let dropped = match (full, policy) {
+            (false, _) => {
+                self.queue.push_back(Some(m));
+                if let Some((_, u)) = &self.budget {
+                    u.stream_msgs.fetch_add(1, Ordering::Relaxed);
+                }
+                false
+            },
+            (true, DropPolicy::DropNewest) => true,
+            (true, DropPolicy::DropOldest) => {
+                if let Some(i) = self.queue.iter().position(|m| m.is_some()) {
+                    self.queue.remove(i);
+                }
+                self.queue.push_back(Some(m));
+                true
+            },
+        };
+
+        if let (true, Some((_, u))) = (dropped, &self.budget) {
+            u.record_drop();
+        }
+
+        self.wake();
+
+        dropped
+    }
+
+    fn wake(&mut self) {
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        if let Some((_, u)) = &self.budget {
+            let n = self.queue.iter().filter(|m| m.is_some()).count();
+            u.stream_msgs.fetch_sub(n, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Release callbacks blocked waiting for inbox space
+impl Drop for InboxStream {
+    fn drop(&mut self) {
+        self.0.inbox.lock().unwrap().closed = true;
+        self.0.space.notify_all();
+    }
+}
+
+impl Stream for InboxStream {
+    type Item = Option<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut i = self.0.inbox.lock().unwrap();
+
+        match i.queue.pop_front() {
+            Some(m) => {
+                if let (Some(_), Some((_, u))) = (&m, &i.budget) {
+                    u.stream_msgs.fetch_sub(1, Ordering::Relaxed);
+                }
+                self.0.space.notify_one();
+
+                Poll::Ready(Some(m))
+            },
+            None => {
+                i.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Broker URL for integration tests, these are ignored by default as they require a running broker
+    /// (ie. `IOT_PAL_MQTT_URL=tcp://localhost:1883 cargo test -- --ignored`)
+    fn broker() -> String {
+        std::env::var("IOT_PAL_MQTT_URL").unwrap_or_else(|_| "tcp://localhost:1883".to_string())
+    }
+
+    /// Wait for a message on the provided topic, returning None on timeout
+    async fn next_on(client: &mut MqttClient, topic: &str, timeout: Duration) -> Option<Vec<u8>> {
+        let wait = async {
+            while let Some((t, d)) = client.next().await {
+                if t == topic {
+                    return Some(d)
+                }
+            }
+            None
+        };
+
+        tokio::time::timeout(timeout, wait).await.ok().flatten()
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    #[ignore]
+    async fn concurrent_subscribe_unsubscribe() {
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        let topic = "iot-pal/test/concurrent";
+
+        for i in 0..50 {
+            // Race subscribe and unsubscribe on the same topic from separate handles
+            let mut tasks = vec![];
+            for j in 0..8 {
+                let h = client.handle();
+                tasks.push(tokio::spawn(async move {
+                    match (i + j) % 2 {
+                        0 => h.subscribe(topic).await,
+                        _ => h.unsubscribe(topic).await,
+                    }
+                }));
+            }
+            for t in tasks {
+                t.await.unwrap().unwrap();
+            }
+
+            // Tracked subscriptions must be consistent with the broker
+            let subscribed = client.handle().subscriptions().iter().any(|t| t == topic);
+
+            let data = format!("{}", i);
+            client.handle().publish_qos(topic, data.as_bytes(), 1).await.unwrap();
+
+            let received = next_on(&mut client, topic, Duration::from_millis(500)).await;
+            assert_eq!(received.is_some(), subscribed, "iteration {}: tracked state diverged from broker", i);
+        }
+
+        // Subsequent changes apply deterministically
+        client.handle().subscribe(topic).await.unwrap();
+        assert_eq!(client.handle().subscriptions(), vec![topic.to_string()]);
+
+        client.handle().unsubscribe(topic).await.unwrap();
+        assert!(client.handle().subscriptions().is_empty());
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn duplicate_subscribe() {
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        let topic = "iot-pal/test/duplicate";
+
+        client.subscribe(topic).await.unwrap();
+        client.subscribe(topic).await.unwrap();
+        assert_eq!(client.handle().subscriptions(), vec![topic.to_string()]);
+
+        client.handle().publish_qos(topic, b"1", 1).await.unwrap();
+
+        // Each message is received once
+        assert_eq!(next_on(&mut client, topic, Duration::from_secs(1)).await, Some(b"1".to_vec()));
+        assert_eq!(next_on(&mut client, topic, Duration::from_millis(500)).await, None);
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn get_retained_keeps_qos() {
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        let topic = "iot-pal/test/get_retained_qos";
+
+        client.publish_retained(topic, b"retained", 1).await.unwrap();
+        client.handle().subscribe_qos(topic, 1).await.unwrap();
+
+        let v = client.get_retained(topic, Duration::from_secs(2)).await.unwrap();
+        assert_eq!(v, Some(b"retained".to_vec()));
+
+        // The existing subscription is retained at its original QoS
+        assert_eq!(client.handle.subs.lock().unwrap().get(topic), Some(&1));
+
+        client.publish_retained(topic, b"", 1).await.unwrap();
+        client.disconnect().await.unwrap();
+    }
+
+    fn full_inbox() -> Arc<Shared> {
+        let shared = Arc::new(Shared{
+            inbox: Mutex::new(Inbox{
+                queue: VecDeque::new(),
+                capacity: 1,
+                policy: DropPolicy::DropNewest,
+                budget: None,
+                waker: None,
+                closed: false,
+            }),
+            space: Condvar::new(),
+        });
+
+        shared.inbox.lock().unwrap().push(Message::new("a", "1", 0));
+        shared
+    }
+
+    #[test]
+    fn inbox_block_timeout() {
+        let shared = full_inbox();
+
+        // Gives up waiting where the stream is not polled
+        let start = Instant::now();
+        let i = shared.wait_space(shared.inbox.lock().unwrap(), Duration::from_millis(100));
+        assert!(i.is_full());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn inbox_block_released() {
+        let shared = full_inbox();
+        let stream = InboxStream(shared.clone());
+
+        // Consuming a message releases the waiting callback
+        let s = shared.clone();
+        let t = std::thread::spawn(move || {
+            let i = s.wait_space(s.inbox.lock().unwrap(), Duration::from_secs(10));
+            i.is_full()
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+        let mut stream = stream;
+        assert!(futures::executor::block_on(stream.next()).is_some());
+        assert!(!t.join().unwrap());
+
+        // Dropping the stream releases waiting callbacks
+        shared.inbox.lock().unwrap().push(Message::new("a", "2", 0));
+
+        let s = shared.clone();
+        let start = Instant::now();
+        let t = std::thread::spawn(move || {
+            let _i = s.wait_space(s.inbox.lock().unwrap(), Duration::from_secs(10));
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+        drop(stream);
+        t.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn new_round_trip() {
+        // Constructed without an existing instance
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        let topic = "iot-pal/test/new";
+
+        client.subscribe(topic).await.unwrap();
+        client.publish(topic, b"hello").await.unwrap();
+        assert_eq!(next_on(&mut client, topic, Duration::from_secs(2)).await, Some(b"hello".to_vec()));
+
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn will_on_connection_lost() {
+        let topic = "iot-pal/test/will";
+
+        let mut observer = MqttClient::new(broker().as_str()).await.unwrap();
+        observer.subscribe(topic).await.unwrap();
+
+        let mut opts = MqttOptions::from(broker().as_str());
+        opts.mqtt_will_topic = Some(topic.to_string());
+        opts.mqtt_will_payload = Some("gone".to_string());
+        opts.mqtt_will_qos = Some(1);
+        let client = MqttClient::new(opts).await.unwrap();
+
+        // Dropping the client closes the connection without sending DISCONNECT
+        drop(client);
+
+        assert_eq!(next_on(&mut observer, topic, Duration::from_secs(5)).await, Some(b"gone".to_vec()));
+
+        observer.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn is_connected() {
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        assert!(client.is_connected());
+        assert!(client.handle().is_connected());
+
+        client.disconnect().await.unwrap();
+        assert!(!client.is_connected());
+        assert!(!client.handle().is_connected());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn reconnect_restores_subscriptions() {
+        let topic = "iot-pal/test/reconnect";
+
+        let mut opts = MqttOptions::from(broker().as_str());
+        opts.mqtt_id = Some("iot-pal-test-reconnect".to_string());
+        opts.mqtt_reconnect = true;
+        opts.mqtt_reconnect_min = Some(Duration::from_millis(100));
+        opts.mqtt_reconnect_max = Some(Duration::from_millis(500));
+        let mut client = MqttClient::new(opts.clone()).await.unwrap();
+        client.subscribe(topic).await.unwrap();
+
+        // Drop the connection by taking over the client ID, then release this
+        let mut takeover = opts.clone();
+        takeover.mqtt_reconnect = false;
+        let mut takeover = MqttClient::new(takeover).await.unwrap();
+        takeover.disconnect().await.unwrap();
+
+        // Polling the stream reconnects and restores the subscription
+        let mut publisher = MqttClient::new(broker().as_str()).await.unwrap();
+        let mut received = None;
+        for _ in 0..50 {
+            publisher.publish_qos(topic, b"resumed", 1).await.unwrap();
+
+            received = next_on(&mut client, topic, Duration::from_millis(200)).await;
+            if received.is_some() {
+                break;
+            }
+        }
+
+        assert_eq!(received, Some(b"resumed".to_vec()));
+        assert!(client.is_connected());
+
+        publisher.disconnect().await.unwrap();
+        client.disconnect().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn retained_fresh_subscriber() {
+        let topic = "iot-pal/test/retained";
+
+        let mut publisher = MqttClient::new(broker().as_str()).await.unwrap();
+        publisher.publish_retained(topic, b"retained", 1).await.unwrap();
+
+        // Subscribers connecting after the publish receive the retained message
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+        client.subscribe(topic).await.unwrap();
+        assert_eq!(next_on(&mut client, topic, Duration::from_secs(2)).await, Some(b"retained".to_vec()));
+
+        // Clearing the retained message
+        client.unsubscribe(topic).await.unwrap();
+        publisher.publish_retained(topic, b"", 1).await.unwrap();
+        client.subscribe(topic).await.unwrap();
+        assert_eq!(next_on(&mut client, topic, Duration::from_millis(500)).await, None);
+
+        client.disconnect().await.unwrap();
+        publisher.disconnect().await.unwrap();
+    }
+
+    #[test]
+    fn qos_validation() {
+        for qos in 0..=2 {
+            assert_eq!(check_qos(qos).unwrap(), qos as i32);
+        }
+        assert!(check_qos(3).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn qos_levels() {
+        let mut client = MqttClient::new(broker().as_str()).await.unwrap();
+
+        for qos in 0..=2 {
+            let topic = format!("iot-pal/test/qos{}", qos);
+
+            client.subscribe_qos(&topic, qos).await.unwrap();
+            client.publish_qos(&topic, &[qos], qos).await.unwrap();
+            assert_eq!(next_on(&mut client, &topic, Duration::from_secs(2)).await, Some(vec![qos]));
+
+            client.unsubscribe(&topic).await.unwrap();
+        }
+
+        // Invalid levels are rejected
+        assert!(client.publish_qos("iot-pal/test/qos3", b"", 3).await.is_err());
+        assert!(client.subscribe_qos("iot-pal/test/qos3", 3).await.is_err());
+
+        client.disconnect().await.unwrap();
+    }
 }
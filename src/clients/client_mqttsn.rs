@@ -0,0 +1,862 @@
+//! MQTT-SN (v1.2) client over UDP
+//!
+//! Topics are registered with the gateway on first use, predefined topic ids and
+//! two character short topic names are used directly and support QoS -1 publishing
+//! without a connection. Sleeping clients are supported via `sleep`, `wake` and `resume`.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use futures::lock::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::time::{Delay, Instant, delay_until, timeout_at};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+use crate::id::{IdGenerator, SharedIdGenerator};
+
+/// Default interval before retransmitting unacknowledged requests (T_retry)
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default number of retransmissions before a request fails (N_retry)
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Maximum MQTT-SN datagram size
+const MAX_PACKET_LEN: usize = 1500;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MqttSnOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Gateway address (host:port, optionally prefixed with mqttsn://)
+    pub mqttsn_gateway: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Client ID (up to 23 characters, generated using `id_generator` if not set)
+    pub mqttsn_client_id: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Predefined topics shared with the gateway, as name=id (ie. sensors/temp=1)
+    pub mqttsn_predefined: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Skip connecting to the gateway, allowing only QoS -1 publishing
+    pub mqttsn_no_connect: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Keepalive interval (defaults to `TransportDefaults::keepalive`)
+    pub mqttsn_keepalive: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval before retransmitting unacknowledged requests (defaults to 5s)
+    pub mqttsn_retry_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Number of retransmissions before a request fails (defaults to 3)
+    pub mqttsn_retries: Option<u32>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    /// Generator for client IDs where `mqttsn_client_id` is not set (defaults to `UuidGenerator`)
+    pub id_generator: SharedIdGenerator,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for MqttSnOptions {
+    fn from(gateway: &str) -> Self {
+        Self {
+            mqttsn_gateway: gateway.to_string(),
+            mqttsn_client_id: None,
+            mqttsn_predefined: vec![],
+            mqttsn_no_connect: false,
+            mqttsn_keepalive: None,
+            mqttsn_retry_interval: None,
+            mqttsn_retries: None,
+            id_generator: SharedIdGenerator::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// MQTT-SN client state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MqttSnState {
+    /// Not connected, only QoS -1 publishing is available
+    Disconnected,
+    /// Connected to the gateway
+    Active,
+    /// Sleeping, the gateway buffers messages until the client wakes
+    Asleep,
+}
+
+/// Generic futures-based MQTT-SN client abstraction
+pub struct MqttSnClient {
+    tx: Arc<AsyncMutex<SendHalf>>,
+    rx: Arc<AsyncMutex<RecvHalf>>,
+    client_id: String,
+    state: MqttSnState,
+
+    /// Registered topic ids, by topic name
+    topics: HashMap<String, u16>,
+    /// Topic names by registered id, including those registered by the gateway
+    names: HashMap<u16, String>,
+    /// Predefined topic ids, by topic name
+    predefined: HashMap<String, u16>,
+    subs: Vec<String>,
+
+    keepalive: Duration,
+    retry_interval: Duration,
+    retries: u32,
+    msg_id: u16,
+
+    /// Received messages pending delivery via `Stream`
+    inbox: VecDeque<(String, Vec<u8>)>,
+    /// Packets (ie. acknowledgements) pending transmission
+    outbox: VecDeque<Vec<u8>>,
+    sending: Option<BoxFuture<'static, Result<(), Error>>>,
+    receiving: Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+    ping_timer: Delay,
+
+    last_error: Option<(std::time::Instant, PalError)>,
+}
+
+impl MqttSnClient {
+    /// Create a new client using the provided options, connecting to the gateway
+    /// unless `mqttsn_no_connect` is set
+    pub async fn new<O: Into<MqttSnOptions>>(opts: O) -> Result<MqttSnClient, Error> {
+        let o = opts.into();
+
+        let gateway = o.mqttsn_gateway.trim_start_matches("mqttsn://");
+
+        let client_id = match &o.mqttsn_client_id {
+            Some(id) if id.len() > 23 => return Err(Error::msg(format!("MQTT-SN client ID {:?} exceeds 23 characters", id))),
+            Some(id) => id.clone(),
+            None => format!("iot-pal-{}", o.id_generator.generate()).chars().take(23).collect(),
+        };
+
+        let mut predefined = HashMap::new();
+        for p in &o.mqttsn_predefined {
+            let (name, id) = match p.rfind('=') {
+                Some(i) => (&p[..i], p[i+1..].parse::<u16>()),
+                None => return Err(Error::msg(format!("Invalid predefined topic {:?} (expected name=id)", p))),
+            };
+            let id = id.map_err(|_| Error::msg(format!("Invalid predefined topic id in {:?}", p)))?;
+
+            predefined.insert(name.to_string(), id);
+        }
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(gateway).await?;
+        let (rx, tx) = socket.split();
+
+        let keepalive = o.mqttsn_keepalive.unwrap_or(o.defaults.keepalive);
+
+        let mut c = MqttSnClient{
+            tx: Arc::new(AsyncMutex::new(tx)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            client_id,
+            state: MqttSnState::Disconnected,
+            topics: HashMap::new(),
+            names: HashMap::new(),
+            predefined,
+            subs: vec![],
+            keepalive,
+            retry_interval: o.mqttsn_retry_interval.unwrap_or(DEFAULT_RETRY_INTERVAL),
+            retries: o.mqttsn_retries.unwrap_or(DEFAULT_RETRIES),
+            msg_id: 0,
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            sending: None,
+            receiving: None,
+            ping_timer: delay_until(Instant::now() + keepalive),
+            last_error: None,
+        };
+
+        if !o.mqttsn_no_connect {
+            c.connect(true).await?;
+        }
+
+        Ok(c)
+    }
+
+    /// Fetch the current client state
+    pub fn state(&self) -> MqttSnState {
+        self.state
+    }
+
+    /// Connect (or reconnect) to the gateway
+    async fn connect(&mut self, clean: bool) -> Result<(), Error> {
+        let p = Packet::Connect{
+            flags: if clean { FLAG_CLEAN } else { 0 },
+            duration: self.keepalive.as_secs() as u16,
+            client_id: self.client_id.clone(),
+        };
+
+        match self.transact(p, |p| matches!(p, Packet::ConnAck{..})).await? {
+            Packet::ConnAck{ code: RC_ACCEPTED } => (),
+            Packet::ConnAck{ code } => return Err(Error::msg(format!("MQTT-SN connect rejected: {}", rc_str(code)))),
+            _ => unreachable!(),
+        }
+
+        debug!("MQTT-SN client {} connected", self.client_id);
+
+        self.state = MqttSnState::Active;
+        self.ping_timer.reset(Instant::now() + self.keepalive);
+
+        Ok(())
+    }
+
+    /// Enter the sleep state for the provided duration
+    ///
+    /// The gateway buffers messages for subscribed topics, the client must `wake`
+    /// (or `resume`) before the duration expires to retain the session.
+    pub async fn sleep(&mut self, duration: Duration) -> Result<(), Error> {
+        self.check_active()?;
+
+        let p = Packet::Disconnect{ duration: Some(duration.as_secs() as u16) };
+        self.transact(p, |p| matches!(p, Packet::Disconnect{..})).await?;
+
+        self.state = MqttSnState::Asleep;
+
+        Ok(())
+    }
+
+    /// Wake from sleep to collect buffered messages, returning to sleep once these
+    /// are received (messages are delivered via `Stream`)
+    pub async fn wake(&mut self) -> Result<(), Error> {
+        if self.state != MqttSnState::Asleep {
+            return Err(Error::msg("MQTT-SN client is not asleep"))
+        }
+
+        let p = Packet::PingReq{ client_id: Some(self.client_id.clone()) };
+        self.transact(p, |p| matches!(p, Packet::PingResp)).await?;
+
+        Ok(())
+    }
+
+    /// Resume the active state, from sleep or following a disconnect
+    pub async fn resume(&mut self) -> Result<(), Error> {
+        self.connect(false).await
+    }
+
+    /// Register a topic with the gateway, returning the topic id
+    ///
+    /// Topics are registered automatically on first publish, predefined topics are not registered.
+    pub async fn register(&mut self, topic: &str) -> Result<u16, Error> {
+        if let Some(id) = self.predefined.get(topic) {
+            return Ok(*id)
+        }
+        if let Some(id) = self.topics.get(topic) {
+            return Ok(*id)
+        }
+
+        self.check_active()?;
+
+        let msg_id = self.next_msg_id();
+        let p = Packet::Register{ topic_id: 0, msg_id, topic: topic.to_string() };
+
+        let topic_id = match self.transact(p, |p| matches!(p, Packet::RegAck{ msg_id: m, .. } if *m == msg_id)).await? {
+            Packet::RegAck{ topic_id, code: RC_ACCEPTED, .. } => topic_id,
+            Packet::RegAck{ code, .. } => return Err(Error::msg(format!("MQTT-SN register {} rejected: {}", topic, rc_str(code)))),
+            _ => unreachable!(),
+        };
+
+        debug!("Registered topic {} (id: {})", topic, topic_id);
+
+        self.topics.insert(topic.to_string(), topic_id);
+        self.names.insert(topic_id, topic.to_string());
+
+        Ok(topic_id)
+    }
+
+    /// Publish data to a topic with the provided QoS (-1, 0, or 1)
+    ///
+    /// QoS -1 does not require a connection, but is limited to predefined and short topics.
+    pub async fn publish_qos(&mut self, topic: &str, data: &[u8], qos: i8) -> Result<(), Error> {
+        let flags = match qos {
+            -1 => QOS_M1,
+            0 => QOS_0,
+            1 => QOS_1,
+            _ => return Err(Error::msg(format!("Unsupported MQTT-SN publish QoS: {} (expected -1, 0, or 1)", qos))),
+        };
+
+        let topic_ref = match (self.topic_ref(topic), qos) {
+            (Some(t), _) => t,
+            (None, -1) => return Err(Error::msg(format!("QoS -1 requires a predefined or short topic ({})", topic))),
+            (None, _) => TopicRef::Id(self.register(topic).await?),
+        };
+
+        if qos >= 0 {
+            self.check_active()?;
+        }
+
+        let msg_id = if qos == 1 { self.next_msg_id() } else { 0 };
+        let p = Packet::Publish{ flags: flags | topic_ref.kind(), topic_id: topic_ref.id(), msg_id, data: data.to_vec() };
+
+        if qos < 1 {
+            return self.send(p.encode()).await
+        }
+
+        match self.transact(p, |p| matches!(p, Packet::PubAck{ msg_id: m, .. } if *m == msg_id)).await? {
+            Packet::PubAck{ code: RC_ACCEPTED, .. } => Ok(()),
+            Packet::PubAck{ topic_id, code, .. } => {
+                // Drop stale registrations so these are refreshed on the next publish
+                if code == RC_INVALID_TOPIC {
+                    if let Some(t) = self.names.remove(&topic_id) {
+                        self.topics.remove(&t);
+                    }
+                }
+                Err(Error::msg(format!("MQTT-SN publish to {} rejected: {}", topic, rc_str(code))))
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Subscribe to a topic with the provided QoS (0 or 1)
+    pub async fn subscribe_qos(&mut self, topic: &str, qos: u8) -> Result<(), Error> {
+        let flags = match qos {
+            0 => QOS_0,
+            1 => QOS_1,
+            _ => return Err(Error::msg(format!("Unsupported MQTT-SN subscribe QoS: {} (expected 0 or 1)", qos))),
+        };
+
+        self.check_active()?;
+
+        let topic_ref = self.topic_ref(topic).unwrap_or_else(|| TopicRef::Name(topic.to_string()));
+        let msg_id = self.next_msg_id();
+        let p = Packet::Subscribe{ flags: flags | topic_ref.kind(), msg_id, topic: topic_ref };
+
+        let topic_id = match self.transact(p, |p| matches!(p, Packet::SubAck{ msg_id: m, .. } if *m == msg_id)).await? {
+            Packet::SubAck{ topic_id, code: RC_ACCEPTED, .. } => topic_id,
+            Packet::SubAck{ code, .. } => return Err(Error::msg(format!("MQTT-SN subscribe to {} rejected: {}", topic, rc_str(code)))),
+            _ => unreachable!(),
+        };
+
+        // The gateway assigns ids for non-wildcard topics, wildcard matches are registered on delivery
+        if topic_id != 0 && !self.predefined.contains_key(topic) {
+            self.topics.insert(topic.to_string(), topic_id);
+            self.names.insert(topic_id, topic.to_string());
+        }
+
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Send a keepalive ping to the gateway
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.check_active()?;
+        self.transact(Packet::PingReq{ client_id: None }, |p| matches!(p, Packet::PingResp)).await?;
+
+        Ok(())
+    }
+
+    fn check_active(&self) -> Result<(), Error> {
+        match self.state {
+            MqttSnState::Active => Ok(()),
+            _ => Err(PalError::NotConnected.into()),
+        }
+    }
+
+    fn next_msg_id(&mut self) -> u16 {
+        self.msg_id = self.msg_id.wrapping_add(1).max(1);
+        self.msg_id
+    }
+
+    /// Resolve predefined and short topics, which do not require registration
+    fn topic_ref(&self, topic: &str) -> Option<TopicRef> {
+        if let Some(id) = self.predefined.get(topic) {
+            return Some(TopicRef::Predefined(*id))
+        }
+
+        let b = topic.as_bytes();
+        if b.len() == 2 && !topic.contains(|c: char| c == '+' || c == '#') {
+            return Some(TopicRef::Short([b[0], b[1]]))
+        }
+
+        None
+    }
+
+    /// Resolve the topic name for a received message
+    fn topic_name(&self, flags: u8, topic_id: u16) -> Option<String> {
+        match flags & TOPIC_MASK {
+            TOPIC_NORMAL => self.names.get(&topic_id).cloned(),
+            TOPIC_PREDEFINED => self.predefined.iter().find(|(_, id)| **id == topic_id).map(|(t, _)| t.clone()),
+            TOPIC_SHORT => String::from_utf8(topic_id.to_be_bytes().to_vec()).ok(),
+            _ => None,
+        }
+    }
+
+    /// Handle unsolicited packets from the gateway
+    fn handle(&mut self, p: Packet) {
+        match p {
+            Packet::Publish{ flags, topic_id, msg_id, data } => {
+                let qos = flags & QOS_MASK;
+                let topic = self.topic_name(flags, topic_id);
+
+                if qos == QOS_1 {
+                    let code = if topic.is_some() { RC_ACCEPTED } else { RC_INVALID_TOPIC };
+                    self.outbox.push_back(Packet::PubAck{ topic_id, msg_id, code }.encode());
+                }
+
+                match topic {
+                    Some(t) => self.inbox.push_back((t, data)),
+                    None => warn!("Dropping MQTT-SN publish for unknown topic id {}", topic_id),
+                }
+            },
+            Packet::Register{ topic_id, msg_id, topic } => {
+                self.topics.insert(topic.clone(), topic_id);
+                self.names.insert(topic_id, topic);
+                self.outbox.push_back(Packet::RegAck{ topic_id, msg_id, code: RC_ACCEPTED }.encode());
+            },
+            Packet::PingReq{ .. } => {
+                self.outbox.push_back(Packet::PingResp.encode());
+            },
+            Packet::Disconnect{ .. } => {
+                warn!("MQTT-SN gateway disconnected client {}", self.client_id);
+                self.state = MqttSnState::Disconnected;
+                self.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+            },
+            p => debug!("Ignoring unexpected MQTT-SN packet: {:?}", p),
+        }
+    }
+
+    /// Send a request and wait for the matching response, retransmitting on timeout
+    ///
+    /// Other packets received while waiting are handled (and messages queued for delivery).
+    async fn transact<F: Fn(&Packet) -> bool>(&mut self, req: Packet, matches: F) -> Result<Packet, Error> {
+        let data = req.encode();
+
+        for _ in 0..=self.retries {
+            self.send(data.clone()).await?;
+
+            let deadline = Instant::now() + self.retry_interval;
+            loop {
+                let p = match timeout_at(deadline, self.recv()).await {
+                    Ok(p) => p?,
+                    Err(_) => break,
+                };
+
+                if matches(&p) {
+                    return Ok(p)
+                }
+
+                self.handle(p);
+            }
+
+            debug!("Retransmitting MQTT-SN request: {:?}", req);
+        }
+
+        Err(PalError::Timeout{ operation: format!("MQTT-SN request {:?}", req), timeout: self.retry_interval * (self.retries + 1) }.into())
+    }
+
+    /// Send a packet, flushing any queued packets first
+    async fn send(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if let Some(f) = self.sending.take() {
+            f.await?;
+        }
+        while let Some(d) = self.outbox.pop_front() {
+            send_packet(self.tx.clone(), d).await?;
+        }
+
+        send_packet(self.tx.clone(), data).await
+    }
+
+    /// Receive and decode a packet, resuming any receive started by `Stream`
+    async fn recv(&mut self) -> Result<Packet, Error> {
+        let f = match self.receiving.take() {
+            Some(f) => f,
+            None => recv_packet(self.rx.clone()),
+        };
+
+        Packet::decode(&f.await?)
+    }
+}
+
+fn send_packet(tx: Arc<AsyncMutex<SendHalf>>, data: Vec<u8>) -> BoxFuture<'static, Result<(), Error>> {
+    async move {
+        tx.lock().await.send(&data).await?;
+        Ok(())
+    }.boxed()
+}
+
+fn recv_packet(rx: Arc<AsyncMutex<RecvHalf>>) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+    async move {
+        let mut buff = vec![0u8; MAX_PACKET_LEN];
+        let n = rx.lock().await.recv(&mut buff).await?;
+        buff.truncate(n);
+        Ok(buff)
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for MqttSnClient {
+    /// Disconnect from the gateway
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        if self.state == MqttSnState::Disconnected {
+            return Ok(())
+        }
+
+        let r = self.transact(Packet::Disconnect{ duration: None }, |p| matches!(p, Packet::Disconnect{..})).await;
+
+        self.state = MqttSnState::Disconnected;
+        self.subs.clear();
+        self.topics.clear();
+        self.names.clear();
+
+        r.map(|_| ())
+    }
+
+    /// Check whether the client has a session with the gateway (active or asleep)
+    fn is_connected(&self) -> bool {
+        self.state != MqttSnState::Disconnected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for MqttSnClient {
+    /// Publish data to a topic at QoS 0
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.publish_qos(topic, data, 0).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for MqttSnClient {
+    /// Subscribe to a topic at QoS 0
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.subscribe_qos(topic, 0).await
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.check_active()?;
+
+        let topic_ref = self.topic_ref(topic).unwrap_or_else(|| TopicRef::Name(topic.to_string()));
+        let msg_id = self.next_msg_id();
+        let p = Packet::Unsubscribe{ flags: topic_ref.kind(), msg_id, topic: topic_ref };
+
+        self.transact(p, |p| matches!(p, Packet::UnsubAck{ msg_id: m } if *m == msg_id)).await?;
+        self.subs.remove(i);
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for MqttSnClient
+///
+/// Polling the stream also sends pending acknowledgements and keepalive pings.
+impl Stream for MqttSnClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(m) = this.inbox.pop_front() {
+                return Poll::Ready(Some(m))
+            }
+
+            // Send keepalive pings while active, responses are ignored by `handle`
+            if this.state == MqttSnState::Active {
+                if let Poll::Ready(_) = Pin::new(&mut this.ping_timer).poll(cx) {
+                    this.outbox.push_back(Packet::PingReq{ client_id: None }.encode());
+                    this.ping_timer.reset(Instant::now() + this.keepalive);
+                }
+            }
+
+            // Drive pending transmissions
+            loop {
+                if this.sending.is_none() {
+                    match this.outbox.pop_front() {
+                        Some(d) => this.sending = Some(send_packet(this.tx.clone(), d)),
+                        None => break,
+                    }
+                }
+
+                match this.sending.as_mut().unwrap().poll_unpin(cx) {
+                    Poll::Ready(r) => {
+                        this.sending = None;
+                        if let Err(e) = r {
+                            warn!("MQTT-SN send failed: {:?}", e);
+                        }
+                    },
+                    Poll::Pending => break,
+                }
+            }
+
+            if this.receiving.is_none() {
+                this.receiving = Some(recv_packet(this.rx.clone()));
+            }
+
+            match this.receiving.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok(d)) => {
+                    this.receiving = None;
+
+                    match Packet::decode(&d) {
+                        Ok(p) => this.handle(p),
+                        Err(e) => warn!("Failed to decode MQTT-SN packet: {:?}", e),
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    this.receiving = None;
+                    warn!("MQTT-SN receive failed: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+
+const FLAG_CLEAN: u8 = 0x04;
+
+const QOS_MASK: u8 = 0x60;
+const QOS_0: u8 = 0x00;
+const QOS_1: u8 = 0x20;
+const QOS_M1: u8 = 0x60;
+
+const TOPIC_MASK: u8 = 0x03;
+const TOPIC_NORMAL: u8 = 0x00;
+const TOPIC_PREDEFINED: u8 = 0x01;
+const TOPIC_SHORT: u8 = 0x02;
+
+const RC_ACCEPTED: u8 = 0x00;
+const RC_INVALID_TOPIC: u8 = 0x02;
+
+fn rc_str(code: u8) -> &'static str {
+    match code {
+        0x00 => "accepted",
+        0x01 => "congestion",
+        0x02 => "invalid topic id",
+        0x03 => "not supported",
+        _ => "unknown return code",
+    }
+}
+
+/// Topic reference for publish / subscribe packets
+#[derive(Debug, Clone, PartialEq)]
+enum TopicRef {
+    /// Full topic name (subscribe / unsubscribe only)
+    Name(String),
+    /// Topic id registered with the gateway
+    Id(u16),
+    /// Predefined topic id
+    Predefined(u16),
+    /// Two character short topic name
+    Short([u8; 2]),
+}
+
+impl TopicRef {
+    fn kind(&self) -> u8 {
+        match self {
+            TopicRef::Name(_) | TopicRef::Id(_) => TOPIC_NORMAL,
+            TopicRef::Predefined(_) => TOPIC_PREDEFINED,
+            TopicRef::Short(_) => TOPIC_SHORT,
+        }
+    }
+
+    fn id(&self) -> u16 {
+        match self {
+            TopicRef::Name(_) => 0,
+            TopicRef::Id(id) | TopicRef::Predefined(id) => *id,
+            TopicRef::Short(s) => u16::from_be_bytes(*s),
+        }
+    }
+}
+
+/// MQTT-SN packets used by the client
+#[derive(Debug, Clone, PartialEq)]
+enum Packet {
+    Connect{ flags: u8, duration: u16, client_id: String },
+    ConnAck{ code: u8 },
+    Register{ topic_id: u16, msg_id: u16, topic: String },
+    RegAck{ topic_id: u16, msg_id: u16, code: u8 },
+    Publish{ flags: u8, topic_id: u16, msg_id: u16, data: Vec<u8> },
+    PubAck{ topic_id: u16, msg_id: u16, code: u8 },
+    Subscribe{ flags: u8, msg_id: u16, topic: TopicRef },
+    SubAck{ flags: u8, topic_id: u16, msg_id: u16, code: u8 },
+    Unsubscribe{ flags: u8, msg_id: u16, topic: TopicRef },
+    UnsubAck{ msg_id: u16 },
+    PingReq{ client_id: Option<String> },
+    PingResp,
+    Disconnect{ duration: Option<u16> },
+    Other{ msg_type: u8 },
+}
+
+const PROTOCOL_ID: u8 = 0x01;
+
+impl Packet {
+    fn msg_type(&self) -> u8 {
+        match self {
+            Packet::Connect{..} => 0x04,
+            Packet::ConnAck{..} => 0x05,
+            Packet::Register{..} => 0x0A,
+            Packet::RegAck{..} => 0x0B,
+            Packet::Publish{..} => 0x0C,
+            Packet::PubAck{..} => 0x0D,
+            Packet::Subscribe{..} => 0x12,
+            Packet::SubAck{..} => 0x13,
+            Packet::Unsubscribe{..} => 0x14,
+            Packet::UnsubAck{..} => 0x15,
+            Packet::PingReq{..} => 0x16,
+            Packet::PingResp => 0x17,
+            Packet::Disconnect{..} => 0x18,
+            Packet::Other{ msg_type } => *msg_type,
+        }
+    }
+
+    /// Encode a packet, including the length and message type header
+    fn encode(&self) -> Vec<u8> {
+        let mut b = vec![];
+
+        match self {
+            Packet::Connect{ flags, duration, client_id } => {
+                b.push(*flags);
+                b.push(PROTOCOL_ID);
+                b.extend_from_slice(&duration.to_be_bytes());
+                b.extend_from_slice(client_id.as_bytes());
+            },
+            Packet::ConnAck{ code } => b.push(*code),
+            Packet::Register{ topic_id, msg_id, topic } => {
+                b.extend_from_slice(&topic_id.to_be_bytes());
+                b.extend_from_slice(&msg_id.to_be_bytes());
+                b.extend_from_slice(topic.as_bytes());
+            },
+            Packet::RegAck{ topic_id, msg_id, code } | Packet::PubAck{ topic_id, msg_id, code } => {
+                b.extend_from_slice(&topic_id.to_be_bytes());
+                b.extend_from_slice(&msg_id.to_be_bytes());
+                b.push(*code);
+            },
+            Packet::Publish{ flags, topic_id, msg_id, data } => {
+                b.push(*flags);
+                b.extend_from_slice(&topic_id.to_be_bytes());
+                b.extend_from_slice(&msg_id.to_be_bytes());
+                b.extend_from_slice(data);
+            },
+            Packet::Subscribe{ flags, msg_id, topic } | Packet::Unsubscribe{ flags, msg_id, topic } => {
+                b.push(*flags);
+                b.extend_from_slice(&msg_id.to_be_bytes());
+                match topic {
+                    TopicRef::Name(n) => b.extend_from_slice(n.as_bytes()),
+                    t => b.extend_from_slice(&t.id().to_be_bytes()),
+                }
+            },
+            Packet::SubAck{ flags, topic_id, msg_id, code } => {
+                b.push(*flags);
+                b.extend_from_slice(&topic_id.to_be_bytes());
+                b.extend_from_slice(&msg_id.to_be_bytes());
+                b.push(*code);
+            },
+            Packet::UnsubAck{ msg_id } => b.extend_from_slice(&msg_id.to_be_bytes()),
+            Packet::PingReq{ client_id } => {
+                if let Some(id) = client_id {
+                    b.extend_from_slice(id.as_bytes());
+                }
+            },
+            Packet::Disconnect{ duration } => {
+                if let Some(d) = duration {
+                    b.extend_from_slice(&d.to_be_bytes());
+                }
+            },
+            Packet::PingResp | Packet::Other{..} => (),
+        }
+
+        // Lengths over 255 use a three byte length field
+        let mut p = match b.len() + 2 {
+            n if n <= 255 => vec![n as u8],
+            n => {
+                let n = (n + 2) as u16;
+                vec![0x01, (n >> 8) as u8, n as u8]
+            },
+        };
+
+        p.push(self.msg_type());
+        p.extend_from_slice(&b);
+        p
+    }
+
+    /// Decode a packet from a datagram
+    fn decode(d: &[u8]) -> Result<Packet, Error> {
+        let (len, offset) = match d.get(0) {
+            Some(0x01) if d.len() >= 3 => (u16::from_be_bytes([d[1], d[2]]) as usize, 3),
+            Some(n) => (*n as usize, 1),
+            None => return Err(Error::msg("Empty MQTT-SN packet")),
+        };
+
+        if len > d.len() || len <= offset {
+            return Err(Error::msg(format!("Invalid MQTT-SN packet length {} (received {})", len, d.len())))
+        }
+
+        let msg_type = d[offset];
+        let b = &d[offset+1..len];
+
+        let u16_at = |i: usize| -> Result<u16, Error> {
+            match (b.get(i), b.get(i+1)) {
+                (Some(h), Some(l)) => Ok(u16::from_be_bytes([*h, *l])),
+                _ => Err(Error::msg(format!("Truncated MQTT-SN packet (type: 0x{:02x})", msg_type))),
+            }
+        };
+        let u8_at = |i: usize| -> Result<u8, Error> {
+            b.get(i).copied().ok_or_else(|| Error::msg(format!("Truncated MQTT-SN packet (type: 0x{:02x})", msg_type)))
+        };
+
+        let p = match msg_type {
+            0x05 => Packet::ConnAck{ code: u8_at(0)? },
+            0x0A => Packet::Register{
+                topic_id: u16_at(0)?,
+                msg_id: u16_at(2)?,
+                topic: String::from_utf8(b[4..].to_vec())?,
+            },
+            0x0B => Packet::RegAck{ topic_id: u16_at(0)?, msg_id: u16_at(2)?, code: u8_at(4)? },
+            0x0C => Packet::Publish{
+                flags: u8_at(0)?,
+                topic_id: u16_at(1)?,
+                msg_id: u16_at(3)?,
+                data: b[5..].to_vec(),
+            },
+            0x0D => Packet::PubAck{ topic_id: u16_at(0)?, msg_id: u16_at(2)?, code: u8_at(4)? },
+            0x13 => Packet::SubAck{ flags: u8_at(0)?, topic_id: u16_at(1)?, msg_id: u16_at(3)?, code: u8_at(5)? },
+            0x15 => Packet::UnsubAck{ msg_id: u16_at(0)? },
+            0x16 => Packet::PingReq{
+                client_id: if b.is_empty() { None } else { Some(String::from_utf8(b.to_vec())?) },
+            },
+            0x17 => Packet::PingResp,
+            0x18 => Packet::Disconnect{
+                duration: if b.len() >= 2 { Some(u16_at(0)?) } else { None },
+            },
+            t => Packet::Other{ msg_type: t },
+        };
+
+        Ok(p)
+    }
+}
@@ -0,0 +1,302 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+use serde_json::Value;
+
+use tokio::time::timeout;
+
+use async_nats::{Connection, Message, Options};
+
+use super::{ClientBase, ClientPub, ClientSub, ClientReq};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NatsOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for NATS server (prefixed with nats:// or tls://)
+    pub nats_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Client name reported to the server
+    pub nats_name: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Publish via JetStream, waiting for the stream to acknowledge each message.
+    ///
+    /// Published subjects must be bound to a JetStream stream on the server.
+    pub nats_jetstream: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// NATS connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub nats_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for JetStream acknowledgements (defaults to `TransportDefaults::request_timeout`)
+    pub nats_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for NatsOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            nats_url: url.to_string(),
+            nats_name: None,
+            nats_jetstream: false,
+            nats_connect_timeout: None,
+            nats_request_timeout: None,
+            tls_opts: TlsOptions::default(),
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for NatsOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Generic futures-based NATS client abstraction, using subjects as topics
+///
+/// MQTT-style topics are not translated, subscriptions use NATS subject wildcards
+/// (`*` for a single token, `>` for all remaining tokens).
+pub struct NatsClient {
+    conn: Connection,
+    jetstream: bool,
+    request_timeout: Duration,
+    subs: Vec<Subscription>,
+    last_error: Option<(Instant, PalError)>,
+    /// Index of the next subscription to poll, rotated for fairness
+    next: usize,
+    /// Cleared on disconnect or when a subscription closes
+    connected: bool,
+}
+
+struct Subscription {
+    topic: String,
+    sub: async_nats::Subscription,
+    pending: Option<BoxFuture<'static, Option<Message>>>,
+}
+
+impl NatsClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<NatsOptions>>(opts: O) -> Result<NatsClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.nats_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        let conn = match timeout(connect_timeout, Self::options(&o)?.connect(&o.nats_url)).await {
+            Ok(r) => r.map_err(|e| o.tls_opts.with_diagnostics(&o.nats_url, e.into()))?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("NATS connect to {}", o.nats_url), timeout: connect_timeout }.into()),
+        };
+
+        debug!("Connected to NATS server {}", o.nats_url);
+
+        Ok(NatsClient{
+            conn,
+            jetstream: o.nats_jetstream,
+            request_timeout: o.nats_request_timeout.unwrap_or(o.defaults.request_timeout),
+            subs: vec![],
+            last_error: None,
+            next: 0,
+            connected: true,
+        })
+    }
+
+    /// Build NATS connection options
+    ///
+    /// The NATS driver loads TLS certificates from files, so inline PEM data
+    /// and unsupported TLS options are rejected rather than silently ignored.
+    fn options(o: &NatsOptions) -> Result<Options, Error> {
+        let tls = &o.tls_opts;
+        tls.validate()?;
+
+        let mut opts = match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(u), Some(p)) => Options::with_user_pass(u, p),
+            (None, None) => Options::new(),
+            _ => return Err(Error::msg("NATS authentication requires both username and password")),
+        };
+
+        if let Some(n) = &o.nats_name {
+            opts = opts.with_name(n);
+        }
+
+        if tls.tls_ca_pem.is_some() || tls.tls_cert_pem.is_some() || tls.tls_key_pem.is_some() {
+            return Err(Error::msg("NATS client does not support inline PEM data, use tls_*_file options"))
+        }
+        if tls.tls_insecure || tls.tls_min_version.is_some() || tls.tls_key_password.is_some() {
+            return Err(Error::msg("NATS client does not support tls_insecure, tls_min_version, or tls_key_password"))
+        }
+
+        if tls.is_configured() || o.nats_url.starts_with("tls://") {
+            opts = opts.tls_required(true);
+        }
+        if let Some(ca) = &tls.tls_ca_file {
+            opts = opts.add_root_certificate(ca);
+        }
+        match (&tls.tls_cert_file, &tls.tls_key_file) {
+            (Some(c), Some(k)) => opts = opts.client_cert(c, k),
+            (None, None) => (),
+            _ => return Err(Error::msg("NATS client certificates require both tls_cert_file and tls_key_file")),
+        }
+
+        Ok(opts)
+    }
+
+    /// Publish via JetStream, waiting for the stream acknowledgement
+    async fn publish_jetstream(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let resp = match timeout(self.request_timeout, self.conn.request(topic, data)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("JetStream publish to {}", topic), timeout: self.request_timeout }.into()),
+        };
+
+        // Acknowledgements are JSON encoded, or `+OK` for older servers
+        if resp.data.starts_with(b"+OK") {
+            return Ok(())
+        }
+
+        let ack: Value = serde_json::from_slice(&resp.data)?;
+        if let Some(e) = ack.get("error") {
+            return Err(Error::msg(format!("JetStream publish to {} failed: {}", topic, e)))
+        }
+
+        debug!("JetStream ack for {}: {}", topic, ack);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientBase for NatsClient {
+    /// Close the NATS connection
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.subs.clear();
+        self.conn.close().await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for NatsClient {
+    /// Publish data to a subject, waiting for a JetStream acknowledgement if enabled
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        match self.jetstream {
+            true => self.publish_jetstream(topic, data).await,
+            false => Ok(self.conn.publish(topic, data).await?),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientReq for NatsClient {
+    /// Issue a NATS request, returning the first response
+    async fn request(&mut self, topic: &str, data: &[u8], t: Duration) -> Result<Vec<u8>, Error> {
+        match timeout(t, self.conn.request(topic, data)).await {
+            Ok(r) => Ok(r?.data),
+            Err(_) => Err(PalError::Timeout{ operation: format!("NATS request to {}", topic), timeout: t }.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientSub for NatsClient {
+    /// Subscribe to a subject
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let sub = self.conn.subscribe(topic).await?;
+
+        self.subs.push(Subscription{ topic: topic.to_string(), sub, pending: None });
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a subject
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s.topic == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let s = self.subs.remove(i);
+        if self.next > i {
+            self.next -= 1;
+        }
+
+        s.sub.unsubscribe().await?;
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for NatsClient, polling subscriptions in turn
+impl Stream for NatsClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let n = this.subs.len();
+
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+            let s = &mut this.subs[idx];
+
+            if s.pending.is_none() {
+                let sub = s.sub.clone();
+                s.pending = Some(async move { sub.next().await }.boxed());
+            }
+            let pending = s.pending.as_mut().unwrap();
+
+            match pending.poll_unpin(cx) {
+                Poll::Ready(Some(m)) => {
+                    s.pending = None;
+                    this.next = (idx + 1) % n;
+                    return Poll::Ready(Some((m.subject, m.data)))
+                },
+                Poll::Ready(None) => {
+                    warn!("NATS subscription to {} closed", s.topic);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => (),
+            }
+        }
+
+        Poll::Pending
+    }
+}
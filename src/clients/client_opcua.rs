@@ -0,0 +1,552 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::timeout;
+
+use opcua_client::prelude::*;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{UserOptions, TransportDefaults, PalError};
+
+/// Default directory for client application certificates and trusted / rejected server certificates
+pub const DEFAULT_PKI_DIR: &str = "./pki";
+
+/// Default subscription publishing interval
+pub const DEFAULT_PUBLISHING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// OPC-UA endpoint security policies
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpcUaSecurityPolicy {
+    None,
+    Basic128Rsa15,
+    Basic256,
+    Basic256Sha256,
+}
+
+impl OpcUaSecurityPolicy {
+    fn policy(&self) -> SecurityPolicy {
+        match self {
+            OpcUaSecurityPolicy::None => SecurityPolicy::None,
+            OpcUaSecurityPolicy::Basic128Rsa15 => SecurityPolicy::Basic128Rsa15,
+            OpcUaSecurityPolicy::Basic256 => SecurityPolicy::Basic256,
+            OpcUaSecurityPolicy::Basic256Sha256 => SecurityPolicy::Basic256Sha256,
+        }
+    }
+}
+
+impl FromStr for OpcUaSecurityPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(OpcUaSecurityPolicy::None),
+            "basic128rsa15" => Ok(OpcUaSecurityPolicy::Basic128Rsa15),
+            "basic256" => Ok(OpcUaSecurityPolicy::Basic256),
+            "basic256sha256" => Ok(OpcUaSecurityPolicy::Basic256Sha256),
+            _ => Err(Error::msg(format!("Unsupported OPC-UA security policy: {:?} (expected none, basic128rsa15, basic256 or basic256sha256)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for OpcUaSecurityPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpcUaSecurityPolicy::None => write!(f, "none"),
+            OpcUaSecurityPolicy::Basic128Rsa15 => write!(f, "basic128rsa15"),
+            OpcUaSecurityPolicy::Basic256 => write!(f, "basic256"),
+            OpcUaSecurityPolicy::Basic256Sha256 => write!(f, "basic256sha256"),
+        }
+    }
+}
+
+/// OPC-UA message security modes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OpcUaSecurityMode {
+    None,
+    Sign,
+    SignAndEncrypt,
+}
+
+impl OpcUaSecurityMode {
+    fn mode(&self) -> MessageSecurityMode {
+        match self {
+            OpcUaSecurityMode::None => MessageSecurityMode::None,
+            OpcUaSecurityMode::Sign => MessageSecurityMode::Sign,
+            OpcUaSecurityMode::SignAndEncrypt => MessageSecurityMode::SignAndEncrypt,
+        }
+    }
+}
+
+impl FromStr for OpcUaSecurityMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(OpcUaSecurityMode::None),
+            "sign" => Ok(OpcUaSecurityMode::Sign),
+            "signandencrypt" | "sign-and-encrypt" => Ok(OpcUaSecurityMode::SignAndEncrypt),
+            _ => Err(Error::msg(format!("Unsupported OPC-UA security mode: {:?} (expected none, sign or sign-and-encrypt)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for OpcUaSecurityMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpcUaSecurityMode::None => write!(f, "none"),
+            OpcUaSecurityMode::Sign => write!(f, "sign"),
+            OpcUaSecurityMode::SignAndEncrypt => write!(f, "sign-and-encrypt"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpcUaOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// OPC-UA endpoint URL (prefixed with opc.tcp://)
+    pub opcua_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "none"))]
+    /// Endpoint security policy (none, basic128rsa15, basic256 or basic256sha256)
+    pub opcua_security_policy: OpcUaSecurityPolicy,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "none"))]
+    /// Endpoint message security mode (none, sign or sign-and-encrypt)
+    pub opcua_security_mode: OpcUaSecurityMode,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "./pki"))]
+    /// Directory for the client application certificate and trusted / rejected server certificates
+    pub opcua_pki_dir: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Client application certificate (DER), relative to `opcua_pki_dir`
+    pub opcua_cert_file: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Client application private key (PEM), relative to `opcua_pki_dir`
+    pub opcua_key_file: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Generate a self-signed client application certificate if one does not exist
+    pub opcua_create_keypair: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Trust unknown server certificates (insecure, servers are otherwise required in the pki trusted directory)
+    pub opcua_trust_server_certs: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Subscription publishing interval (defaults to 1s)
+    pub opcua_publishing_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// OPC-UA connect timeout, including session activation (defaults to `TransportDefaults::connect_timeout`)
+    pub opcua_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for OpcUaOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            opcua_url: url.to_string(),
+            opcua_security_policy: OpcUaSecurityPolicy::None,
+            opcua_security_mode: OpcUaSecurityMode::None,
+            opcua_pki_dir: DEFAULT_PKI_DIR.to_string(),
+            opcua_cert_file: None,
+            opcua_key_file: None,
+            opcua_create_keypair: false,
+            opcua_trust_server_certs: false,
+            opcua_publishing_interval: None,
+            opcua_connect_timeout: None,
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// Generic futures-based OPC-UA client abstraction, using node ids as topics
+///
+/// Topics are OPC-UA node ids (ie. `ns=2;s=Temperature`), subscriptions create monitored
+/// items on a single OPC-UA subscription and publishing writes node values.
+/// Received topics are formatted from the parsed node id, so may differ from the subscribed topic
+/// (ie. `ns=0;i=2258` is received as `i=2258`).
+///
+/// Values are exchanged as text (or raw bytes for ByteString nodes), writes are converted to the
+/// type of the node's current value.
+///
+/// The underlying driver is synchronous, so requests are executed on the blocking thread pool
+/// and the session runs on its own thread until disconnected or the client is dropped.
+pub struct OpcUaClient {
+    session: Arc<RwLock<Session>>,
+    /// Stops the session thread
+    stop: Option<Box<dyn FnOnce() + Send>>,
+    publishing_interval: Duration,
+    subscription_id: Option<u32>,
+    /// Subscribed topics and monitored item ids
+    subs: Vec<(String, u32)>,
+    tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+    rx: mpsc::UnboundedReceiver<(String, Vec<u8>)>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect
+    connected: bool,
+}
+
+impl OpcUaClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<OpcUaOptions>>(opts: O) -> Result<OpcUaClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.opcua_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        let session = match timeout(connect_timeout, Self::connect(&o)).await {
+            Ok(r) => r?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("OPC-UA connect to {}", o.opcua_url), timeout: connect_timeout }.into()),
+        };
+
+        debug!("Opened OPC-UA session to {} ({} / {})", o.opcua_url, o.opcua_security_policy, o.opcua_security_mode);
+
+        let tx = Session::run_async(session.clone());
+        let stop = Box::new(move || {
+            let _ = tx.send(SessionCommand::Stop);
+        });
+
+        let (tx, rx) = mpsc::unbounded();
+
+        Ok(OpcUaClient{
+            session,
+            stop: Some(stop),
+            publishing_interval: o.opcua_publishing_interval.unwrap_or(DEFAULT_PUBLISHING_INTERVAL),
+            subscription_id: None,
+            subs: vec![],
+            tx,
+            rx,
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    async fn connect(o: &OpcUaOptions) -> Result<Arc<RwLock<Session>>, Error> {
+        let mut builder = ClientBuilder::new()
+            .application_name("iot-pal")
+            .application_uri("urn:iot-pal")
+            .product_uri("urn:iot-pal")
+            .pki_dir(o.opcua_pki_dir.as_str())
+            .create_sample_keypair(o.opcua_create_keypair)
+            .trust_server_certs(o.opcua_trust_server_certs)
+            .session_retry_limit(0);
+
+        match (&o.opcua_cert_file, &o.opcua_key_file) {
+            (Some(c), Some(k)) => builder = builder.certificate_path(c.as_str()).private_key_path(k.as_str()),
+            (None, None) => (),
+            _ => return Err(Error::msg("OPC-UA client certificate requires both cert and key files")),
+        }
+
+        if o.opcua_security_policy != OpcUaSecurityPolicy::None && o.opcua_security_mode == OpcUaSecurityMode::None {
+            return Err(Error::msg(format!("OPC-UA security policy {} requires security mode sign or sign-and-encrypt", o.opcua_security_policy)))
+        }
+
+        let identity = match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(u), Some(p)) => IdentityToken::UserName(u.clone(), p.clone()),
+            (None, None) => IdentityToken::Anonymous,
+            _ => return Err(Error::msg("OPC-UA authentication requires both username and password")),
+        };
+
+        let mut client = builder.client()
+            .ok_or_else(|| Error::msg("Invalid OPC-UA client configuration"))?;
+
+        let url = o.opcua_url.clone();
+        let policy = o.opcua_security_policy.policy();
+        let mode = o.opcua_security_mode.mode();
+
+        tokio::task::spawn_blocking(move || {
+            let endpoint = (url.as_str(), policy.to_str(), mode, UserTokenPolicy::anonymous());
+
+            client.connect_to_endpoint(endpoint, identity)
+                .map_err(|e| Error::msg(format!("OPC-UA connect to {} failed: {:?}", url, e)))
+        }).await?
+    }
+
+    /// Execute a request on the session using the blocking thread pool
+    async fn request<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Session) -> Result<R, Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let session = self.session.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut s = session.write().map_err(|_| Error::msg("OPC-UA session lock poisoned"))?;
+            f(&mut s)
+        }).await?
+    }
+
+    /// Fetch the OPC-UA subscription, creating this on first use
+    async fn subscription(&mut self) -> Result<u32, Error> {
+        if let Some(id) = self.subscription_id {
+            return Ok(id)
+        }
+
+        let interval = self.publishing_interval.as_millis() as f64;
+        let tx = self.tx.clone();
+
+        let id = self.request(move |s| {
+            let callback = DataChangeCallback::new(move |items| {
+                for i in items {
+                    let topic = i.item_to_monitor().node_id.to_string();
+                    let value = match &i.last_value().value {
+                        Some(v) => encode_value(v),
+                        None => continue,
+                    };
+
+                    let _ = tx.unbounded_send((topic, value));
+                }
+            });
+
+            s.create_subscription(interval, 30, 10, 0, 0, true, callback)
+                .map_err(|e| Error::msg(format!("OPC-UA subscription failed: {:?}", e)))
+        }).await?;
+
+        self.subscription_id = Some(id);
+
+        Ok(id)
+    }
+}
+
+/// Encode a node value as text (or raw bytes for ByteStrings)
+fn encode_value(v: &Variant) -> Vec<u8> {
+    match v {
+        Variant::Empty => vec![],
+        Variant::ByteString(b) => b.value.clone().unwrap_or_default(),
+        Variant::String(s) => s.as_ref().as_bytes().to_vec(),
+        Variant::Boolean(b) => b.to_string().into_bytes(),
+        Variant::SByte(n) => n.to_string().into_bytes(),
+        Variant::Byte(n) => n.to_string().into_bytes(),
+        Variant::Int16(n) => n.to_string().into_bytes(),
+        Variant::UInt16(n) => n.to_string().into_bytes(),
+        Variant::Int32(n) => n.to_string().into_bytes(),
+        Variant::UInt32(n) => n.to_string().into_bytes(),
+        Variant::Int64(n) => n.to_string().into_bytes(),
+        Variant::UInt64(n) => n.to_string().into_bytes(),
+        Variant::Float(n) => n.to_string().into_bytes(),
+        Variant::Double(n) => n.to_string().into_bytes(),
+        v => format!("{:?}", v).into_bytes(),
+    }
+}
+
+/// Decode a value using the type of the node's current value
+fn decode_value(current: &Variant, data: &[u8]) -> Result<Variant, Error> {
+    if let Variant::ByteString(_) = current {
+        return Ok(Variant::ByteString(ByteString::from(data.to_vec())))
+    }
+
+    let s = std::str::from_utf8(data)?.trim();
+
+    let v = match current {
+        Variant::String(_) => Variant::String(UAString::from(s)),
+        Variant::Boolean(_) => Variant::Boolean(s.parse()?),
+        Variant::SByte(_) => Variant::SByte(s.parse()?),
+        Variant::Byte(_) => Variant::Byte(s.parse()?),
+        Variant::Int16(_) => Variant::Int16(s.parse()?),
+        Variant::UInt16(_) => Variant::UInt16(s.parse()?),
+        Variant::Int32(_) => Variant::Int32(s.parse()?),
+        Variant::UInt32(_) => Variant::UInt32(s.parse()?),
+        Variant::Int64(_) => Variant::Int64(s.parse()?),
+        Variant::UInt64(_) => Variant::UInt64(s.parse()?),
+        Variant::Float(_) => Variant::Float(s.parse()?),
+        Variant::Double(_) => Variant::Double(s.parse()?),
+        v => return Err(Error::msg(format!("Unsupported OPC-UA value type for write: {:?}", v))),
+    };
+
+    Ok(v)
+}
+
+fn parse_node_id(topic: &str) -> Result<NodeId, Error> {
+    NodeId::from_str(topic)
+        .map_err(|_| Error::msg(format!("Invalid OPC-UA node id: {:?} (expected ie. ns=2;s=name)", topic)))
+}
+
+/// Disconnect the session and stop the session thread where `disconnect` was not called
+///
+/// Note this disconnects synchronously, blocking the current thread until the session is closed.
+impl Drop for OpcUaClient {
+    fn drop(&mut self) {
+        let stop = match self.stop.take() {
+            Some(s) => s,
+            None => return,
+        };
+
+        if let Ok(mut s) = self.session.write() {
+            s.disconnect();
+        }
+
+        stop();
+    }
+}
+
+#[async_trait]
+impl ClientBase for OpcUaClient {
+    /// Close the session and stop the session thread
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        let session = self.session.clone();
+
+        tokio::task::spawn_blocking(move || {
+            if let Ok(mut s) = session.write() {
+                s.disconnect();
+            }
+        }).await?;
+
+        if let Some(stop) = self.stop.take() {
+            stop();
+        }
+
+        self.connected = false;
+        self.subscription_id = None;
+        self.subs.clear();
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for OpcUaClient {
+    /// Write data to a node value
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let node_id = parse_node_id(topic)?;
+        let data = data.to_vec();
+        let topic = topic.to_string();
+
+        self.request(move |s| {
+            // Read the current value to determine the node type
+            let current = s.read(&[ReadValueId::from(node_id.clone())])
+                .map_err(|e| Error::msg(format!("OPC-UA read of {} failed: {:?}", topic, e)))?
+                .into_iter().next()
+                .and_then(|v| v.value)
+                .ok_or_else(|| Error::msg(format!("OPC-UA node {} has no value", topic)))?;
+
+            let value = WriteValue {
+                node_id,
+                attribute_id: AttributeId::Value as u32,
+                index_range: UAString::null(),
+                value: DataValue::new(decode_value(&current, &data)?),
+            };
+
+            let status = s.write(&[value])
+                .map_err(|e| Error::msg(format!("OPC-UA write to {} failed: {:?}", topic, e)))?;
+
+            match status.first() {
+                Some(s) if s.is_good() => Ok(()),
+                Some(s) => Err(Error::msg(format!("OPC-UA write to {} rejected: {:?}", topic, s))),
+                None => Err(Error::msg(format!("OPC-UA write to {} returned no status", topic))),
+            }
+        }).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for OpcUaClient {
+    /// Subscribe to a node value, creating a monitored item
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|(t, _)| t == topic) {
+            return Ok(())
+        }
+
+        let node_id = parse_node_id(topic)?;
+        let subscription_id = self.subscription().await?;
+        let t = topic.to_string();
+
+        let item_id = self.request(move |s| {
+            let items: Vec<MonitoredItemCreateRequest> = vec![node_id.into()];
+
+            let r = s.create_monitored_items(subscription_id, TimestampsToReturn::Both, &items)
+                .map_err(|e| Error::msg(format!("OPC-UA subscribe to {} failed: {:?}", t, e)))?;
+
+            match r.first() {
+                Some(r) if r.status_code.is_good() => Ok(r.monitored_item_id),
+                Some(r) => Err(Error::msg(format!("OPC-UA subscribe to {} rejected: {:?}", t, r.status_code))),
+                None => Err(Error::msg(format!("OPC-UA subscribe to {} returned no result", t))),
+            }
+        }).await?;
+
+        self.subs.push((topic.to_string(), item_id));
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a node value, deleting the monitored item
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|(t, _)| t == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let (_, item_id) = self.subs.remove(i);
+        let subscription_id = match self.subscription_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let t = topic.to_string();
+
+        self.request(move |s| {
+            s.delete_monitored_items(subscription_id, &[item_id])
+                .map_err(|e| Error::msg(format!("OPC-UA unsubscribe from {} failed: {:?}", t, e)))?;
+            Ok(())
+        }).await
+    }
+}
+
+/// Stream implementation for OpcUaClient, yielding monitored item value changes
+impl Stream for OpcUaClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let (topic, value) = match this.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => {
+                    warn!("OPC-UA subscription channel closed");
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // Drop notifications for items removed since the change was queued
+            if this.subs.iter().any(|(t, _)| parse_node_id(t).map(|n| n.to_string() == topic).unwrap_or(false)) {
+                return Poll::Ready(Some((topic, value)))
+            }
+        }
+    }
+}
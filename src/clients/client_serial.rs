@@ -0,0 +1,445 @@
+//! Serial / UART client, framing messages over a serial port
+//!
+//! Frames are either newline delimited (`line`) or COBS encoded with zero delimiters (`cobs`).
+//! Frames optionally carry topics (`topic payload` for line framing, a length-prefixed topic
+//! for COBS framing), otherwise all frames use a single configured topic.
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_serial::{Serial, SerialPortSettings, DataBits, FlowControl, Parity, StopBits};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+use crate::topics::topic_matches;
+
+/// Default baud rate
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+/// Default maximum frame length
+pub const DEFAULT_MAX_FRAME: usize = 4096;
+
+/// Size of serial reads
+const READ_LEN: usize = 256;
+
+/// Message framing for serial ports
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SerialFraming {
+    /// Newline delimited frames (trailing carriage returns are stripped), payloads must not contain newlines
+    Line,
+    /// COBS encoded frames delimited by zero bytes, supporting arbitrary binary payloads
+    Cobs,
+}
+
+impl Default for SerialFraming {
+    fn default() -> Self {
+        SerialFraming::Line
+    }
+}
+
+impl FromStr for SerialFraming {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "line" => Ok(SerialFraming::Line),
+            "cobs" => Ok(SerialFraming::Cobs),
+            _ => Err(Error::msg(format!("Unsupported serial framing: {:?} (expected line or cobs)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for SerialFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerialFraming::Line => write!(f, "line"),
+            SerialFraming::Cobs => write!(f, "cobs"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SerialOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Serial port path (ie. /dev/ttyUSB0 or COM3)
+    pub serial_port: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "115200"))]
+    /// Baud rate (8N1 without flow control)
+    pub serial_baud: u32,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "line"))]
+    /// Message framing (line or cobs)
+    pub serial_framing: SerialFraming,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Prefix frames with topics (`topic payload` for line framing, length-prefixed for COBS)
+    pub serial_topics: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Topic for received frames without topics (defaults to the port path)
+    pub serial_topic: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "4096"))]
+    /// Maximum frame length, longer frames are discarded
+    pub serial_max_frame: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for SerialOptions {
+    fn from(port: &str) -> Self {
+        Self {
+            serial_port: port.to_string(),
+            serial_baud: DEFAULT_BAUD,
+            serial_framing: SerialFraming::Line,
+            serial_topics: false,
+            serial_topic: None,
+            serial_max_frame: DEFAULT_MAX_FRAME,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// Generic futures-based serial client abstraction
+pub struct SerialClient {
+    rx: Arc<AsyncMutex<ReadHalf<Serial>>>,
+    tx: Arc<AsyncMutex<WriteHalf<Serial>>>,
+    framing: SerialFraming,
+    topics: bool,
+    topic: String,
+    max_frame: usize,
+    request_timeout: Duration,
+
+    /// Received data pending framing
+    buf: Vec<u8>,
+    /// Set while discarding an oversized frame, until the next delimiter
+    discarding: bool,
+    reading: Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+
+    subs: Vec<String>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect or when the port fails
+    connected: bool,
+}
+
+impl SerialClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<SerialOptions>>(opts: O) -> Result<SerialClient, Error> {
+        let o = opts.into();
+
+        let settings = SerialPortSettings {
+            baud_rate: o.serial_baud,
+            data_bits: DataBits::Eight,
+            flow_control: FlowControl::None,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            timeout: o.defaults.request_timeout,
+        };
+
+        let port = Serial::from_path(&o.serial_port, &settings)
+            .map_err(|e| Error::msg(format!("Failed to open serial port {:?}: {}", o.serial_port, e)))?;
+
+        let (rx, tx) = tokio::io::split(port);
+
+        debug!("Opened serial port {} ({} baud, framing: {})", o.serial_port, o.serial_baud, o.serial_framing);
+
+        Ok(SerialClient{
+            rx: Arc::new(AsyncMutex::new(rx)),
+            tx: Arc::new(AsyncMutex::new(tx)),
+            framing: o.serial_framing,
+            topics: o.serial_topics,
+            topic: o.serial_topic.clone().unwrap_or_else(|| o.serial_port.clone()),
+            max_frame: o.serial_max_frame,
+            request_timeout: o.defaults.request_timeout,
+            buf: vec![],
+            discarding: false,
+            reading: None,
+            subs: vec![],
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Encode a message into a frame (including the delimiter)
+    fn encode(&self, topic: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.framing {
+            SerialFraming::Line => {
+                if data.contains(&b'\n') || (self.topics && topic.contains(|c| c == ' ' || c == '\n')) {
+                    return Err(Error::msg("Line framed serial messages must not contain newlines (or spaces in topics)"))
+                }
+
+                let mut f = vec![];
+                if self.topics {
+                    f.extend_from_slice(topic.as_bytes());
+                    f.push(b' ');
+                }
+                f.extend_from_slice(data);
+                f.push(b'\n');
+                Ok(f)
+            },
+            SerialFraming::Cobs => {
+                let mut m = vec![];
+                if self.topics {
+                    if topic.len() > 255 {
+                        return Err(Error::msg(format!("Serial topic exceeds 255 bytes: {}", topic)))
+                    }
+                    m.push(topic.len() as u8);
+                    m.extend_from_slice(topic.as_bytes());
+                }
+                m.extend_from_slice(data);
+
+                let mut f = cobs_encode(&m);
+                f.push(0);
+                Ok(f)
+            },
+        }
+    }
+
+    /// Decode a frame (excluding the delimiter) into a message
+    fn decode(&self, frame: &[u8]) -> Result<(String, Vec<u8>), Error> {
+        let m = match self.framing {
+            SerialFraming::Line => match frame.last() {
+                Some(b'\r') => frame[..frame.len()-1].to_vec(),
+                _ => frame.to_vec(),
+            },
+            SerialFraming::Cobs => cobs_decode(frame)?,
+        };
+
+        if !self.topics {
+            return Ok((self.topic.clone(), m))
+        }
+
+        let (topic, data) = match self.framing {
+            SerialFraming::Line => match m.iter().position(|c| *c == b' ') {
+                Some(i) => (&m[..i], &m[i+1..]),
+                None => (&m[..], &[][..]),
+            },
+            SerialFraming::Cobs => {
+                let n = *m.get(0).ok_or_else(|| Error::msg("Empty serial frame"))? as usize;
+                if m.len() < n + 1 {
+                    return Err(Error::msg("Truncated serial frame topic"))
+                }
+                (&m[1..n+1], &m[n+1..])
+            },
+        };
+
+        let topic = std::str::from_utf8(topic).map_err(|_| Error::msg("Invalid serial frame topic"))?;
+
+        Ok((topic.to_string(), data.to_vec()))
+    }
+
+    /// Extract the next complete frame from the receive buffer
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let delim = match self.framing {
+            SerialFraming::Line => b'\n',
+            SerialFraming::Cobs => 0,
+        };
+
+        loop {
+            let i = match self.buf.iter().position(|c| *c == delim) {
+                Some(i) => i,
+                None => {
+                    // Discard oversized frames until the next delimiter
+                    if self.buf.len() > self.max_frame {
+                        warn!("Discarding serial frame exceeding {} bytes", self.max_frame);
+                        self.buf.clear();
+                        self.discarding = true;
+                    }
+                    return None
+                },
+            };
+
+            let f: Vec<u8> = self.buf.drain(..=i).take(i).collect();
+
+            if self.discarding {
+                self.discarding = false;
+                continue
+            }
+            if f.is_empty() || f.len() > self.max_frame {
+                continue
+            }
+
+            return Some(f)
+        }
+    }
+}
+
+/// COBS encode a message (without the trailing delimiter)
+fn cobs_encode(d: &[u8]) -> Vec<u8> {
+    let mut o = Vec::with_capacity(d.len() + d.len() / 254 + 2);
+    let mut code_idx = 0;
+    o.push(0);
+
+    for b in d {
+        if *b != 0 {
+            o.push(*b);
+        }
+        if *b == 0 || o.len() - code_idx == 0xff {
+            o[code_idx] = (o.len() - code_idx) as u8;
+            code_idx = o.len();
+            o.push(0);
+        }
+    }
+
+    o[code_idx] = (o.len() - code_idx) as u8;
+    o
+}
+
+/// COBS decode a frame (without the trailing delimiter)
+fn cobs_decode(d: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut o = Vec::with_capacity(d.len());
+    let mut i = 0;
+
+    while i < d.len() {
+        let code = d[i] as usize;
+        if code == 0 || i + code > d.len() {
+            return Err(Error::msg("Invalid COBS frame"))
+        }
+
+        o.extend_from_slice(&d[i+1..i+code]);
+        i += code;
+
+        if code < 0xff && i < d.len() {
+            o.push(0);
+        }
+    }
+
+    Ok(o)
+}
+
+fn read(rx: Arc<AsyncMutex<ReadHalf<Serial>>>) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+    async move {
+        let mut buff = vec![0u8; READ_LEN];
+        let n = rx.lock().await.read(&mut buff).await?;
+        buff.truncate(n);
+        Ok(buff)
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for SerialClient {
+    /// Flush pending writes and stop receiving
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.reading = None;
+        self.tx.lock().await.flush().await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for SerialClient {
+    /// Write a framed message (the topic is ignored where frames do not carry topics)
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let f = self.encode(topic, data)?;
+
+        let tx = self.tx.clone();
+        let write = async move {
+            let mut tx = tx.lock().await;
+            tx.write_all(&f).await?;
+            tx.flush().await
+        };
+
+        match tokio::time::timeout(self.request_timeout, write).await {
+            Ok(r) => Ok(r?),
+            Err(_) => Err(PalError::Timeout{ operation: format!("Serial write to {}", self.topic), timeout: self.request_timeout }.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientSub for SerialClient {
+    /// Subscribe to received frames with a matching topic (supporting `+` and `#` wildcards)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        match self.subs.iter().position(|s| s == topic) {
+            Some(i) => {
+                self.subs.remove(i);
+                Ok(())
+            },
+            None => Err(Error::msg(format!("Not subscribed to {}", topic))),
+        }
+    }
+}
+
+/// Stream implementation for SerialClient, emitting received frames matching subscriptions
+impl Stream for SerialClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            if let Some(f) = this.next_frame() {
+                match this.decode(&f) {
+                    Ok((t, d)) if this.subs.iter().any(|s| topic_matches(s, &t)) => return Poll::Ready(Some((t, d))),
+                    Ok(_) => (),
+                    Err(e) => warn!("Invalid serial frame: {:?}", e),
+                }
+                continue
+            }
+
+            if this.reading.is_none() {
+                this.reading = Some(read(this.rx.clone()));
+            }
+
+            match this.reading.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok(d)) if !d.is_empty() => {
+                    this.reading = None;
+                    this.buf.extend_from_slice(&d);
+                },
+                Poll::Ready(r) => {
+                    this.reading = None;
+                    if let Err(e) = r {
+                        warn!("Serial read failed: {:?}", e);
+                    }
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,1002 @@
+//! SNMP (v2c) client, polling OIDs on an interval and receiving traps
+//!
+//! OIDs (ie. `1.3.6.1.2.1.1.3.0`) are used as topics. Subscribing to an OID polls the agent
+//! each poll interval, emitting the value as text. Subscribing to `trap` (or `trap/<oid>` for
+//! traps with a matching snmpTrapOID prefix) emits traps received on the trap listener as
+//! `trap/<oid>` with variable bindings as `oid=value` lines. Publishing sets an OID, encoding
+//! the value using the type of the current value (read prior to setting).
+//!
+//! SNMPv1 traps are converted to SNMPv2 trap OIDs (RFC 3584), informs are acknowledged.
+//! OIDs are only polled and traps received while the client is polled as a `Stream`.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use futures::lock::Mutex as AsyncMutex;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::time::{Delay, Instant, delay_until, timeout_at};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+/// Default SNMP agent port
+pub const DEFAULT_PORT: u16 = 161;
+
+/// Default community
+pub const DEFAULT_COMMUNITY: &str = "public";
+
+/// Default interval for polling subscribed OIDs
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Topic (and prefix) for trap subscriptions
+pub const TRAP_TOPIC: &str = "trap";
+
+/// Interval before retransmitting requests
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum SNMP datagram size
+const MAX_PACKET_LEN: usize = 65507;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_IP_ADDRESS: u8 = 0x40;
+const TAG_COUNTER32: u8 = 0x41;
+const TAG_GAUGE32: u8 = 0x42;
+const TAG_TIMETICKS: u8 = 0x43;
+const TAG_OPAQUE: u8 = 0x44;
+const TAG_COUNTER64: u8 = 0x46;
+const TAG_NO_SUCH_OBJECT: u8 = 0x80;
+const TAG_NO_SUCH_INSTANCE: u8 = 0x81;
+const TAG_END_OF_MIB_VIEW: u8 = 0x82;
+
+const PDU_GET: u8 = 0xa0;
+const PDU_RESPONSE: u8 = 0xa2;
+const PDU_SET: u8 = 0xa3;
+const PDU_TRAP_V1: u8 = 0xa4;
+const PDU_INFORM: u8 = 0xa6;
+const PDU_TRAP_V2: u8 = 0xa7;
+
+/// snmpTrapOID.0, the trap identifier binding in SNMPv2 traps
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+/// Generic trap OID prefix (snmpTraps) for SNMPv1 generic traps
+const SNMP_TRAPS: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 5];
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnmpOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// SNMP agent address (optionally prefixed with snmp://, port defaults to 161)
+    pub snmp_agent: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env, default_value = "public"))]
+    /// Community for requests (and accepted traps)
+    pub snmp_community: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// OIDs to poll on connection
+    pub snmp_oids: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval for polling subscribed OIDs (defaults to 10s)
+    pub snmp_poll_interval: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Address to bind for receiving traps (ie. 0.0.0.0:162)
+    pub snmp_trap_listen: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Accept traps with any community
+    pub snmp_trap_any_community: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for requests, including retries (defaults to `TransportDefaults::request_timeout`)
+    pub snmp_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for SnmpOptions {
+    fn from(agent: &str) -> Self {
+        Self {
+            snmp_agent: agent.to_string(),
+            snmp_community: DEFAULT_COMMUNITY.to_string(),
+            snmp_oids: vec![],
+            snmp_poll_interval: None,
+            snmp_trap_listen: None,
+            snmp_trap_any_community: false,
+            snmp_request_timeout: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// SNMP object identifier
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Oid(pub Vec<u32>);
+
+impl Oid {
+    /// Check whether this OID starts with the provided prefix
+    pub fn starts_with(&self, prefix: &Oid) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut b = vec![];
+        let (first, rest) = match self.0.as_slice() {
+            [a, b, rest @ ..] => (a * 40 + b, rest),
+            [a] => (a * 40, &[][..]),
+            [] => (0, &[][..]),
+        };
+
+        for v in std::iter::once(&first).chain(rest.iter()) {
+            let mut v = *v;
+            let mut s = vec![(v & 0x7f) as u8];
+            v >>= 7;
+            while v > 0 {
+                s.push((v & 0x7f) as u8 | 0x80);
+                v >>= 7;
+            }
+            s.reverse();
+            b.extend_from_slice(&s);
+        }
+
+        b
+    }
+
+    fn decode(d: &[u8]) -> Result<Self, Error> {
+        let mut v = vec![];
+        let mut acc: u32 = 0;
+
+        for (i, b) in d.iter().enumerate() {
+            acc = acc.checked_mul(128).ok_or_else(|| Error::msg("SNMP OID component overflow"))? | (b & 0x7f) as u32;
+            if b & 0x80 != 0 {
+                if i == d.len() - 1 {
+                    return Err(Error::msg("Truncated SNMP OID"))
+                }
+                continue
+            }
+
+            if v.is_empty() {
+                let first = std::cmp::min(acc / 40, 2);
+                v.push(first);
+                v.push(acc - first * 40);
+            } else {
+                v.push(acc);
+            }
+            acc = 0;
+        }
+
+        Ok(Oid(v))
+    }
+}
+
+impl FromStr for Oid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let v: Result<Vec<u32>, _> = s.trim_start_matches('.').split('.').map(|c| c.parse()).collect();
+        match v {
+            Ok(v) if v.len() >= 2 => Ok(Oid(v)),
+            _ => Err(Error::msg(format!("Invalid SNMP OID: {:?} (expected dotted numeric form, ie. 1.3.6.1.2.1.1.3.0)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Oid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s: Vec<_> = self.0.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", s.join("."))
+    }
+}
+
+/// SNMP variable binding value
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnmpValue {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Null,
+    Oid(Oid),
+    IpAddress([u8; 4]),
+    Counter32(u32),
+    Gauge32(u32),
+    TimeTicks(u32),
+    Opaque(Vec<u8>),
+    Counter64(u64),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+impl SnmpValue {
+    /// Check whether the value is an exception (noSuchObject, noSuchInstance, endOfMibView)
+    pub fn is_exception(&self) -> bool {
+        match self {
+            SnmpValue::NoSuchObject | SnmpValue::NoSuchInstance | SnmpValue::EndOfMibView => true,
+            _ => false,
+        }
+    }
+
+    /// Parse a value from text using the type of this value
+    pub fn parse_as(&self, s: &str) -> Result<SnmpValue, Error> {
+        let s = s.trim();
+        let invalid = || Error::msg(format!("Invalid SNMP value for {:?}: {:?}", self, s));
+
+        let v = match self {
+            SnmpValue::Integer(_) => SnmpValue::Integer(s.parse().map_err(|_| invalid())?),
+            SnmpValue::OctetString(_) => SnmpValue::OctetString(s.as_bytes().to_vec()),
+            SnmpValue::Oid(_) => SnmpValue::Oid(Oid::from_str(s)?),
+            SnmpValue::IpAddress(_) => {
+                let a: std::net::Ipv4Addr = s.parse().map_err(|_| invalid())?;
+                SnmpValue::IpAddress(a.octets())
+            },
+            SnmpValue::Counter32(_) => SnmpValue::Counter32(s.parse().map_err(|_| invalid())?),
+            SnmpValue::Gauge32(_) => SnmpValue::Gauge32(s.parse().map_err(|_| invalid())?),
+            SnmpValue::TimeTicks(_) => SnmpValue::TimeTicks(s.parse().map_err(|_| invalid())?),
+            SnmpValue::Counter64(_) => SnmpValue::Counter64(s.parse().map_err(|_| invalid())?),
+            _ => return Err(Error::msg(format!("Setting SNMP {:?} values is not supported", self))),
+        };
+
+        Ok(v)
+    }
+
+    fn decode(tag: u8, d: &[u8]) -> Result<Self, Error> {
+        let v = match tag {
+            TAG_INTEGER => SnmpValue::Integer(signed(d)),
+            TAG_OCTET_STRING => SnmpValue::OctetString(d.to_vec()),
+            TAG_NULL => SnmpValue::Null,
+            TAG_OID => SnmpValue::Oid(Oid::decode(d)?),
+            TAG_IP_ADDRESS if d.len() == 4 => SnmpValue::IpAddress([d[0], d[1], d[2], d[3]]),
+            TAG_COUNTER32 => SnmpValue::Counter32(unsigned(d) as u32),
+            TAG_GAUGE32 => SnmpValue::Gauge32(unsigned(d) as u32),
+            TAG_TIMETICKS => SnmpValue::TimeTicks(unsigned(d) as u32),
+            TAG_OPAQUE => SnmpValue::Opaque(d.to_vec()),
+            TAG_COUNTER64 => SnmpValue::Counter64(unsigned(d)),
+            TAG_NO_SUCH_OBJECT => SnmpValue::NoSuchObject,
+            TAG_NO_SUCH_INSTANCE => SnmpValue::NoSuchInstance,
+            TAG_END_OF_MIB_VIEW => SnmpValue::EndOfMibView,
+            _ => return Err(Error::msg(format!("Unsupported SNMP value type: 0x{:02x} (length: {})", tag, d.len()))),
+        };
+
+        Ok(v)
+    }
+
+    fn encode(&self, b: &mut Vec<u8>) {
+        match self {
+            SnmpValue::Integer(v) => tlv(b, TAG_INTEGER, &signed_bytes(*v)),
+            SnmpValue::OctetString(v) => tlv(b, TAG_OCTET_STRING, v),
+            SnmpValue::Null => tlv(b, TAG_NULL, &[]),
+            SnmpValue::Oid(v) => tlv(b, TAG_OID, &v.encode()),
+            SnmpValue::IpAddress(v) => tlv(b, TAG_IP_ADDRESS, v),
+            SnmpValue::Counter32(v) => tlv(b, TAG_COUNTER32, &unsigned_bytes(*v as u64)),
+            SnmpValue::Gauge32(v) => tlv(b, TAG_GAUGE32, &unsigned_bytes(*v as u64)),
+            SnmpValue::TimeTicks(v) => tlv(b, TAG_TIMETICKS, &unsigned_bytes(*v as u64)),
+            SnmpValue::Opaque(v) => tlv(b, TAG_OPAQUE, v),
+            SnmpValue::Counter64(v) => tlv(b, TAG_COUNTER64, &unsigned_bytes(*v)),
+            SnmpValue::NoSuchObject => tlv(b, TAG_NO_SUCH_OBJECT, &[]),
+            SnmpValue::NoSuchInstance => tlv(b, TAG_NO_SUCH_INSTANCE, &[]),
+            SnmpValue::EndOfMibView => tlv(b, TAG_END_OF_MIB_VIEW, &[]),
+        }
+    }
+}
+
+impl std::fmt::Display for SnmpValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnmpValue::Integer(v) => write!(f, "{}", v),
+            // Octet strings are displayed as text where printable, hex otherwise
+            SnmpValue::OctetString(v) => match std::str::from_utf8(v) {
+                Ok(s) if s.chars().all(|c| !c.is_control() || c.is_whitespace()) => write!(f, "{}", s),
+                _ => v.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+            },
+            SnmpValue::Null => write!(f, "null"),
+            SnmpValue::Oid(v) => write!(f, "{}", v),
+            SnmpValue::IpAddress(v) => write!(f, "{}", std::net::Ipv4Addr::from(*v)),
+            SnmpValue::Counter32(v) | SnmpValue::Gauge32(v) | SnmpValue::TimeTicks(v) => write!(f, "{}", v),
+            SnmpValue::Opaque(v) => v.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+            SnmpValue::Counter64(v) => write!(f, "{}", v),
+            SnmpValue::NoSuchObject => write!(f, "noSuchObject"),
+            SnmpValue::NoSuchInstance => write!(f, "noSuchInstance"),
+            SnmpValue::EndOfMibView => write!(f, "endOfMibView"),
+        }
+    }
+}
+
+/// Decoded SNMP message
+struct Message {
+    version: i64,
+    community: Vec<u8>,
+    pdu: u8,
+    request_id: i32,
+    error_status: i64,
+    error_index: i64,
+    /// Trap OID (for traps, converted from SNMPv1 enterprise / generic / specific values)
+    trap_oid: Option<Oid>,
+    bindings: Vec<(Oid, SnmpValue)>,
+}
+
+impl Message {
+    fn encode(version: i64, community: &[u8], pdu: u8, request_id: i32, bindings: &[(Oid, SnmpValue)]) -> Vec<u8> {
+        let mut vb = vec![];
+        for (o, v) in bindings {
+            let mut b = vec![];
+            tlv(&mut b, TAG_OID, &o.encode());
+            v.encode(&mut b);
+            tlv(&mut vb, TAG_SEQUENCE, &b);
+        }
+
+        let mut p = vec![];
+        tlv(&mut p, TAG_INTEGER, &signed_bytes(request_id as i64));
+        tlv(&mut p, TAG_INTEGER, &[0]);
+        tlv(&mut p, TAG_INTEGER, &[0]);
+        tlv(&mut p, TAG_SEQUENCE, &vb);
+
+        let mut m = vec![];
+        tlv(&mut m, TAG_INTEGER, &signed_bytes(version));
+        tlv(&mut m, TAG_OCTET_STRING, community);
+        tlv(&mut m, pdu, &p);
+
+        let mut b = vec![];
+        tlv(&mut b, TAG_SEQUENCE, &m);
+        b
+    }
+
+    fn decode(d: &[u8]) -> Result<Self, Error> {
+        let mut m = Ber::new(Ber::new(d).expect(TAG_SEQUENCE)?);
+
+        let version = m.integer()?;
+        if version != VERSION_1 && version != VERSION_2C {
+            return Err(Error::msg(format!("Unsupported SNMP version: {}", version)))
+        }
+        let community = m.expect(TAG_OCTET_STRING)?.to_vec();
+        let (pdu, body) = m.tlv()?;
+        let mut p = Ber::new(body);
+
+        let mut msg = Message{ version, community, pdu, request_id: 0, error_status: 0, error_index: 0, trap_oid: None, bindings: vec![] };
+
+        if pdu == PDU_TRAP_V1 {
+            let enterprise = Oid::decode(p.expect(TAG_OID)?)?;
+            p.expect(TAG_IP_ADDRESS)?;
+            let (generic, specific) = (p.integer()?, p.integer()?);
+            p.expect(TAG_TIMETICKS)?;
+
+            // RFC 3584 trap OID conversion
+            msg.trap_oid = Some(match generic {
+                6 => {
+                    let mut o = enterprise;
+                    o.0.push(0);
+                    o.0.push(specific as u32);
+                    o
+                },
+                g => {
+                    let mut o = Oid(SNMP_TRAPS.to_vec());
+                    o.0.push(g as u32 + 1);
+                    o
+                },
+            });
+        } else {
+            msg.request_id = p.integer()? as i32;
+            msg.error_status = p.integer()?;
+            msg.error_index = p.integer()?;
+        }
+
+        let mut vb = Ber::new(p.expect(TAG_SEQUENCE)?);
+        while !vb.d.is_empty() {
+            let mut b = Ber::new(vb.expect(TAG_SEQUENCE)?);
+            let oid = Oid::decode(b.expect(TAG_OID)?)?;
+            let (t, v) = b.tlv()?;
+            msg.bindings.push((oid, SnmpValue::decode(t, v)?));
+        }
+
+        if pdu == PDU_TRAP_V2 || pdu == PDU_INFORM {
+            let trap_oid = Oid(SNMP_TRAP_OID.to_vec());
+            msg.trap_oid = msg.bindings.iter().find(|(o, _)| *o == trap_oid).and_then(|(_, v)| match v {
+                SnmpValue::Oid(o) => Some(o.clone()),
+                _ => None,
+            });
+        }
+
+        Ok(msg)
+    }
+}
+
+fn error_status(s: i64) -> &'static str {
+    match s {
+        1 => "tooBig",
+        2 => "noSuchName",
+        3 => "badValue",
+        4 => "readOnly",
+        5 => "genErr",
+        6 => "noAccess",
+        7 => "wrongType",
+        8 => "wrongLength",
+        10 => "wrongValue",
+        11 => "noCreation",
+        12 => "inconsistentValue",
+        13 => "resourceUnavailable",
+        16 => "authorizationError",
+        17 => "notWritable",
+        _ => "error",
+    }
+}
+
+/// Generic futures-based SNMP client abstraction
+pub struct SnmpClient {
+    tx: Arc<AsyncMutex<SendHalf>>,
+    rx: Arc<AsyncMutex<RecvHalf>>,
+    /// Trap listener, where configured
+    traps: Option<(Arc<AsyncMutex<SendHalf>>, Arc<AsyncMutex<RecvHalf>>)>,
+    agent: SocketAddr,
+    community: String,
+    trap_any_community: bool,
+    request_timeout: Duration,
+
+    /// Polled OID subscriptions
+    subs: Vec<(String, Oid)>,
+    /// Trap subscriptions, with optional trap OID prefix
+    trap_subs: Vec<(String, Option<Oid>)>,
+    poll_interval: Duration,
+    timer: Delay,
+    /// Request ID of the outstanding poll
+    poll_id: Option<i32>,
+
+    request_id: i32,
+    /// Received values and traps pending delivery via `Stream`
+    inbox: VecDeque<(String, Vec<u8>)>,
+    /// Packets (ie. polls and inform responses) pending transmission
+    outbox: VecDeque<(Arc<AsyncMutex<SendHalf>>, SocketAddr, Vec<u8>)>,
+    sending: Option<BoxFuture<'static, Result<(), Error>>>,
+    receiving: Option<BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>>>,
+    receiving_trap: Option<BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>>>,
+
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Cleared on disconnect or when a socket fails
+    connected: bool,
+}
+
+impl SnmpClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<SnmpOptions>>(opts: O) -> Result<SnmpClient, Error> {
+        let o = opts.into();
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let (rx, tx) = socket.split();
+
+        let traps = match &o.snmp_trap_listen {
+            Some(addr) => {
+                let s = UdpSocket::bind(addr.as_str()).await
+                    .map_err(|e| Error::msg(format!("Failed to bind SNMP trap listener {:?}: {}", addr, e)))?;
+                let (rx, tx) = s.split();
+                Some((Arc::new(AsyncMutex::new(tx)), Arc::new(AsyncMutex::new(rx))))
+            },
+            None => None,
+        };
+
+        let agent = resolve(&o.snmp_agent).await?;
+
+        let mut subs = vec![];
+        for oid in &o.snmp_oids {
+            subs.push((oid.clone(), Oid::from_str(oid)?));
+        }
+
+        debug!("Created SNMP client for agent {} (trap listener: {:?})", agent, o.snmp_trap_listen);
+
+        Ok(SnmpClient{
+            tx: Arc::new(AsyncMutex::new(tx)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            traps,
+            agent,
+            community: o.snmp_community,
+            trap_any_community: o.snmp_trap_any_community,
+            request_timeout: o.snmp_request_timeout.unwrap_or(o.defaults.request_timeout),
+            subs,
+            trap_subs: vec![],
+            poll_interval: o.snmp_poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL),
+            timer: delay_until(Instant::now()),
+            poll_id: None,
+            request_id: rand::random::<u16>() as i32,
+            inbox: VecDeque::new(),
+            outbox: VecDeque::new(),
+            sending: None,
+            receiving: None,
+            receiving_trap: None,
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Fetch values for the provided OIDs
+    pub async fn get(&mut self, oids: &[Oid]) -> Result<Vec<(Oid, SnmpValue)>, Error> {
+        let b: Vec<_> = oids.iter().map(|o| (o.clone(), SnmpValue::Null)).collect();
+        self.transact(PDU_GET, &b).await
+    }
+
+    /// Set values for the provided OIDs
+    pub async fn set(&mut self, bindings: &[(Oid, SnmpValue)]) -> Result<Vec<(Oid, SnmpValue)>, Error> {
+        self.transact(PDU_SET, bindings).await
+    }
+
+    /// Send a request and wait for the response, retransmitting until the request timeout
+    ///
+    /// Poll responses received while waiting are handled as usual.
+    async fn transact(&mut self, pdu: u8, bindings: &[(Oid, SnmpValue)]) -> Result<Vec<(Oid, SnmpValue)>, Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let request_id = self.next_request_id();
+        let req = Message::encode(VERSION_2C, self.community.as_bytes(), pdu, request_id, bindings);
+
+        let deadline = Instant::now() + self.request_timeout;
+
+        while Instant::now() < deadline {
+            self.outbox.push_back((self.tx.clone(), self.agent, req.clone()));
+            self.flush().await?;
+
+            let retry = std::cmp::min(Instant::now() + RETRY_INTERVAL, deadline);
+            loop {
+                let (from, d) = match timeout_at(retry, self.recv()).await {
+                    Ok(r) => r?,
+                    Err(_) => break,
+                };
+
+                let m = match Message::decode(&d) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        warn!("Invalid SNMP message from {}: {:?}", from, e);
+                        continue
+                    },
+                };
+
+                if m.pdu != PDU_RESPONSE || m.request_id != request_id || from != self.agent {
+                    self.handle(from, m);
+                    continue
+                }
+
+                if m.error_status != 0 {
+                    let oid = m.bindings.get(m.error_index.saturating_sub(1) as usize).map(|(o, _)| o.to_string());
+                    return Err(Error::msg(format!("SNMP request failed: {} (OID: {})", error_status(m.error_status), oid.unwrap_or_default())))
+                }
+
+                return Ok(m.bindings)
+            }
+        }
+
+        Err(PalError::Timeout{ operation: format!("SNMP request to {}", self.agent), timeout: self.request_timeout }.into())
+    }
+
+    /// Handle poll responses from the agent
+    fn handle(&mut self, from: SocketAddr, m: Message) {
+        if m.pdu != PDU_RESPONSE || self.poll_id != Some(m.request_id) || from != self.agent {
+            debug!("Ignoring SNMP message (PDU: 0x{:02x}) from {}", m.pdu, from);
+            return
+        }
+        self.poll_id = None;
+
+        if m.error_status != 0 {
+            warn!("SNMP poll failed: {} (index: {})", error_status(m.error_status), m.error_index);
+            self.last_error = Some((std::time::Instant::now(), PalError::Subscription{
+                topic: self.subs.get(m.error_index.saturating_sub(1) as usize).map(|(t, _)| t.clone()).unwrap_or_default(),
+                error: error_status(m.error_status).to_string(),
+            }));
+            return
+        }
+
+        for (oid, v) in m.bindings {
+            for (topic, _) in self.subs.iter().filter(|(_, o)| *o == oid) {
+                if v.is_exception() {
+                    warn!("SNMP poll of {} failed: {}", topic, v);
+                    self.last_error = Some((std::time::Instant::now(), PalError::Subscription{ topic: topic.clone(), error: v.to_string() }));
+                    continue
+                }
+
+                self.inbox.push_back((topic.clone(), v.to_string().into_bytes()));
+            }
+        }
+    }
+
+    /// Handle a received trap, acknowledging informs
+    fn handle_trap(&mut self, from: SocketAddr, d: &[u8]) {
+        let m = match Message::decode(d) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Invalid SNMP trap from {}: {:?}", from, e);
+                return
+            },
+        };
+
+        if !self.trap_any_community && m.community != self.community.as_bytes() {
+            debug!("Ignoring SNMP trap from {} with unexpected community", from);
+            return
+        }
+
+        if m.pdu == PDU_INFORM {
+            if let Some((tx, _)) = &self.traps {
+                let r = Message::encode(m.version, &m.community, PDU_RESPONSE, m.request_id, &m.bindings);
+                self.outbox.push_back((tx.clone(), from, r));
+            }
+        }
+
+        let trap_oid = match (m.pdu, m.trap_oid) {
+            (PDU_TRAP_V1, Some(o)) | (PDU_TRAP_V2, Some(o)) | (PDU_INFORM, Some(o)) => o,
+            (p, _) => {
+                debug!("Ignoring SNMP message (PDU: 0x{:02x}, version: {}) from {}", p, m.version, from);
+                return
+            },
+        };
+
+        if !self.trap_subs.iter().any(|(_, p)| p.as_ref().map(|p| trap_oid.starts_with(p)).unwrap_or(true)) {
+            return
+        }
+
+        let lines: Vec<_> = m.bindings.iter().map(|(o, v)| format!("{}={}", o, v)).collect();
+        self.inbox.push_back((format!("{}/{}", TRAP_TOPIC, trap_oid), lines.join("\n").into_bytes()));
+    }
+
+    /// Send queued packets
+    async fn flush(&mut self) -> Result<(), Error> {
+        if let Some(f) = self.sending.take() {
+            f.await?;
+        }
+        while let Some((tx, addr, d)) = self.outbox.pop_front() {
+            send_packet(tx, addr, d).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive a packet from the agent socket, resuming any receive started by `Stream`
+    async fn recv(&mut self) -> Result<(SocketAddr, Vec<u8>), Error> {
+        let f = match self.receiving.take() {
+            Some(f) => f,
+            None => recv_packet(self.rx.clone()),
+        };
+
+        f.await
+    }
+
+    fn next_request_id(&mut self) -> i32 {
+        self.request_id = self.request_id.wrapping_add(1) & 0x7fff_ffff;
+        self.request_id
+    }
+}
+
+/// Resolve an snmp:// address to a socket address (defaulting to port 161)
+async fn resolve(url: &str) -> Result<SocketAddr, Error> {
+    let host = url.trim_start_matches("snmp://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    let host = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:{}", host, DEFAULT_PORT),
+    };
+
+    match tokio::net::lookup_host(host.as_str()).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(Error::msg(format!("Could not resolve SNMP agent {:?}", url))),
+    }
+}
+
+fn send_packet(tx: Arc<AsyncMutex<SendHalf>>, addr: SocketAddr, data: Vec<u8>) -> BoxFuture<'static, Result<(), Error>> {
+    async move {
+        tx.lock().await.send_to(&data, &addr).await?;
+        Ok(())
+    }.boxed()
+}
+
+fn recv_packet(rx: Arc<AsyncMutex<RecvHalf>>) -> BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>> {
+    async move {
+        let mut buff = vec![0u8; MAX_PACKET_LEN];
+        let (n, from) = rx.lock().await.recv_from(&mut buff).await?;
+        buff.truncate(n);
+        Ok((from, buff))
+    }.boxed()
+}
+
+fn unsigned(d: &[u8]) -> u64 {
+    d.iter().fold(0, |a, b| (a << 8) | *b as u64)
+}
+
+fn signed(d: &[u8]) -> i64 {
+    match d.first() {
+        Some(b) if b & 0x80 != 0 => d.iter().fold(-1, |a, b| (a << 8) | *b as i64),
+        _ => unsigned(d) as i64,
+    }
+}
+
+/// Encode an unsigned value, with a leading zero where the high bit is set
+fn unsigned_bytes(v: u64) -> Vec<u8> {
+    let b = v.to_be_bytes();
+    let i = b.iter().position(|b| *b != 0).unwrap_or(7);
+    let mut o = b[i..].to_vec();
+    if o[0] & 0x80 != 0 {
+        o.insert(0, 0);
+    }
+    o
+}
+
+fn signed_bytes(v: i64) -> Vec<u8> {
+    let mut b = v.to_be_bytes().to_vec();
+    while b.len() > 1 && ((b[0] == 0x00 && b[1] & 0x80 == 0) || (b[0] == 0xff && b[1] & 0x80 != 0)) {
+        b.remove(0);
+    }
+    b
+}
+
+/// Write a BER tag, length and value
+fn tlv(b: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    b.push(tag);
+
+    let len = data.len();
+    if len < 0x80 {
+        b.push(len as u8);
+    } else {
+        let l = (len as u32).to_be_bytes();
+        let i = l.iter().position(|b| *b != 0).unwrap_or(3);
+        b.push(0x80 | (4 - i) as u8);
+        b.extend_from_slice(&l[i..]);
+    }
+
+    b.extend_from_slice(data);
+}
+
+/// BER decoder
+struct Ber<'a> {
+    d: &'a [u8],
+}
+
+impl <'a> Ber<'a> {
+    fn new(d: &'a [u8]) -> Self {
+        Self{ d }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.d.len() < n {
+            return Err(Error::msg("Truncated SNMP message"))
+        }
+        let (a, b) = self.d.split_at(n);
+        self.d = b;
+        Ok(a)
+    }
+
+    fn tlv(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let tag = self.take(1)?[0];
+
+        let len = match self.take(1)?[0] {
+            l if l & 0x80 == 0 => l as usize,
+            l => {
+                let n = (l & 0x7f) as usize;
+                if n == 0 || n > 4 {
+                    return Err(Error::msg("Unsupported SNMP length encoding"))
+                }
+                unsigned(self.take(n)?) as usize
+            },
+        };
+
+        Ok((tag, self.take(len)?))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], Error> {
+        match self.tlv()? {
+            (t, d) if t == tag => Ok(d),
+            (t, _) => Err(Error::msg(format!("Unexpected SNMP type 0x{:02x} (expected 0x{:02x})", t, tag))),
+        }
+    }
+
+    fn integer(&mut self) -> Result<i64, Error> {
+        Ok(signed(self.expect(TAG_INTEGER)?))
+    }
+}
+
+#[async_trait]
+impl ClientBase for SnmpClient {
+    /// Stop polling and receiving traps
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+
+        self.connected = false;
+        self.subs.clear();
+        self.trap_subs.clear();
+        self.receiving_trap = None;
+        self.traps = None;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for SnmpClient {
+    /// Set an OID value (as text), using the type of the current value
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let oid = Oid::from_str(topic)?;
+        let text = std::str::from_utf8(data)
+            .map_err(|_| Error::msg(format!("SNMP values must be UTF-8 text (topic: {})", topic)))?;
+
+        let current = self.get(&[oid.clone()]).await?;
+        let value = match current.first() {
+            Some((_, v)) if !v.is_exception() => v.parse_as(text)?,
+            Some((_, v)) => return Err(Error::msg(format!("SNMP OID {} has no value to infer a type from ({})", oid, v))),
+            None => return Err(Error::msg(format!("SNMP OID {} has no value to infer a type from", oid))),
+        };
+
+        self.set(&[(oid, value)]).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for SnmpClient {
+    /// Subscribe to an OID (polled each poll interval) or to traps (`trap` or `trap/<oid>`)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|(t, _)| t == topic) || self.trap_subs.iter().any(|(t, _)| t == topic) {
+            return Ok(())
+        }
+
+        if topic == TRAP_TOPIC || topic.starts_with("trap/") {
+            if self.traps.is_none() {
+                return Err(Error::msg("SNMP trap subscriptions require a trap listener (snmp_trap_listen)"))
+            }
+
+            let prefix = match topic.strip_prefix("trap/") {
+                Some(o) => Some(Oid::from_str(o)?),
+                None => None,
+            };
+            self.trap_subs.push((topic.to_string(), prefix));
+        } else {
+            self.subs.push((topic.to_string(), Oid::from_str(topic)?));
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribe from an OID or traps
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if let Some(i) = self.subs.iter().position(|(t, _)| t == topic) {
+            self.subs.remove(i);
+            return Ok(())
+        }
+        if let Some(i) = self.trap_subs.iter().position(|(t, _)| t == topic) {
+            self.trap_subs.remove(i);
+            return Ok(())
+        }
+
+        Err(Error::msg(format!("Not subscribed to {}", topic)))
+    }
+}
+
+/// Stream implementation for SnmpClient
+///
+/// Polling the stream polls subscribed OIDs each poll interval and receives traps.
+impl Stream for SnmpClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(m) = this.inbox.pop_front() {
+                return Poll::Ready(Some(m))
+            }
+
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            // Poll subscribed OIDs, responses are matched in `handle`
+            if let Poll::Ready(_) = Pin::new(&mut this.timer).poll(cx) {
+                this.timer.reset(Instant::now() + this.poll_interval);
+
+                if !this.subs.is_empty() {
+                    if this.poll_id.is_some() {
+                        warn!("SNMP poll timed out");
+                    }
+
+                    let request_id = this.next_request_id();
+                    let b: Vec<_> = this.subs.iter().map(|(_, o)| (o.clone(), SnmpValue::Null)).collect();
+                    let req = Message::encode(VERSION_2C, this.community.as_bytes(), PDU_GET, request_id, &b);
+
+                    this.poll_id = Some(request_id);
+                    this.outbox.push_back((this.tx.clone(), this.agent, req));
+                }
+            }
+
+            // Drive pending transmissions
+            loop {
+                if this.sending.is_none() {
+                    match this.outbox.pop_front() {
+                        Some((tx, addr, d)) => this.sending = Some(send_packet(tx, addr, d)),
+                        None => break,
+                    }
+                }
+
+                match this.sending.as_mut().unwrap().poll_unpin(cx) {
+                    Poll::Ready(r) => {
+                        this.sending = None;
+                        if let Err(e) = r {
+                            warn!("SNMP send failed: {:?}", e);
+                        }
+                    },
+                    Poll::Pending => break,
+                }
+            }
+
+            // Receive traps
+            if let Some(rx) = this.traps.as_ref().map(|(_, rx)| rx.clone()) {
+                if this.receiving_trap.is_none() {
+                    this.receiving_trap = Some(recv_packet(rx));
+                }
+
+                match this.receiving_trap.as_mut().unwrap().poll_unpin(cx) {
+                    Poll::Ready(Ok((from, d))) => {
+                        this.receiving_trap = None;
+                        this.handle_trap(from, &d);
+                        continue
+                    },
+                    Poll::Ready(Err(e)) => {
+                        this.receiving_trap = None;
+                        warn!("SNMP trap receive failed: {:?}", e);
+                        this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                        this.connected = false;
+                        return Poll::Ready(None)
+                    },
+                    Poll::Pending => (),
+                }
+            }
+
+            if this.receiving.is_none() {
+                this.receiving = Some(recv_packet(this.rx.clone()));
+            }
+
+            match this.receiving.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok((from, d))) => {
+                    this.receiving = None;
+
+                    match Message::decode(&d) {
+                        Ok(m) => this.handle(from, m),
+                        Err(e) => warn!("Invalid SNMP message from {}: {:?}", from, e),
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    this.receiving = None;
+                    warn!("SNMP receive failed: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
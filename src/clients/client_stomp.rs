@@ -0,0 +1,632 @@
+//! STOMP (v1.2) client over TCP or TLS
+//!
+//! Topics are mapped to broker destinations by replacing `/` separators with `.` and MQTT-style
+//! wildcards with ActiveMQ / Artemis wildcards (`+` to `*`, `#` to `>`) under a destination prefix
+//! (ie. `sensors/+/temp` maps to `/topic/sensors.*.temp`), unless raw destinations are enabled.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{Delay, Instant, delay_until, timeout};
+use tokio_rustls::TlsConnector;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// Default destination prefix for mapped topics
+pub const DEFAULT_DESTINATION_PREFIX: &str = "/topic/";
+
+/// Size of socket reads
+const READ_LEN: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StompOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for STOMP broker (prefixed with stomp:// or stomp+ssl://)
+    pub stomp_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Virtual host sent in the connect frame (defaults to the URL host)
+    pub stomp_vhost: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "/topic/"))]
+    /// Prefix for destinations mapped from topics
+    pub stomp_destination_prefix: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Use topics as destinations without mapping
+    pub stomp_raw_destinations: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval at which the client can send heartbeats, 0 to disable (defaults to `TransportDefaults::keepalive`)
+    pub stomp_heartbeat_send: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Interval at which the client expects heartbeats, 0 to disable (defaults to `TransportDefaults::keepalive`)
+    pub stomp_heartbeat_recv: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// STOMP connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    pub stomp_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for StompOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            stomp_url: url.to_string(),
+            stomp_vhost: None,
+            stomp_destination_prefix: DEFAULT_DESTINATION_PREFIX.to_string(),
+            stomp_raw_destinations: false,
+            stomp_heartbeat_send: None,
+            stomp_heartbeat_recv: None,
+            stomp_connect_timeout: None,
+            tls_opts: TlsOptions::default(),
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for StompOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Transport stream, either plain TCP or TLS
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl <T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// STOMP frame
+#[derive(Debug, Clone, PartialEq)]
+struct Frame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Frame {
+    fn new(command: &str) -> Self {
+        Self{ command: command.to_string(), headers: vec![], body: vec![] }
+    }
+
+    fn header(mut self, k: &str, v: &str) -> Self {
+        self.headers.push((k.to_string(), v.to_string()));
+        self
+    }
+
+    /// Fetch a header value (the first occurrence takes precedence)
+    fn get(&self, k: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == k).map(|(_, v)| v.as_str())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // Header values are not escaped in CONNECT frames
+        let escape = self.command != "CONNECT";
+
+        let mut b = self.command.clone().into_bytes();
+        b.push(b'\n');
+        for (k, v) in &self.headers {
+            match escape {
+                true => b.extend_from_slice(format!("{}:{}\n", escape_header(k), escape_header(v)).as_bytes()),
+                false => b.extend_from_slice(format!("{}:{}\n", k, v).as_bytes()),
+            }
+        }
+        if !self.body.is_empty() {
+            b.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        b.push(b'\n');
+        b.extend_from_slice(&self.body);
+        b.push(0);
+        b
+    }
+
+    /// Decode a frame from the start of the buffer, removing consumed data (including heartbeats)
+    fn decode(buf: &mut Vec<u8>) -> Result<Option<Frame>, Error> {
+        // Skip heartbeats
+        let n = buf.iter().take_while(|c| **c == b'\n' || **c == b'\r').count();
+        buf.drain(..n);
+
+        // Locate the end of headers
+        let head_end = match buf.windows(2).position(|w| w == b"\n\n") {
+            Some(i) => i,
+            None => match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(i) => i + 2,
+                None => return Ok(None),
+            },
+        };
+
+        let head = std::str::from_utf8(&buf[..head_end])?;
+        let mut lines = head.lines();
+
+        let command = lines.next().unwrap_or_default().trim_end_matches('\r').to_string();
+        let escaped = command != "CONNECTED";
+        let mut headers = vec![];
+        for l in lines {
+            let l = l.trim_end_matches('\r');
+            let i = l.find(':').ok_or_else(|| Error::msg(format!("Invalid STOMP header: {:?}", l)))?;
+            let (k, v) = (&l[..i], &l[i+1..]);
+            match escaped {
+                true => headers.push((unescape_header(k)?, unescape_header(v)?)),
+                false => headers.push((k.to_string(), v.to_string())),
+            }
+        }
+
+        let body_start = head_end + 2;
+        let len = headers.iter().find(|(k, _)| k == "content-length")
+            .map(|(_, v)| v.parse::<usize>())
+            .transpose()
+            .map_err(|_| Error::msg("Invalid STOMP content-length header"))?;
+
+        let body_end = match len {
+            Some(l) if buf.len() > body_start + l => body_start + l,
+            Some(_) => return Ok(None),
+            None => match buf[body_start..].iter().position(|c| *c == 0) {
+                Some(i) => body_start + i,
+                None => return Ok(None),
+            },
+        };
+
+        if buf[body_end] != 0 {
+            return Err(Error::msg("STOMP frame missing NULL terminator"))
+        }
+
+        let body = buf[body_start..body_end].to_vec();
+        buf.drain(..body_end + 1);
+
+        Ok(Some(Frame{ command, headers, body }))
+    }
+}
+
+fn escape_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\r', "\\r").replace('\n', "\\n").replace(':', "\\c")
+}
+
+fn unescape_header(s: &str) -> Result<String, Error> {
+    let mut o = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            o.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('\\') => o.push('\\'),
+            Some('r') => o.push('\r'),
+            Some('n') => o.push('\n'),
+            Some('c') => o.push(':'),
+            e => return Err(Error::msg(format!("Invalid STOMP header escape: \\{:?}", e))),
+        }
+    }
+
+    Ok(o)
+}
+
+/// Parse a heart-beat header (`cx,cy` in milliseconds)
+fn parse_heartbeat(v: Option<&str>) -> (u64, u64) {
+    let mut p = v.unwrap_or("0,0").split(',').map(|v| v.trim().parse().unwrap_or(0));
+    (p.next().unwrap_or(0), p.next().unwrap_or(0))
+}
+
+/// Generic futures-based STOMP client abstraction
+pub struct StompClient {
+    rx: Arc<AsyncMutex<ReadHalf<Box<dyn Io>>>>,
+    tx: Arc<AsyncMutex<WriteHalf<Box<dyn Io>>>>,
+    /// Received data pending decoding
+    buf: Vec<u8>,
+    reading: Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+    sending: Option<BoxFuture<'static, Result<(), Error>>>,
+
+    /// Negotiated heartbeat send interval and timer
+    heartbeat: Option<(Duration, Delay)>,
+    /// Negotiated receive timeout (twice the server heartbeat interval) and watchdog
+    watchdog: Option<(Duration, Delay)>,
+
+    prefix: String,
+    raw: bool,
+    /// Subscribed topics and subscription IDs
+    subs: Vec<(String, u32)>,
+    next_id: u32,
+
+    last_error: Option<(std::time::Instant, PalError)>,
+    /// Cleared on disconnect or when the connection is lost
+    connected: bool,
+}
+
+impl StompClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<StompOptions>>(opts: O) -> Result<StompClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.stomp_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        match timeout(connect_timeout, Self::connect(&o)).await {
+            Ok(r) => r.map_err(|e| o.tls_opts.with_diagnostics(&o.stomp_url, e)),
+            Err(_) => Err(PalError::Timeout{ operation: format!("STOMP connect to {}", o.stomp_url), timeout: connect_timeout }.into()),
+        }
+    }
+
+    async fn connect(o: &StompOptions) -> Result<StompClient, Error> {
+        let (secure, rest) = match o.stomp_url.find("://") {
+            Some(i) => match &o.stomp_url[..i] {
+                "stomp" => (false, &o.stomp_url[i+3..]),
+                "stomp+ssl" | "stomps" => (true, &o.stomp_url[i+3..]),
+                _ => return Err(Error::msg(format!("STOMP URL must be prefixed with stomp:// or stomp+ssl:// ({:?})", o.stomp_url))),
+            },
+            None => (false, o.stomp_url.as_str()),
+        };
+
+        let rest = rest.split('/').next().unwrap_or(rest);
+        let (host, port) = match rest.rfind(':') {
+            Some(i) => (&rest[..i], rest[i+1..].parse::<u16>()
+                .map_err(|_| Error::msg(format!("Invalid port in STOMP URL: {:?}", o.stomp_url)))?),
+            None => (rest, if secure { 61614 } else { 61613 }),
+        };
+
+        debug!("Connecting to STOMP broker {}:{} (tls: {})", host, port, secure);
+
+        let tcp = TcpStream::connect((host, port)).await?;
+
+        let stream: Box<dyn Io> = match secure {
+            true => {
+                let config = o.tls_opts.build_rustls_config()?;
+                let name = webpki::DNSNameRef::try_from_ascii_str(host)
+                    .map_err(|_| Error::msg(format!("Invalid DNS name for TLS: {:?}", host)))?;
+
+                let tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+                Box::new(tls)
+            },
+            false => Box::new(tcp),
+        };
+
+        let (rx, tx) = tokio::io::split(stream);
+
+        let mut c = StompClient{
+            rx: Arc::new(AsyncMutex::new(rx)),
+            tx: Arc::new(AsyncMutex::new(tx)),
+            buf: vec![],
+            reading: None,
+            sending: None,
+            heartbeat: None,
+            watchdog: None,
+            prefix: o.stomp_destination_prefix.clone(),
+            raw: o.stomp_raw_destinations,
+            subs: vec![],
+            next_id: 1,
+            last_error: None,
+            connected: false,
+        };
+
+        // Send connect frame with heartbeat intervals
+        let cx = o.stomp_heartbeat_send.unwrap_or(o.defaults.keepalive).as_millis() as u64;
+        let cy = o.stomp_heartbeat_recv.unwrap_or(o.defaults.keepalive).as_millis() as u64;
+
+        let mut f = Frame::new("CONNECT")
+            .header("accept-version", "1.2")
+            .header("host", o.stomp_vhost.as_deref().unwrap_or(host))
+            .header("heart-beat", &format!("{},{}", cx, cy));
+
+        match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(u), Some(p)) => f = f.header("login", u).header("passcode", p),
+            (Some(u), None) => f = f.header("login", u),
+            (None, Some(_)) => return Err(Error::msg("STOMP passcode requires a login")),
+            (None, None) => (),
+        }
+
+        write(c.tx.clone(), f.encode()).await?;
+
+        let resp = c.recv().await?;
+        match resp.command.as_str() {
+            "CONNECTED" => (),
+            "ERROR" => return Err(Error::msg(format!("STOMP connect failed: {}", resp.get("message").unwrap_or("unknown error")))),
+            r => return Err(Error::msg(format!("Unexpected STOMP response to connect: {}", r))),
+        }
+
+        // Negotiate heartbeats (0 disables in either direction)
+        let (sx, sy) = parse_heartbeat(resp.get("heart-beat"));
+        if cx != 0 && sy != 0 {
+            let d = Duration::from_millis(cx.max(sy));
+            c.heartbeat = Some((d, delay_until(Instant::now() + d)));
+        }
+        if cy != 0 && sx != 0 {
+            let d = Duration::from_millis(cy.max(sx)) * 2;
+            c.watchdog = Some((d, delay_until(Instant::now() + d)));
+        }
+
+        debug!("Connected to STOMP broker {}:{} (server: {:?}, heartbeats: {:?} / {:?})", host, port,
+            resp.get("server"), c.heartbeat.as_ref().map(|h| h.0), c.watchdog.as_ref().map(|w| w.0));
+
+        c.connected = true;
+
+        Ok(c)
+    }
+
+    /// Map a topic to a destination
+    pub fn destination(&self, topic: &str) -> String {
+        if self.raw {
+            return topic.to_string()
+        }
+
+        let d: Vec<_> = topic.split('/').map(|s| match s {
+            "+" => "*",
+            "#" => ">",
+            s => s,
+        }).collect();
+
+        format!("{}{}", self.prefix, d.join("."))
+    }
+
+    /// Map a received destination to a topic
+    fn topic(&self, destination: &str) -> String {
+        if self.raw {
+            return destination.to_string()
+        }
+
+        destination.trim_start_matches(self.prefix.as_str()).replace('.', "/")
+    }
+
+    /// Send a frame, completing any pending heartbeat first
+    async fn send(&mut self, f: Frame) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        if let Some(s) = self.sending.take() {
+            s.await?;
+        }
+
+        write(self.tx.clone(), f.encode()).await?;
+
+        if let Some((d, t)) = &mut self.heartbeat {
+            t.reset(Instant::now() + *d);
+        }
+
+        Ok(())
+    }
+
+    /// Receive the next frame, resuming any read started by `Stream`
+    async fn recv(&mut self) -> Result<Frame, Error> {
+        loop {
+            if let Some(f) = Frame::decode(&mut self.buf)? {
+                return Ok(f)
+            }
+
+            let r = match self.reading.take() {
+                Some(r) => r,
+                None => read(self.rx.clone()),
+            };
+
+            let d = r.await?;
+            if d.is_empty() {
+                return Err(PalError::ConnectionLost.into())
+            }
+            self.buf.extend_from_slice(&d);
+        }
+    }
+}
+
+fn write<W: AsyncWrite + Unpin + Send + 'static>(tx: Arc<AsyncMutex<W>>, data: Vec<u8>) -> BoxFuture<'static, Result<(), Error>> {
+    async move {
+        let mut tx = tx.lock().await;
+        tx.write_all(&data).await?;
+        tx.flush().await?;
+        Ok(())
+    }.boxed()
+}
+
+fn read<R: AsyncRead + Unpin + Send + 'static>(rx: Arc<AsyncMutex<R>>) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+    async move {
+        let mut buff = vec![0u8; READ_LEN];
+        let n = rx.lock().await.read(&mut buff).await?;
+        buff.truncate(n);
+        Ok(buff)
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for StompClient {
+    /// Disconnect, awaiting the broker receipt
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.send(Frame::new("DISCONNECT").header("receipt", "disconnect")).await?;
+        self.connected = false;
+
+        // Wait for the receipt, dropping any remaining messages
+        loop {
+            let f = self.recv().await?;
+            if f.command == "RECEIPT" && f.get("receipt-id") == Some("disconnect") {
+                break
+            }
+        }
+
+        self.tx.lock().await.shutdown().await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(std::time::Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for StompClient {
+    /// Send data to the destination for a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let mut f = Frame::new("SEND").header("destination", &self.destination(topic));
+        f.body = data.to_vec();
+
+        self.send(f).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for StompClient {
+    /// Subscribe to the destination for a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|(t, _)| t == topic) {
+            return Ok(())
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let f = Frame::new("SUBSCRIBE")
+            .header("id", &id.to_string())
+            .header("destination", &self.destination(topic))
+            .header("ack", "auto");
+        self.send(f).await?;
+
+        self.subs.push((topic.to_string(), id));
+
+        Ok(())
+    }
+
+    /// Unsubscribe from the destination for a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|(t, _)| t == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let (_, id) = self.subs.remove(i);
+
+        self.send(Frame::new("UNSUBSCRIBE").header("id", &id.to_string())).await
+    }
+}
+
+/// Stream implementation for StompClient, sending heartbeats and ending when the connection is lost
+impl Stream for StompClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if !this.connected {
+            return Poll::Ready(None)
+        }
+
+        // Drive pending heartbeats
+        if let Some(s) = this.sending.as_mut() {
+            if let Poll::Ready(r) = s.poll_unpin(cx) {
+                this.sending = None;
+                if let Err(e) = r {
+                    warn!("STOMP heartbeat failed: {:?}", e);
+                }
+            }
+        }
+
+        if let Some((d, t)) = &mut this.heartbeat {
+            while let Poll::Ready(_) = Pin::new(&mut *t).poll(cx) {
+                if this.sending.is_none() {
+                    let mut s = write(this.tx.clone(), b"\n".to_vec());
+                    if let Poll::Pending = s.poll_unpin(cx) {
+                        this.sending = Some(s);
+                    }
+                }
+                t.reset(Instant::now() + *d);
+            }
+        }
+
+        if let Some((_, t)) = &mut this.watchdog {
+            if let Poll::Ready(_) = Pin::new(t).poll(cx) {
+                warn!("STOMP heartbeat timeout, connection lost");
+                this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                this.connected = false;
+                return Poll::Ready(None)
+            }
+        }
+
+        loop {
+            let f = match Frame::decode(&mut this.buf) {
+                Ok(Some(f)) => f,
+                Ok(None) => {
+                    if this.reading.is_none() {
+                        this.reading = Some(read(this.rx.clone()));
+                    }
+
+                    match this.reading.as_mut().unwrap().poll_unpin(cx) {
+                        Poll::Ready(Ok(d)) if !d.is_empty() => {
+                            this.reading = None;
+                            this.buf.extend_from_slice(&d);
+
+                            if let Some((d, t)) = &mut this.watchdog {
+                                t.reset(Instant::now() + *d);
+                            }
+                            continue
+                        },
+                        Poll::Ready(r) => {
+                            this.reading = None;
+                            if let Err(e) = r {
+                                warn!("STOMP receive failed: {:?}", e);
+                            }
+                            this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                            this.connected = false;
+                            return Poll::Ready(None)
+                        },
+                        Poll::Pending => return Poll::Pending,
+                    }
+                },
+                Err(e) => {
+                    warn!("STOMP decode failed: {:?}", e);
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+            };
+
+            match f.command.as_str() {
+                "MESSAGE" => {
+                    // Drop messages for subscriptions removed since delivery
+                    let sub = f.get("subscription").and_then(|s| s.parse::<u32>().ok());
+                    if !this.subs.iter().any(|(_, id)| Some(*id) == sub) {
+                        continue
+                    }
+
+                    let topic = this.topic(f.get("destination").unwrap_or_default());
+                    return Poll::Ready(Some((topic, f.body)))
+                },
+                "ERROR" => {
+                    warn!("STOMP error: {} {}", f.get("message").unwrap_or_default(), String::from_utf8_lossy(&f.body));
+                    this.last_error = Some((std::time::Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                c => debug!("Ignoring STOMP {} frame", c),
+            }
+        }
+    }
+}
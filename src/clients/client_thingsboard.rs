@@ -0,0 +1,331 @@
+//! ThingsBoard device client, built on the MQTT client
+//!
+//! This implements the ThingsBoard device MQTT API (access token authentication, telemetry,
+//! client / shared attributes and server / client-side RPC) with JSON payloads
+//! encoded and decoded via serde.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use super::client_mqtt::{MqttClient, MqttOptions};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// Telemetry topic
+pub const TELEMETRY_TOPIC: &str = "v1/devices/me/telemetry";
+
+/// Client attribute publish / shared attribute update topic
+pub const ATTRIBUTES_TOPIC: &str = "v1/devices/me/attributes";
+
+const ATTRIBUTES_REQUEST_PREFIX: &str = "v1/devices/me/attributes/request/";
+const ATTRIBUTES_RESPONSE_PREFIX: &str = "v1/devices/me/attributes/response/";
+const RPC_REQUEST_PREFIX: &str = "v1/devices/me/rpc/request/";
+const RPC_RESPONSE_PREFIX: &str = "v1/devices/me/rpc/response/";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThingsBoardOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for ThingsBoard MQTT transport (prefixed by tcp:// or ssl://)
+    pub tb_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    /// Device access token
+    pub tb_access_token: String,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl ThingsBoardOptions {
+    /// Create options for the provided URL and device access token
+    pub fn new(url: &str, access_token: &str) -> Self {
+        Self {
+            tb_url: url.to_string(),
+            tb_access_token: access_token.to_string(),
+            tls_opts: TlsOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Build MQTT options, authenticating with the access token
+    pub fn mqtt_options(&self) -> MqttOptions {
+        let mut o = MqttOptions::from((self.tb_url.as_str(), self.tls_opts.clone()));
+
+        o.user_opts = UserOptions {
+            username: Some(self.tb_access_token.clone()),
+            password: None,
+        };
+        o.defaults = self.defaults.clone();
+
+        o
+    }
+}
+
+/// Attribute values returned from an attribute request
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Attributes {
+    #[serde(default)]
+    pub client: Map<String, Value>,
+    #[serde(default)]
+    pub shared: Map<String, Value>,
+}
+
+/// Events received from the server
+#[derive(Debug, Clone, PartialEq)]
+pub enum ThingsBoardEvent {
+    /// Shared attributes updated (requires `subscribe_attributes`)
+    AttributeUpdate(Map<String, Value>),
+    /// Server-side RPC request, respond with `respond_rpc` (requires `subscribe_rpc`)
+    RpcRequest{ id: u32, method: String, params: Value },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Rpc {
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+impl ThingsBoardEvent {
+    /// Parse an event from a received message, returning None for other topics
+    pub fn parse(topic: &str, data: &[u8]) -> Option<Result<ThingsBoardEvent, Error>> {
+        if topic == ATTRIBUTES_TOPIC {
+            return Some(serde_json::from_slice(data)
+                .map(ThingsBoardEvent::AttributeUpdate)
+                .map_err(Error::from))
+        }
+
+        let id = topic.strip_prefix(RPC_REQUEST_PREFIX)?;
+        let r = id.parse::<u32>()
+            .map_err(|_| Error::msg(format!("Invalid ThingsBoard RPC request ID: {:?}", id)))
+            .and_then(|id| {
+                let rpc: Rpc = serde_json::from_slice(data)?;
+                Ok(ThingsBoardEvent::RpcRequest{ id, method: rpc.method, params: rpc.params })
+            });
+
+        Some(r)
+    }
+}
+
+/// ThingsBoard device client
+///
+/// Raw topics are passed through to the underlying MQTT client, `next_event` decodes
+/// attribute updates and RPC requests.
+pub struct ThingsBoardClient {
+    mqtt: MqttClient,
+    /// Next attribute / client-side RPC request ID
+    request_id: u32,
+    /// Response topic filters subscribed for requests
+    responses: Vec<&'static str>,
+    /// Messages received while waiting on responses, emitted before `mqtt`
+    pending: VecDeque<(String, Vec<u8>)>,
+}
+
+impl ThingsBoardClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: ThingsBoardOptions) -> Result<ThingsBoardClient, Error> {
+        let mqtt = MqttClient::new(opts.mqtt_options()).await?;
+
+        debug!("Connected to ThingsBoard at {}", opts.tb_url);
+
+        Ok(ThingsBoardClient{
+            mqtt,
+            request_id: 1,
+            responses: vec![],
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Fetch the inner MQTT client
+    pub fn inner(&mut self) -> &mut MqttClient {
+        &mut self.mqtt
+    }
+
+    /// Send telemetry values (a JSON object of keys to values), timestamped by the server
+    pub async fn send_telemetry<T: Serialize + Sync>(&mut self, values: &T) -> Result<(), Error> {
+        let data = serde_json::to_vec(values)?;
+        self.mqtt.publish_qos(TELEMETRY_TOPIC, &data, 1).await
+    }
+
+    /// Send telemetry values with a client timestamp
+    pub async fn send_telemetry_at<T: Serialize + Sync>(&mut self, ts: SystemTime, values: &T) -> Result<(), Error> {
+        #[derive(Serialize)]
+        struct Timestamped<'a, T> {
+            ts: u128,
+            values: &'a T,
+        }
+
+        let ts = ts.duration_since(UNIX_EPOCH)?.as_millis();
+        let data = serde_json::to_vec(&Timestamped{ ts, values })?;
+        self.mqtt.publish_qos(TELEMETRY_TOPIC, &data, 1).await
+    }
+
+    /// Publish client attributes (a JSON object of keys to values)
+    pub async fn send_attributes<T: Serialize + Sync>(&mut self, attributes: &T) -> Result<(), Error> {
+        let data = serde_json::to_vec(attributes)?;
+        self.mqtt.publish_qos(ATTRIBUTES_TOPIC, &data, 1).await
+    }
+
+    /// Subscribe to shared attribute updates
+    pub async fn subscribe_attributes(&mut self) -> Result<(), Error> {
+        self.mqtt.subscribe_qos(ATTRIBUTES_TOPIC, 1).await
+    }
+
+    /// Subscribe to server-side RPC requests
+    pub async fn subscribe_rpc(&mut self) -> Result<(), Error> {
+        self.mqtt.subscribe_qos(&format!("{}+", RPC_REQUEST_PREFIX), 1).await
+    }
+
+    /// Respond to a server-side RPC request
+    pub async fn respond_rpc<T: Serialize + Sync>(&mut self, id: u32, resp: &T) -> Result<(), Error> {
+        let data = serde_json::to_vec(resp)?;
+        self.mqtt.publish_qos(&format!("{}{}", RPC_RESPONSE_PREFIX, id), &data, 1).await
+    }
+
+    /// Request client and / or shared attribute values
+    pub async fn request_attributes(&mut self, client_keys: &[&str], shared_keys: &[&str], timeout: Duration) -> Result<Attributes, Error> {
+        let mut req = Map::new();
+        if !client_keys.is_empty() {
+            req.insert("clientKeys".to_string(), Value::from(client_keys.join(",")));
+        }
+        if !shared_keys.is_empty() {
+            req.insert("sharedKeys".to_string(), Value::from(shared_keys.join(",")));
+        }
+
+        let data = self.request(ATTRIBUTES_REQUEST_PREFIX, ATTRIBUTES_RESPONSE_PREFIX, &Value::Object(req), timeout).await?;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Issue a client-side RPC request, returning the response
+    pub async fn call_rpc(&mut self, method: &str, params: Value, timeout: Duration) -> Result<Value, Error> {
+        let rpc = Rpc{ method: method.to_string(), params };
+
+        let data = self.request(RPC_REQUEST_PREFIX, RPC_RESPONSE_PREFIX, &rpc, timeout).await?;
+
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Await the next event, skipping messages on other topics
+    pub async fn next_event(&mut self) -> Option<Result<ThingsBoardEvent, Error>> {
+        while let Some((t, d)) = self.next().await {
+            if let Some(e) = ThingsBoardEvent::parse(&t, &d) {
+                return Some(e)
+            }
+        }
+
+        None
+    }
+
+    /// Publish a request and await the response with the matching ID
+    ///
+    /// Response topics remain subscribed for later requests. Messages received on
+    /// other topics while waiting are buffered and emitted by the stream as usual.
+    async fn request<T: Serialize + Sync>(&mut self, req_prefix: &str, resp_prefix: &'static str, req: &T, timeout: Duration) -> Result<Vec<u8>, Error> {
+        if !self.responses.contains(&resp_prefix) {
+            self.mqtt.subscribe_qos(&format!("{}+", resp_prefix), 1).await?;
+            self.responses.push(resp_prefix);
+        }
+
+        let id = self.request_id;
+        self.request_id = self.request_id.wrapping_add(1);
+
+        let data = serde_json::to_vec(req)?;
+        self.mqtt.publish_qos(&format!("{}{}", req_prefix, id), &data, 1).await?;
+
+        let resp_topic = format!("{}{}", resp_prefix, id);
+        let (mqtt, pending) = (&mut self.mqtt, &mut self.pending);
+        let wait = async {
+            while let Some((t, d)) = mqtt.next().await {
+                if t == resp_topic {
+                    return Ok(d)
+                } else if t.starts_with(resp_prefix) {
+                    debug!("Ignoring uncorrelated ThingsBoard response {}", t);
+                } else {
+                    pending.push_back((t, d));
+                }
+            }
+            Err(Error::from(PalError::ConnectionLost))
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(r) => r,
+            Err(_) => Err(PalError::Timeout{ operation: format!("ThingsBoard request {}{}", req_prefix, id), timeout }.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientBase for ThingsBoardClient {
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.mqtt.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.mqtt.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.mqtt.last_error()
+    }
+}
+
+#[async_trait]
+impl ClientPub for ThingsBoardClient {
+    /// Publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.mqtt.publish(topic, data).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for ThingsBoardClient {
+    /// Subscribe to a topic
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.mqtt.subscribe(topic).await
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.mqtt.unsubscribe(topic).await
+    }
+}
+
+/// Stream implementation for ThingsBoardClient, emitting messages buffered during requests first
+impl Stream for ThingsBoardClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(m) = this.pending.pop_front() {
+            return Poll::Ready(Some(m))
+        }
+
+        loop {
+            match this.mqtt.poll_next_unpin(cx) {
+                // Drop late request responses
+                Poll::Ready(Some((t, _))) if this.responses.iter().any(|p| t.starts_with(p)) => {
+                    debug!("Ignoring late ThingsBoard response {}", t);
+                },
+                r => return r,
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! The Things Network (TTN / The Things Stack v3) integration client, built on the MQTT client
+//!
+//! This subscribes to application uplinks, decoding the uplink JSON envelope (including
+//! payloads decoded by the application's payload formatter), and schedules downlinks.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use log::{debug, warn};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use super::client_mqtt::{MqttClient, MqttOptions};
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+
+/// Default tenant for The Things Network community edition
+pub const DEFAULT_TENANT: &str = "ttn";
+
+/// Default FPort for downlinks published without a port
+pub const DEFAULT_F_PORT: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TtnOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for the cluster MQTT server (ie. ssl://eu1.cloud.thethings.network:8883)
+    pub ttn_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Application ID
+    pub ttn_app_id: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "ttn"))]
+    /// Tenant ID (ttn for The Things Network)
+    pub ttn_tenant: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    /// Application API key (requires traffic read / write rights)
+    pub ttn_api_key: String,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl TtnOptions {
+    /// Create options for the provided cluster URL, application and API key
+    pub fn new(url: &str, app_id: &str, api_key: &str) -> Self {
+        Self {
+            ttn_url: url.to_string(),
+            ttn_app_id: app_id.to_string(),
+            ttn_tenant: DEFAULT_TENANT.to_string(),
+            ttn_api_key: api_key.to_string(),
+            tls_opts: TlsOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Application user ID (`app-id@tenant`), used as the MQTT username and in topics
+    fn user_id(&self) -> String {
+        format!("{}@{}", self.ttn_app_id, self.ttn_tenant)
+    }
+
+    /// Build MQTT options, authenticating with the application API key
+    pub fn mqtt_options(&self) -> MqttOptions {
+        let mut o = MqttOptions::from((self.ttn_url.as_str(), self.tls_opts.clone()));
+
+        o.user_opts = UserOptions {
+            username: Some(self.user_id()),
+            password: Some(self.ttn_api_key.clone()),
+        };
+        o.defaults = self.defaults.clone();
+
+        o
+    }
+}
+
+/// Decoded uplink message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uplink {
+    pub device_id: String,
+    pub dev_eui: Option<String>,
+    pub received_at: Option<String>,
+    pub f_port: Option<u8>,
+    pub f_cnt: Option<u32>,
+    /// Raw application payload
+    pub payload: Vec<u8>,
+    /// Payload decoded by the application payload formatter, where configured
+    pub decoded_payload: Option<Value>,
+    pub rx_metadata: Vec<RxMetadata>,
+}
+
+/// Gateway reception metadata for an uplink
+#[derive(Debug, Clone, PartialEq)]
+pub struct RxMetadata {
+    pub gateway_id: String,
+    pub rssi: Option<f64>,
+    pub snr: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UplinkEnvelope {
+    end_device_ids: DeviceIds,
+    received_at: Option<String>,
+    uplink_message: UplinkMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceIds {
+    device_id: String,
+    dev_eui: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UplinkMessage {
+    f_port: Option<u8>,
+    f_cnt: Option<u32>,
+    frm_payload: Option<String>,
+    decoded_payload: Option<Value>,
+    #[serde(default)]
+    rx_metadata: Vec<UplinkRxMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UplinkRxMetadata {
+    gateway_ids: GatewayIds,
+    rssi: Option<f64>,
+    snr: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GatewayIds {
+    gateway_id: String,
+}
+
+impl Uplink {
+    /// Decode an uplink JSON envelope
+    pub fn parse(data: &[u8]) -> Result<Uplink, Error> {
+        let e: UplinkEnvelope = serde_json::from_slice(data)?;
+
+        let payload = match &e.uplink_message.frm_payload {
+            Some(p) => base64::decode(p)
+                .map_err(|err| Error::msg(format!("Invalid TTN uplink payload for {}: {:?}", e.end_device_ids.device_id, err)))?,
+            None => vec![],
+        };
+
+        Ok(Uplink {
+            device_id: e.end_device_ids.device_id,
+            dev_eui: e.end_device_ids.dev_eui,
+            received_at: e.received_at,
+            f_port: e.uplink_message.f_port,
+            f_cnt: e.uplink_message.f_cnt,
+            payload,
+            decoded_payload: e.uplink_message.decoded_payload,
+            rx_metadata: e.uplink_message.rx_metadata.into_iter().map(|m| RxMetadata{
+                gateway_id: m.gateway_ids.gateway_id,
+                rssi: m.rssi,
+                snr: m.snr,
+            }).collect(),
+        })
+    }
+}
+
+/// Downlink message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Downlink {
+    pub f_port: u8,
+    pub payload: Vec<u8>,
+    /// Request acknowledgement from the device
+    pub confirmed: bool,
+    /// Scheduling priority (ie. LOWEST, LOW, NORMAL, HIGH, HIGHEST), defaults to NORMAL
+    pub priority: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DownlinkEnvelope<'a> {
+    downlinks: Vec<DownlinkMessage<'a>>,
+}
+
+#[derive(Serialize)]
+struct DownlinkMessage<'a> {
+    f_port: u8,
+    frm_payload: String,
+    confirmed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<&'a str>,
+}
+
+/// The Things Network application client
+///
+/// Topics are device IDs, subscribing to a device (or `+` for all devices) subscribes to uplinks.
+/// The stream emits device IDs and raw uplink payloads, `next_uplink` returns decoded uplinks.
+/// Publishing to a device ID (or `device_id/f_port`) queues a downlink.
+pub struct TtnClient {
+    mqtt: MqttClient,
+    /// Topic prefix (`v3/app-id@tenant/devices/`)
+    prefix: String,
+}
+
+impl TtnClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: TtnOptions) -> Result<TtnClient, Error> {
+        let mqtt = MqttClient::new(opts.mqtt_options()).await?;
+
+        debug!("Connected to TTN {} for application {}", opts.ttn_url, opts.user_id());
+
+        Ok(TtnClient{
+            mqtt,
+            prefix: format!("v3/{}/devices/", opts.user_id()),
+        })
+    }
+
+    /// Fetch the inner MQTT client
+    pub fn inner(&mut self) -> &mut MqttClient {
+        &mut self.mqtt
+    }
+
+    /// Uplink topic for a device (or `+` for all devices)
+    pub fn uplink_topic(&self, device_id: &str) -> String {
+        format!("{}{}/up", self.prefix, device_id)
+    }
+
+    /// Schedule downlinks for a device, appending to (or replacing) the device downlink queue
+    pub async fn send_downlinks(&mut self, device_id: &str, downlinks: &[Downlink], replace: bool) -> Result<(), Error> {
+        let e = DownlinkEnvelope {
+            downlinks: downlinks.iter().map(|d| DownlinkMessage{
+                f_port: d.f_port,
+                frm_payload: base64::encode(&d.payload),
+                confirmed: d.confirmed,
+                priority: d.priority.as_deref(),
+            }).collect(),
+        };
+
+        let op = match replace {
+            true => "replace",
+            false => "push",
+        };
+
+        let data = serde_json::to_vec(&e)?;
+        self.mqtt.publish_qos(&format!("{}{}/down/{}", self.prefix, device_id, op), &data, 1).await
+    }
+
+    /// Await the next uplink, skipping messages on other topics or that fail to decode
+    pub async fn next_uplink(&mut self) -> Option<Uplink> {
+        while let Some((t, d)) = self.mqtt.next().await {
+            if !self.is_uplink(&t) {
+                continue
+            }
+
+            match Uplink::parse(&d) {
+                Ok(u) => return Some(u),
+                Err(e) => warn!("Failed to decode TTN uplink on {}: {:?}", t, e),
+            }
+        }
+
+        None
+    }
+
+    fn is_uplink(&self, topic: &str) -> bool {
+        topic.starts_with(&self.prefix) && topic.ends_with("/up")
+    }
+}
+
+#[async_trait]
+impl ClientBase for TtnClient {
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.mqtt.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.mqtt.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.mqtt.last_error()
+    }
+}
+
+#[async_trait]
+impl ClientPub for TtnClient {
+    /// Queue a downlink for a device (`device_id` or `device_id/f_port`)
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let (device_id, f_port) = match topic.find('/') {
+            Some(i) => {
+                let p = topic[i+1..].parse()
+                    .map_err(|_| Error::msg(format!("Invalid TTN downlink FPort in {:?} (expected device_id/f_port)", topic)))?;
+                (&topic[..i], p)
+            },
+            None => (topic, DEFAULT_F_PORT),
+        };
+
+        let d = Downlink{ f_port, payload: data.to_vec(), confirmed: false, priority: None };
+        self.send_downlinks(device_id, &[d], false).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for TtnClient {
+    /// Subscribe to uplinks for a device (or `+` for all devices)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let t = self.uplink_topic(topic);
+        self.mqtt.subscribe(&t).await
+    }
+
+    /// Unsubscribe from uplinks for a device
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let t = self.uplink_topic(topic);
+        self.mqtt.unsubscribe(&t).await
+    }
+}
+
+/// Stream implementation for TtnClient, emitting device IDs and raw uplink payloads
+impl Stream for TtnClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let (t, d) = match this.mqtt.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            if !this.is_uplink(&t) {
+                continue
+            }
+
+            match Uplink::parse(&d) {
+                Ok(u) => return Poll::Ready(Some((u.device_id, u.payload))),
+                Err(e) => warn!("Failed to decode TTN uplink on {}: {:?}", t, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Raw UDP datagram client, for lightweight protocols without a dedicated client
+//!
+//! Topics are socket addresses. Publishing sends a datagram to the topic address (or the
+//! configured peer where the topic is empty), and received datagrams are emitted with the
+//! source address as the topic. Subscribe to `#` to receive datagrams from any source.
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::net::UdpSocket;
+use tokio::net::udp::{RecvHalf, SendHalf};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+use crate::topics::topic_matches;
+
+/// Default bind address (any interface, ephemeral port)
+pub const DEFAULT_BIND: &str = "0.0.0.0:0";
+
+/// Maximum UDP payload length
+const MAX_DATAGRAM_LEN: usize = 65_507;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UdpOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "0.0.0.0:0"))]
+    /// Local address to bind (ie. 0.0.0.0:5000 to receive datagrams on a fixed port)
+    pub udp_bind: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Default peer for publishing with an empty topic (ie. udp://sensor.local:5000)
+    pub udp_peer: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for UdpOptions {
+    fn from(peer: &str) -> Self {
+        Self {
+            udp_bind: DEFAULT_BIND.to_string(),
+            udp_peer: Some(peer.to_string()),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+/// Generic futures-based raw UDP client abstraction
+pub struct UdpClient {
+    tx: Arc<AsyncMutex<SendHalf>>,
+    rx: Arc<AsyncMutex<RecvHalf>>,
+    peer: Option<SocketAddr>,
+
+    receiving: Option<BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>>>,
+
+    subs: Vec<String>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect or when the socket fails
+    connected: bool,
+}
+
+impl UdpClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<UdpOptions>>(opts: O) -> Result<UdpClient, Error> {
+        let o = opts.into();
+
+        let socket = UdpSocket::bind(o.udp_bind.as_str()).await
+            .map_err(|e| Error::msg(format!("Failed to bind UDP socket {:?}: {}", o.udp_bind, e)))?;
+
+        let peer = match &o.udp_peer {
+            Some(p) => Some(resolve(p).await?),
+            None => None,
+        };
+
+        debug!("Bound UDP socket {:?} (peer: {:?})", socket.local_addr(), peer);
+
+        let (rx, tx) = socket.split();
+
+        Ok(UdpClient{
+            tx: Arc::new(AsyncMutex::new(tx)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            peer,
+            receiving: None,
+            subs: vec![],
+            last_error: None,
+            connected: true,
+        })
+    }
+}
+
+/// Resolve a `host:port` address, with or without the `udp://` scheme
+async fn resolve(addr: &str) -> Result<SocketAddr, Error> {
+    let host = addr.trim_start_matches("udp://");
+    let host = host.split('/').next().unwrap_or(host);
+
+    match tokio::net::lookup_host(host).await?.next() {
+        Some(a) => Ok(a),
+        None => Err(Error::msg(format!("Could not resolve UDP address {:?}", addr))),
+    }
+}
+
+fn recv_packet(rx: Arc<AsyncMutex<RecvHalf>>) -> BoxFuture<'static, Result<(SocketAddr, Vec<u8>), Error>> {
+    async move {
+        let mut buff = vec![0u8; MAX_DATAGRAM_LEN];
+        let (n, from) = rx.lock().await.recv_from(&mut buff).await?;
+        buff.truncate(n);
+        Ok((from, buff))
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for UdpClient {
+    /// Stop receiving datagrams (UDP is connectionless, so there is nothing to close)
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.receiving = None;
+        self.subs.clear();
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for UdpClient {
+    /// Send a datagram to the topic address, or the configured peer for empty topics
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+        if data.len() > MAX_DATAGRAM_LEN {
+            return Err(Error::msg(format!("UDP datagram length {} exceeds {} bytes", data.len(), MAX_DATAGRAM_LEN)))
+        }
+
+        let addr = match (topic.is_empty(), self.peer) {
+            (true, Some(p)) => p,
+            (true, None) => return Err(Error::msg("Empty UDP topic with no peer configured")),
+            (false, _) => resolve(topic).await?,
+        };
+
+        self.tx.lock().await.send_to(data, &addr).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for UdpClient {
+    /// Subscribe to datagrams from a source address (or `#` for any source)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from a source address
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        match self.subs.iter().position(|s| s == topic) {
+            Some(i) => {
+                self.subs.remove(i);
+                Ok(())
+            },
+            None => Err(Error::msg(format!("Not subscribed to {}", topic))),
+        }
+    }
+}
+
+/// Stream implementation for UdpClient, emitting received datagrams from subscribed sources
+impl Stream for UdpClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            if this.receiving.is_none() {
+                this.receiving = Some(recv_packet(this.rx.clone()));
+            }
+
+            match this.receiving.as_mut().unwrap().poll_unpin(cx) {
+                Poll::Ready(Ok((from, data))) => {
+                    this.receiving = None;
+
+                    let topic = from.to_string();
+                    if this.subs.iter().any(|s| topic_matches(s, &topic)) {
+                        return Poll::Ready(Some((topic, data)))
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    this.receiving = None;
+                    warn!("UDP receive failed: {:?}", e);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::sink::SinkExt;
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::{Context as _, Error};
+use serde_json::{json, Value};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{client_async, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TlsOptions, TransportDefaults, PalError};
+use crate::topics::topic_matches;
+
+
+/// Message framing for WebSocket connections
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WsFraming {
+    /// Payloads are sent as binary messages, the topic for received messages is the URL path
+    Raw,
+    /// Payloads are wrapped in a JSON envelope (`{"topic": "...", "payload": "<base64>"}`) sent as text messages
+    Envelope,
+}
+
+impl Default for WsFraming {
+    fn default() -> Self {
+        WsFraming::Raw
+    }
+}
+
+impl FromStr for WsFraming {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(WsFraming::Raw),
+            "envelope" => Ok(WsFraming::Envelope),
+            _ => Err(Error::msg(format!("Unsupported WebSocket framing: {:?} (expected raw or envelope)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for WsFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WsFraming::Raw => write!(f, "raw"),
+            WsFraming::Envelope => write!(f, "envelope"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WsOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// URL for WebSocket endpoint (prefixed with ws:// or wss://)
+    pub ws_url: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "raw"))]
+    /// Message framing (raw or envelope)
+    pub ws_framing: WsFraming,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// WebSocket connect timeout, including the TLS and WebSocket handshakes
+    /// (defaults to `TransportDefaults::connect_timeout`)
+    pub ws_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub tls_opts: TlsOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl From<&str> for WsOptions {
+    fn from(url: &str) -> Self {
+        Self {
+            ws_url: url.to_string(),
+            ws_framing: WsFraming::default(),
+            ws_connect_timeout: None,
+            tls_opts: TlsOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<(&str, TlsOptions)> for WsOptions {
+    fn from(c: (&str, TlsOptions)) -> Self {
+        Self {
+            tls_opts: c.1,
+            ..c.0.into()
+        }
+    }
+}
+
+/// Transport stream, either plain TCP or TLS
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl <T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// Generic futures-based WebSocket client abstraction
+///
+/// WebSockets have no native topics, so subscriptions filter received messages by topic
+/// (supporting MQTT-style wildcards) and messages matching no subscription are dropped.
+///
+/// Note `clients::connect` maps `ws://` and `wss://` URLs to MQTT over WebSockets,
+/// use `WsClient::new` to connect to plain WebSocket endpoints.
+pub struct WsClient {
+    ws: WebSocketStream<Box<dyn Io>>,
+    framing: WsFraming,
+    /// URL path, used as the topic for raw messages
+    path: String,
+    subs: Vec<String>,
+    connected: bool,
+    last_error: Option<(Instant, PalError)>,
+}
+
+impl WsClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<WsOptions>>(opts: O) -> Result<WsClient, Error> {
+        let o = opts.into();
+
+        let connect_timeout = o.ws_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        match timeout(connect_timeout, Self::connect(&o)).await {
+            Ok(r) => r.map_err(|e| o.tls_opts.with_diagnostics(&o.ws_url, e)),
+            Err(_) => Err(PalError::Timeout{ operation: format!("WebSocket connect to {}", o.ws_url), timeout: connect_timeout }.into()),
+        }
+    }
+
+    async fn connect(o: &WsOptions) -> Result<WsClient, Error> {
+        let uri: Uri = o.ws_url.parse()
+            .with_context(|| format!("Invalid WebSocket URL: {:?}", o.ws_url))?;
+
+        let secure = match uri.scheme_str() {
+            Some("ws") => false,
+            Some("wss") => true,
+            _ => return Err(Error::msg(format!("WebSocket URL must be prefixed with ws:// or wss:// ({:?})", o.ws_url))),
+        };
+
+        let host = match uri.host() {
+            Some(h) => h.to_string(),
+            None => return Err(Error::msg(format!("Missing host in WebSocket URL: {:?}", o.ws_url))),
+        };
+        let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+
+        debug!("Connecting to WebSocket {}:{} (tls: {})", host, port, secure);
+
+        let tcp = TcpStream::connect((host.as_str(), port)).await?;
+
+        let stream: Box<dyn Io> = match secure {
+            true => {
+                let config = o.tls_opts.build_rustls_config()?;
+                let name = webpki::DNSNameRef::try_from_ascii_str(&host)
+                    .map_err(|_| Error::msg(format!("Invalid DNS name for TLS: {:?}", host)))?;
+
+                let tls = TlsConnector::from(Arc::new(config)).connect(name, tcp).await?;
+                Box::new(tls)
+            },
+            false => Box::new(tcp),
+        };
+
+        let (ws, _resp) = client_async(o.ws_url.as_str(), stream).await?;
+
+        Ok(WsClient{
+            ws,
+            framing: o.ws_framing,
+            path: uri.path().trim_start_matches('/').to_string(),
+            subs: vec![],
+            connected: true,
+            last_error: None,
+        })
+    }
+
+    /// Decode a received message to a topic and payload
+    fn decode(&self, m: Message) -> Result<Option<(String, Vec<u8>)>, Error> {
+        let (topic, data) = match (self.framing, m) {
+            (WsFraming::Raw, Message::Binary(d)) => (self.path.clone(), d),
+            (WsFraming::Raw, Message::Text(t)) => (self.path.clone(), t.into_bytes()),
+            (WsFraming::Envelope, Message::Text(t)) => {
+                let v: Value = serde_json::from_str(&t)?;
+
+                match (v["topic"].as_str(), v["payload"].as_str()) {
+                    (Some(t), Some(p)) => (t.to_string(), base64::decode(p)?),
+                    _ => return Err(Error::msg("WebSocket envelope requires topic and payload fields")),
+                }
+            },
+            (WsFraming::Envelope, Message::Binary(_)) => {
+                return Err(Error::msg("Unexpected binary message for envelope framing"))
+            },
+            // Control messages are handled by the underlying driver
+            _ => return Ok(None),
+        };
+
+        match self.subs.iter().any(|s| topic_matches(s, &topic)) {
+            true => Ok(Some((topic, data))),
+            false => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl ClientBase for WsClient {
+    /// Close the WebSocket connection
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.ws.close(None).await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for WsClient {
+    /// Publish data to a topic
+    ///
+    /// For raw framing the topic is ignored and the payload sent as a binary message.
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let m = match self.framing {
+            WsFraming::Raw => Message::Binary(data.to_vec()),
+            WsFraming::Envelope => Message::Text(json!({
+                "topic": topic,
+                "payload": base64::encode(data),
+            }).to_string()),
+        };
+
+        self.ws.send(m).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for WsClient {
+    /// Subscribe to a topic (filtering received messages)
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.subs.iter().any(|s| s == topic) {
+            self.subs.push(topic.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        match self.subs.iter().position(|s| s == topic) {
+            Some(i) => {
+                self.subs.remove(i);
+                Ok(())
+            },
+            None => Err(Error::msg(format!("Not subscribed to {}", topic))),
+        }
+    }
+}
+
+/// Stream implementation for WsClient, ending when the connection is closed
+impl Stream for WsClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let m = match this.ws.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(m))) => m,
+                Poll::Ready(Some(Err(e))) => {
+                    warn!("WebSocket connection lost: {:?}", e);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Ready(None) => {
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match this.decode(m) {
+                Ok(Some(v)) => return Poll::Ready(Some(v)),
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to decode WebSocket message: {:?}", e);
+                    continue
+                },
+            }
+        }
+    }
+}
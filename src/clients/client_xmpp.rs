@@ -0,0 +1,400 @@
+//! XMPP publish / subscribe (XEP-0060) client
+//!
+//! Topics map to pubsub nodes on the configured pubsub service. Payloads are published as
+//! base64 `<data xmlns='urn:iot-pal:data'/>` items, items with other payloads (ie. from
+//! other publishers) are emitted as serialized XML.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::timeout;
+use tokio_xmpp::{Client, Event, Packet};
+use xmpp_parsers::Element;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+
+const NS_CLIENT: &str = "jabber:client";
+const NS_PUBSUB: &str = "http://jabber.org/protocol/pubsub";
+const NS_PUBSUB_EVENT: &str = "http://jabber.org/protocol/pubsub#event";
+const NS_PING: &str = "urn:xmpp:ping";
+
+/// Namespace for binary payload items
+pub const NS_DATA: &str = "urn:iot-pal:data";
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct XmppOptions {
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Client JID (ie. device@example.com/resource)
+    pub xmpp_jid: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    /// Client password
+    pub xmpp_password: String,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Pubsub service JID (defaults to `pubsub.` followed by the JID domain)
+    pub xmpp_pubsub_service: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// XMPP connect timeout, including STARTTLS and authentication
+    /// (defaults to `TransportDefaults::connect_timeout`)
+    pub xmpp_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Timeout for pubsub requests (defaults to `TransportDefaults::request_timeout`)
+    pub xmpp_request_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl XmppOptions {
+    /// Create options for the provided JID and password
+    pub fn new(jid: &str, password: &str) -> Self {
+        Self {
+            xmpp_jid: jid.to_string(),
+            xmpp_password: password.to_string(),
+            xmpp_pubsub_service: None,
+            xmpp_connect_timeout: None,
+            xmpp_request_timeout: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Bare JID (without resource), used for subscriptions
+    fn bare_jid(&self) -> &str {
+        self.xmpp_jid.split('/').next().unwrap_or(&self.xmpp_jid)
+    }
+
+    /// Pubsub service JID
+    fn service(&self) -> String {
+        match &self.xmpp_pubsub_service {
+            Some(s) => s.clone(),
+            None => {
+                let bare = self.bare_jid();
+                let domain = bare.rsplit('@').next().unwrap_or(bare);
+                format!("pubsub.{}", domain)
+            },
+        }
+    }
+}
+
+/// XMPP pubsub client
+///
+/// Topics are node names on the pubsub service, nodes must exist (or be auto-created
+/// by the service on publish) and permit the client to publish / subscribe.
+pub struct XmppClient {
+    client: Client,
+    jid: String,
+    service: String,
+    request_timeout: Duration,
+    next_id: u32,
+    /// Subscribed nodes
+    subs: Vec<String>,
+    /// Items received but not yet emitted (including those received while waiting on requests)
+    pending: VecDeque<(String, Vec<u8>)>,
+    /// Stanzas queued for sending from `Stream` (ie. ping responses)
+    replies: VecDeque<Element>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect or when the connection is lost
+    connected: bool,
+}
+
+impl XmppClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: XmppOptions) -> Result<XmppClient, Error> {
+        let connect_timeout = opts.xmpp_connect_timeout.unwrap_or(opts.defaults.connect_timeout);
+
+        let mut client = Client::new(&opts.xmpp_jid, opts.xmpp_password.clone())
+            .map_err(|e| Error::msg(format!("Invalid XMPP JID {:?}: {:?}", opts.xmpp_jid, e)))?;
+
+        // Wait for the session to come online
+        let online = async {
+            while let Some(e) = client.next().await {
+                match e {
+                    Event::Online{ .. } => return Ok(()),
+                    Event::Disconnected(e) => return Err(Error::msg(format!("XMPP connect failed: {:?}", e))),
+                    Event::Stanza(_) => (),
+                }
+            }
+            Err(Error::from(PalError::ConnectionLost))
+        };
+
+        match timeout(connect_timeout, online).await {
+            Ok(r) => r?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("XMPP connect as {}", opts.xmpp_jid), timeout: connect_timeout }.into()),
+        }
+
+        // Send initial presence so events are delivered
+        client.send(Packet::Stanza(Element::builder("presence").ns(NS_CLIENT).build())).await?;
+
+        let service = opts.service();
+
+        debug!("Connected to XMPP as {} (pubsub service: {})", opts.xmpp_jid, service);
+
+        Ok(XmppClient{
+            client,
+            jid: opts.bare_jid().to_string(),
+            service,
+            request_timeout: opts.xmpp_request_timeout.unwrap_or(opts.defaults.request_timeout),
+            next_id: 1,
+            subs: vec![],
+            pending: VecDeque::new(),
+            replies: VecDeque::new(),
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Issue a pubsub request to the service, awaiting the result
+    ///
+    /// Items received while waiting are buffered and emitted by the stream as usual.
+    async fn request(&mut self, op: &str, node: &str, child: Element) -> Result<Element, Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let id = format!("pal-{}", self.next_id);
+        self.next_id += 1;
+
+        let iq = Element::builder("iq").ns(NS_CLIENT)
+            .attr("type", "set")
+            .attr("id", &id)
+            .attr("to", &self.service)
+            .append(Element::builder("pubsub").ns(NS_PUBSUB).append(child).build())
+            .build();
+
+        self.client.send(Packet::Stanza(iq)).await?;
+
+        let (client, pending, replies) = (&mut self.client, &mut self.pending, &mut self.replies);
+        let wait = async {
+            while let Some(e) = client.next().await {
+                let s = match e {
+                    Event::Stanza(s) => s,
+                    Event::Disconnected(_) => break,
+                    Event::Online{ .. } => continue,
+                };
+
+                if s.is("iq", NS_CLIENT) && s.attr("id") == Some(id.as_str()) {
+                    return match s.attr("type") {
+                        Some("result") => Ok(s),
+                        _ => Err(Error::msg(format!("XMPP pubsub {} for node {} failed: {}", op, node, to_string(&s)))),
+                    }
+                }
+
+                handle_stanza(s, pending, replies);
+            }
+            Err(Error::from(PalError::ConnectionLost))
+        };
+
+        match timeout(self.request_timeout, wait).await {
+            Ok(Err(e)) if e.is::<PalError>() => {
+                self.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                self.connected = false;
+                Err(e)
+            },
+            Ok(r) => r,
+            Err(_) => Err(PalError::Timeout{ operation: format!("XMPP pubsub {} for node {}", op, node), timeout: self.request_timeout }.into()),
+        }
+    }
+}
+
+/// Serialize an element for diagnostics or as a payload
+fn to_string(e: &Element) -> String {
+    let mut b = vec![];
+    let _ = e.write_to(&mut b);
+    String::from_utf8_lossy(&b).to_string()
+}
+
+/// Handle an unsolicited stanza, queuing pubsub items and replies to pings
+fn handle_stanza(s: Element, pending: &mut VecDeque<(String, Vec<u8>)>, replies: &mut VecDeque<Element>) {
+    if s.is("message", NS_CLIENT) {
+        let items = match s.get_child("event", NS_PUBSUB_EVENT).and_then(|e| e.get_child("items", NS_PUBSUB_EVENT)) {
+            Some(i) => i,
+            None => return,
+        };
+        let node = items.attr("node").unwrap_or_default();
+
+        for i in items.children().filter(|i| i.is("item", NS_PUBSUB_EVENT)) {
+            let p = match i.children().next() {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let data = match p.is("data", NS_DATA) {
+                true => match base64::decode(p.text().trim()) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        warn!("Invalid XMPP payload on node {}: {:?}", node, e);
+                        continue
+                    },
+                },
+                false => to_string(p).into_bytes(),
+            };
+
+            pending.push_back((node.to_string(), data));
+        }
+
+    } else if s.is("iq", NS_CLIENT) && s.attr("type") == Some("get") && s.has_child("ping", NS_PING) {
+        let mut r = Element::builder("iq").ns(NS_CLIENT)
+            .attr("type", "result")
+            .attr("id", s.attr("id").unwrap_or_default());
+        if let Some(from) = s.attr("from") {
+            r = r.attr("to", from);
+        }
+        replies.push_back(r.build());
+
+    } else {
+        debug!("Ignoring XMPP stanza: {}", s.name());
+    }
+}
+
+#[async_trait]
+impl ClientBase for XmppClient {
+    /// Close the XMPP stream
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.client.send(Packet::StreamEnd).await?;
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for XmppClient {
+    /// Publish an item containing the provided data to a node
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let p = Element::builder("publish").ns(NS_PUBSUB)
+            .attr("node", topic)
+            .append(Element::builder("item").ns(NS_PUBSUB)
+                .append(Element::builder("data").ns(NS_DATA).append(base64::encode(data)).build())
+                .build())
+            .build();
+
+        self.request("publish", topic, p).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for XmppClient {
+    /// Subscribe to items published to a node
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if self.subs.iter().any(|s| s == topic) {
+            return Ok(())
+        }
+
+        let s = Element::builder("subscribe").ns(NS_PUBSUB)
+            .attr("node", topic)
+            .attr("jid", &self.jid)
+            .build();
+
+        self.request("subscribe", topic, s).await
+            .map_err(|e| PalError::Subscription{ topic: topic.to_string(), error: e.to_string() })?;
+
+        self.subs.push(topic.to_string());
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a node
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let u = Element::builder("unsubscribe").ns(NS_PUBSUB)
+            .attr("node", topic)
+            .attr("jid", &self.jid)
+            .build();
+
+        self.request("unsubscribe", topic, u).await?;
+
+        self.subs.remove(i);
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for XmppClient, emitting node names and item payloads
+impl Stream for XmppClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Send queued replies
+        while !this.replies.is_empty() {
+            match Pin::new(&mut this.client).poll_ready(cx) {
+                Poll::Ready(Ok(_)) => {
+                    let r = this.replies.pop_front().unwrap();
+                    if let Err(e) = Pin::new(&mut this.client).start_send(Packet::Stanza(r)) {
+                        warn!("XMPP send failed: {:?}", e);
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    warn!("XMPP send failed: {:?}", e);
+                    this.replies.clear();
+                },
+                Poll::Pending => break,
+            }
+        }
+        let _ = Pin::new(&mut this.client).poll_flush(cx);
+
+        loop {
+            if let Some(m) = this.pending.pop_front() {
+                // Drop items for nodes unsubscribed since delivery
+                if !this.subs.iter().any(|s| *s == m.0) {
+                    continue
+                }
+                return Poll::Ready(Some(m))
+            }
+
+            if !this.connected {
+                return Poll::Ready(None)
+            }
+
+            match this.client.poll_next_unpin(cx) {
+                Poll::Ready(Some(Event::Stanza(s))) => {
+                    handle_stanza(s, &mut this.pending, &mut this.replies);
+                    if !this.replies.is_empty() {
+                        cx.waker().wake_by_ref();
+                    }
+                },
+                Poll::Ready(Some(Event::Online{ .. })) => (),
+                Poll::Ready(Some(Event::Disconnected(e))) => {
+                    warn!("XMPP connection lost: {:?}", e);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                },
+                Poll::Ready(None) => {
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use tokio::time::timeout;
+
+use zenoh::net::{self, Session, Subscriber, SubInfo, Reliability, SubMode, RBuf, ResKey};
+use zenoh::net::config::{self, ConfigProperties};
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{UserOptions, TransportDefaults, PalError};
+
+
+/// Zenoh session modes
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ZenohMode {
+    /// Connect directly to other peers (discovered via scouting or `zenoh_connect`)
+    Peer,
+    /// Connect via a zenoh router
+    Client,
+}
+
+impl FromStr for ZenohMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "peer" => Ok(ZenohMode::Peer),
+            "client" => Ok(ZenohMode::Client),
+            _ => Err(Error::msg(format!("Unsupported zenoh mode: {:?} (expected peer or client)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for ZenohMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZenohMode::Peer => write!(f, "peer"),
+            ZenohMode::Client => write!(f, "client"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ZenohOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "peer"))]
+    /// Session mode (peer or client, connecting via a router)
+    pub zenoh_mode: ZenohMode,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Locators for peers or routers to connect to (ie. tcp/10.0.0.1:7447),
+    /// client mode scouts for a router if not set
+    pub zenoh_connect: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Locators to listen on for incoming peer connections
+    pub zenoh_listen: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Disable multicast scouting for peers and routers
+    pub zenoh_no_scouting: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Subscribe in best-effort mode, allowing samples to be dropped under load
+    pub zenoh_best_effort: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// Session open timeout, including scouting (defaults to `TransportDefaults::connect_timeout`)
+    pub zenoh_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl Default for ZenohOptions {
+    fn default() -> Self {
+        Self {
+            zenoh_mode: ZenohMode::Peer,
+            zenoh_connect: vec![],
+            zenoh_listen: vec![],
+            zenoh_no_scouting: false,
+            zenoh_best_effort: false,
+            zenoh_connect_timeout: None,
+            user_opts: UserOptions::default(),
+            defaults: TransportDefaults::default(),
+        }
+    }
+}
+
+impl From<&str> for ZenohOptions {
+    /// Create options for a client mode session connecting to the provided router locator
+    fn from(locator: &str) -> Self {
+        Self {
+            zenoh_mode: ZenohMode::Client,
+            zenoh_connect: vec![locator.to_string()],
+            ..Default::default()
+        }
+    }
+}
+
+/// Generic futures-based zenoh client abstraction, using key expressions as topics
+///
+/// Topics are not translated, key expressions must be absolute (ie. `/demo/example`)
+/// and subscriptions use zenoh wildcards (`*` within a chunk, `**` across chunks).
+///
+/// Note subscribers borrow the session, so the session is leaked on creation
+/// and remains open after `disconnect` (which undeclares all subscribers).
+/// Clients are expected to live for the duration of the application.
+pub struct ZenohClient {
+    session: &'static Session,
+    sub_info: SubInfo,
+    subs: Vec<(String, Subscriber<'static>)>,
+    last_error: Option<(Instant, PalError)>,
+    /// Index of the next subscription to poll, rotated for fairness
+    next: usize,
+    /// Cleared on disconnect
+    connected: bool,
+}
+
+impl ZenohClient {
+    /// Create a new client using the provided options
+    pub async fn new<O: Into<ZenohOptions>>(opts: O) -> Result<ZenohClient, Error> {
+        let o = opts.into();
+
+        let config = Self::config(&o)?;
+        let connect_timeout = o.zenoh_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+
+        let session = match timeout(connect_timeout, net::open(config)).await {
+            Ok(r) => r.map_err(|e| Error::msg(format!("Failed to open zenoh session: {:?}", e)))?,
+            Err(_) => return Err(PalError::Timeout{ operation: format!("zenoh {} session open", o.zenoh_mode), timeout: connect_timeout }.into()),
+        };
+
+        debug!("Opened zenoh {} session", o.zenoh_mode);
+
+        let reliability = match o.zenoh_best_effort {
+            true => Reliability::BestEffort,
+            false => Reliability::Reliable,
+        };
+
+        Ok(ZenohClient{
+            session: Box::leak(Box::new(session)),
+            sub_info: SubInfo{ reliability, mode: SubMode::Push, period: None },
+            subs: vec![],
+            last_error: None,
+            next: 0,
+            connected: true,
+        })
+    }
+
+    /// Build zenoh session configuration
+    fn config(o: &ZenohOptions) -> Result<ConfigProperties, Error> {
+        let mut c = match o.zenoh_mode {
+            ZenohMode::Peer => config::peer(),
+            ZenohMode::Client => config::client(None),
+        };
+
+        if !o.zenoh_connect.is_empty() {
+            c.insert(config::ZN_PEER_KEY, o.zenoh_connect.join(","));
+        }
+        if !o.zenoh_listen.is_empty() {
+            c.insert(config::ZN_LISTENER_KEY, o.zenoh_listen.join(","));
+        }
+        if o.zenoh_no_scouting {
+            c.insert(config::ZN_MULTICAST_SCOUTING_KEY, "false".to_string());
+        }
+
+        match (&o.user_opts.username, &o.user_opts.password) {
+            (Some(u), Some(p)) => {
+                c.insert(config::ZN_USER_KEY, u.clone());
+                c.insert(config::ZN_PASSWORD_KEY, p.clone());
+            },
+            (None, None) => (),
+            _ => return Err(Error::msg("zenoh authentication requires both username and password")),
+        }
+
+        Ok(c)
+    }
+}
+
+#[async_trait]
+impl ClientBase for ZenohClient {
+    /// Undeclare all subscribers
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+
+        for (topic, s) in self.subs.drain(..) {
+            if let Err(e) = s.undeclare().await {
+                warn!("Failed to undeclare zenoh subscriber for {}: {:?}", topic, e);
+            }
+        }
+        self.next = 0;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for ZenohClient {
+    /// Write data to a key expression
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        self.session.write(&ResKey::from(topic), RBuf::from(data.to_vec())).await
+            .map_err(|e| Error::msg(format!("zenoh write to {} failed: {:?}", topic, e)))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ClientSub for ZenohClient {
+    /// Declare a subscriber for a key expression
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let s = self.session.declare_subscriber(&ResKey::from(topic), &self.sub_info).await
+            .map_err(|e| Error::msg(format!("zenoh subscribe to {} failed: {:?}", topic, e)))?;
+
+        self.subs.push((topic.to_string(), s));
+
+        Ok(())
+    }
+
+    /// Undeclare the subscriber for a key expression
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|(t, _)| t == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        let (_, s) = self.subs.remove(i);
+        if self.next > i {
+            self.next -= 1;
+        }
+
+        s.undeclare().await
+            .map_err(|e| Error::msg(format!("zenoh unsubscribe from {} failed: {:?}", topic, e)))?;
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for ZenohClient, polling subscribers in turn
+impl Stream for ZenohClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let n = this.subs.len();
+
+        for i in 0..n {
+            let idx = (this.next + i) % n;
+
+            match this.subs[idx].1.stream().poll_next_unpin(cx) {
+                Poll::Ready(Some(s)) => {
+                    this.next = (idx + 1) % n;
+                    return Poll::Ready(Some((s.res_name, s.payload.to_vec())))
+                },
+                Poll::Ready(None) => {
+                    warn!("zenoh subscriber for {} closed", this.subs[idx].0);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+                Poll::Pending => (),
+            }
+        }
+
+        Poll::Pending
+    }
+}
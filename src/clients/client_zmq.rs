@@ -0,0 +1,398 @@
+//! ZeroMQ client supporting PUB/SUB and PUSH/PULL socket patterns
+//!
+//! Messages are sent as two-part messages (topic, payload) for both patterns. Subscriptions
+//! set prefix filters on SUB sockets (up to the first wildcard), with received topics
+//! matched against subscriptions locally. Blocking socket operations are executed on
+//! the tokio blocking thread pool.
+
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use async_trait::async_trait;
+use anyhow::Error;
+
+use super::{ClientBase, ClientPub, ClientSub};
+use crate::{TransportDefaults, PalError};
+use crate::topics::topic_matches;
+
+/// Receive timeout for blocking reads, bounding the time subscribe / unsubscribe
+/// operations wait for the receive socket
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// ZeroMQ socket patterns
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ZmqPattern {
+    /// PUB socket for outgoing and SUB socket for incoming messages
+    PubSub,
+    /// PUSH socket for outgoing and PULL socket for incoming messages
+    PushPull,
+}
+
+impl ZmqPattern {
+    fn sockets(&self) -> (zmq::SocketType, zmq::SocketType) {
+        match self {
+            ZmqPattern::PubSub => (zmq::PUB, zmq::SUB),
+            ZmqPattern::PushPull => (zmq::PUSH, zmq::PULL),
+        }
+    }
+}
+
+impl FromStr for ZmqPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pubsub" => Ok(ZmqPattern::PubSub),
+            "pushpull" => Ok(ZmqPattern::PushPull),
+            _ => Err(Error::msg(format!("Unsupported ZeroMQ pattern: {:?} (expected pubsub or pushpull)", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for ZmqPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ZmqPattern::PubSub => write!(f, "pubsub"),
+            ZmqPattern::PushPull => write!(f, "pushpull"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ZmqOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "pubsub"))]
+    /// Socket pattern (pubsub or pushpull)
+    pub zmq_pattern: ZmqPattern,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Endpoint for outgoing (PUB / PUSH) messages (ie. tcp://127.0.0.1:5556)
+    pub zmq_out_endpoint: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Endpoint for incoming (SUB / PULL) messages
+    pub zmq_in_endpoint: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Bind the outgoing endpoint rather than connecting
+    pub zmq_bind_out: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Bind the incoming endpoint rather than connecting
+    pub zmq_bind_in: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// CURVE public key for this client (Z85 encoded)
+    pub zmq_curve_public_key: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    /// CURVE secret key for this client (Z85 encoded), bound sockets act as CURVE servers
+    pub zmq_curve_secret_key: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// CURVE public key of the server for connected sockets (Z85 encoded)
+    pub zmq_curve_server_key: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
+}
+
+impl ZmqOptions {
+    /// Create options for the provided pattern and endpoints
+    pub fn new(pattern: ZmqPattern, out_endpoint: Option<&str>, in_endpoint: Option<&str>) -> Self {
+        Self {
+            zmq_pattern: pattern,
+            zmq_out_endpoint: out_endpoint.map(|e| e.to_string()),
+            zmq_in_endpoint: in_endpoint.map(|e| e.to_string()),
+            zmq_bind_out: false,
+            zmq_bind_in: false,
+            zmq_curve_public_key: None,
+            zmq_curve_secret_key: None,
+            zmq_curve_server_key: None,
+            defaults: TransportDefaults::default(),
+        }
+    }
+
+    /// Create and bind / connect a socket
+    fn socket(&self, ctx: &zmq::Context, kind: zmq::SocketType, endpoint: &str, bind: bool) -> Result<zmq::Socket, Error> {
+        let s = ctx.socket(kind)?;
+
+        s.set_linger(self.defaults.request_timeout.as_millis() as i32)?;
+
+        match (bind, &self.zmq_curve_secret_key) {
+            (true, Some(secret)) => {
+                s.set_curve_server(true)?;
+                s.set_curve_secretkey(&z85_key(secret)?)?;
+            },
+            (false, Some(secret)) => {
+                let public = self.zmq_curve_public_key.as_ref()
+                    .ok_or_else(|| Error::msg("ZeroMQ CURVE client requires a public key"))?;
+                let server = self.zmq_curve_server_key.as_ref()
+                    .ok_or_else(|| Error::msg("ZeroMQ CURVE client requires the server public key"))?;
+
+                s.set_curve_publickey(&z85_key(public)?)?;
+                s.set_curve_secretkey(&z85_key(secret)?)?;
+                s.set_curve_serverkey(&z85_key(server)?)?;
+            },
+            (_, None) => (),
+        }
+
+        match bind {
+            true => s.bind(endpoint),
+            false => s.connect(endpoint),
+        }.map_err(|e| Error::msg(format!("ZeroMQ {} {} failed: {}", if bind { "bind to" } else { "connect to" }, endpoint, e)))?;
+
+        Ok(s)
+    }
+}
+
+/// Decode a Z85 encoded CURVE key
+fn z85_key(k: &str) -> Result<Vec<u8>, Error> {
+    match zmq::z85_decode(k) {
+        Ok(v) if v.len() == 32 => Ok(v),
+        _ => Err(Error::msg(format!("Invalid ZeroMQ CURVE key (expected 40 Z85 characters): {:?}", k))),
+    }
+}
+
+/// Generate a CURVE keypair, returning Z85 encoded (public, secret) keys
+pub fn curve_keypair() -> Result<(String, String), Error> {
+    let k = zmq::CurveKeyPair::new()?;
+    Ok((zmq::z85_encode(&k.public_key)?, zmq::z85_encode(&k.secret_key)?))
+}
+
+/// Subscription filter for a topic, up to the first wildcard
+fn filter(topic: &str) -> &str {
+    match topic.find(|c| c == '+' || c == '#') {
+        Some(i) => &topic[..i],
+        None => topic,
+    }
+}
+
+/// ZeroMQ client
+///
+/// Either endpoint may be omitted for publish-only or subscribe-only clients.
+pub struct ZmqClient {
+    pattern: ZmqPattern,
+    /// Outgoing socket
+    tx: Option<Arc<Mutex<zmq::Socket>>>,
+    /// Incoming socket
+    rx: Option<Arc<Mutex<zmq::Socket>>>,
+    /// Subscribed topics, matched against received topics
+    subs: Vec<String>,
+    receiving: Option<BoxFuture<'static, Result<Option<Vec<Vec<u8>>>, Error>>>,
+    last_error: Option<(Instant, PalError)>,
+    /// Cleared on disconnect
+    connected: bool,
+}
+
+impl ZmqClient {
+    /// Create a new client using the provided options
+    pub async fn new(opts: ZmqOptions) -> Result<ZmqClient, Error> {
+        if opts.zmq_out_endpoint.is_none() && opts.zmq_in_endpoint.is_none() {
+            return Err(Error::msg("ZeroMQ requires an outgoing and / or incoming endpoint"))
+        }
+
+        let ctx = zmq::Context::new();
+        let (out_kind, in_kind) = opts.zmq_pattern.sockets();
+
+        let tx = match &opts.zmq_out_endpoint {
+            Some(e) => Some(opts.socket(&ctx, out_kind, e, opts.zmq_bind_out)?),
+            None => None,
+        };
+
+        let rx = match &opts.zmq_in_endpoint {
+            Some(e) => {
+                let s = opts.socket(&ctx, in_kind, e, opts.zmq_bind_in)?;
+                s.set_rcvtimeo(RECV_TIMEOUT.as_millis() as i32)?;
+                Some(s)
+            },
+            None => None,
+        };
+
+        debug!("Created ZeroMQ {} client (out: {:?}, in: {:?})", opts.zmq_pattern, opts.zmq_out_endpoint, opts.zmq_in_endpoint);
+
+        Ok(ZmqClient{
+            pattern: opts.zmq_pattern,
+            tx: tx.map(|s| Arc::new(Mutex::new(s))),
+            rx: rx.map(|s| Arc::new(Mutex::new(s))),
+            subs: vec![],
+            receiving: None,
+            last_error: None,
+            connected: true,
+        })
+    }
+
+    /// Execute an operation on a socket using the blocking thread pool
+    async fn with_socket<R, F>(socket: &Option<Arc<Mutex<zmq::Socket>>>, name: &str, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&zmq::Socket) -> Result<R, zmq::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let s = match socket {
+            Some(s) => s.clone(),
+            None => return Err(Error::msg(format!("ZeroMQ {} endpoint not configured", name))),
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let s = s.lock().map_err(|_| Error::msg("ZeroMQ socket lock poisoned"))?;
+            f(&s).map_err(Error::from)
+        }).await?
+    }
+}
+
+/// Receive a message, returning None on timeout
+fn receive(rx: Arc<Mutex<zmq::Socket>>) -> BoxFuture<'static, Result<Option<Vec<Vec<u8>>>, Error>> {
+    async move {
+        tokio::task::spawn_blocking(move || {
+            let s = rx.lock().map_err(|_| Error::msg("ZeroMQ socket lock poisoned"))?;
+            match s.recv_multipart(0) {
+                Ok(m) => Ok(Some(m)),
+                Err(zmq::Error::EAGAIN) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }).await?
+    }.boxed()
+}
+
+#[async_trait]
+impl ClientBase for ZmqClient {
+    /// Close sockets, pending outgoing messages are sent until the linger period
+    /// (`TransportDefaults::request_timeout`) expires
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.connected = false;
+        self.receiving = None;
+        self.subs.clear();
+
+        let (tx, rx) = (self.tx.take(), self.rx.take());
+        tokio::task::spawn_blocking(move || {
+            drop(rx);
+            drop(tx);
+        }).await?;
+
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.last_error.clone()
+    }
+}
+
+#[async_trait]
+impl ClientPub for ZmqClient {
+    /// Send a (topic, payload) message
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+
+        let m = vec![topic.as_bytes().to_vec(), data.to_vec()];
+        Self::with_socket(&self.tx, "outgoing", move |s| s.send_multipart(m, 0)).await
+    }
+}
+
+#[async_trait]
+impl ClientSub for ZmqClient {
+    /// Subscribe to a topic (supporting `+` and `#` wildcards)
+    ///
+    /// For PUSH/PULL all messages are received by the socket and filtered by topic.
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        if !self.connected {
+            return Err(PalError::NotConnected.into())
+        }
+        if self.subs.iter().any(|s| s == topic) {
+            return Ok(())
+        }
+
+        if self.pattern == ZmqPattern::PubSub {
+            let f = filter(topic).as_bytes().to_vec();
+            Self::with_socket(&self.rx, "incoming", move |s| s.set_subscribe(&f)).await
+                .map_err(|e| PalError::Subscription{ topic: topic.to_string(), error: e.to_string() })?;
+        } else if self.rx.is_none() {
+            return Err(Error::msg("ZeroMQ incoming endpoint not configured"))
+        }
+
+        self.subs.push(topic.to_string());
+
+        Ok(())
+    }
+
+    /// Unsubscribe from a topic
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        let i = match self.subs.iter().position(|s| s == topic) {
+            Some(i) => i,
+            None => return Err(Error::msg(format!("Not subscribed to {}", topic))),
+        };
+
+        self.subs.remove(i);
+
+        if self.pattern == ZmqPattern::PubSub {
+            let f = filter(topic).as_bytes().to_vec();
+            Self::with_socket(&self.rx, "incoming", move |s| s.set_unsubscribe(&f)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Stream implementation for ZmqClient, emitting messages matching subscriptions
+impl Stream for ZmqClient {
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let rx = match (&this.rx, this.connected) {
+            (Some(rx), true) => rx.clone(),
+            _ => return Poll::Ready(None),
+        };
+
+        loop {
+            let f = this.receiving.get_or_insert_with(|| receive(rx.clone()));
+
+            let r = match f.poll_unpin(cx) {
+                Poll::Ready(r) => r,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.receiving = None;
+
+            let mut m = match r {
+                Ok(Some(m)) => m,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("ZeroMQ receive failed: {:?}", e);
+                    this.last_error = Some((Instant::now(), PalError::ConnectionLost));
+                    this.connected = false;
+                    return Poll::Ready(None)
+                },
+            };
+
+            // Single part messages have an empty topic
+            let (topic, data) = match m.len() {
+                1 => (String::new(), m.remove(0)),
+                2 => (String::from_utf8_lossy(&m[0]).to_string(), m.remove(1)),
+                n => {
+                    warn!("Dropping ZeroMQ message with {} parts (expected topic and payload)", n);
+                    continue
+                },
+            };
+
+            if this.subs.iter().any(|s| topic_matches(s, &topic)) {
+                return Poll::Ready(Some((topic, data)))
+            }
+        }
+    }
+}
@@ -1,27 +1,180 @@
 
+use std::time::{Duration, Instant};
+
+use futures::Future;
 use futures::stream::Stream;
+use log::{debug, warn};
 use async_trait::async_trait;
 
+use anyhow::Error;
 pub use anyhow::Result;
 
+use crate::{PalError, TlsOptions};
+
+#[cfg(feature = "serde")]
+use {
+    futures::future,
+    futures::stream::{StreamExt, BoxStream},
+    serde::de::DeserializeOwned,
+    crate::codec::Codec,
+    crate::topics::topic_matches,
+};
+
 
 #[cfg(feature = "client_mqtt")]
 pub mod client_mqtt;
 #[cfg(feature = "client_mqtt")]
-pub use client_mqtt::{MqttClient, MqttOptions};
+pub use client_mqtt::{MqttClient, MqttHandle, MqttOptions, MqttVersion, SubscribeError, DropPolicy, subscription_ids};
 
 #[cfg(feature = "client_coap")]
 pub mod client_coap;
 #[cfg(feature = "client_coap")]
-pub use client_coap::{CoapClient, CoapOptions};
+pub use client_coap::{CoapClient, CoapOptions, CoapMethod};
+
+#[cfg(feature = "client_http")]
+pub mod client_http;
+#[cfg(feature = "client_http")]
+pub use client_http::{HttpClient, HttpOptions, HttpMethod};
+
+#[cfg(feature = "client_ws")]
+pub mod client_ws;
+#[cfg(feature = "client_ws")]
+pub use client_ws::{WsClient, WsOptions, WsFraming};
+
+#[cfg(feature = "client_amqp")]
+pub mod client_amqp;
+#[cfg(feature = "client_amqp")]
+pub use client_amqp::{AmqpClient, AmqpOptions};
+
+#[cfg(feature = "client_nats")]
+pub mod client_nats;
+#[cfg(feature = "client_nats")]
+pub use client_nats::{NatsClient, NatsOptions};
+
+#[cfg(feature = "client_kafka")]
+pub mod client_kafka;
+#[cfg(feature = "client_kafka")]
+pub use client_kafka::{KafkaClient, KafkaOptions};
+
+#[cfg(feature = "client_zenoh")]
+pub mod client_zenoh;
+#[cfg(feature = "client_zenoh")]
+pub use client_zenoh::{ZenohClient, ZenohOptions, ZenohMode};
+
+#[cfg(feature = "client_grpc")]
+pub mod client_grpc;
+#[cfg(feature = "client_grpc")]
+pub use client_grpc::{GrpcClient, GrpcOptions};
+
+#[cfg(feature = "client_mqttsn")]
+pub mod client_mqttsn;
+#[cfg(feature = "client_mqttsn")]
+pub use client_mqttsn::{MqttSnClient, MqttSnOptions, MqttSnState};
+
+#[cfg(feature = "client_lwm2m")]
+pub mod client_lwm2m;
+#[cfg(feature = "client_lwm2m")]
+pub use client_lwm2m::{Lwm2mClient, Lwm2mOptions, Lwm2mPath};
+
+#[cfg(feature = "client_opcua")]
+pub mod client_opcua;
+#[cfg(feature = "client_opcua")]
+pub use client_opcua::{OpcUaClient, OpcUaOptions, OpcUaSecurityPolicy, OpcUaSecurityMode};
+
+#[cfg(feature = "client_modbus")]
+pub mod client_modbus;
+#[cfg(feature = "client_modbus")]
+pub use client_modbus::{ModbusClient, ModbusOptions, Registers, RegisterKind};
+
+#[cfg(feature = "client_azure_iot")]
+pub mod client_azure_iot;
+#[cfg(feature = "client_azure_iot")]
+pub use client_azure_iot::{AzureIotClient, AzureIotOptions};
+
+#[cfg(feature = "client_aws_iot")]
+pub mod client_aws_iot;
+#[cfg(feature = "client_aws_iot")]
+pub use client_aws_iot::{AwsIotClient, AwsIotOptions, Shadow};
+
+#[cfg(feature = "client_mqtt_jwt")]
+pub mod client_mqtt_jwt;
+#[cfg(feature = "client_mqtt_jwt")]
+pub use client_mqtt_jwt::{JwtMqttClient, JwtOptions, JwtAlgorithm};
+
+#[cfg(feature = "client_thingsboard")]
+pub mod client_thingsboard;
+#[cfg(feature = "client_thingsboard")]
+pub use client_thingsboard::{ThingsBoardClient, ThingsBoardOptions, ThingsBoardEvent};
+
+#[cfg(feature = "client_ttn")]
+pub mod client_ttn;
+#[cfg(feature = "client_ttn")]
+pub use client_ttn::{TtnClient, TtnOptions, Uplink, Downlink};
+
+#[cfg(feature = "client_stomp")]
+pub mod client_stomp;
+#[cfg(feature = "client_stomp")]
+pub use client_stomp::{StompClient, StompOptions};
+
+#[cfg(feature = "client_xmpp")]
+pub mod client_xmpp;
+#[cfg(feature = "client_xmpp")]
+pub use client_xmpp::{XmppClient, XmppOptions};
+
+#[cfg(feature = "client_zmq")]
+pub mod client_zmq;
+#[cfg(feature = "client_zmq")]
+pub use client_zmq::{ZmqClient, ZmqOptions, ZmqPattern};
+
+#[cfg(feature = "client_dds")]
+pub mod client_dds;
+#[cfg(feature = "client_dds")]
+pub use client_dds::{DdsClient, DdsOptions, DdsReliability, DdsDurability};
+
+#[cfg(feature = "client_bacnet")]
+pub mod client_bacnet;
+#[cfg(feature = "client_bacnet")]
+pub use client_bacnet::{BacnetClient, BacnetOptions, BacnetPath, BacnetValue};
+
+#[cfg(feature = "client_snmp")]
+pub mod client_snmp;
+#[cfg(feature = "client_snmp")]
+pub use client_snmp::{SnmpClient, SnmpOptions, SnmpValue, Oid};
+
+#[cfg(feature = "client_serial")]
+pub mod client_serial;
+#[cfg(feature = "client_serial")]
+pub use client_serial::{SerialClient, SerialOptions, SerialFraming};
+
+#[cfg(feature = "client_ble")]
+pub mod client_ble;
+#[cfg(feature = "client_ble")]
+pub use client_ble::{BleClient, BleOptions};
+
+#[cfg(feature = "client_udp")]
+pub mod client_udp;
+#[cfg(feature = "client_udp")]
+pub use client_udp::{UdpClient, UdpOptions};
+
+pub mod registry;
+pub use registry::ClientRegistry;
 
 
 /// Abstract client base trait, provides connect / status / disconnect
 #[async_trait]
-pub trait ClientBase: Sized + Send {
+pub trait ClientBase: Send {
 
     /// Disconnect a client
     async fn disconnect(&mut self) -> Result<()>;
+
+    /// Check whether the client is currently connected
+    fn is_connected(&self) -> bool;
+
+    /// Fetch the most recent error not returned directly to the caller
+    /// (ie. a lost connection or failed background re-registration), for diagnostics
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        None
+    }
 }
 
 /// Abstract client publish trait, allows writing data
@@ -31,6 +184,47 @@ pub trait ClientPub {
     async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<()>;
 }
 
+/// Abstract client request trait, allows request / response interactions
+#[async_trait]
+pub trait ClientReq {
+    /// Issue a request to a topic / resource / endpoint, returning the response
+    /// or an error if no response is received within `timeout`
+    async fn request(&mut self, topic: &str, data: &[u8], timeout: Duration) -> Result<Vec<u8>>;
+}
+
+/// Abstract client non-blocking publish trait, allows writing data without waiting
+pub trait ClientTryPub {
+    /// Attempt to publish data to a topic / resource / endpoint without waiting,
+    /// returning `TryPublishError::WouldBlock` if the client cannot currently accept the message
+    fn try_publish(&mut self, topic: &str, data: &[u8]) -> Result<(), TryPublishError>;
+}
+
+/// Error returned by non-blocking publish
+#[derive(Debug)]
+pub enum TryPublishError {
+    /// The client could not accept the message without waiting
+    WouldBlock,
+    /// Publishing failed
+    Other(Error),
+}
+
+impl std::fmt::Display for TryPublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryPublishError::WouldBlock => write!(f, "Publish would block"),
+            TryPublishError::Other(e) => write!(f, "Publish failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TryPublishError {}
+
+impl From<Error> for TryPublishError {
+    fn from(e: Error) -> Self {
+        TryPublishError::Other(e)
+    }
+}
+
 /// Abstract client subscribe trait, allows subscription and streaming of data
 #[async_trait]
 pub trait ClientSub: Stream<Item = (String, Vec<u8>)> {
@@ -41,4 +235,349 @@ pub trait ClientSub: Stream<Item = (String, Vec<u8>)> {
     async fn unsubscribe(&mut self, topic: &str) -> Result<()>;
 }
 
+/// Typed subscription extension, enabled with the `serde` feature
+#[cfg(feature = "serde")]
+#[async_trait]
+pub trait ClientSubTyped: ClientSub + Unpin + Send {
+    /// Subscribe to a topic, returning a stream of messages matching this topic decoded
+    /// with the provided codec. Decode failures are returned as `Err` items.
+    async fn subscribe_typed<'a, T, C>(&'a mut self, topic: &str, codec: C) -> Result<BoxStream<'a, Result<(String, T)>>>
+    where
+        T: DeserializeOwned + Send + 'static,
+        C: Codec + Send + 'a;
+}
+
+#[cfg(feature = "serde")]
+#[async_trait]
+impl <S> ClientSubTyped for S where S: ClientSub + Unpin + Send {
+    async fn subscribe_typed<'a, T, C>(&'a mut self, topic: &str, codec: C) -> Result<BoxStream<'a, Result<(String, T)>>>
+    where
+        T: DeserializeOwned + Send + 'static,
+        C: Codec + Send + 'a,
+    {
+        self.subscribe(topic).await?;
+
+        let pattern = topic.to_string();
+        let s = self
+            .filter(move |(t, _)| future::ready(topic_matches(&pattern, t)) )
+            .map(move |(t, d)| {
+                let v = codec.decode(&d)
+                    .map_err(|e| e.context(format!("Failed to decode message on {}", t)))?;
+                Ok((t, v))
+            });
+
+        Ok(Box::pin(s))
+    }
+}
+
+
+
+/// Object-safe client trait combining base / publish / subscribe, for dynamic dispatch
+pub trait DynClient: ClientBase + ClientPub + ClientSub + Unpin + Send {}
+
+impl <T> DynClient for T where T: ClientBase + ClientPub + ClientSub + Unpin + Send {}
+
+/// Client protocols selected by URL scheme in `connect`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Protocol {
+    Mqtt,
+    Coap,
+    Http,
+    Amqp,
+    Nats,
+    Kafka,
+    MqttSn,
+    OpcUa,
+    Modbus,
+    Bacnet,
+    Snmp,
+    Udp,
+    Stomp,
+}
+
+impl Protocol {
+    /// Determine the client protocol from the scheme of the provided URL
+    pub(crate) fn from_url(url: &str) -> Result<Self> {
+        let scheme = match url.find("://") {
+            Some(i) => &url[..i],
+            None => return Err(Error::msg(format!("Missing scheme in client URL: {:?}", url))),
+        };
+
+        let p = match scheme {
+            "tcp" | "ssl" | "ws" | "wss" | "mqtt" | "mqtts" => Protocol::Mqtt,
+            "coap" => Protocol::Coap,
+            "http" | "https" => Protocol::Http,
+            "amqp" | "amqps" => Protocol::Amqp,
+            "nats" => Protocol::Nats,
+            "kafka" | "kafkas" => Protocol::Kafka,
+            "mqttsn" => Protocol::MqttSn,
+            "opc.tcp" => Protocol::OpcUa,
+            "modbus" => Protocol::Modbus,
+            "bacnet" => Protocol::Bacnet,
+            "snmp" => Protocol::Snmp,
+            "udp" => Protocol::Udp,
+            "stomp" | "stomp+ssl" | "stomps" => Protocol::Stomp,
+            "coaps" => return Err(Error::msg(format!("CoAP over DTLS is not supported (URL: {:?})", url))),
+            _ => return Err(Error::msg(format!("Unsupported client URL scheme: {:?}", scheme))),
+        };
+
+        Ok(p)
+    }
+}
+
+/// Connect to a client using the scheme of the provided URL
+///
+/// - `tcp://`, `ssl://`, `mqtt://` and `mqtts://` connect via MQTT (requires `client_mqtt`)
+/// - `ws://` and `wss://` connect via MQTT over WebSockets (requires `client_mqtt`), plain
+///   WebSocket (JSON message) connections are not dispatched by URL, use `WsClient::new`
+/// - `coap://` connects via CoAP (requires `client_coap`)
+/// - `http://` and `https://` connect via HTTP (requires `client_http`)
+/// - `amqp://` and `amqps://` connect via AMQP 0-9-1 (requires `client_amqp`)
+/// - `nats://` connects via NATS (requires `client_nats`)
+/// - `kafka://` and `kafkas://` connect via Kafka (requires `client_kafka`)
+/// - `mqttsn://` connects to an MQTT-SN gateway over UDP (requires `client_mqttsn`, TLS is not supported)
+/// - `opc.tcp://` connects to an OPC-UA server without security (requires `client_opcua`, see `OpcUaOptions` for endpoint security)
+/// - `modbus://` connects to a Modbus TCP server (requires `client_modbus`, TLS is not supported)
+/// - `bacnet://` connects to a BACnet/IP device (requires `client_bacnet`, TLS is not supported)
+/// - `snmp://` connects to an SNMP agent (requires `client_snmp`, TLS is not supported)
+/// - `udp://` sends and receives raw UDP datagrams (requires `client_udp`, TLS is not supported)
+/// - `stomp://`, `stomp+ssl://` and `stomps://` connect via STOMP (requires `client_stomp`)
+pub async fn connect(url: &str) -> Result<Box<dyn DynClient>> {
+    connect_tls(url, TlsOptions::default()).await
+}
+
+/// Connect to a client using the scheme of the provided URL and TLS options (see `connect`)
+///
+/// `coaps://` (CoAP over DTLS) is not supported by the underlying CoAP driver and returns an error.
+#[allow(unused_variables)]
+pub async fn connect_tls(url: &str, tls: TlsOptions) -> Result<Box<dyn DynClient>> {
+    match Protocol::from_url(url)? {
+        Protocol::Mqtt => {
+            #[cfg(feature = "client_mqtt")]
+            {
+                // paho expects tcp:// or ssl:// in place of mqtt:// or mqtts://
+                let url = match url {
+                    u if u.starts_with("mqtt://") => format!("tcp://{}", &u[7..]),
+                    u if u.starts_with("mqtts://") => format!("ssl://{}", &u[8..]),
+                    u => u.to_string(),
+                };
 
+                let c = MqttClient::new((url, tls)).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_mqtt"))]
+            Err(Error::msg(format!("MQTT URL {:?} requires the client_mqtt feature", url)))
+        },
+        Protocol::Coap => {
+            #[cfg(feature = "client_coap")]
+            {
+                let mut o: CoapOptions = url.into();
+                o.tls_opts = tls;
+
+                let c = CoapClient::new(o).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_coap"))]
+            Err(Error::msg(format!("CoAP URL {:?} requires the client_coap feature", url)))
+        },
+        Protocol::Http => {
+            #[cfg(feature = "client_http")]
+            {
+                let mut o: HttpOptions = url.into();
+                o.tls_opts = tls;
+
+                let c = HttpClient::new(o)?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_http"))]
+            Err(Error::msg(format!("HTTP URL {:?} requires the client_http feature", url)))
+        },
+        Protocol::Amqp => {
+            #[cfg(feature = "client_amqp")]
+            {
+                let c = AmqpClient::new((url, tls)).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_amqp"))]
+            Err(Error::msg(format!("AMQP URL {:?} requires the client_amqp feature", url)))
+        },
+        Protocol::Nats => {
+            #[cfg(feature = "client_nats")]
+            {
+                let c = NatsClient::new((url, tls)).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_nats"))]
+            Err(Error::msg(format!("NATS URL {:?} requires the client_nats feature", url)))
+        },
+        Protocol::Kafka => {
+            #[cfg(feature = "client_kafka")]
+            {
+                let c = KafkaClient::new((url, tls))?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_kafka"))]
+            Err(Error::msg(format!("Kafka URL {:?} requires the client_kafka feature", url)))
+        },
+        Protocol::MqttSn => {
+            #[cfg(feature = "client_mqttsn")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("MQTT-SN does not support TLS (URL: {:?})", url)))
+                }
+
+                let c = MqttSnClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_mqttsn"))]
+            Err(Error::msg(format!("MQTT-SN URL {:?} requires the client_mqttsn feature", url)))
+        },
+        Protocol::OpcUa => {
+            #[cfg(feature = "client_opcua")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("OPC-UA uses endpoint security policies rather than TLS (URL: {:?})", url)))
+                }
+
+                let c = OpcUaClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_opcua"))]
+            Err(Error::msg(format!("OPC-UA URL {:?} requires the client_opcua feature", url)))
+        },
+        Protocol::Modbus => {
+            #[cfg(feature = "client_modbus")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("Modbus TCP does not support TLS (URL: {:?})", url)))
+                }
+
+                let c = ModbusClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_modbus"))]
+            Err(Error::msg(format!("Modbus URL {:?} requires the client_modbus feature", url)))
+        },
+        Protocol::Bacnet => {
+            #[cfg(feature = "client_bacnet")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("BACnet/IP does not support TLS (URL: {:?})", url)))
+                }
+
+                let c = BacnetClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_bacnet"))]
+            Err(Error::msg(format!("BACnet URL {:?} requires the client_bacnet feature", url)))
+        },
+        Protocol::Snmp => {
+            #[cfg(feature = "client_snmp")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("SNMP does not support TLS (URL: {:?})", url)))
+                }
+
+                let c = SnmpClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_snmp"))]
+            Err(Error::msg(format!("SNMP URL {:?} requires the client_snmp feature", url)))
+        },
+        Protocol::Udp => {
+            #[cfg(feature = "client_udp")]
+            {
+                if tls.is_configured() {
+                    return Err(Error::msg(format!("UDP does not support TLS (URL: {:?})", url)))
+                }
+
+                let c = UdpClient::new(url).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_udp"))]
+            Err(Error::msg(format!("UDP URL {:?} requires the client_udp feature", url)))
+        },
+        Protocol::Stomp => {
+            #[cfg(feature = "client_stomp")]
+            {
+                let c = StompClient::new((url, tls)).await?;
+                Ok(Box::new(c))
+            }
+            #[cfg(not(feature = "client_stomp"))]
+            Err(Error::msg(format!("STOMP URL {:?} requires the client_stomp feature", url)))
+        },
+    }
+}
+
+/// Wait for the provided shutdown signal (for example `tokio::signal::ctrl_c()`),
+/// then disconnect all provided clients.
+///
+/// All clients are disconnected even if some fail, with the first error returned.
+pub async fn shutdown<S: Future>(signal: S, clients: &mut [&mut dyn DynClient]) -> Result<()> {
+    signal.await;
+
+    debug!("Shutdown signal received, disconnecting {} clients", clients.len());
+
+    let mut res = Ok(());
+
+    for c in clients.iter_mut() {
+        if let Err(e) = c.disconnect().await {
+            warn!("Error disconnecting client: {:?}", e);
+            if res.is_ok() {
+                res = Err(e);
+            }
+        }
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_schemes() {
+        let tests = &[
+            ("tcp://localhost:1883", Protocol::Mqtt),
+            ("ssl://localhost:8883", Protocol::Mqtt),
+            ("mqtt://localhost:1883", Protocol::Mqtt),
+            ("mqtts://localhost:8883", Protocol::Mqtt),
+            // MQTT over WebSockets, not `WsClient`
+            ("ws://localhost:8080", Protocol::Mqtt),
+            ("wss://localhost:8443", Protocol::Mqtt),
+            ("coap://localhost:5683", Protocol::Coap),
+            ("http://localhost:8080", Protocol::Http),
+            ("https://localhost:8443", Protocol::Http),
+            ("amqp://localhost:5672", Protocol::Amqp),
+            ("amqps://localhost:5671", Protocol::Amqp),
+            ("nats://localhost:4222", Protocol::Nats),
+            ("kafka://localhost:9092", Protocol::Kafka),
+            ("kafkas://localhost:9093", Protocol::Kafka),
+            ("mqttsn://localhost:1884", Protocol::MqttSn),
+            ("opc.tcp://localhost:4840", Protocol::OpcUa),
+            ("modbus://localhost:502", Protocol::Modbus),
+            ("bacnet://localhost:47808", Protocol::Bacnet),
+            ("snmp://localhost:161", Protocol::Snmp),
+            ("udp://localhost:9000", Protocol::Udp),
+            ("stomp://localhost:61613", Protocol::Stomp),
+            ("stomp+ssl://localhost:61614", Protocol::Stomp),
+            ("stomps://localhost:61614", Protocol::Stomp),
+        ];
+
+        for (url, expected) in tests.iter() {
+            assert_eq!(Protocol::from_url(url).unwrap(), *expected, "{}", url);
+        }
+
+        for url in &["coaps://localhost:5684", "foo://localhost", "localhost:1883"] {
+            assert!(Protocol::from_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_unsupported() {
+        for url in &["coaps://127.0.0.1:1", "foo://127.0.0.1:1", "127.0.0.1:1"] {
+            assert!(connect(url).await.is_err(), "{}", url);
+        }
+    }
+}
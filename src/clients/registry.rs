@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use log::{debug, warn};
+use anyhow::Error;
+
+use super::{connect, DynClient};
+
+
+/// Registry of named clients, managing the lifecycle of many connections
+///
+/// Clients are connected on `add` and disconnected on `remove` or `disconnect_all`.
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: BTreeMap<String, Box<dyn DynClient>>,
+}
+
+impl ClientRegistry {
+    /// Create a new, empty, registry
+    pub fn new() -> Self {
+        Self { clients: BTreeMap::new() }
+    }
+
+    /// Connect a client using the provided URL (see `clients::connect`) and add it to the registry
+    pub async fn add(&mut self, name: &str, url: &str) -> Result<(), Error> {
+        if self.clients.contains_key(name) {
+            return Err(Error::msg(format!("Client {} already exists", name)))
+        }
+
+        debug!("Connecting client {} ({})", name, url);
+
+        let c = connect(url).await?;
+        self.clients.insert(name.to_string(), c);
+
+        Ok(())
+    }
+
+    /// Add an existing client to the registry, returning any client previously registered with this name
+    pub fn insert(&mut self, name: &str, client: Box<dyn DynClient>) -> Option<Box<dyn DynClient>> {
+        self.clients.insert(name.to_string(), client)
+    }
+
+    /// Fetch a client by name
+    pub fn get(&mut self, name: &str) -> Option<&mut (dyn DynClient + 'static)> {
+        self.clients.get_mut(name).map(|c| c.as_mut())
+    }
+
+    /// Disconnect and remove a client by name
+    pub async fn remove(&mut self, name: &str) -> Result<(), Error> {
+        let mut c = match self.clients.remove(name) {
+            Some(c) => c,
+            None => return Err(Error::msg(format!("Unknown client {}", name))),
+        };
+
+        debug!("Disconnecting client {}", name);
+
+        c.disconnect().await
+    }
+
+    /// Fetch registered client names
+    pub fn names(&self) -> Vec<String> {
+        self.clients.keys().cloned().collect()
+    }
+
+    /// Fetch the number of registered clients
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Check whether the registry is empty
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Publish data to a topic on all registered clients, returning the result for each client by name
+    pub async fn broadcast_publish(&mut self, topic: &str, data: &[u8]) -> Vec<(String, Result<(), Error>)> {
+        let mut results = Vec::with_capacity(self.clients.len());
+
+        for (name, c) in self.clients.iter_mut() {
+            let res = c.publish(topic, data).await;
+            if let Err(e) = &res {
+                warn!("Broadcast publish to {} via {} failed: {:?}", topic, name, e);
+            }
+            results.push((name.clone(), res));
+        }
+
+        results
+    }
+
+    /// Disconnect and remove all clients, returning the first error encountered
+    pub async fn disconnect_all(&mut self) -> Result<(), Error> {
+        let mut res = Ok(());
+
+        for (name, mut c) in std::mem::take(&mut self.clients) {
+            if let Err(e) = c.disconnect().await {
+                warn!("Error disconnecting client {}: {:?}", name, e);
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+
+        res
+    }
+}
@@ -0,0 +1,155 @@
+//! Time source abstraction, allowing time based components to be tested deterministically
+//!
+//! Components take an `Arc<dyn Clock>` (defaulting to `SystemClock`) for reading the current
+//! time and creating timers, so tests can use a `TestClock` and advance time synthetically.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tokio")]
+use std::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+
+#[cfg(feature = "tokio")]
+use futures::future::{BoxFuture, FutureExt};
+
+
+/// Clock trait, provides the current time and timers to time based components
+pub trait Clock: Send + Sync {
+    /// Fetch the current time
+    fn now(&self) -> Instant;
+
+    /// Fetch the time elapsed since the provided instant
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    /// Create a timer completing once the clock reaches the provided deadline
+    #[cfg(feature = "tokio")]
+    fn timer(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+/// Real (system) clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[cfg(feature = "tokio")]
+    fn timer(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::delay_until(tokio::time::Instant::from_std(deadline)).boxed()
+    }
+}
+
+/// Manually advanced clock for testing, clones share the same time
+///
+/// Timers complete when the clock is advanced past their deadline.
+#[derive(Debug, Clone)]
+pub struct TestClock {
+    state: Arc<Mutex<TestState>>,
+}
+
+#[derive(Debug)]
+struct TestState {
+    now: Instant,
+    /// Wakers for pending timers, woken on advance
+    #[cfg(feature = "tokio")]
+    wakers: Vec<Waker>,
+}
+
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestClock {
+    /// Create a new test clock starting at the current time
+    pub fn new() -> Self {
+        Self{ state: Arc::new(Mutex::new(TestState{
+            now: Instant::now(),
+            #[cfg(feature = "tokio")]
+            wakers: vec![],
+        })) }
+    }
+
+    /// Advance the clock by the provided duration, completing any timers now due
+    pub fn advance(&self, d: Duration) {
+        let mut s = self.state.lock().unwrap();
+        s.now += d;
+
+        #[cfg(feature = "tokio")]
+        for w in s.wakers.drain(..) {
+            w.wake();
+        }
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    #[cfg(feature = "tokio")]
+    fn timer(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        TestTimer{ clock: self.clone(), deadline }.boxed()
+    }
+}
+
+/// Timer for a `TestClock`
+#[cfg(feature = "tokio")]
+struct TestTimer {
+    clock: TestClock,
+    deadline: Instant,
+}
+
+#[cfg(feature = "tokio")]
+impl Future for TestTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let mut s = self.clock.state.lock().unwrap();
+
+        if s.now >= self.deadline {
+            return Poll::Ready(())
+        }
+
+        s.wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_advance() {
+        let clock = TestClock::new();
+        let start = clock.now();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(5));
+
+        // Clones share the same time
+        let c = clock.clone();
+        c.advance(Duration::from_secs(1));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(6));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_clock_timer() {
+        let clock = TestClock::new();
+        let mut t = clock.timer(clock.now() + Duration::from_secs(10));
+
+        assert!((&mut t).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(9));
+        assert!((&mut t).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(t.now_or_never().is_some());
+    }
+}
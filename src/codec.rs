@@ -0,0 +1,30 @@
+//! Payload codecs for encoding / decoding typed messages
+
+use anyhow::Error;
+use serde::{Serialize, de::DeserializeOwned};
+
+
+/// Codec trait, encodes and decodes typed payloads
+pub trait Codec {
+    /// Decode a payload
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error>;
+
+    /// Encode a payload
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error>;
+}
+
+/// JSON codec, enabled with the `json` feature
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, Error> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    fn encode<T: Serialize>(&self, v: &T) -> Result<Vec<u8>, Error> {
+        Ok(serde_json::to_vec(v)?)
+    }
+}
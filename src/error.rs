@@ -0,0 +1,39 @@
+//! Common error types
+//!
+//! Functions return `anyhow::Error`, use `downcast_ref::<PalError>()` to match these.
+
+use std::fmt;
+use std::time::Duration;
+
+
+/// Errors raised by clients and stores
+#[derive(Debug, Clone, PartialEq)]
+pub enum PalError {
+    /// Client is not connected
+    NotConnected,
+    /// Connection to the server was lost
+    ConnectionLost,
+    /// A background subscription operation (ie. re-registration or polling) failed
+    Subscription { topic: String, error: String },
+    /// A conditional update failed as the document was modified concurrently
+    VersionConflict { index: String, id: String },
+    /// A buffer reached its `MemoryBudget` bound
+    BudgetExceeded { buffer: String, limit: usize },
+    /// An operation (ie. connecting) did not complete within the configured timeout
+    Timeout { operation: String, timeout: Duration },
+}
+
+impl fmt::Display for PalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PalError::NotConnected => write!(f, "Client is not connected"),
+            PalError::ConnectionLost => write!(f, "Connection lost"),
+            PalError::Subscription{ topic, error } => write!(f, "Subscription to {} failed: {}", topic, error),
+            PalError::VersionConflict{ index, id } => write!(f, "Version conflict updating {}/{}", index, id),
+            PalError::BudgetExceeded{ buffer, limit } => write!(f, "Memory budget exceeded for {} (limit: {})", buffer, limit),
+            PalError::Timeout{ operation, timeout } => write!(f, "{} timed out after {:?}", operation, timeout),
+        }
+    }
+}
+
+impl std::error::Error for PalError {}
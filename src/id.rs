@@ -0,0 +1,171 @@
+//! Pluggable ID generation for client IDs, correlation data and other unique keys
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+
+/// ID generator trait, generated IDs must be unique for the life of the generator
+///
+/// IDs may be used in topics and so should not contain MQTT topic separators or wildcards (`/`, `+`, `#`).
+pub trait IdGenerator: Send + Sync {
+    /// Generate a new ID
+    fn generate(&self) -> String;
+}
+
+/// Random (UUIDv4) ID generator, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        let mut b: [u8; 16] = rand::thread_rng().gen();
+
+        // Set version (4) and variant (RFC 4122) bits
+        b[6] = (b[6] & 0x0f) | 0x40;
+        b[8] = (b[8] & 0x3f) | 0x80;
+
+        format!("{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+            b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15])
+    }
+}
+
+/// Monotonic timestamp-based ID generator, producing lexicographically sortable IDs
+///
+/// IDs are the microseconds since the UNIX epoch (hex encoded), incremented where
+/// required to remain strictly increasing. IDs are only unique within a generator.
+#[derive(Debug, Default)]
+pub struct TimestampGenerator {
+    last: AtomicU64,
+}
+
+impl TimestampGenerator {
+    /// Create a new timestamp generator
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IdGenerator for TimestampGenerator {
+    fn generate(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let next = now.max(last + 1);
+            match self.last.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return format!("{:016x}", next),
+                Err(l) => last = l,
+            }
+        }
+    }
+}
+
+/// Deterministic sequential ID generator (`{prefix}{n}`), for testing
+#[derive(Debug, Default)]
+pub struct SequentialGenerator {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SequentialGenerator {
+    /// Create a new sequential generator with the provided prefix
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IdGenerator for SequentialGenerator {
+    fn generate(&self) -> String {
+        format!("{}{}", self.prefix, self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Shared ID generator for client options, defaulting to `UuidGenerator`
+///
+/// Options compare equal where both use the default or the same generator instance.
+#[derive(Clone, Default)]
+pub struct SharedIdGenerator(Option<Arc<dyn IdGenerator>>);
+
+impl SharedIdGenerator {
+    /// Create a shared ID generator from the provided generator
+    pub fn new<G: IdGenerator + 'static>(g: G) -> Self {
+        Self(Some(Arc::new(g)))
+    }
+
+    /// Generate an opaque token of up to 8 bytes (ie. for CoAP tokens), longer IDs are hashed
+    pub fn generate_token(&self) -> Vec<u8> {
+        let id = self.generate();
+        if id.len() <= 8 {
+            return id.into_bytes()
+        }
+
+        let mut h = DefaultHasher::new();
+        id.hash(&mut h);
+        h.finish().to_be_bytes().to_vec()
+    }
+}
+
+impl IdGenerator for SharedIdGenerator {
+    fn generate(&self) -> String {
+        match &self.0 {
+            Some(g) => g.generate(),
+            None => UuidGenerator.generate(),
+        }
+    }
+}
+
+impl From<Arc<dyn IdGenerator>> for SharedIdGenerator {
+    fn from(g: Arc<dyn IdGenerator>) -> Self {
+        Self(Some(g))
+    }
+}
+
+impl std::fmt::Debug for SharedIdGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(_) => write!(f, "SharedIdGenerator(custom)"),
+            None => write!(f, "SharedIdGenerator(uuid)"),
+        }
+    }
+}
+
+impl PartialEq for SharedIdGenerator {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (None, None) => true,
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_generator() {
+        let ids = SharedIdGenerator::new(SequentialGenerator::new("t"));
+        assert_eq!(ids.generate(), "t0");
+
+        // Short IDs are used as tokens directly, longer IDs hashed to 8 bytes
+        assert_eq!(ids.generate_token(), b"t1".to_vec());
+        assert_eq!(SharedIdGenerator::default().generate_token().len(), 8);
+
+        assert_eq!(SharedIdGenerator::default(), SharedIdGenerator::default());
+        assert_eq!(ids, ids.clone());
+        assert_ne!(ids, SharedIdGenerator::new(SequentialGenerator::new("t")));
+        assert_ne!(ids, SharedIdGenerator::default());
+    }
+}
@@ -1,16 +1,54 @@
 //! IoT Protocol Abstraction Library
+//!
+//! Clients, wrappers and stores do not spawn tasks, timers (ie. CoAP re-registration and batch
+//! flushes) are driven from `poll_next` or `tick` on the caller's task. Some underlying drivers
+//! run their own threads (ie. the paho MQTT library and OPC-UA session), these are stopped on
+//! disconnect or when the client is dropped.
 
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::Error;
 
+pub mod error;
+pub use error::PalError;
+
 pub mod clients;
+pub use clients::{connect, connect_tls};
 
 pub mod stores;
 
+pub mod wrappers;
+
+pub mod bridge;
+
+pub mod link_format;
+pub use link_format::{parse_core_links, ResourceLink};
+
+pub mod topics;
+pub use topics::TopicMatcher;
+
+#[cfg(feature = "serde")]
+pub mod codec;
+
+pub mod id;
+pub use id::{IdGenerator, SharedIdGenerator, UuidGenerator, TimestampGenerator, SequentialGenerator};
+
+pub mod clock;
+pub use clock::{Clock, SystemClock, TestClock};
+
+pub mod tls;
+pub use tls::{TlsMode, TlsVersion};
+
+pub mod budget;
+pub use budget::{MemoryBudget, MemoryUsage, BudgetPolicy};
+
+pub mod backoff;
+pub use backoff::{Backoff, BackoffOptions, Jitter};
+
 
 /// General TLS Configuration options
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 #[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TlsOptions {
@@ -25,6 +63,41 @@ pub struct TlsOptions {
     #[cfg_attr(feature = "structopt", structopt(long, env))]
     /// TLS client key file in PEM format
     pub tls_key_file: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env, hide_env_values = true))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// TLS Certificate Authority (CA) in PEM format, takes precedence over `tls_ca_file`
+    pub tls_ca_pem: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env, hide_env_values = true))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// TLS client certificate in PEM format, takes precedence over `tls_cert_file`
+    pub tls_cert_pem: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env, hide_env_values = true))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// TLS client key in PEM format, takes precedence over `tls_key_file`
+    pub tls_key_pem: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env, hide_env_values = true))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Password for an encrypted TLS client key
+    ///
+    /// Keys are decrypted natively by MQTT and Kafka clients, HTTP (reqwest) and rustls based
+    /// clients require the `tls_key_decrypt` feature.
+    pub tls_key_password: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, env))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Minimum TLS version (1.2 or 1.3)
+    ///
+    /// Not supported by HTTP (reqwest) clients, which return an error where this is set.
+    pub tls_min_version: Option<TlsVersion>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Disable TLS server certificate verification (insecure, for testing only)
+    pub tls_insecure: bool,
 }
 
 impl Default for TlsOptions {
@@ -33,31 +106,69 @@ impl Default for TlsOptions {
             tls_ca_file: None,
             tls_cert_file: None,
             tls_key_file: None,
+            tls_ca_pem: None,
+            tls_cert_pem: None,
+            tls_key_pem: None,
+            tls_key_password: None,
+            tls_min_version: None,
+            tls_insecure: false,
         }
     }
 }
 
+/// Debug implementation redacting inline keys and passwords
+impl std::fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |v: &Option<String>| v.as_ref().map(|_| "<redacted>");
+
+        f.debug_struct("TlsOptions")
+            .field("tls_ca_file", &self.tls_ca_file)
+            .field("tls_cert_file", &self.tls_cert_file)
+            .field("tls_key_file", &self.tls_key_file)
+            .field("tls_ca_pem", &self.tls_ca_pem.as_ref().map(|_| "<inline>"))
+            .field("tls_cert_pem", &self.tls_cert_pem.as_ref().map(|_| "<inline>"))
+            .field("tls_key_pem", &redact(&self.tls_key_pem))
+            .field("tls_key_password", &redact(&self.tls_key_password))
+            .field("tls_min_version", &self.tls_min_version)
+            .field("tls_insecure", &self.tls_insecure)
+            .finish()
+    }
+}
+
 impl TlsOptions {
     pub fn validate(&self) -> Result<(), anyhow::Error> {
 
-        match &self.tls_ca_file {
-            Some(f) if !Path::new(f).exists() => {
+        // Inline PEM data takes precedence over files
+        match (&self.tls_ca_pem, &self.tls_ca_file) {
+            (Some(p), _) if !p.contains("-----BEGIN") => {
+                return Err(Error::msg("TLS CA is not in PEM format"))
+            }
+            (None, Some(f)) if !Path::new(f).exists() => {
                 return Err(Error::msg(format!("Could not access TLS CA file: {:?}", f)))
             }
             _ => (),
         }
 
-        match &self.tls_cert_file {
-            Some(f) if !Path::new(f).exists() => {
+        match (&self.tls_cert_pem, &self.tls_cert_file) {
+            (Some(p), _) if !p.contains("-----BEGIN") => {
+                return Err(Error::msg("TLS cert is not in PEM format"))
+            }
+            (None, Some(f)) if !Path::new(f).exists() => {
                 return Err(Error::msg(format!("Could not access TLS cert file: {:?}", f)))
             }
             _ => (),
         }
 
-        match &self.tls_key_file {
-            Some(f) if !Path::new(f).exists() => {
+        match (&self.tls_key_pem, &self.tls_key_file) {
+            (Some(p), _) if !p.contains("-----BEGIN") => {
+                return Err(Error::msg("TLS key is not in PEM format"))
+            }
+            (None, Some(f)) if !Path::new(f).exists() => {
                 return Err(Error::msg(format!("Could not access TLS key file: {:?}", f)))
             }
+            (None, None) if self.tls_key_password.is_some() => {
+                return Err(Error::msg("TLS key password requires a key"))
+            }
             _ => (),
         }
 
@@ -65,6 +176,42 @@ impl TlsOptions {
     }
 }
 
+/// Shared keepalive and timeout defaults, used by clients and stores where the
+/// corresponding transport-specific option is not set.
+///
+/// Transports ignore values that do not apply (ie. keepalive for CoAP over UDP).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransportDefaults {
+    /// Interval for connection keepalive messages
+    pub keepalive: Duration,
+
+    /// Timeout for establishing a connection
+    pub connect_timeout: Duration,
+
+    /// Timeout for individual requests
+    pub request_timeout: Duration,
+}
+
+impl TransportDefaults {
+    /// Default keepalive interval (60s)
+    pub const KEEPALIVE: Duration = Duration::from_secs(60);
+    /// Default connect timeout (30s)
+    pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+    /// Default request timeout (30s)
+    pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+}
+
+impl Default for TransportDefaults {
+    fn default() -> Self {
+        Self {
+            keepalive: Self::KEEPALIVE,
+            connect_timeout: Self::CONNECT_TIMEOUT,
+            request_timeout: Self::REQUEST_TIMEOUT,
+        }
+    }
+}
+
 /// General User (username / password) configuration options
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
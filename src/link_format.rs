@@ -0,0 +1,318 @@
+//! CoRE Link Format ([RFC 6690](https://tools.ietf.org/html/rfc6690)) parsing, as used for
+//! CoAP resource discovery via `/.well-known/core`
+//!
+//! Input is typically received from devices and so is untrusted, parsing returns errors
+//! for malformed input rather than panicking (see the `core_links` fuzz target).
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use anyhow::Error;
+
+
+/// Link to a resource with associated attributes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceLink {
+    /// Target URI reference (ie. `/sensors/temp`)
+    pub target: String,
+    /// Link attributes in order, with optional values
+    pub attrs: Vec<(String, Option<String>)>,
+}
+
+impl ResourceLink {
+    /// Fetch the first value for the named attribute
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(n, _)| n == name).and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Check whether the named attribute is present
+    pub fn has_attr(&self, name: &str) -> bool {
+        self.attrs.iter().any(|(n, _)| n == name)
+    }
+
+    /// Resource type (`rt`)
+    pub fn resource_type(&self) -> Option<&str> {
+        self.attr("rt")
+    }
+
+    /// Interface description (`if`)
+    pub fn interface(&self) -> Option<&str> {
+        self.attr("if")
+    }
+
+    /// Content format (`ct`)
+    pub fn content_format(&self) -> Option<&str> {
+        self.attr("ct")
+    }
+
+    /// Whether the resource is observable (`obs`)
+    pub fn observable(&self) -> bool {
+        self.has_attr("obs")
+    }
+}
+
+/// Encode as link format, values are always quoted so output parses back to the same link
+impl fmt::Display for ResourceLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}>", self.target)?;
+
+        for (n, v) in &self.attrs {
+            write!(f, ";{}", n)?;
+
+            if let Some(v) = v {
+                write!(f, "=\"")?;
+                for c in v.chars() {
+                    if c == '"' || c == '\\' {
+                        write!(f, "\\")?;
+                    }
+                    write!(f, "{}", c)?;
+                }
+                write!(f, "\"")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a CoRE link format document into resource links
+pub fn parse_core_links(input: &str) -> Result<Vec<ResourceLink>, Error> {
+    let mut p = Parser{ chars: input.char_indices().peekable() };
+    let mut links = vec![];
+
+    p.skip_ws();
+    if p.chars.peek().is_none() {
+        return Ok(links)
+    }
+
+    loop {
+        links.push(p.link()?);
+
+        p.skip_ws();
+        match p.chars.next() {
+            None => break,
+            Some((_, ',')) => p.skip_ws(),
+            Some((i, c)) => return Err(error(i, &format!("expected ',' but found {:?}", c))),
+        }
+    }
+
+    Ok(links)
+}
+
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl <'a> Parser<'a> {
+    /// Parse a single link-value
+    fn link(&mut self) -> Result<ResourceLink, Error> {
+        self.expect('<')?;
+
+        let mut target = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '>')) => break,
+                Some((i, '<')) => return Err(error(i, "unexpected '<' in link target")),
+                Some((_, c)) => target.push(c),
+                None => return Err(Error::msg("Invalid link format: unterminated link target")),
+            }
+        }
+
+        let mut attrs = vec![];
+        loop {
+            self.skip_ws();
+            match self.chars.peek() {
+                Some((_, ';')) => { self.chars.next(); },
+                _ => break,
+            }
+
+            self.skip_ws();
+            let name = self.take_while(is_parmname);
+            if name.is_empty() {
+                return Err(self.error_here("expected link parameter name"))
+            }
+
+            self.skip_ws();
+            let value = match self.chars.peek() {
+                Some((_, '=')) => {
+                    self.chars.next();
+                    self.skip_ws();
+                    Some(self.value()?)
+                },
+                _ => None,
+            };
+
+            attrs.push((name, value));
+        }
+
+        Ok(ResourceLink{ target, attrs })
+    }
+
+    /// Parse a ptoken or quoted-string parameter value
+    fn value(&mut self) -> Result<String, Error> {
+        if let Some((_, '"')) = self.chars.peek() {
+            self.chars.next();
+
+            let mut v = String::new();
+            loop {
+                match self.chars.next() {
+                    Some((_, '"')) => return Ok(v),
+                    Some((_, '\\')) => match self.chars.next() {
+                        Some((_, c)) => v.push(c),
+                        None => break,
+                    },
+                    Some((_, c)) => v.push(c),
+                    None => break,
+                }
+            }
+
+            return Err(Error::msg("Invalid link format: unterminated quoted string"))
+        }
+
+        let v = self.take_while(is_ptokenchar);
+        if v.is_empty() {
+            return Err(self.error_here("expected link parameter value"))
+        }
+
+        Ok(v)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        match self.chars.next() {
+            Some((_, n)) if n == c => Ok(()),
+            Some((i, n)) => Err(error(i, &format!("expected {:?} but found {:?}", c, n))),
+            None => Err(Error::msg(format!("Invalid link format: expected {:?} but found end of input", c))),
+        }
+    }
+
+    fn take_while(&mut self, f: fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some((_, c)) = self.chars.peek() {
+            if !f(*c) {
+                break;
+            }
+            s.push(*c);
+            self.chars.next();
+        }
+        s
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn error_here(&mut self, msg: &str) -> Error {
+        match self.chars.peek() {
+            Some((i, c)) => error(*i, &format!("{} but found {:?}", msg, c)),
+            None => Error::msg(format!("Invalid link format: {} but found end of input", msg)),
+        }
+    }
+}
+
+fn error(index: usize, msg: &str) -> Error {
+    Error::msg(format!("Invalid link format at {}: {}", index, msg))
+}
+
+/// Link parameter name characters (RFC 5987 token, plus `*` for extended parameters)
+fn is_parmname(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$&+-.^_`|~*".contains(c)
+}
+
+/// Unquoted parameter value characters (RFC 6690 ptokenchar)
+fn is_ptokenchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()*+-./:<=>?@[]^_`{|}~".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+
+    const CASES: usize = 2_000;
+
+    /// Characters weighted towards link format syntax
+    const ALPHABET: &[char] = &['<', '>', ';', '=', '"', ',', '\\', ' ', '/', '*', 'a', 'r', 't', '0', 'é', '\u{1F600}', '\t'];
+
+    const PARMNAME: &[char] = &['a', 'z', 'A', 'Z', '0', '9', '!', '#', '$', '&', '+', '-', '.', '^', '_', '`', '|', '~', '*'];
+
+    fn string(rng: &mut StdRng, alphabet: &[char], max: usize) -> String {
+        let n = rng.gen_range(0, max + 1);
+        (0..n).map(|_| *alphabet.choose(rng).unwrap()).collect()
+    }
+
+    fn link(rng: &mut StdRng) -> ResourceLink {
+        let target_chars: Vec<_> = ALPHABET.iter().cloned().filter(|c| *c != '<' && *c != '>').collect();
+
+        let attrs = (0..rng.gen_range(0, 4)).map(|_| {
+            let mut name = string(rng, PARMNAME, 8);
+            name.push('x');
+
+            let value = match rng.gen_bool(0.5) {
+                true => Some(string(rng, ALPHABET, 16)),
+                false => None,
+            };
+
+            (name, value)
+        }).collect();
+
+        ResourceLink{ target: string(rng, &target_chars, 16), attrs }
+    }
+
+    #[test]
+    fn parse_never_panics() {
+        let mut rng = StdRng::seed_from_u64(6690);
+
+        for _ in 0..CASES {
+            let _ = parse_core_links(&string(&mut rng, ALPHABET, 64));
+        }
+
+        // Truncated and corrupted valid documents
+        for _ in 0..CASES / 10 {
+            let s: String = (0..3).map(|_| link(&mut rng).to_string()).collect::<Vec<_>>().join(",");
+
+            for (i, _) in s.char_indices() {
+                let _ = parse_core_links(&s[..i]);
+
+                let mut c = s.clone();
+                c.insert(i, *ALPHABET.choose(&mut rng).unwrap());
+                let _ = parse_core_links(&c);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = StdRng::seed_from_u64(5987);
+
+        for _ in 0..CASES {
+            let links: Vec<_> = (0..rng.gen_range(1, 4)).map(|_| link(&mut rng)).collect();
+            let encoded = links.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(",");
+
+            let decoded = parse_core_links(&encoded)
+                .unwrap_or_else(|e| panic!("failed to parse {:?}: {:?}", encoded, e));
+            assert_eq!(links, decoded, "encoded: {:?}", encoded);
+        }
+    }
+
+    #[test]
+    fn parse_example() {
+        let links = parse_core_links("</sensors/temp>;rt=\"temperature-c\";if=sensor;obs, </sensors/light>;ct=0").unwrap();
+
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].target, "/sensors/temp");
+        assert_eq!(links[0].resource_type(), Some("temperature-c"));
+        assert_eq!(links[0].interface(), Some("sensor"));
+        assert!(links[0].observable());
+        assert_eq!(links[1].content_format(), Some("0"));
+        assert!(!links[1].observable());
+    }
+}
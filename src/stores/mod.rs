@@ -1,13 +1,49 @@
 
 
+use std::time::Instant;
+
 use async_trait::async_trait;
+use anyhow::Error;
 
 #[cfg(feature = "store_elastic")]
 pub mod store_elastic;
 #[cfg(feature = "store_elastic")]
-pub use store_elastic::{ElasticStore, ElasticOptions};
+pub use store_elastic::{ElasticStore, ElasticOptions, ElasticBatch, BatchOptions, DocVersion, Versioned};
 
+/// Common storage backend interface for records of type `R`
+///
+/// Functions generic over backends should take `impl Store<R>` (or `S: Store<R>`)
+/// rather than a concrete store type.
 #[async_trait]
-pub trait Store {
+pub trait Store<R: Send + 'static>: Send {
+    /// Backend-specific query type used for searching
+    type Query: Send;
+
+    /// Store a record
+    async fn store(&mut self, record: R) -> Result<(), Error>;
+
+    /// Search for records matching the provided query
+    async fn search(&mut self, query: Self::Query) -> Result<Vec<R>, Error>;
+
+    /// Key identifying where a record is stored (ie. the index), used as the topic when
+    /// validating records (defaults to the record type name)
+    fn key(&self, _record: &R) -> String {
+        std::any::type_name::<R>().to_string()
+    }
+
+    /// Fetch the time at which pending records are due to be flushed, `None` for unbuffered
+    /// stores or where nothing is pending
+    fn next_flush(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Write any pending (buffered or batched) records, no-op for unbuffered stores
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 
+    /// Flush pending records and shut down the store
+    async fn close(mut self) -> Result<(), Error> where Self: Sized {
+        self.flush().await
+    }
 }
@@ -1,46 +1,166 @@
 
-use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{debug};
-use anyhow::Error;
+use log::{debug, warn};
+use anyhow::{Context, Error};
+use async_trait::async_trait;
 use futures::compat::{Future01CompatExt};
+use futures::io::{AsyncWrite, AsyncWriteExt};
 
 use elastic::prelude::*;
 use serde::{Serialize, de::DeserializeOwned};
-use serde_json::{json};
+use serde_json::{json, Value};
 
-use reqwest::{Certificate, Identity};
 use reqwest::r#async::ClientBuilder as HttpClientBuilder;
 use reqwest::header::{AUTHORIZATION, HeaderValue};
 
-use crate::{TlsOptions, UserOptions};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::timeout;
+
+use crate::{TlsOptions, UserOptions, TransportDefaults, PalError};
+use crate::clock::{Clock, SystemClock};
+use crate::budget::{MemoryBudget, BudgetPolicy, Usage};
+use super::Store;
+
+/// Document version for optimistic concurrency control
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DocVersion {
+    pub seq_no: u64,
+    pub primary_term: u64,
+}
+
+/// Document with ID and version information, as returned by `ElasticStore::get_versioned`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned<R> {
+    pub id: String,
+    pub version: DocVersion,
+    pub doc: R,
+}
 
 /// Generic futures-based ElasticSearch client abstraction
+///
+/// Clones share the underlying connection pool and request limit
+#[derive(Clone)]
 pub struct ElasticStore {
     client: AsyncClient,
+    limit: Option<Arc<Semaphore>>,
+    routing: Option<String>,
+    flatten: Option<Flatten>,
+    data_stream: Option<String>,
+    bulk_size: usize,
+    /// Primary URL and connect timeout for `ping`
+    url: String,
+    connect_timeout: Duration,
+}
+
+/// Document flattening configuration
+#[derive(Clone, Debug)]
+struct Flatten {
+    separator: String,
+    depth: Option<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ElasticOptions {
+    #[cfg_attr(feature = "structopt", structopt(long = "es-url"))]
+    #[cfg_attr(feature = "serde", serde(default, alias = "es_url", deserialize_with = "one_or_many"))]
+    /// URLs for ElasticSearch nodes, requests are distributed round-robin across these
+    ///
+    /// Serialized configs may provide a single `es_url` for compatibility.
+    pub es_urls: Vec<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Sniff cluster nodes from the first URL rather than using a static node list
+    pub es_sniff: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Maximum number of concurrent in-flight requests (unlimited if not set)
+    pub es_max_concurrent_requests: Option<usize>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Disable gzip response decompression (enabled by default)
+    ///
+    /// Note deflate and brotli encodings are not supported by the underlying HTTP client
+    pub es_disable_gzip: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Document field used as the routing key for stored documents, nested fields
+    /// may be specified with dotted keys (ie. `device.id`)
+    ///
+    /// Documents without this field are routed by ID as usual. Note this is not applied to `ElasticBatch`.
+    pub es_routing_field: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Flatten nested objects into top-level fields with joined keys (ie. `{"a":{"b":1}}` to `{"a.b":1}`)
+    /// before storing documents, avoiding mapping explosion for deep or variable documents
+    pub es_flatten: bool,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Maximum number of nested levels to flatten (unlimited if not set),
+    /// objects below this are stored nested under the flattened key
+    pub es_flatten_depth: Option<usize>,
+
     #[cfg_attr(feature = "structopt", structopt(long))]
-    /// URL for ElasticSearch server
-    pub es_url: String,
+    /// Separator for flattened keys (defaults to `.`)
+    pub es_flatten_separator: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Data stream for stored documents, replacing the document index.
+    ///
+    /// Documents are written with the `create` op and must include an `@timestamp` field.
+    pub es_data_stream: Option<String>,
+
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    /// Maximum number of documents per request for `store_bulk` (defaults to 1000)
+    pub es_bulk_size: Option<usize>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// HTTP connect timeout (defaults to `TransportDefaults::connect_timeout`)
+    ///
+    /// Connections are established lazily, so this applies to each request rather than `ElasticStore::new`,
+    /// use `ElasticStore::ping` to check the cluster is reachable.
+    pub es_connect_timeout: Option<Duration>,
+
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = humantime::parse_duration)))]
+    /// HTTP request timeout (defaults to `TransportDefaults::request_timeout`)
+    pub es_request_timeout: Option<Duration>,
 
     #[cfg_attr(feature = "structopt", structopt(flatten))]
     pub tls_opts: TlsOptions,
 
     #[cfg_attr(feature = "structopt", structopt(flatten))]
     pub user_opts: UserOptions,
+
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(default))]
+    /// Defaults for unset keepalive / timeout options, shared across transports
+    pub defaults: TransportDefaults,
 }
 
 impl From<&str> for ElasticOptions {
     fn from(url: &str) -> Self {
         Self {
-            es_url: url.to_string(),
+            es_urls: vec![url.to_string()],
+            es_sniff: false,
+            es_max_concurrent_requests: None,
+            es_disable_gzip: false,
+            es_routing_field: None,
+            es_flatten: false,
+            es_flatten_depth: None,
+            es_flatten_separator: None,
+            es_data_stream: None,
+            es_bulk_size: None,
+            es_connect_timeout: None,
+            es_request_timeout: None,
             tls_opts: Default::default(),
             user_opts: Default::default(),
+            defaults: Default::default(),
         }
     }
 }
@@ -48,9 +168,21 @@ impl From<&str> for ElasticOptions {
 impl From<(&str, UserOptions)> for ElasticOptions {
     fn from(o: (&str, UserOptions)) -> Self {
         Self {
-            es_url: o.0.to_string(),
+            es_urls: vec![o.0.to_string()],
+            es_sniff: false,
+            es_max_concurrent_requests: None,
+            es_disable_gzip: false,
+            es_routing_field: None,
+            es_flatten: false,
+            es_flatten_depth: None,
+            es_flatten_separator: None,
+            es_data_stream: None,
+            es_bulk_size: None,
+            es_connect_timeout: None,
+            es_request_timeout: None,
             tls_opts: Default::default(),
             user_opts: o.1,
+            defaults: Default::default(),
         }
     }
 }
@@ -58,9 +190,21 @@ impl From<(&str, UserOptions)> for ElasticOptions {
 impl From<(&str, TlsOptions)> for ElasticOptions {
     fn from(o: (&str, TlsOptions)) -> Self {
         Self {
-            es_url: o.0.to_string(),
+            es_urls: vec![o.0.to_string()],
+            es_sniff: false,
+            es_max_concurrent_requests: None,
+            es_disable_gzip: false,
+            es_routing_field: None,
+            es_flatten: false,
+            es_flatten_depth: None,
+            es_flatten_separator: None,
+            es_data_stream: None,
+            es_bulk_size: None,
+            es_connect_timeout: None,
+            es_request_timeout: None,
             tls_opts: o.1,
             user_opts: Default::default(),
+            defaults: Default::default(),
         }
     }
 }
@@ -68,9 +212,21 @@ impl From<(&str, TlsOptions)> for ElasticOptions {
 impl From<(&str, UserOptions, TlsOptions)> for ElasticOptions {
     fn from(o: (&str, UserOptions, TlsOptions)) -> Self {
         Self {
-            es_url: o.0.to_string(),
+            es_urls: vec![o.0.to_string()],
+            es_sniff: false,
+            es_max_concurrent_requests: None,
+            es_disable_gzip: false,
+            es_routing_field: None,
+            es_flatten: false,
+            es_flatten_depth: None,
+            es_flatten_separator: None,
+            es_data_stream: None,
+            es_bulk_size: None,
+            es_connect_timeout: None,
+            es_request_timeout: None,
             tls_opts: o.2,
             user_opts: o.1,
+            defaults: Default::default(),
         }
     }
 }
@@ -80,54 +236,43 @@ impl ElasticStore {
     pub fn new<O: Into<ElasticOptions>>(opts: O) -> Result<Self, Error> {
         let o = opts.into();
 
-        // Setup HTTP client options
-        let mut http_client_builder = HttpClientBuilder::new();
-
-        // Load CA if provided
-        if let Some(f) = &o.tls_opts.tls_ca_file {
-            debug!("loading TLS CA certificate: {:?}", f);
+        // Primary URL for diagnostics
+        let url = match o.es_urls.first() {
+            Some(u) => u.clone(),
+            None => return Err(Error::msg("At least one ElasticSearch URL is required")),
+        };
 
-            let ca = fs::read_to_string(f)?;
-            let ca = Certificate::from_pem(ca.as_bytes())?;
-
-            http_client_builder = http_client_builder.add_root_certificate(ca);
-        }
-
-        // Load client certificate and keys if provided
-        match (&o.tls_opts.tls_cert_file, &o.tls_opts.tls_key_file) {
-            (Some(c), Some(k)) => {
-                debug!("Loading TLS client cert / key: {:?} {:?}", c, k);
+        // Setup HTTP client options
+        let connect_timeout = o.es_connect_timeout.unwrap_or(o.defaults.connect_timeout);
+        let http_client_builder = HttpClientBuilder::new()
+            .gzip(!o.es_disable_gzip)
+            .connect_timeout(connect_timeout)
+            .timeout(o.es_request_timeout.unwrap_or(o.defaults.request_timeout));
 
-                // Read files
-                let mut cert = fs::read(c)?;
-                let mut key = fs::read(k)?;
-                key.append(&mut cert);
+        // Load CA, client certificate and key, and apply insecure mode
+        let http_client_builder = o.tls_opts.configure_reqwest(&url, http_client_builder)?;
 
-                let client = Identity::from_pem(&key)?;
-
-                http_client_builder = http_client_builder.identity(client);
-            },
-            (Some(_), None) | (None, Some(_)) => {
-                return Err(Error::msg("TLS requires both tls-cert and tls-key arguments"))
-            },
-            _ => (),
-        }
-
-        let http_client = http_client_builder.build().unwrap();
+        let http_client = http_client_builder.build()
+            .map_err(|e| o.tls_opts.with_diagnostics(&url, e.into()))
+            .with_context(|| format!("Failed to build HTTP client (TLS CA: {:?}, cert: {:?}, key: {:?})",
+                o.tls_opts.tls_ca_file, o.tls_opts.tls_cert_file, o.tls_opts.tls_key_file))?;
 
         // Setup Elastic client options
-        let mut client_builder = AsyncClient::builder()
-            .static_node(o.es_url)
-            .http_client(http_client);
+        let mut client_builder = match o.es_sniff {
+            true => AsyncClient::builder().sniff_nodes(url.clone()),
+            false => AsyncClient::builder().static_nodes(o.es_urls.clone()),
+        };
+        client_builder = client_builder.http_client(http_client);
 
         // Load username / password if provided for HTTP basic auth
         match (&o.user_opts.username, &o.user_opts.password) {
             (Some(username), Some(password)) => {
                 // Generate HTTP basic auth header
                 let v = format!("Basic {}", base64::encode(&format!("{}:{}", username, password)));
-                let auth = HeaderValue::from_str(&v).unwrap();
+                let a = HeaderValue::from_str(&v)
+                    .context("Invalid username / password for HTTP basic auth")?;
 
-                client_builder = client_builder.params_fluent(move |p| p.header(AUTHORIZATION, auth.clone()));
+                client_builder = client_builder.params_fluent(move |p| p.header(AUTHORIZATION, a.clone()));
             },
             (Some(_), None) | (None, Some(_)) => {
                 return Err(Error::msg("User auth requires both username and password arguments"))
@@ -136,10 +281,24 @@ impl ElasticStore {
         }
 
         // Build client
-        let client = client_builder.build().unwrap();
+        let client = client_builder.build()
+            .with_context(|| format!("Failed to build ElasticSearch client for {}", url))?;
            
         Ok(Self {
             client,
+            limit: o.es_max_concurrent_requests.map(|n| Arc::new(Semaphore::new(n))),
+            routing: o.es_routing_field.clone(),
+            flatten: match o.es_flatten {
+                true => Some(Flatten{
+                    separator: o.es_flatten_separator.clone().unwrap_or(".".to_string()),
+                    depth: o.es_flatten_depth,
+                }),
+                false => None,
+            },
+            data_stream: o.es_data_stream.clone(),
+            bulk_size: o.es_bulk_size.unwrap_or(DEFAULT_BULK_SIZE).max(1),
+            url,
+            connect_timeout,
         })
     }
 
@@ -148,10 +307,204 @@ impl ElasticStore {
         &mut self.client
     }
 
+    /// Key for a record (the record index), see `Store::key`
+    pub fn record_key<R: DocumentType>(record: &R) -> String {
+        record.index().to_string()
+    }
+
+    /// Check the cluster is reachable, returning `PalError::Timeout` if no response is
+    /// received within the connect timeout
+    ///
+    /// No connection is made by `new`, so unreachable nodes are otherwise only detected on the first request.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        let err = || PalError::Timeout{ operation: format!("ElasticSearch ping of {}", self.url), timeout: self.connect_timeout };
+
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = match timeout(self.connect_timeout, self.client.ping().send().compat()).await {
+            Ok(r) => r,
+            Err(_) => return Err(err().into()),
+        };
+        record_metrics("ping", "_all", 0, start, &res);
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let e = Error::from(e);
+                match is_timeout(&e) {
+                    true => Err(err().into()),
+                    false => Err(e),
+                }
+            },
+        }
+    }
+
 
     /// Store a record in the database
     pub async fn store<R: DocumentType + Serialize + Send + 'static>(&mut self, record: R) -> Result<(), Error> {
-        self.client.document().index(record).send().compat().await.unwrap();
+        let index = record.index().to_string();
+
+        // Extract routing key where configured
+        let routing = match &self.routing {
+            Some(f) => routing_value(&serde_json::to_value(&record)?, f),
+            None => None,
+        };
+        // Data streams only accept the create op
+        let create = self.data_stream.is_some();
+        let params = move |p: RequestParams| {
+            let p = match &routing {
+                Some(r) => p.url_param("routing", r.clone()),
+                None => p,
+            };
+            match create {
+                true => p.url_param("op_type", "create"),
+                false => p,
+            }
+        };
+
+        if self.flatten.is_none() && self.data_stream.is_none() {
+            let req = self.client.document().index(record).params_fluent(params);
+
+            let _permit = acquire(&self.limit).await;
+
+            let start = Instant::now();
+            let res = req.send().compat().await;
+            record_metrics("store", &index, 1, start, &res);
+            res?;
+
+            return Ok(())
+        }
+
+        // Flattened and data stream documents are sent as raw index requests
+        let ty = record.ty().to_string();
+        let doc = self.prepare(serde_json::to_value(&record)?)?;
+        let index = self.data_stream.clone().unwrap_or(index);
+
+        let req = elastic::endpoints::IndexRequest::for_index_ty(index.clone(), ty, doc.to_string());
+        let req = self.client.request(req).params_fluent(params);
+
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = req.send().compat().await;
+        record_metrics("store", &index, 1, start, &res);
+        let _: Value = res?.into_response().compat().await?;
+
+        Ok(())
+    }
+
+    /// Store a set of records using the bulk API, split into requests of up to `es_bulk_size` documents
+    ///
+    /// Routing, flattening and data streams are applied per document as with `store`, and requests
+    /// are distributed across the node pool. All requests are attempted, with per-document failures reported in the returned error.
+    pub async fn store_bulk<R: DocumentType + Serialize + Send + 'static>(&mut self, records: Vec<R>) -> Result<(), Error> {
+        let op = match self.data_stream.is_some() {
+            true => "create",
+            false => "index",
+        };
+
+        // Encode actions and documents
+        let mut lines = Vec::with_capacity(records.len());
+        for r in records.iter() {
+            let doc = serde_json::to_value(r)?;
+
+            let mut meta = serde_json::Map::new();
+            meta.insert("_index".to_string(), json!(self.data_stream.clone().unwrap_or_else(|| r.index().to_string())));
+            if let Some(v) = self.routing.as_ref().and_then(|f| routing_value(&doc, f)) {
+                meta.insert("routing".to_string(), json!(v));
+            }
+
+            let doc = self.prepare(doc)?;
+            lines.push(bulk_line(op, meta, &doc));
+        }
+
+        let mut failed = vec![];
+
+        for (n, chunk) in lines.chunks(self.bulk_size).enumerate() {
+            debug!("Bulk storing {} documents", chunk.len());
+
+            let _permit = acquire(&self.limit).await;
+
+            let resp = match self.bulk(chunk.concat(), chunk.len() as u64).await {
+                Ok(Bulk::Response(r)) => r,
+                Ok(Bulk::Rejected) => {
+                    failed.push(format!("request {} ({} documents): rejected (HTTP 429)", n, chunk.len()));
+                    continue;
+                },
+                Err(e) => {
+                    failed.push(format!("request {} ({} documents): {:?}", n, chunk.len(), e));
+                    continue;
+                },
+            };
+
+            if resp["errors"].as_bool() != Some(true) {
+                continue;
+            }
+
+            // Collect per-document failures
+            let items = resp["items"].as_array().cloned().unwrap_or_default();
+            for (i, item) in items.iter().enumerate() {
+                if let Some(e) = item[op].get("error") {
+                    failed.push(format!("document {}: {}", n * self.bulk_size + i, e));
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(Error::msg(format!("Bulk store failed for {} of {} documents / requests: {:?}", failed.len(), records.len(), failed)));
+        }
+
+        Ok(())
+    }
+
+    /// Issue a bulk request with the provided NDJSON body
+    async fn bulk(&self, body: String, docs: u64) -> Result<Bulk, Error> {
+        let req = elastic::endpoints::BulkRequest::new(body);
+
+        let start = Instant::now();
+        let res = self.client.request(req).send().compat().await;
+        record_metrics("bulk", "_bulk", docs, start, &res);
+
+        let resp = res?;
+        if resp.status().as_u16() == STATUS_TOO_MANY_REQUESTS {
+            return Ok(Bulk::Rejected)
+        }
+
+        let v: Value = resp.into_response().compat().await?;
+
+        Ok(Bulk::Response(v))
+    }
+
+    /// Apply flattening and check data stream requirements for a document prior to storing
+    fn prepare(&self, mut doc: Value) -> Result<Value, Error> {
+        if let Some(f) = &self.flatten {
+            doc = flatten_doc(doc, &f.separator, f.depth);
+        }
+
+        if let Some(ds) = &self.data_stream {
+            if doc.get("@timestamp").is_none() {
+                return Err(Error::msg(format!("Documents for data stream {} require an @timestamp field", ds)))
+            }
+        }
+
+        Ok(doc)
+    }
+
+    /// Create a data stream, this requires a matching index template with `data_stream` enabled
+    pub async fn create_data_stream(&mut self, name: &str) -> Result<(), Error> {
+        debug!("Creating data stream: {}", name);
+
+        // Data stream endpoints are not provided by the elastic client, the create index
+        // endpoint issues the same `PUT /{path}` request
+        let req = elastic::endpoints::IndicesCreateRequest::for_index(format!("_data_stream/{}", name), String::new());
+
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = self.client.request(req).send().compat().await;
+        record_metrics("create_data_stream", name, 0, start, &res);
+        let _: Value = res?.into_response().compat().await?;
 
         Ok(())
     }
@@ -163,7 +516,12 @@ impl ElasticStore {
         let q = serde_json::to_string(&query)?;
 
         // Issue request
-        let resp = self.client.search::<R>().body(q).send().compat().await.unwrap();
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = self.client.search::<R>().body(q).send().compat().await;
+        record_metrics("search", "_all", 0, start, &res);
+        let resp = res?;
 
         // Parse out response
         let docs: Vec<_> = resp.into_documents().collect();
@@ -171,10 +529,188 @@ impl ElasticStore {
         Ok(docs)
     }
 
+    /// Fetch a document by ID with its current version, returning `None` if the document does not exist
+    ///
+    /// Note this uses the default `_doc` document type.
+    pub async fn get_versioned<R: DeserializeOwned>(&mut self, index: &str, id: &str) -> Result<Option<Versioned<R>>, Error> {
+        let req = elastic::endpoints::GetRequest::for_index_ty_id(index.to_string(), DOC_TYPE, id.to_string());
+
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = self.client.request(req).send().compat().await;
+        record_metrics("get", index, 0, start, &res);
+
+        let resp = res?;
+        if resp.status().as_u16() == 404 {
+            return Ok(None)
+        }
+
+        let v: Value = resp.into_response().compat().await?;
+        if v["found"] == Value::Bool(false) {
+            return Ok(None)
+        }
+
+        Ok(Some(Versioned {
+            id: id.to_string(),
+            version: doc_version(&v)?,
+            doc: serde_json::from_value(v["_source"].clone())?,
+        }))
+    }
+
+    /// Index a document with the provided ID, conditional on the document being unmodified
+    /// since `version` (or not existing where `version` is `None`), returning the new version.
+    ///
+    /// On mismatch this returns `PalError::VersionConflict`, callers should then fetch the
+    /// current document (see `get_versioned`) and retry the update.
+    pub async fn update_versioned<R>(&mut self, id: &str, record: R, version: Option<DocVersion>) -> Result<DocVersion, Error>
+    where
+        R: DocumentType + Serialize + Send + 'static,
+    {
+        let index = record.index().to_string();
+        let ty = record.ty().to_string();
+        let doc = serde_json::to_string(&record)?;
+
+        let req = elastic::endpoints::IndexRequest::for_index_ty_id(index.clone(), ty, id.to_string(), doc);
+        let req = self.client.request(req).params_fluent(move |p| match version {
+            Some(v) => p.url_param("if_seq_no", v.seq_no).url_param("if_primary_term", v.primary_term),
+            None => p.url_param("op_type", "create"),
+        });
+
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = req.send().compat().await;
+        record_metrics("update", &index, 1, start, &res);
+
+        let resp = res?;
+        if resp.status().as_u16() == 409 {
+            return Err(PalError::VersionConflict{ index, id: id.to_string() }.into())
+        }
+
+        let v: Value = resp.into_response().compat().await?;
+
+        doc_version(&v)
+    }
+
+    /// Search an index for records matching the provided JSON query, fetching only the included
+    /// (and not excluded) source fields and deserializing these into a partial record type
+    pub async fn search_fields<Q, R>(&mut self, index: &str, query: Q, includes: &[&str], excludes: &[&str]) -> Result<Vec<R>, Error>
+    where
+        Q: Serialize + Send,
+        R: DeserializeOwned + Send + 'static,
+    {
+        // Encode query with source filter
+        let mut q = serde_json::to_value(&query)?;
+        match q.as_object_mut() {
+            Some(o) => {
+                o.insert("_source".to_string(), json!({
+                    "includes": includes,
+                    "excludes": excludes,
+                }));
+            },
+            None => return Err(Error::msg("Search query must be a JSON object")),
+        }
+
+        // Issue request
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = self.client.search::<R>().index(index.to_string()).body(q.to_string()).send().compat().await;
+        record_metrics("search", index, 0, start, &res);
+
+        // Parse out response
+        let docs: Vec<_> = res?.into_documents().collect();
+
+        Ok(docs)
+    }
+
+    /// Export records matching the provided JSON query as CSV, paging through results
+    /// with `search_after` and writing each page to the writer as it is received.
+    ///
+    /// Records are deserialized as `R` with nested fields flattened to dotted keys (ie. `a.b`),
+    /// the header is taken from the fields of the first record. If the query does not specify
+    /// a sort results are exported in index order (`_doc`), for consistent paging over a changing
+    /// index a sort with a unique tiebreaker field should be provided.
+    ///
+    /// Returns the number of records written.
+    pub async fn export_csv<Q, R, W>(&mut self, index: &str, query: Q, mut writer: W) -> Result<usize, Error>
+    where
+        Q: Serialize + Send,
+        R: Serialize + DeserializeOwned,
+        W: AsyncWrite + Unpin,
+    {
+        let mut q = serde_json::to_value(&query)?;
+        let o = match q.as_object_mut() {
+            Some(o) => o,
+            None => return Err(Error::msg("Search query must be a JSON object")),
+        };
+        o.entry("sort").or_insert(json!(["_doc"]));
+        o.entry("size").or_insert(json!(EXPORT_PAGE_SIZE));
+
+        let mut header: Option<Vec<String>> = None;
+        let mut count = 0;
+
+        loop {
+            // Fetch the next page
+            let req = elastic::endpoints::SearchRequest::for_index(index.to_string(), q.to_string());
+
+            let _permit = acquire(&self.limit).await;
+
+            let start = Instant::now();
+            let res = self.client.request(req).send().compat().await;
+            record_metrics("export", index, 0, start, &res);
+            let resp: Value = res?.into_response().compat().await?;
+
+            let hits = match resp["hits"]["hits"].as_array() {
+                Some(h) if !h.is_empty() => h,
+                _ => break,
+            };
+
+            // Encode page
+            let mut w = csv::Writer::from_writer(vec![]);
+
+            for h in hits {
+                let r: R = serde_json::from_value(h["_source"].clone())?;
+
+                let mut fields = vec![];
+                flatten("", &serde_json::to_value(&r)?, &mut fields);
+
+                // Write header from the first record
+                if header.is_none() {
+                    let h: Vec<_> = fields.iter().map(|(k, _)| k.clone()).collect();
+                    w.write_record(&h)?;
+                    header = Some(h);
+                }
+
+                w.write_record(header.iter().flatten().map(|k| {
+                    fields.iter().find(|(f, _)| f == k).map(|(_, v)| v.as_str()).unwrap_or("")
+                }))?;
+
+                count += 1;
+            }
+
+            let data = w.into_inner().map_err(|e| Error::msg(format!("CSV encoding failed: {}", e)))?;
+            writer.write_all(&data).await?;
+
+            // Continue from the last hit
+            match hits.last().map(|h| h["sort"].clone()) {
+                Some(s) if !s.is_null() => { q["search_after"] = s; },
+                _ => break,
+            }
+
+            debug!("Exported {} records from {}", count, index);
+        }
+
+        writer.flush().await?;
+
+        Ok(count)
+    }
+
     /// Create an index for the provided document on the specified index
     pub async fn map<T: DocumentType>(&mut self, index: &str) -> Result<(), Error> {
         let doc = T::index_mapping();
-        let mapping = serde_json::to_string(&doc).unwrap();
+        let mapping = serde_json::to_string(&doc)?;
 
         let i = index.to_string();
         let body = json!({
@@ -183,11 +719,692 @@ impl ElasticStore {
             }
         });
 
-        self.client.index(i.clone()).create().send().compat().await.unwrap();
+        let _permit = acquire(&self.limit).await;
+
+        let start = Instant::now();
+        let res = self.client.index(i.clone()).create().send().compat().await;
+        record_metrics("create_index", &i, 0, start, &res);
+        res?;
 
         let req = elastic::endpoints::IndicesPutMappingRequest::for_index(i.clone(), body);
-        self.client.request(req).send().compat().await.unwrap();
+
+        let start = Instant::now();
+        let res = self.client.request(req).send().compat().await;
+        record_metrics("map", &i, 0, start, &res);
+        let _: Value = res?.into_response().compat().await?;
+
+        Ok(())
+    }
+}
+
+
+/// Adaptive batching options for ElasticBatch
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "structopt", derive(structopt::StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchOptions {
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "500"))]
+    /// Maximum number of documents per bulk request
+    pub batch_docs: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "5242880"))]
+    /// Maximum encoded size (in bytes) of documents per bulk request
+    pub batch_bytes: usize,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "1s", parse(try_from_str = humantime::parse_duration)))]
+    /// Maximum time a document is buffered before flushing
+    pub batch_latency: Duration,
+
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "10"))]
+    /// Minimum document limit when backing off due to cluster rejections (HTTP 429)
+    pub batch_min_docs: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            batch_docs: 500,
+            batch_bytes: 5 * 1024 * 1024,
+            batch_latency: Duration::from_secs(1),
+            batch_min_docs: 10,
+        }
+    }
+}
+
+/// Adaptive bulk batching wrapper for ElasticStore
+///
+/// Records are buffered and flushed via the bulk API on whichever comes first of
+/// the document count, byte size, or latency thresholds. When the cluster rejects
+/// requests (HTTP 429) the document limit is halved, growing back towards
+/// `batch_docs` on subsequent successful flushes.
+///
+/// Thresholds are checked on `push`, `tick` should be called periodically
+/// (or at `next_flush`) so idle batches are flushed within `batch_latency`.
+pub struct ElasticBatch {
+    store: ElasticStore,
+    opts: BatchOptions,
+    buff: Vec<(String, String, Value)>,
+    bytes: usize,
+    oldest: Option<Instant>,
+    limit: usize,
+    clock: Arc<dyn Clock>,
+    budget: Option<MemoryBudget>,
+    /// Buffered bytes last reported to the budget
+    held: usize,
+}
+
+impl ElasticBatch {
+    /// Create a new batching wrapper around the provided store
+    pub fn new(store: ElasticStore, opts: BatchOptions) -> Self {
+        Self::with_clock(store, opts, Arc::new(SystemClock))
+    }
+
+    /// Create a new batching wrapper using the provided clock for latency thresholds
+    pub fn with_clock(store: ElasticStore, opts: BatchOptions, clock: Arc<dyn Clock>) -> Self {
+        let limit = opts.batch_docs;
+
+        Self {
+            store,
+            opts,
+            buff: vec![],
+            bytes: 0,
+            oldest: None,
+            limit,
+            clock,
+            budget: None,
+            held: 0,
+        }
+    }
+
+    /// Bound and track buffered document bytes using the provided memory budget
+    ///
+    /// This bounds documents retained following rejections as well as newly pushed
+    /// documents, and should be larger than `batch_bytes`. When the bound is reached
+    /// `BudgetPolicy::Block` flushes the batch prior to buffering the new document.
+    pub fn with_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.budget = Some(budget.clone());
+        self
+    }
+
+    /// Buffer a record, flushing if a batch threshold has been reached
+    pub async fn push<R: DocumentType + Serialize>(&mut self, record: R) -> Result<(), Error> {
+        let index = record.index().to_string();
+        let ty = record.ty().to_string();
+
+        self.push_doc(index, ty, serde_json::to_value(&record)?).await
+    }
+
+    /// Buffer an untyped document for the provided index, flushing if a batch threshold has been reached
+    pub async fn push_value(&mut self, index: &str, doc: Value) -> Result<(), Error> {
+        self.push_doc(index.to_string(), DOC_TYPE.to_string(), doc).await
+    }
+
+    async fn push_doc(&mut self, index: String, ty: String, doc: Value) -> Result<(), Error> {
+        let doc = self.store.prepare(doc)?;
+        let index = self.store.data_stream.clone().unwrap_or(index);
+        let len = serde_json::to_vec(&doc)?.len();
+
+        if let Some((limit, policy, usage)) = self.budget.as_ref().and_then(|b| b.batch_limit().map(|l| (l, b.policy(), b.tracker()))) {
+            if self.bytes + len > limit {
+                if policy == BudgetPolicy::Block {
+                    debug!("Batch budget reached, flushing {} documents", self.buff.len());
+                    self.flush().await?;
+                }
+
+                // Rejected documents may be retained after a blocking flush
+                if self.bytes + len > limit {
+                    usage.record_drop();
+
+                    if policy == BudgetPolicy::Drop {
+                        warn!("Batch budget reached, dropping document for {}", index);
+                        return Ok(());
+                    }
+
+                    return Err(PalError::BudgetExceeded{ buffer: "elastic_batch".to_string(), limit }.into());
+                }
+            }
+        }
+
+        self.bytes += len;
+        self.buff.push((index, ty, doc));
+        self.track();
+
+        if self.oldest.is_none() {
+            self.oldest = Some(self.clock.now());
+        }
+
+        if self.flush_due() {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Flush the buffer if the latency threshold has elapsed
+    pub async fn tick(&mut self) -> Result<(), Error> {
+        if self.flush_due() {
+            self.flush().await?;
+        }
 
         Ok(())
     }
+
+    /// Fetch the time at which the current batch is due to be flushed
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.oldest.map(|t| t + self.opts.batch_latency)
+    }
+
+    /// Fetch the current adaptive document limit
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Fetch the number of buffered documents
+    pub fn len(&self) -> usize {
+        self.buff.len()
+    }
+
+    /// Check whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.buff.is_empty()
+    }
+
+    /// Fetch the inner store
+    pub fn inner<'a>(&'a mut self) -> &'a mut ElasticStore {
+        &mut self.store
+    }
+
+    fn flush_due(&self) -> bool {
+        if self.buff.len() >= self.limit || self.bytes >= self.opts.batch_bytes {
+            return true;
+        }
+
+        match self.oldest {
+            Some(t) => self.clock.elapsed(t) >= self.opts.batch_latency,
+            None => false,
+        }
+    }
+
+    /// Flush all buffered records via the bulk API
+    ///
+    /// Records rejected due to cluster backpressure are retained for the next flush,
+    /// other per-document failures are dropped and reported in the returned error.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let res = self.flush_batch().await;
+        self.track();
+        res
+    }
+
+    async fn flush_batch(&mut self) -> Result<(), Error> {
+        if self.buff.is_empty() {
+            return Ok(());
+        }
+
+        let docs: Vec<_> = self.buff.drain(..).collect();
+        self.bytes = 0;
+        self.oldest = None;
+
+        // Data streams only accept the create op
+        let op = match self.store.data_stream.is_some() {
+            true => "create",
+            false => "index",
+        };
+
+        let body: String = docs.iter().map(|(i, t, d)| {
+            let mut meta = serde_json::Map::new();
+            meta.insert("_index".to_string(), json!(i));
+            meta.insert("_type".to_string(), json!(t));
+            bulk_line(op, meta, d)
+        }).collect();
+
+        debug!("Flushing {} documents (limit: {})", docs.len(), self.limit);
+
+        let limit = self.store.limit.clone();
+        let _permit = acquire(&limit).await;
+
+        let res = self.store.bulk(body, docs.len() as u64).await;
+
+        let resp = match res {
+            Ok(Bulk::Response(r)) => r,
+            Ok(Bulk::Rejected) => {
+                // Retain records for retry
+                let n = docs.len();
+                self.backoff();
+                for d in docs {
+                    self.restore(d)?;
+                }
+                return Err(Error::msg(format!("Bulk request for {} documents rejected (HTTP 429)", n)));
+            },
+            Err(e) => {
+                // Retain records for retry
+                for d in docs {
+                    self.restore(d)?;
+                }
+                return Err(e);
+            },
+        };
+
+        let mut rejected = 0;
+        let mut failed = vec![];
+
+        let items = resp["items"].as_array().cloned().unwrap_or_default();
+        for (item, doc) in items.iter().zip(docs.into_iter()) {
+            let item = &item[op];
+            match item.get("error") {
+                None => (),
+                Some(_) if is_rejection(item) => {
+                    rejected += 1;
+                    self.restore(doc)?;
+                },
+                Some(e) => failed.push(e.to_string()),
+            }
+        }
+
+        if rejected > 0 {
+            warn!("Bulk request rejected {} documents, backing off", rejected);
+            self.backoff();
+        } else {
+            // Additive increase back towards the configured limit
+            let step = (self.opts.batch_docs / 8).max(1);
+            self.limit = (self.limit + step).min(self.opts.batch_docs);
+        }
+
+        if !failed.is_empty() {
+            return Err(Error::msg(format!("Bulk request failed for {} documents: {:?}", failed.len(), failed)));
+        }
+
+        Ok(())
+    }
+
+    fn restore(&mut self, d: (String, String, Value)) -> Result<(), Error> {
+        self.bytes += serde_json::to_vec(&d.2)?.len();
+        self.buff.push(d);
+
+        if self.oldest.is_none() {
+            self.oldest = Some(self.clock.now());
+        }
+
+        Ok(())
+    }
+
+    fn backoff(&mut self) {
+        self.limit = (self.limit / 2).max(self.opts.batch_min_docs).max(1);
+    }
+
+    /// Report buffered byte changes to the memory budget
+    fn track(&mut self) {
+        if let Some(b) = &self.budget {
+            Usage::update(&b.tracker().batch_bytes, self.held, self.bytes);
+            self.held = self.bytes;
+        }
+    }
+}
+
+impl Drop for ElasticBatch {
+    fn drop(&mut self) {
+        if let Some(b) = &self.budget {
+            Usage::update(&b.tracker().batch_bytes, self.held, 0);
+        }
+    }
+}
+
+/// Parse the document version from a get or index response
+fn doc_version(v: &Value) -> Result<DocVersion, Error> {
+    match (v["_seq_no"].as_u64(), v["_primary_term"].as_u64()) {
+        (Some(seq_no), Some(primary_term)) => Ok(DocVersion{ seq_no, primary_term }),
+        _ => Err(Error::msg("Response missing document version (_seq_no / _primary_term)")),
+    }
+}
+
+/// Fetch a routing value from a (dotted) document field
+fn routing_value(doc: &Value, field: &str) -> Option<String> {
+    let v = field.split('.').fold(Some(doc), |v, k| v.and_then(|v| v.get(k)) )?;
+
+    match v {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Flatten nested objects in a document into top-level fields with joined keys,
+/// up to the provided depth
+fn flatten_doc(doc: Value, separator: &str, depth: Option<usize>) -> Value {
+    match doc {
+        Value::Object(o) => {
+            let mut out = serde_json::Map::new();
+            for (k, v) in o {
+                flatten_field(k, v, separator, depth, &mut out);
+            }
+            Value::Object(out)
+        },
+        v => v,
+    }
+}
+
+fn flatten_field(key: String, v: Value, separator: &str, depth: Option<usize>, out: &mut serde_json::Map<String, Value>) {
+    match v {
+        Value::Object(o) if depth != Some(0) && !o.is_empty() => {
+            for (k, v) in o {
+                flatten_field(format!("{}{}{}", key, separator, k), v, separator, depth.map(|d| d - 1), out);
+            }
+        },
+        v => {
+            out.insert(key, v);
+        },
+    }
+}
+
+/// Deserialize a single value or a list of values, for fields accepting multiple values
+/// that previously accepted one
+fn one_or_many<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match serde::Deserialize::deserialize(d)? {
+        OneOrMany::One(v) => Ok(vec![v]),
+        OneOrMany::Many(v) => Ok(v),
+    }
+}
+
+/// Default document type
+const DOC_TYPE: &str = "_doc";
+
+/// Default maximum documents per `store_bulk` request
+const DEFAULT_BULK_SIZE: usize = 1000;
+
+/// Page size for CSV export where not specified in the query
+const EXPORT_PAGE_SIZE: usize = 1000;
+
+/// Flatten a JSON value into dotted keys and CSV field values
+fn flatten(prefix: &str, v: &Value, fields: &mut Vec<(String, String)>) {
+    match v {
+        Value::Object(o) => {
+            for (k, v) in o {
+                let key = match prefix {
+                    "" => k.clone(),
+                    p => format!("{}.{}", p, k),
+                };
+                flatten(&key, v, fields);
+            }
+        },
+        Value::Null => fields.push((prefix.to_string(), String::new())),
+        Value::String(s) => fields.push((prefix.to_string(), s.clone())),
+        // Arrays are encoded as JSON to preserve structure
+        v => fields.push((prefix.to_string(), v.to_string())),
+    }
+}
+
+/// Unbuffered store with no-op flush / close, queried using JSON search bodies
+#[async_trait]
+impl <R> Store<R> for ElasticStore
+where
+    R: DocumentType + Serialize + DeserializeOwned + Send + 'static,
+{
+    type Query = Value;
+
+    async fn store(&mut self, record: R) -> Result<(), Error> {
+        ElasticStore::store(self, record).await
+    }
+
+    async fn search(&mut self, query: Value) -> Result<Vec<R>, Error> {
+        ElasticStore::search(self, query).await
+    }
+
+    /// Records are keyed by index
+    fn key(&self, record: &R) -> String {
+        ElasticStore::record_key(record)
+    }
+}
+
+/// Batched store, searches are issued against the inner store and do not include buffered records
+#[async_trait]
+impl <R> Store<R> for ElasticBatch
+where
+    R: DocumentType + Serialize + DeserializeOwned + Send + 'static,
+{
+    type Query = Value;
+
+    async fn store(&mut self, record: R) -> Result<(), Error> {
+        self.push(record).await
+    }
+
+    async fn search(&mut self, query: Value) -> Result<Vec<R>, Error> {
+        self.store.search(query).await
+    }
+
+    fn key(&self, record: &R) -> String {
+        ElasticStore::record_key(record)
+    }
+
+    fn next_flush(&self) -> Option<Instant> {
+        ElasticBatch::next_flush(self)
+    }
+
+    /// Flush buffered records
+    async fn flush(&mut self) -> Result<(), Error> {
+        ElasticBatch::flush(self).await
+    }
+}
+
+/// Acquire a request permit where concurrency is limited
+async fn acquire(limit: &Option<Arc<Semaphore>>) -> Option<SemaphorePermit<'_>> {
+    match limit {
+        Some(s) => Some(s.acquire().await),
+        None => None,
+    }
+}
+
+/// Record request latency, submitted documents and errors by operation and index
+#[allow(unused_variables)]
+fn record_metrics<T>(op: &'static str, index: &str, docs: u64, start: Instant, res: &Result<T, elastic::Error>) {
+    #[cfg(feature = "metrics")]
+    {
+        let index = index.to_string();
+        let latency = start.elapsed().as_micros() as u64;
+
+        metrics::histogram!("iot_pal_store_latency_us", latency, "store" => "elastic", "op" => op, "index" => index.clone());
+
+        match res {
+            Ok(_) => {
+                metrics::counter!("iot_pal_store_requests", 1, "store" => "elastic", "op" => op, "index" => index.clone());
+                if docs > 0 {
+                    metrics::counter!("iot_pal_store_documents", docs, "store" => "elastic", "op" => op, "index" => index);
+                }
+            },
+            Err(e) => {
+                let kind = match e {
+                    elastic::Error::Api(_) => "api",
+                    elastic::Error::Client(_) => "client",
+                    #[allow(unreachable_patterns)]
+                    _ => "other",
+                };
+                metrics::counter!("iot_pal_store_errors", 1, "store" => "elastic", "op" => op, "index" => index, "kind" => kind);
+            },
+        }
+    }
+}
+
+/// Bulk request response, or rejection where the cluster is overloaded
+enum Bulk {
+    Response(Value),
+    Rejected,
+}
+
+/// HTTP status returned where the cluster rejects requests due to backpressure
+const STATUS_TOO_MANY_REQUESTS: u16 = 429;
+
+/// Error type for requests rejected due to backpressure
+const REJECTED_EXECUTION: &str = "es_rejected_execution_exception";
+
+/// Encode a bulk action and document as NDJSON lines
+fn bulk_line(op: &str, meta: serde_json::Map<String, Value>, doc: &Value) -> String {
+    let mut action = serde_json::Map::new();
+    action.insert(op.to_string(), Value::Object(meta));
+
+    format!("{}\n{}\n", Value::Object(action), doc)
+}
+
+/// Check whether a bulk response item was rejected due to cluster backpressure,
+/// by the HTTP 429 item status or `es_rejected_execution_exception` error type
+fn is_rejection(item: &Value) -> bool {
+    item["status"].as_u64() == Some(STATUS_TOO_MANY_REQUESTS as u64)
+        || item["error"]["type"].as_str() == Some(REJECTED_EXECUTION)
+}
+
+/// Check whether an error was caused by a connect or request timeout
+fn is_timeout(e: &Error) -> bool {
+    e.chain().any(|c| {
+        c.downcast_ref::<reqwest::Error>().map(|r| r.is_timeout()).unwrap_or(false)
+            || c.downcast_ref::<std::io::Error>().map(|i| i.kind() == std::io::ErrorKind::TimedOut).unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use elastic_derive::ElasticType;
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, ElasticType)]
+    #[elastic(index = "iot-pal-test")]
+    struct Reading {
+        sensor: String,
+        value: u32,
+    }
+
+    fn reading(n: u32) -> Reading {
+        Reading{ sensor: "test".to_string(), value: n }
+    }
+
+    #[test]
+    fn rejection_errors() {
+        assert!(is_rejection(&json!({ "status": 429, "error": { "type": "other" } })));
+        assert!(is_rejection(&json!({ "status": 503, "error": { "type": "es_rejected_execution_exception" } })));
+
+        // Unrelated values containing 429
+        assert!(!is_rejection(&json!({ "_index": "sensors-429", "status": 404, "error": { "type": "document_missing_exception" } })));
+        assert!(!is_rejection(&json!({ "_id": "429", "status": 400, "error": { "type": "mapper_parsing_exception" } })));
+        assert!(!is_rejection(&json!({ "status": 4290 })));
+        assert!(!is_rejection(&json!({ "status": "429" })));
+    }
+
+    #[test]
+    fn timeout_errors() {
+        let e = Error::from(std::io::Error::new(std::io::ErrorKind::TimedOut, "connect")).context("request failed");
+        assert!(is_timeout(&e));
+
+        // Matched on the error kind rather than the message
+        let e = Error::from(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "timed out"));
+        assert!(!is_timeout(&e));
+        assert!(!is_timeout(&Error::msg("timed out")));
+    }
+
+    #[test]
+    fn options_compatibility() {
+        // Configs prior to multiple node support
+        let o: ElasticOptions = serde_json::from_str(r#"{
+            "es_url": "http://localhost:9200",
+            "tls_opts": {},
+            "user_opts": {}
+        }"#).unwrap();
+
+        assert_eq!(o, ElasticOptions::from("http://localhost:9200"));
+
+        let o: ElasticOptions = serde_json::from_str(r#"{
+            "es_urls": ["http://a:9200", "http://b:9200"],
+            "es_sniff": true,
+            "tls_opts": {},
+            "user_opts": {}
+        }"#).unwrap();
+
+        assert_eq!(o.es_urls, vec!["http://a:9200".to_string(), "http://b:9200".to_string()]);
+        assert!(o.es_sniff);
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let mut opts = ElasticOptions::from("http://127.0.0.1:1");
+        opts.es_request_timeout = Some(Duration::from_secs(2));
+        let mut store = ElasticStore::new(opts).unwrap();
+
+        // Connection failures are returned as errors rather than panicking
+        assert!(store.store(reading(1)).await.is_err());
+
+        let res: Result<Vec<Reading>, _> = store.search(json!({ "query": { "match_all": {} } })).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn store_bulk_chunks() {
+        let mut opts = ElasticOptions::from("http://127.0.0.1:1");
+        opts.es_bulk_size = Some(10);
+        opts.es_request_timeout = Some(Duration::from_secs(2));
+        let mut store = ElasticStore::new(opts).unwrap();
+
+        // Each chunk is issued (and fails) as a separate request
+        let e = store.store_bulk((0..25).map(reading).collect()).await.unwrap_err();
+        let e = format!("{:?}", e);
+        assert!(e.contains("failed for 3 of 25"), "{}", e);
+        assert!(e.contains("request 2 (5 documents)"), "{}", e);
+    }
+
+    /// ElasticSearch URL for integration tests, these are ignored by default as they require a running
+    /// node (ie. `IOT_PAL_ES_URL=http://localhost:9200 cargo test -- --ignored`)
+    fn elastic() -> String {
+        std::env::var("IOT_PAL_ES_URL").unwrap_or_else(|_| "http://localhost:9200".to_string())
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn store_bulk_search() {
+        let mut opts = ElasticOptions::from(elastic().as_str());
+        opts.es_bulk_size = Some(10);
+        let mut store = ElasticStore::new(opts).unwrap();
+
+        // Unique sensor per run so previous runs are not matched
+        let sensor = format!("bulk-{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis());
+        let records: Vec<_> = (0..25).map(|n| Reading{ sensor: sensor.clone(), value: n }).collect();
+
+        store.store_bulk(records.clone()).await.unwrap();
+
+        // Documents are searchable following the index refresh interval
+        let query = json!({ "size": 100, "query": { "term": { "sensor.keyword": sensor } } });
+        let mut found: Vec<Reading> = vec![];
+        for _ in 0..20 {
+            found = store.search(query.clone()).await.unwrap();
+            if found.len() == records.len() {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(250)).await;
+        }
+
+        found.sort_by_key(|r| r.value);
+        assert_eq!(found, records);
+    }
+
+    #[tokio::test]
+    async fn ping_timeout() {
+        let connect_timeout = Duration::from_millis(500);
+
+        // Non-routable address, connections are never established
+        let mut opts = ElasticOptions::from("http://10.255.255.1:9200");
+        opts.es_connect_timeout = Some(connect_timeout);
+        let mut store = ElasticStore::new(opts).unwrap();
+
+        let start = Instant::now();
+        let e = store.ping().await.unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(elapsed < connect_timeout * 2, "{:?}", elapsed);
+
+        // Hosts may be reported unreachable immediately where there is no route
+        if elapsed >= connect_timeout {
+            assert!(matches!(e.downcast_ref::<PalError>(), Some(PalError::Timeout{ .. })), "{:?}", e);
+        }
+    }
 }
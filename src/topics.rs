@@ -0,0 +1,193 @@
+//! MQTT-style topic matching
+//!
+//! Topics are split into levels by `/`, with `+` matching any single level and `#`
+//! matching any number of remaining levels (including none). As per MQTT, topics
+//! starting with `$` are not matched by wildcards at the first level.
+
+use std::collections::HashMap;
+use std::str::Split;
+
+
+/// Compiled set of topic patterns (a topic trie), matching an incoming topic against all
+/// patterns in a single traversal without allocating.
+#[derive(Debug, Clone)]
+pub struct TopicMatcher<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    /// Values for patterns ending at this level
+    values: Vec<T>,
+    /// Values for patterns ending with `#` at this level
+    multi: Vec<T>,
+    /// Child for `+` wildcard
+    single: Option<Box<Node<T>>>,
+    /// Children for literal levels
+    children: HashMap<String, Node<T>>,
+}
+
+impl <T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            values: vec![],
+            multi: vec![],
+            single: None,
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl <T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.multi.is_empty() && self.single.is_none() && self.children.is_empty()
+    }
+}
+
+impl <T> Default for TopicMatcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl <T> TopicMatcher<T> {
+    /// Create a new empty matcher
+    pub fn new() -> Self {
+        Self {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+
+    /// Fetch the number of patterns in the matcher
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether the matcher is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a pattern with an associated value (subscription handle)
+    pub fn insert(&mut self, pattern: &str, value: T) {
+        let mut node = &mut self.root;
+
+        for l in pattern.split('/') {
+            match l {
+                "#" => {
+                    node.multi.push(value);
+                    self.len += 1;
+                    return;
+                },
+                "+" => node = node.single.get_or_insert_with(|| Box::new(Node::default())),
+                _ => node = node.children.entry(l.to_string()).or_insert_with(Node::default),
+            }
+        }
+
+        node.values.push(value);
+        self.len += 1;
+    }
+
+    /// Remove a pattern, returning any associated values
+    pub fn remove(&mut self, pattern: &str) -> Vec<T> {
+        let removed = Self::remove_inner(&mut self.root, pattern.split('/'));
+        self.len -= removed.len();
+        removed
+    }
+
+    fn remove_inner(node: &mut Node<T>, mut levels: Split<'_, char>) -> Vec<T> {
+        let l = match levels.next() {
+            Some(l) => l,
+            None => return std::mem::take(&mut node.values),
+        };
+
+        match l {
+            "#" => std::mem::take(&mut node.multi),
+            "+" => {
+                let (removed, empty) = match &mut node.single {
+                    Some(c) => (Self::remove_inner(c, levels), c.is_empty()),
+                    None => return vec![],
+                };
+                if empty {
+                    node.single = None;
+                }
+                removed
+            },
+            _ => {
+                let (removed, empty) = match node.children.get_mut(l) {
+                    Some(c) => (Self::remove_inner(c, levels), c.is_empty()),
+                    None => return vec![],
+                };
+                if empty {
+                    node.children.remove(l);
+                }
+                removed
+            },
+        }
+    }
+
+    /// Call `f` for each value with a pattern matching the provided topic
+    pub fn for_each_match<'a, F: FnMut(&'a T)>(&'a self, topic: &str, mut f: F) {
+        Self::visit(&self.root, topic.split('/'), true, &mut f)
+    }
+
+    /// Fetch values for all patterns matching the provided topic
+    pub fn matches<'a>(&'a self, topic: &str) -> Vec<&'a T> {
+        let mut m = vec![];
+        self.for_each_match(topic, |v| m.push(v));
+        m
+    }
+
+    /// Check whether any pattern matches the provided topic
+    pub fn is_match(&self, topic: &str) -> bool {
+        let mut found = false;
+        self.for_each_match(topic, |_| found = true);
+        found
+    }
+
+    fn visit<'a, F: FnMut(&'a T)>(node: &'a Node<T>, mut levels: Split<'_, char>, first: bool, f: &mut F) {
+        let next = levels.next();
+
+        // Wildcards do not match `$` topics at the first level
+        let wild = !(first && next.map(|l| l.starts_with('$')).unwrap_or(false));
+
+        // `#` matches the remaining levels (including none)
+        if wild {
+            node.multi.iter().for_each(|v| f(v));
+        }
+
+        match next {
+            None => node.values.iter().for_each(|v| f(v)),
+            Some(l) => {
+                if let Some(c) = node.children.get(l) {
+                    Self::visit(c, levels.clone(), false, f);
+                }
+                if let (true, Some(c)) = (wild, &node.single) {
+                    Self::visit(c, levels, false, f);
+                }
+            },
+        }
+    }
+}
+
+/// Check whether a single pattern matches the provided topic
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut p = pattern.split('/');
+    let mut t = topic.split('/');
+    let mut first = true;
+
+    loop {
+        match (p.next(), t.next()) {
+            (Some("#"), Some(l)) if first && l.starts_with('$') => return false,
+            (Some("#"), _) => return true,
+            (Some("+"), Some(l)) if first && l.starts_with('$') => return false,
+            (Some("+"), Some(_)) => (),
+            (Some(a), Some(b)) if a == b => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+        first = false;
+    }
+}
@@ -0,0 +1,197 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::collections::HashMap;
+use std::time::Instant;
+
+use log::{trace};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use crate::clients::{ClientBase, ClientPub, ClientSub};
+use crate::PalError;
+
+
+/// Publish wrapper suppressing redundant publishes of unchanged payloads
+///
+/// The last payload published on each topic is retained and publishes matching this
+/// are skipped. Use `reset` to force re-publishing (for example after a reconnect).
+pub struct ChangeFilter<C> {
+    inner: C,
+    last: HashMap<String, Vec<u8>>,
+    eq: Box<dyn Fn(&[u8], &[u8]) -> bool + Send + Sync>,
+}
+
+impl <C> ChangeFilter<C> {
+    /// Create a new filter skipping byte-identical payloads
+    pub fn new(inner: C) -> Self {
+        Self::with_predicate(inner, |a, b| a == b)
+    }
+
+    /// Create a new filter skipping payloads where `eq(last, next)` returns true
+    pub fn with_predicate<F>(inner: C, eq: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            last: HashMap::new(),
+            eq: Box::new(eq),
+        }
+    }
+
+    /// Clear all retained payloads so the next publish on each topic is forwarded
+    pub fn reset(&mut self) {
+        self.last.clear();
+    }
+
+    /// Clear the retained payload for a topic so the next publish is forwarded
+    pub fn reset_topic(&mut self, topic: &str) {
+        self.last.remove(topic);
+    }
+
+    /// Fetch inner client
+    pub fn inner<'a>(&'a mut self) -> &'a mut C {
+        &mut self.inner
+    }
+
+    /// Consume the filter, returning the inner client
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl <C: ClientPub + Send> ChangeFilter<C> {
+    /// Publish data to a topic regardless of the last published value
+    pub async fn publish_forced(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.inner.publish(topic, data).await?;
+        self.last.insert(topic.to_string(), data.to_vec());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl <C: ClientPub + Send> ClientPub for ChangeFilter<C> {
+    /// Publish data to a topic if it differs from the last published value
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if let Some(l) = self.last.get(topic) {
+            if (self.eq)(l, data) {
+                trace!("Skipping unchanged publish to {}", topic);
+                return Ok(())
+            }
+        }
+
+        self.publish_forced(topic, data).await
+    }
+}
+
+#[async_trait]
+impl <C: ClientBase> ClientBase for ChangeFilter<C> {
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.inner.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.inner.last_error()
+    }
+}
+
+#[async_trait]
+impl <C: ClientSub + Unpin + Send> ClientSub for ChangeFilter<C> {
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.inner.subscribe(topic).await
+    }
+
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.inner.unsubscribe(topic).await
+    }
+}
+
+impl <C: Stream + Unpin> Stream for ChangeFilter<C> {
+    type Item = C::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    /// Publisher recording published messages
+    #[derive(Default)]
+    struct Recorder(Vec<(String, Vec<u8>)>);
+
+    #[async_trait]
+    impl ClientPub for Recorder {
+        async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+            self.0.push((topic.to_string(), data.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn msg(topic: &str, data: &[u8]) -> (String, Vec<u8>) {
+        (topic.to_string(), data.to_vec())
+    }
+
+    #[test]
+    fn unchanged_suppressed() {
+        let mut c = ChangeFilter::new(Recorder::default());
+
+        block_on(async {
+            c.publish("a", b"1").await.unwrap();
+            c.publish("a", b"1").await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1")]);
+
+            // Changed payloads are forwarded
+            c.publish("a", b"2").await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1"), msg("a", b"2")]);
+
+            // Forced and reset publishes bypass the filter
+            c.publish_forced("a", b"2").await.unwrap();
+            c.reset();
+            c.publish("a", b"2").await.unwrap();
+            assert_eq!(c.inner().0.len(), 4);
+        });
+    }
+
+    #[test]
+    fn per_topic() {
+        let mut c = ChangeFilter::new(Recorder::default());
+
+        block_on(async {
+            c.publish("a", b"1").await.unwrap();
+            c.publish("b", b"1").await.unwrap();
+            c.publish("a", b"1").await.unwrap();
+            c.publish("b", b"2").await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1"), msg("b", b"1"), msg("b", b"2")]);
+
+            // Resetting one topic does not affect others
+            c.reset_topic("a");
+            c.publish("a", b"1").await.unwrap();
+            c.publish("b", b"2").await.unwrap();
+            assert_eq!(c.inner().0.len(), 4);
+            assert_eq!(c.inner().0.last(), Some(&msg("a", b"1")));
+        });
+    }
+
+    #[test]
+    fn predicate() {
+        // Treat payloads of the same length as unchanged
+        let mut c = ChangeFilter::with_predicate(Recorder::default(), |a, b| a.len() == b.len());
+
+        block_on(async {
+            c.publish("a", b"1").await.unwrap();
+            c.publish("a", b"2").await.unwrap();
+            c.publish("a", b"22").await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1"), msg("a", b"22")]);
+        });
+    }
+}
@@ -0,0 +1,268 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{trace};
+use futures::stream::{Stream, StreamExt};
+use async_trait::async_trait;
+use anyhow::Error;
+
+use crate::clients::{ClientBase, ClientPub, ClientSub};
+use crate::clock::{Clock, SystemClock};
+use crate::PalError;
+
+
+/// Publish wrapper coalescing publishes per topic, publishing at most once per interval
+///
+/// The first publish on a topic is forwarded immediately, subsequent publishes within the
+/// interval replace the pending payload which is published (latest value only) once the
+/// interval elapses. As no background tasks are spawned `tick` must be called periodically
+/// (see `next_flush`) to publish pending payloads. Use `publish_now` or `flush` to force
+/// immediate publishing of important updates.
+pub struct Coalescing<C> {
+    inner: C,
+    interval: Duration,
+    topics: HashMap<String, Topic>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Topic {
+    last: Instant,
+    pending: Option<Vec<u8>>,
+}
+
+impl <C> Coalescing<C> {
+    /// Create a new coalescing wrapper with the provided interval
+    pub fn new(inner: C, interval: Duration) -> Self {
+        Self::with_clock(inner, interval, Arc::new(SystemClock))
+    }
+
+    /// Create a new coalescing wrapper using the provided clock
+    pub fn with_clock(inner: C, interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            inner,
+            interval,
+            topics: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Fetch the number of topics with pending payloads
+    pub fn pending(&self) -> usize {
+        self.topics.values().filter(|t| t.pending.is_some()).count()
+    }
+
+    /// Fetch the time at which the next pending payload is due, if any
+    pub fn next_flush(&self) -> Option<Instant> {
+        self.topics.values()
+            .filter(|t| t.pending.is_some())
+            .map(|t| t.last + self.interval)
+            .min()
+    }
+
+    /// Fetch inner client
+    pub fn inner<'a>(&'a mut self) -> &'a mut C {
+        &mut self.inner
+    }
+
+    /// Consume the wrapper, returning the inner client (discarding pending payloads)
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl <C: ClientPub + Send> Coalescing<C> {
+    /// Publish data to a topic immediately, replacing any pending payload
+    pub async fn publish_now(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.inner.publish(topic, data).await?;
+
+        self.topics.insert(topic.to_string(), Topic{
+            last: self.clock.now(),
+            pending: None,
+        });
+
+        Ok(())
+    }
+
+    /// Publish pending payloads where the interval has elapsed
+    pub async fn tick(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let interval = self.interval;
+
+        for (topic, t) in self.topics.iter_mut() {
+            if now.saturating_duration_since(t.last) < interval {
+                continue;
+            }
+
+            if let Some(d) = t.pending.take() {
+                self.inner.publish(topic, &d).await?;
+                t.last = now;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish all pending payloads immediately
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+
+        for (topic, t) in self.topics.iter_mut() {
+            if let Some(d) = t.pending.take() {
+                self.inner.publish(topic, &d).await?;
+                t.last = now;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl <C: ClientPub + Send> ClientPub for Coalescing<C> {
+    /// Publish data to a topic, deferring this if the topic was published within the interval
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        let now = self.clock.now();
+
+        if let Some(t) = self.topics.get_mut(topic) {
+            if now.saturating_duration_since(t.last) < self.interval {
+                trace!("Coalescing publish to {}", topic);
+                t.pending = Some(data.to_vec());
+                return Ok(())
+            }
+        }
+
+        self.publish_now(topic, data).await
+    }
+}
+
+#[async_trait]
+impl <C: ClientBase + ClientPub> ClientBase for Coalescing<C> {
+    /// Flush pending payloads and disconnect
+    async fn disconnect(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        self.inner.disconnect().await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.inner.is_connected()
+    }
+
+    fn last_error(&self) -> Option<(Instant, PalError)> {
+        self.inner.last_error()
+    }
+}
+
+#[async_trait]
+impl <C: ClientSub + Unpin + Send> ClientSub for Coalescing<C> {
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.inner.subscribe(topic).await
+    }
+
+    async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.inner.unsubscribe(topic).await
+    }
+}
+
+impl <C: Stream + Unpin> Stream for Coalescing<C> {
+    type Item = C::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+
+    use crate::clock::TestClock;
+
+    /// Publisher recording published messages
+    #[derive(Default)]
+    struct Recorder(Vec<(String, Vec<u8>)>);
+
+    #[async_trait]
+    impl ClientPub for Recorder {
+        async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+            self.0.push((topic.to_string(), data.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn msg(topic: &str, data: &[u8]) -> (String, Vec<u8>) {
+        (topic.to_string(), data.to_vec())
+    }
+
+    #[test]
+    fn latest_value() {
+        let clock = TestClock::new();
+        let mut c = Coalescing::with_clock(Recorder::default(), Duration::from_secs(1), Arc::new(clock.clone()));
+
+        block_on(async {
+            // First publish is forwarded, following publishes coalesced
+            c.publish("a", b"1").await.unwrap();
+            c.publish("a", b"2").await.unwrap();
+            c.publish("a", b"3").await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1")]);
+            assert_eq!(c.pending(), 1);
+
+            // Not yet due
+            clock.advance(Duration::from_millis(500));
+            c.tick().await.unwrap();
+            assert_eq!(c.inner().0.len(), 1);
+
+            // Only the latest value is published once the interval elapses
+            clock.advance(Duration::from_millis(500));
+            c.tick().await.unwrap();
+            assert_eq!(c.inner().0, vec![msg("a", b"1"), msg("a", b"3")]);
+            assert_eq!(c.pending(), 0);
+            assert!(c.next_flush().is_none());
+        });
+    }
+
+    #[test]
+    fn force_flush() {
+        let clock = TestClock::new();
+        let mut c = Coalescing::with_clock(Recorder::default(), Duration::from_secs(1), Arc::new(clock.clone()));
+
+        block_on(async {
+            c.publish("a", b"1").await.unwrap();
+            c.publish("b", b"1").await.unwrap();
+            c.publish("a", b"2").await.unwrap();
+            c.publish("b", b"2").await.unwrap();
+            assert_eq!(c.pending(), 2);
+
+            // publish_now bypasses the interval, replacing the pending payload
+            c.publish_now("a", b"3").await.unwrap();
+            assert_eq!(c.pending(), 1);
+            assert_eq!(c.inner().0.last(), Some(&msg("a", b"3")));
+
+            // flush publishes remaining payloads without waiting for the interval
+            c.flush().await.unwrap();
+            assert_eq!(c.pending(), 0);
+            assert_eq!(c.inner().0.last(), Some(&msg("b", b"2")));
+            assert_eq!(c.inner().0.len(), 4);
+        });
+    }
+
+    #[tokio::test]
+    async fn no_background_flush() {
+        let mut c = Coalescing::new(Recorder::default(), Duration::from_millis(50));
+
+        c.publish("a", b"1").await.unwrap();
+        c.publish("a", b"2").await.unwrap();
+
+        // No task is spawned to publish pending payloads, these wait for the caller
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert_eq!(c.inner().0.len(), 1);
+        assert_eq!(c.pending(), 1);
+
+        c.tick().await.unwrap();
+        assert_eq!(c.inner().0.last(), Some(&msg("a", b"2")));
+    }
+}
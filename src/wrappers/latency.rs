@@ -0,0 +1,77 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use log::trace;
+use futures::stream::{Stream, StreamExt};
+
+
+/// Stream adapter computing the age (end-to-end latency) of each message from a
+/// caller-provided origin timestamp extractor
+///
+/// Messages are emitted as `(topic, data, age)`, where age is `None` if no timestamp
+/// could be extracted or the timestamp is in the future (ie. due to clock skew).
+/// With the `metrics` feature ages are also recorded to the `iot_pal_message_age_us` histogram.
+pub struct Latency<S, F> {
+    inner: S,
+    extract: F,
+}
+
+impl <S, F> Latency<S, F>
+where
+    S: Stream<Item = (String, Vec<u8>)> + Unpin,
+    F: FnMut(&str, &[u8]) -> Option<SystemTime> + Unpin,
+{
+    /// Create a new latency adapter with the provided timestamp extractor
+    pub fn new(inner: S, extract: F) -> Self {
+        Self { inner, extract }
+    }
+
+    /// Fetch inner stream
+    pub fn inner<'a>(&'a mut self) -> &'a mut S {
+        &mut self.inner
+    }
+}
+
+/// Extension trait adding `with_latency` to message streams
+pub trait LatencyExt: Stream<Item = (String, Vec<u8>)> + Unpin + Sized {
+    /// Compute message ages using the provided timestamp extractor (see `Latency`)
+    fn with_latency<F>(self, extract: F) -> Latency<Self, F>
+    where
+        F: FnMut(&str, &[u8]) -> Option<SystemTime> + Unpin,
+    {
+        Latency::new(self, extract)
+    }
+}
+
+impl <S> LatencyExt for S where S: Stream<Item = (String, Vec<u8>)> + Unpin {}
+
+impl <S, F> Stream for Latency<S, F>
+where
+    S: Stream<Item = (String, Vec<u8>)> + Unpin,
+    F: FnMut(&str, &[u8]) -> Option<SystemTime> + Unpin,
+{
+    type Item = (String, Vec<u8>, Option<Duration>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let (topic, data) = match this.inner.poll_next_unpin(cx) {
+            Poll::Ready(Some(m)) => m,
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let age = (this.extract)(&topic, &data)
+            .and_then(|t| SystemTime::now().duration_since(t).ok());
+
+        if let Some(a) = age {
+            trace!("Message on {} age: {:?}", topic, a);
+
+            #[cfg(feature = "metrics")]
+            metrics::histogram!("iot_pal_message_age_us", a.as_micros() as u64, "topic" => topic.clone());
+        }
+
+        Poll::Ready(Some((topic, data, age)))
+    }
+}
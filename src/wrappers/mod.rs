@@ -0,0 +1,18 @@
+//! Wrappers and stream adapters composing over client implementations
+
+pub mod reorder;
+pub use reorder::Reorder;
+
+pub mod change_filter;
+pub use change_filter::ChangeFilter;
+
+pub mod latency;
+pub use latency::{Latency, LatencyExt};
+
+pub mod validated;
+pub use validated::{Validated, Validator};
+#[cfg(feature = "validate_jsonschema")]
+pub use validated::JsonSchemaValidator;
+
+pub mod coalescing;
+pub use coalescing::Coalescing;
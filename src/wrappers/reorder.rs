@@ -0,0 +1,311 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::collections::{HashMap, BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use futures::stream::{Stream, StreamExt};
+
+use crate::budget::{MemoryBudget, BudgetPolicy, Usage};
+use crate::clock::{Clock, SystemClock};
+
+
+/// Stream adapter restoring per-topic message ordering using a caller-provided sequence number
+///
+/// Messages are buffered per topic until the next expected sequence number arrives.
+/// The first message received on a topic sets the expected sequence for that topic.
+///
+/// If more than `window` messages are buffered for a topic the missing messages are
+/// considered lost, buffered messages are then released in order from the lowest
+/// sequence number and any late arrivals from before this point are dropped.
+///
+/// Messages with no sequence number are passed through immediately. Note MQTT packet IDs
+/// are reused and per-connection and so are not suitable for use as sequence numbers.
+///
+/// The window applies per topic, use `with_budget` to bound messages held across all topics.
+///
+/// Use `with_timeout` to also skip gaps held for longer than a timeout. As no timers are used
+/// expired gaps are released the next time the stream is polled (ie. on receipt of a message
+/// on any topic).
+pub struct Reorder<S, F> {
+    inner: S,
+    window: usize,
+    seq: F,
+    topics: HashMap<String, Topic>,
+    ready: VecDeque<(String, Vec<u8>)>,
+    budget: Option<MemoryBudget>,
+    /// Buffered count last reported to the budget
+    held: usize,
+    timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+struct Topic {
+    next: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// Time at which the topic started waiting on the current gap
+    since: Option<Instant>,
+}
+
+impl <S, F> Reorder<S, F>
+where
+    S: Stream<Item = (String, Vec<u8>)> + Unpin,
+    F: FnMut(&str, &[u8]) -> Option<u64> + Unpin,
+{
+    /// Create a new reordering adapter with the provided window and sequence extractor
+    pub fn new(inner: S, window: usize, seq: F) -> Self {
+        Self {
+            inner,
+            window,
+            seq,
+            topics: HashMap::new(),
+            ready: VecDeque::new(),
+            budget: None,
+            held: 0,
+            timeout: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Skip gaps that have been held for longer than the provided timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Use the provided clock for gap timeouts
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Bound and track messages held for reordering using the provided memory budget
+    ///
+    /// When the bound is reached `BudgetPolicy::Block` releases all buffered messages
+    /// (treating gaps as lost), otherwise newly received messages are dropped.
+    pub fn with_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.budget = Some(budget.clone());
+        self
+    }
+
+    /// Fetch the number of messages currently buffered for reordering
+    pub fn buffered(&self) -> usize {
+        self.topics.values().map(|t| t.pending.len()).sum()
+    }
+
+    /// Fetch inner stream
+    pub fn inner<'a>(&'a mut self) -> &'a mut S {
+        &mut self.inner
+    }
+
+    fn push(&mut self, topic: String, data: Vec<u8>, seq: u64) {
+        if let Some((limit, policy, usage)) = self.budget.as_ref().and_then(|b| b.reorder_limit().map(|l| (l, b.policy(), b.tracker()))) {
+            if self.buffered() >= limit {
+                match policy {
+                    BudgetPolicy::Block => {
+                        debug!("Reorder budget reached, releasing {} buffered messages", limit);
+                        self.release();
+                    },
+                    _ => {
+                        warn!("Reorder budget reached, dropping message on {} (seq: {})", topic, seq);
+                        usage.record_drop();
+                        return;
+                    },
+                }
+            }
+        }
+
+        self.insert(topic, data, seq);
+        self.track();
+    }
+
+    fn insert(&mut self, topic: String, data: Vec<u8>, seq: u64) {
+        let now = self.clock.now();
+        let t = self.topics.entry(topic.clone())
+            .or_insert_with(|| Topic{ next: seq, pending: BTreeMap::new(), since: None });
+
+        if seq < t.next {
+            warn!("Dropping late message on {} (seq: {}, expected: {})", topic, seq, t.next);
+            return;
+        }
+
+        t.pending.insert(seq, data);
+
+        // Skip the gap if the window is exceeded
+        if t.pending.len() > self.window {
+            let first = *t.pending.keys().next().unwrap();
+            debug!("Reorder window exceeded on {}, skipping {} to {}", topic, t.next, first);
+            t.next = first;
+        }
+
+        // Release in-order messages
+        while let Some(d) = t.pending.remove(&t.next) {
+            self.ready.push_back((topic.clone(), d));
+            t.next += 1;
+            t.since = None;
+        }
+
+        if !t.pending.is_empty() && t.since.is_none() {
+            t.since = Some(now);
+        }
+    }
+
+    /// Skip gaps held for longer than the configured timeout
+    fn expire(&mut self) {
+        let timeout = match self.timeout {
+            Some(t) => t,
+            None => return,
+        };
+        let now = self.clock.now();
+        let mut expired = false;
+
+        for (topic, t) in self.topics.iter_mut() {
+            match t.since {
+                Some(since) if now.saturating_duration_since(since) >= timeout => (),
+                _ => continue,
+            }
+
+            let first = *t.pending.keys().next().unwrap();
+            debug!("Reorder timeout on {}, skipping {} to {}", topic, t.next, first);
+            t.next = first;
+
+            while let Some(d) = t.pending.remove(&t.next) {
+                self.ready.push_back((topic.clone(), d));
+                t.next += 1;
+            }
+
+            // Restart the timeout for any following gap
+            t.since = match t.pending.is_empty() {
+                true => None,
+                false => Some(now),
+            };
+            expired = true;
+        }
+
+        if expired {
+            self.track();
+        }
+    }
+
+    fn drain(&mut self) {
+        for (topic, t) in self.topics.iter_mut() {
+            for (_, d) in std::mem::take(&mut t.pending) {
+                self.ready.push_back((topic.clone(), d));
+            }
+            t.since = None;
+        }
+        self.track();
+    }
+
+    /// Release all buffered messages in order, skipping any gaps
+    fn release(&mut self) {
+        for (topic, t) in self.topics.iter_mut() {
+            for (n, d) in std::mem::take(&mut t.pending) {
+                self.ready.push_back((topic.clone(), d));
+                t.next = n + 1;
+            }
+            t.since = None;
+        }
+        self.track();
+    }
+
+    /// Report buffered message changes to the memory budget
+    fn track(&mut self) {
+        if let Some(b) = &self.budget {
+            let n = self.topics.values().map(|t| t.pending.len()).sum();
+            Usage::update(&b.tracker().reorder_msgs, self.held, n);
+            self.held = n;
+        }
+    }
+}
+
+impl <S, F> Stream for Reorder<S, F>
+where
+    S: Stream<Item = (String, Vec<u8>)> + Unpin,
+    F: FnMut(&str, &[u8]) -> Option<u64> + Unpin,
+{
+    type Item = (String, Vec<u8>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            this.expire();
+
+            if let Some(m) = this.ready.pop_front() {
+                return Poll::Ready(Some(m));
+            }
+
+            let (topic, data) = match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(m)) => m,
+                Poll::Ready(None) => {
+                    // Release anything remaining when the inner stream ends
+                    this.drain();
+                    return Poll::Ready(this.ready.pop_front());
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match (this.seq)(&topic, &data) {
+                Some(n) => this.push(topic, data, n),
+                None => return Poll::Ready(Some((topic, data))),
+            }
+        }
+    }
+}
+
+impl <S, F> Drop for Reorder<S, F> {
+    fn drop(&mut self) {
+        if let Some(b) = &self.budget {
+            Usage::update(&b.tracker().reorder_msgs, self.held, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use futures::FutureExt;
+
+    use crate::clock::TestClock;
+
+    fn seq(_t: &str, d: &[u8]) -> Option<u64> {
+        Some(d[0] as u64)
+    }
+
+    #[test]
+    fn gap_timeout() {
+        let clock = TestClock::new();
+        let (tx, rx) = mpsc::unbounded();
+        let mut r = Reorder::new(rx, 10, seq)
+            .with_timeout(Duration::from_secs(5))
+            .with_clock(Arc::new(clock.clone()));
+
+        // Gap at 1 holds 2
+        tx.unbounded_send(("a".to_string(), vec![0])).unwrap();
+        tx.unbounded_send(("a".to_string(), vec![2])).unwrap();
+        assert_eq!(r.next().now_or_never(), Some(Some(("a".to_string(), vec![0]))));
+        assert!(r.next().now_or_never().is_none());
+        assert_eq!(r.buffered(), 1);
+
+        // Not yet expired
+        clock.advance(Duration::from_secs(4));
+        tx.unbounded_send(("b".to_string(), vec![0])).unwrap();
+        assert_eq!(r.next().now_or_never(), Some(Some(("b".to_string(), vec![0]))));
+        assert!(r.next().now_or_never().is_none());
+        assert_eq!(r.buffered(), 1);
+
+        // Expired, gap is skipped on the next poll
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(r.next().now_or_never(), Some(Some(("a".to_string(), vec![2]))));
+        assert_eq!(r.buffered(), 0);
+
+        // Late arrivals are then dropped
+        tx.unbounded_send(("a".to_string(), vec![1])).unwrap();
+        tx.unbounded_send(("a".to_string(), vec![3])).unwrap();
+        assert_eq!(r.next().now_or_never(), Some(Some(("a".to_string(), vec![3]))));
+    }
+}
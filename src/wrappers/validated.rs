@@ -0,0 +1,262 @@
+use async_trait::async_trait;
+use anyhow::Error;
+
+use crate::clients::ClientPub;
+#[cfg(all(feature = "serde", feature = "serde_json"))]
+use crate::stores::Store;
+
+
+/// Payload validator, applied to outgoing messages and stored records
+pub trait Validator: Send + Sync {
+    /// Validate a payload for the provided topic (or index for stores),
+    /// returning a descriptive error on failure
+    fn validate(&self, topic: &str, data: &[u8]) -> Result<(), Error>;
+}
+
+/// Validator implementation for closures
+impl <F> Validator for F
+where
+    F: Fn(&str, &[u8]) -> Result<(), Error> + Send + Sync,
+{
+    fn validate(&self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        (self)(topic, data)
+    }
+}
+
+/// Wrapper validating payloads before publishing or storing these
+///
+/// Payloads failing validation are not forwarded and the validation error is returned,
+/// rejects may also be passed to a dead-letter sink for later inspection.
+pub struct Validated<T> {
+    inner: T,
+    validator: Box<dyn Validator>,
+    dead_letter: Option<Box<dyn FnMut(&str, &[u8], &Error) + Send>>,
+}
+
+impl <T> Validated<T> {
+    /// Create a new validating wrapper
+    pub fn new<V: Validator + 'static>(inner: T, validator: V) -> Self {
+        Self {
+            inner,
+            validator: Box::new(validator),
+            dead_letter: None,
+        }
+    }
+
+    /// Set a dead-letter sink called with the topic, payload, and error for each rejected payload
+    pub fn with_dead_letter<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&str, &[u8], &Error) + Send + 'static,
+    {
+        self.dead_letter = Some(Box::new(f));
+        self
+    }
+
+    /// Fetch inner client / store
+    pub fn inner<'a>(&'a mut self) -> &'a mut T {
+        &mut self.inner
+    }
+
+    /// Consume the wrapper, returning the inner client / store
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Validate a payload, passing rejects to the dead-letter sink
+    fn check(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        if let Err(e) = self.validator.validate(topic, data) {
+            if let Some(d) = &mut self.dead_letter {
+                (d)(topic, data, &e);
+            }
+
+            return Err(e.context(format!("Validation failed for {}", topic)))
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl <C: ClientPub + Send> ClientPub for Validated<C> {
+    /// Validate and publish data to a topic
+    async fn publish(&mut self, topic: &str, data: &[u8]) -> Result<(), Error> {
+        self.check(topic, data)?;
+        self.inner.publish(topic, data).await
+    }
+}
+
+/// Validated store, records are JSON encoded and validated using the inner store's record key
+/// (see `Store::key`) as the topic, searches are passed through to the inner store
+#[cfg(all(feature = "serde", feature = "serde_json"))]
+#[async_trait]
+impl <R, S> Store<R> for Validated<S>
+where
+    R: serde::Serialize + Send + 'static,
+    S: Store<R>,
+{
+    type Query = S::Query;
+
+    async fn store(&mut self, record: R) -> Result<(), Error> {
+        let topic = self.inner.key(&record);
+        let data = serde_json::to_vec(&record)?;
+        self.check(&topic, &data)?;
+
+        self.inner.store(record).await
+    }
+
+    async fn search(&mut self, query: S::Query) -> Result<Vec<R>, Error> {
+        self.inner.search(query).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush().await
+    }
+
+    fn key(&self, record: &R) -> String {
+        self.inner.key(record)
+    }
+}
+
+#[cfg(feature = "store_elastic")]
+mod elastic {
+    use anyhow::Error;
+    use elastic::prelude::DocumentType;
+    use serde::Serialize;
+
+    use crate::stores::ElasticStore;
+    use super::Validated;
+
+    impl Validated<ElasticStore> {
+        /// Validate the JSON encoded record (using the record index as the topic, as with `Store::store`) and store this
+        pub async fn store<R: DocumentType + Serialize + Send + 'static>(&mut self, record: R) -> Result<(), Error> {
+            let data = serde_json::to_vec(&record)?;
+            self.check(&ElasticStore::record_key(&record), &data)?;
+
+            self.inner.store(record).await
+        }
+    }
+}
+
+/// JSON Schema validator, enabled with the `validate_jsonschema` feature
+#[cfg(feature = "validate_jsonschema")]
+pub struct JsonSchemaValidator {
+    schema: jsonschema::JSONSchema<'static>,
+}
+
+#[cfg(feature = "validate_jsonschema")]
+impl JsonSchemaValidator {
+    /// Compile a validator from the provided JSON Schema
+    ///
+    /// Note the schema is leaked to satisfy the compiled schema lifetime,
+    /// validators are expected to live for the duration of the application.
+    pub fn new(schema: serde_json::Value) -> Result<Self, Error> {
+        let schema: &'static serde_json::Value = Box::leak(Box::new(schema));
+
+        let schema = jsonschema::JSONSchema::compile(schema, None)
+            .map_err(|e| Error::msg(format!("Invalid JSON schema: {:?}", e)))?;
+
+        Ok(Self{ schema })
+    }
+}
+
+#[cfg(feature = "validate_jsonschema")]
+impl Validator for JsonSchemaValidator {
+    fn validate(&self, _topic: &str, data: &[u8]) -> Result<(), Error> {
+        let v: serde_json::Value = serde_json::from_slice(data)?;
+
+        if let Err(errors) = self.schema.validate(&v) {
+            let errors: Vec<_> = errors.map(|e| e.to_string()).collect();
+            return Err(Error::msg(format!("Schema validation failed: {}", errors.join(", "))))
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "serde", feature = "serde_json"))]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    /// In-memory store for exercising the generic wrapper
+    #[derive(Default)]
+    struct MemStore {
+        records: Vec<u32>,
+    }
+
+    #[async_trait]
+    impl Store<u32> for MemStore {
+        type Query = ();
+
+        async fn store(&mut self, record: u32) -> Result<(), Error> {
+            self.records.push(record);
+            Ok(())
+        }
+
+        async fn search(&mut self, _query: ()) -> Result<Vec<u32>, Error> {
+            Ok(self.records.clone())
+        }
+    }
+
+    /// Store records via any backend, fails to compile if `Validated<S>` is not a `Store`
+    async fn store_all<S: Store<u32>>(s: &mut S, records: &[u32]) -> Vec<bool> {
+        let mut res = vec![];
+        for r in records {
+            res.push(s.store(*r).await.is_ok());
+        }
+        res
+    }
+
+    #[test]
+    fn generic_store() {
+        let rejects = Arc::new(Mutex::new(vec![]));
+        let r = rejects.clone();
+
+        let mut s = Validated::new(MemStore::default(), |topic: &str, data: &[u8]| {
+            assert_eq!(topic, "u32");
+            match data.len() {
+                1 => Ok(()),
+                _ => Err(Error::msg("too long")),
+            }
+        }).with_dead_letter(move |_t: &str, d: &[u8], _e: &Error| r.lock().unwrap().push(d.to_vec()));
+
+        let res = futures::executor::block_on(store_all(&mut s, &[1, 22, 3]));
+        assert_eq!(res, vec![true, false, true]);
+
+        let found = futures::executor::block_on(s.search(())).unwrap();
+        assert_eq!(found, vec![1, 3]);
+
+        assert_eq!(rejects.lock().unwrap().as_slice(), &[b"22".to_vec()]);
+    }
+
+    #[cfg(feature = "store_elastic")]
+    #[test]
+    fn elastic_store_topics() {
+        use elastic_derive::ElasticType;
+
+        use crate::stores::ElasticStore;
+
+        #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, ElasticType)]
+        #[elastic(index = "iot-pal-validated")]
+        struct Reading {
+            value: u32,
+        }
+
+        let topics = Arc::new(Mutex::new(vec![]));
+        let t = topics.clone();
+
+        // Rejects all records, so nothing is sent to the (unreachable) store
+        let store = ElasticStore::new("http://127.0.0.1:1").unwrap();
+        let mut s = Validated::new(store, move |topic: &str, _data: &[u8]| {
+            t.lock().unwrap().push(topic.to_string());
+            Err(Error::msg("rejected"))
+        });
+
+        // Inherent and `Store` paths validate against the same topic
+        assert!(futures::executor::block_on(s.store(Reading{ value: 1 })).is_err());
+        assert!(futures::executor::block_on(Store::store(&mut s, Reading{ value: 2 })).is_err());
+
+        assert_eq!(topics.lock().unwrap().as_slice(), &["iot-pal-validated".to_string(), "iot-pal-validated".to_string()]);
+    }
+}